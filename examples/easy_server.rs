@@ -1,14 +1,9 @@
 use std::env;
-use std::{future, net::SocketAddr, time::Duration};
+use std::{net::SocketAddr, time::Duration};
 
 use esphome_native_api::{
-    esphomeapi::EspHomeApi,
-    esphomeserver::EspHomeServer,
-    parser::ProtoMessage,
-    proto::{
-        ListEntitiesBinarySensorResponse, ListEntitiesButtonResponse, ListEntitiesLightResponse,
-        ListEntitiesSensorResponse, ListEntitiesSwitchResponse, SensorStateResponse,
-    },
+    esphomeserver::{BinarySensor, Button, EspHomeServer, Light, Sensor, Switch},
+    proto::{ColorMode, SensorStateClass, SensorStateResponse},
 };
 use log::{LevelFilter, debug, info};
 use tokio::{net::TcpSocket, signal, time::sleep};
@@ -34,169 +29,120 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     debug!("Listening on: {}", addr);
 
-    let main_server = async {
-        loop {
-            let (stream, _) = listener
-                .accept()
-                .await
-                .expect("Failed to accept connection");
-            debug!("Accepted request from {}", stream.peer_addr().unwrap());
-
-            // Spawn a tokio task to serve multiple connections concurrently
-            // tokio::task::spawn(async move {
-
-            //     let mut server = EspHomeServer::builder()
-            //             .api_version_major(1)
-            //             .api_version_minor(42)
-            //             // .password("password".to_string())
-            //             .server_info("test_server_info".to_string())
-            //             .name("test_device".to_string())
-            //             .friendly_name("friendly_test_device".to_string())
-            //             .bluetooth_mac_address("B0:00:00:00:00:00".to_string())
-            //             .mac("00:00:00:00:00:01".to_string())
-            //             .manufacturer("Test Inc.".to_string())
-            //             .model("Test Model".to_string())
-            //             .suggested_area("Test Area".to_string())
-            //             .build();
-
-            //     // All supported entities in alphabetical order
-            //     let binary_sensor =
-            //         ProtoMessage::ListEntitiesBinarySensorResponse(
-            //             ListEntitiesBinarySensorResponse {
-            //                 object_id: "test_binary_sensor_object_id".to_string(),
-            //                 key: 3,
-            //                 name: "test_binary_sensor".to_string(),
-            //                 unique_id: "test_binary_sensor_unique_id".to_string(),
-            //                 icon: "mdi:test-binary-sensor-icon".to_string(),
-            //                 device_class: "test_binary_sensor_device_class".to_string(),
-            //                 is_status_binary_sensor: true,
-            //                 disabled_by_default: false,
-            //                 entity_category: 0, // EntityCategory::None as i32
-            //             },
-            //         );
-            //     server.add_entity("test_binary_sensor", binary_sensor.clone());
-
-            //     let button = ProtoMessage::ListEntitiesButtonResponse(
-            //         ListEntitiesButtonResponse {
-            //             object_id: "test_button_object_id".to_string(),
-            //             key: 0,
-            //             name: "test_button".to_string(),
-            //             unique_id: "test_button_unique_id".to_string(),
-            //             icon: "mdi:test-button-icon".to_string(),
-            //             disabled_by_default: false,
-            //             entity_category: 0,
-            //             device_class: "test_button_device_class".to_string(),
-            //         },
-            //     );
-            //     server.add_entity("test_button", button.clone());
-
-            //     let light = ProtoMessage::ListEntitiesLightResponse(
-            //         ListEntitiesLightResponse {
-            //             object_id: "test_light_object_id".to_string(),
-            //             key: 4,
-            //             name: "test_light".to_string(),
-            //             unique_id: "test_light_unique_id".to_string(),
-            //             icon: "mdi:test-light-icon".to_string(),
-            //             disabled_by_default: false,
-            //             entity_category: 0, // EntityCategory::None as i32
-            //             supported_color_modes: vec![], // Can be populated based on capabilities
-            //             min_mireds: 153.0,
-            //             max_mireds: 500.0,
-            //             effects: vec![], // Light effects can be added later
-            //             legacy_supports_brightness: false,
-            //             legacy_supports_rgb: false,
-            //             legacy_supports_white_value: false,
-            //             legacy_supports_color_temperature: false,
-            //         },
-            //     );
-            //     server.add_entity("test_light", light.clone());
-
-            //     let sensor = ProtoMessage::ListEntitiesSensorResponse(
-            //         ListEntitiesSensorResponse {
-            //             object_id: "test_sensor_object_id".to_string(),
-            //             key: 2,
-            //             name: "test_sensor".to_string(),
-            //             unique_id: "test_sensor_unique_id".to_string(),
-            //             icon: "mdi:test-sensor-icon".to_string(),
-            //             unit_of_measurement: "°C".to_string(),
-            //             accuracy_decimals: 2,
-            //             force_update: false,
-            //             device_class: "temperature".to_string(),
-            //             state_class: 1, // SensorStateClass::StateClassMeasurement as i32
-            //             legacy_last_reset_type: 0, // SensorLastResetType::LastResetNone as i32
-            //             disabled_by_default: false,
-            //             entity_category: 0, // EntityCategory::None as i32
-            //         },
-            //     );
-            //     server.add_entity("test_sensor", sensor.clone());
-
-            //     let switch = ProtoMessage::ListEntitiesSwitchResponse(
-            //         ListEntitiesSwitchResponse {
-            //             object_id: "test_switch_object_id".to_string(),
-            //             key: 1,
-            //             name: "test_switch".to_string(),
-            //             unique_id: "test_switch_unique_id".to_string(),
-            //             icon: "mdi:test-switch-icon".to_string(),
-            //             device_class: "test_switch_device_class".to_string(),
-            //             disabled_by_default: false,
-            //             entity_category: 0,
-            //             assumed_state: false,
-            //         }
-            //     );
-            //     server.add_entity("test_switch", switch.clone());
-
-            //     let (tx) = server.start(stream).await
-            //         .expect("Failed to start server");
-
-            //     debug!("Server started");
-            //     sleep(Duration::from_secs(3)).await;
-
-            //     let message = ProtoMessage::SensorStateResponse(
-            //         SensorStateResponse {
-            //             key: 0,
-            //             state: 25.0,
-            //             missing_state: false,
-            //         },
-            //     );
-            //     for n in 1..=10 {
-            //     sleep(Duration::from_secs(3)).await;
-            //         debug!("Sending message number {}", n);
-            //         tx.send(message.clone()).await.expect("Failed to send message");
-            //     }
-
-            //     debug!("Queue message to sent");
-
-            //     // Wait indefinitely for the interrupts
-            //     let future = future::pending();
-            //     let () = future.await;
-            //     tx.send(message.clone()).await.expect("Failed to send message");
-            // });
+    let mut server = EspHomeServer::builder()
+        .api_version_major(1)
+        .api_version_minor(42)
+        .server_info("test_server_info".to_string())
+        .name("test_device".to_string())
+        .friendly_name("friendly_test_device".to_string())
+        .bluetooth_mac_address("B0:00:00:00:00:00".to_string())
+        .mac("00:00:00:00:00:01".to_string())
+        .manufacturer("Test Inc.".to_string())
+        .model("Test Model".to_string())
+        .suggested_area("Test Area".to_string())
+        .build();
+
+    // All supported entities in alphabetical order
+    server.add_entity(
+        "test_binary_sensor",
+        BinarySensor {
+            name: "test_binary_sensor".to_string(),
+            icon: "mdi:test-binary-sensor-icon".to_string(),
+            device_class: "test_binary_sensor_device_class".to_string(),
+            is_status_binary_sensor: true,
+            ..Default::default()
+        }
+        .into(),
+    )?;
+    server.add_entity(
+        "test_button",
+        Button {
+            name: "test_button".to_string(),
+            icon: "mdi:test-button-icon".to_string(),
+            device_class: "test_button_device_class".to_string(),
+            ..Default::default()
+        }
+        .into(),
+    )?;
+    server.add_entity(
+        "test_light",
+        Light {
+            name: "test_light".to_string(),
+            icon: "mdi:test-light-icon".to_string(),
+            supported_color_modes: vec![ColorMode::Brightness],
+            min_mireds: 153.0,
+            max_mireds: 500.0,
+            ..Default::default()
+        }
+        .into(),
+    )?;
+    server.add_entity(
+        "test_sensor",
+        Sensor {
+            name: "test_sensor".to_string(),
+            icon: "mdi:test-sensor-icon".to_string(),
+            unit_of_measurement: "°C".to_string(),
+            accuracy_decimals: 2,
+            device_class: "temperature".to_string(),
+            state_class: SensorStateClass::Measurement,
+            ..Default::default()
+        }
+        .into(),
+    )?;
+    server.add_entity(
+        "test_switch",
+        Switch {
+            name: "test_switch".to_string(),
+            icon: "mdi:test-switch-icon".to_string(),
+            device_class: "test_switch_device_class".to_string(),
+            ..Default::default()
+        }
+        .into(),
+    )?;
+
+    let publish_states = async {
+        for n in 1.. {
+            sleep(Duration::from_secs(3)).await;
+            let state = SensorStateResponse {
+                state: 20.0 + (n % 10) as f32,
+                ..Default::default()
+            };
+            let delivered = server.publish_state("test_sensor", state).await.unwrap();
+            debug!("Sent state number {} to {} clients", n, delivered);
         }
     };
 
-    let ctrl_c = async {
-        signal::ctrl_c()
-            .await
-            .expect("failed to install Ctrl+C handler");
-    };
-
-    #[cfg(unix)]
-    let terminate = async {
-        signal::unix::signal(signal::unix::SignalKind::terminate())
-            .expect("failed to install signal handler")
-            .recv()
-            .await;
-    };
-
-    #[cfg(not(unix))]
-    let terminate = std::future::pending::<()>();
+    let shutdown = server.shutdown_handle();
+    tokio::spawn(async move {
+        let ctrl_c = async {
+            signal::ctrl_c()
+                .await
+                .expect("failed to install Ctrl+C handler");
+        };
+
+        #[cfg(unix)]
+        let terminate = async {
+            signal::unix::signal(signal::unix::SignalKind::terminate())
+                .expect("failed to install signal handler")
+                .recv()
+                .await;
+        };
+
+        #[cfg(not(unix))]
+        let terminate = std::future::pending::<()>();
+
+        tokio::select! {
+            _ = ctrl_c => {},
+            _ = terminate => {},
+        }
+        shutdown.cancel();
+    });
 
     tokio::select! {
-        _ = main_server => {},
-        _ = ctrl_c => {},
-        _ = terminate => {},
+        served = server.serve_listener(listener) => served?,
+        _ = publish_states => {},
     }
     info!("Stopped");
 
-    std::process::exit(0);
+    Ok(())
 }
@@ -0,0 +1,770 @@
+//! Device side of the Bluetooth proxy messages.
+//!
+//! An ESPHome Bluetooth proxy scans for BLE advertisements and connects to
+//! peripherals on behalf of Home Assistant. To offer this from Rust, implement
+//! [`BluetoothProxyBackend`] on top of the local radio and pass it to
+//! [`crate::esphomeapi::EspHomeApi`]. The connection then answers all Bluetooth
+//! proxy requests itself: it forwards advertisements, keeps track of the connection
+//! slots shared by all API connections of the device, answers the requests for each
+//! peripheral in order and reports the `bluetooth_proxy_feature_flags` of the backend in the
+//! `DeviceInfoResponse`.
+//!
+//! [`simulated::SimulatedBackend`] is a backend without a radio, e.g. for tests.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! use esphome_native_api::bluetooth_proxy::simulated::{SimulatedBackend, SimulatedPeripheral};
+//! use esphome_native_api::esphomeapi::EspHomeApi;
+//! use std::sync::Arc;
+//! use tokio::net::TcpListener;
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     let backend = Arc::new(SimulatedBackend::new());
+//!     backend.add_peripheral(
+//!         SimulatedPeripheral::builder()
+//!             .address(0xA4C1380D1E2F)
+//!             .name("Thermometer".to_string())
+//!             .build(),
+//!     );
+//!
+//!     let api = EspHomeApi::builder()
+//!         .name("bluetooth-proxy".to_string())
+//!         .bluetooth_proxy(backend)
+//!         .build();
+//!
+//!     let listener = TcpListener::bind("0.0.0.0:6053").await?;
+//!     let (stream, _) = listener.accept().await?;
+//!     let (_tx, mut rx) = api.start(stream).await?;
+//!     while let Ok(message) = rx.recv().await {
+//!         println!("Received: {:?}", message);
+//!     }
+//!     Ok(())
+//! }
+//! ```
+
+pub mod simulated;
+
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use log::debug;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio_stream::StreamExt;
+
+use crate::esphomeclient::advertisements::Advertisement;
+use crate::parser::ProtoMessage;
+use crate::proto::{
+    BluetoothConnectionsFreeResponse, BluetoothDeviceClearCacheResponse,
+    BluetoothDeviceConnectionResponse, BluetoothDevicePairingResponse, BluetoothDeviceRequest,
+    BluetoothDeviceRequestType, BluetoothDeviceUnpairingResponse, BluetoothGattErrorResponse,
+    BluetoothGattGetServicesDoneResponse, BluetoothGattGetServicesResponse,
+    BluetoothGattNotifyDataResponse, BluetoothGattNotifyResponse, BluetoothGattReadResponse,
+    BluetoothGattService, BluetoothGattWriteResponse, BluetoothLeAdvertisementResponse,
+    BluetoothLeRawAdvertisement, BluetoothLeRawAdvertisementsResponse, BluetoothServiceData,
+};
+
+/// The proxy forwards advertisements it receives.
+pub const FEATURE_PASSIVE_SCAN: u32 = 1 << 0;
+/// The proxy connects to peripherals.
+pub const FEATURE_ACTIVE_CONNECTIONS: u32 = 1 << 1;
+/// The proxy caches the services of peripherals.
+pub const FEATURE_REMOTE_CACHING: u32 = 1 << 2;
+/// The proxy pairs with peripherals.
+pub const FEATURE_PAIRING: u32 = 1 << 3;
+/// The proxy clears its service cache on request.
+pub const FEATURE_CACHE_CLEARING: u32 = 1 << 4;
+/// The proxy forwards advertisements undecoded.
+pub const FEATURE_RAW_ADVERTISEMENTS: u32 = 1 << 5;
+
+/// The peripheral is not connected.
+pub const ERROR_NOT_CONNECTED: i32 = -1;
+/// The handle does not belong to a characteristic or descriptor.
+pub const ERROR_INVALID_HANDLE: i32 = 0x01;
+/// All connection slots are in use.
+pub const ERROR_NO_RESOURCES: i32 = 0x80;
+/// Generic GATT failure.
+pub const ERROR_GATT: i32 = 0x85;
+
+/// Subscription flag asking for raw advertisements.
+const FLAG_RAW_ADVERTISEMENTS: u32 = 1;
+/// ESPHome sends at most this many raw advertisements per message.
+const ADVERTISEMENT_BATCH_SIZE: usize = 16;
+const ADVERTISEMENT_BATCH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Error of a backend operation, reported to the client as error code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BluetoothProxyError(pub i32);
+
+impl fmt::Display for BluetoothProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Bluetooth proxy error {}", self.0)
+    }
+}
+
+impl std::error::Error for BluetoothProxyError {}
+
+/// Result of a backend operation.
+pub type BackendFuture<'a, T> = BoxFuture<'a, Result<T, BluetoothProxyError>>;
+
+/// Something that happened on a connected peripheral.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeripheralEvent {
+    /// A characteristic with enabled notifications changed.
+    Notification {
+        /// Address of the peripheral
+        address: u64,
+        /// Handle of the characteristic
+        handle: u32,
+        /// New value
+        data: Vec<u8>,
+    },
+    /// The peripheral disconnected on its own.
+    Disconnected {
+        /// Address of the peripheral
+        address: u64,
+        /// Reason reported to the client
+        error: i32,
+    },
+}
+
+/// The radio behind a Bluetooth proxy.
+///
+/// Addresses are MAC addresses as numbers and handles are the attribute handles of
+/// the services returned by [`BluetoothProxyBackend::services`]. Operations are only
+/// called for peripherals connected through [`BluetoothProxyBackend::connect`].
+pub trait BluetoothProxyBackend: Send + Sync + 'static {
+    /// Feature flags reported in the `DeviceInfoResponse`.
+    fn feature_flags(&self) -> u32 {
+        FEATURE_PASSIVE_SCAN
+            | FEATURE_ACTIVE_CONNECTIONS
+            | FEATURE_REMOTE_CACHING
+            | FEATURE_PAIRING
+            | FEATURE_CACHE_CLEARING
+            | FEATURE_RAW_ADVERTISEMENTS
+    }
+
+    /// Number of peripherals that can be connected at the same time.
+    fn connection_slots(&self) -> u32 {
+        3
+    }
+
+    /// Starts scanning and returns the received advertisements.
+    ///
+    /// Scanning stops when the stream is dropped.
+    fn scan(&self) -> BoxStream<'static, BluetoothLeRawAdvertisement>;
+
+    /// Connects to a peripheral and resolves with the negotiated MTU.
+    ///
+    /// Notifications and unexpected disconnects of the peripheral are sent to
+    /// `events` until it is disconnected.
+    fn connect(
+        &self,
+        address: u64,
+        address_type: Option<u32>,
+        use_cache: bool,
+        events: mpsc::Sender<PeripheralEvent>,
+    ) -> BackendFuture<'_, u32>;
+
+    /// Disconnects from a peripheral.
+    fn disconnect(&self, address: u64) -> BackendFuture<'_, ()>;
+
+    /// Discovers the services of a peripheral.
+    fn services(&self, address: u64) -> BackendFuture<'_, Vec<BluetoothGattService>>;
+
+    /// Reads a characteristic.
+    fn read(&self, address: u64, handle: u32) -> BackendFuture<'_, Vec<u8>>;
+
+    /// Writes a characteristic, with or without response of the peripheral.
+    fn write(
+        &self,
+        address: u64,
+        handle: u32,
+        data: Vec<u8>,
+        response: bool,
+    ) -> BackendFuture<'_, ()>;
+
+    /// Reads a descriptor.
+    fn read_descriptor(&self, address: u64, handle: u32) -> BackendFuture<'_, Vec<u8>>;
+
+    /// Writes a descriptor.
+    fn write_descriptor(&self, address: u64, handle: u32, data: Vec<u8>) -> BackendFuture<'_, ()>;
+
+    /// Enables or disables notifications of a characteristic.
+    fn notify(&self, address: u64, handle: u32, enable: bool) -> BackendFuture<'_, ()>;
+
+    /// Pairs with a peripheral.
+    fn pair(&self, address: u64) -> BackendFuture<'_, ()>;
+
+    /// Removes the pairing of a peripheral.
+    fn unpair(&self, address: u64) -> BackendFuture<'_, ()>;
+
+    /// Clears the cached services of a peripheral.
+    fn clear_cache(&self, address: u64) -> BackendFuture<'_, ()>;
+}
+
+/// Returns whether the Bluetooth proxy of a connection answers `message`.
+pub(crate) fn is_request(message: &ProtoMessage) -> bool {
+    matches!(
+        message,
+        ProtoMessage::SubscribeBluetoothLeAdvertisementsRequest(_)
+            | ProtoMessage::UnsubscribeBluetoothLeAdvertisementsRequest(_)
+            | ProtoMessage::SubscribeBluetoothConnectionsFreeRequest(_)
+            | ProtoMessage::BluetoothDeviceRequest(_)
+            | ProtoMessage::BluetoothGattGetServicesRequest(_)
+            | ProtoMessage::BluetoothGattReadRequest(_)
+            | ProtoMessage::BluetoothGattWriteRequest(_)
+            | ProtoMessage::BluetoothGattReadDescriptorRequest(_)
+            | ProtoMessage::BluetoothGattWriteDescriptorRequest(_)
+            | ProtoMessage::BluetoothGattNotifyRequest(_)
+    )
+}
+
+/// Returns the peripheral a request is for.
+fn request_address(message: &ProtoMessage) -> u64 {
+    match message {
+        ProtoMessage::BluetoothDeviceRequest(request) => request.address,
+        ProtoMessage::BluetoothGattGetServicesRequest(request) => request.address,
+        ProtoMessage::BluetoothGattReadRequest(request) => request.address,
+        ProtoMessage::BluetoothGattWriteRequest(request) => request.address,
+        ProtoMessage::BluetoothGattReadDescriptorRequest(request) => request.address,
+        ProtoMessage::BluetoothGattWriteDescriptorRequest(request) => request.address,
+        ProtoMessage::BluetoothGattNotifyRequest(request) => request.address,
+        _ => 0,
+    }
+}
+
+/// Connection slots of a backend, shared by all API connections of a device.
+pub(crate) struct Slots {
+    state: watch::Sender<SlotState>,
+    connections: AtomicUsize,
+}
+
+impl Default for Slots {
+    fn default() -> Self {
+        Slots {
+            state: watch::channel(SlotState::default()).0,
+            connections: AtomicUsize::new(0),
+        }
+    }
+}
+
+#[derive(Default)]
+struct SlotState {
+    /// Connected peripherals and the API connection they were connected for.
+    allocated: Vec<(u64, usize)>,
+    /// API connection that made the last change.
+    changed_by: usize,
+}
+
+struct Proxy {
+    backend: Arc<dyn BluetoothProxyBackend>,
+    answers: mpsc::Sender<ProtoMessage>,
+    events: mpsc::Sender<PeripheralEvent>,
+    slots: Arc<Slots>,
+    /// Identifies the API connection in the shared slots.
+    connection: usize,
+    subscribed: AtomicBool,
+}
+
+/// Starts answering the Bluetooth proxy requests of one API connection.
+///
+/// Requests are passed through the returned sender. Once it is dropped, all
+/// peripherals connected for this API connection are disconnected.
+pub(crate) fn spawn(
+    backend: Arc<dyn BluetoothProxyBackend>,
+    slots: Arc<Slots>,
+    answers: mpsc::Sender<ProtoMessage>,
+) -> mpsc::Sender<ProtoMessage> {
+    let (requests_tx, mut requests_rx) = mpsc::channel::<ProtoMessage>(16);
+    let (events_tx, mut events_rx) = mpsc::channel::<PeripheralEvent>(64);
+    let mut slots_rx = slots.state.subscribe();
+    let proxy = Arc::new(Proxy {
+        backend,
+        answers,
+        events: events_tx,
+        connection: slots.connections.fetch_add(1, Ordering::SeqCst),
+        slots,
+        subscribed: AtomicBool::new(false),
+    });
+
+    tokio::spawn(async move {
+        let mut scan_task: Option<JoinHandle<()>> = None;
+        let mut queues: HashMap<u64, mpsc::Sender<ProtoMessage>> = HashMap::new();
+        loop {
+            tokio::select! {
+                request = requests_rx.recv() => match request {
+                    Some(ProtoMessage::SubscribeBluetoothLeAdvertisementsRequest(request)) => {
+                        if let Some(scan_task) = scan_task.take() {
+                            scan_task.abort();
+                        }
+                        let raw = request.flags & FLAG_RAW_ADVERTISEMENTS != 0;
+                        scan_task = Some(tokio::spawn(proxy.clone().forward_advertisements(raw)));
+                    }
+                    Some(ProtoMessage::UnsubscribeBluetoothLeAdvertisementsRequest(_)) => {
+                        if let Some(scan_task) = scan_task.take() {
+                            scan_task.abort();
+                        }
+                    }
+                    Some(ProtoMessage::SubscribeBluetoothConnectionsFreeRequest(_)) => {
+                        proxy.subscribed.store(true, Ordering::SeqCst);
+                        proxy.send_connections_free(true).await;
+                    }
+                    // Not queued, so that it cancels a pending connect.
+                    Some(ProtoMessage::BluetoothDeviceRequest(request))
+                        if request.request_type == BluetoothDeviceRequestType::Disconnect as i32 =>
+                    {
+                        tokio::spawn(
+                            proxy.clone().handle(ProtoMessage::BluetoothDeviceRequest(request)),
+                        );
+                    }
+                    Some(request) => {
+                        let queue = queues
+                            .entry(request_address(&request))
+                            .or_insert_with(|| proxy.clone().spawn_queue());
+                        let _ = queue.send(request).await;
+                    }
+                    None => break,
+                },
+                Ok(()) = slots_rx.changed() => {
+                    let changed_by = slots_rx.borrow_and_update().changed_by;
+                    // Changes of this connection are reported right away.
+                    if changed_by != proxy.connection {
+                        proxy.send_connections_free(false).await;
+                    }
+                }
+                Some(event) = events_rx.recv() => match event {
+                    PeripheralEvent::Notification { address, handle, data } => {
+                        proxy
+                            .send(ProtoMessage::BluetoothGattNotifyDataResponse(
+                                BluetoothGattNotifyDataResponse { address, handle, data },
+                            ))
+                            .await;
+                    }
+                    PeripheralEvent::Disconnected { address, error } => {
+                        proxy.disconnected(address, error).await;
+                    }
+                },
+            }
+        }
+
+        debug!("Bluetooth proxy stopped");
+        if let Some(scan_task) = scan_task {
+            scan_task.abort();
+        }
+        for address in proxy.release_all() {
+            let _ = proxy.backend.disconnect(address).await;
+        }
+    });
+
+    requests_tx
+}
+
+impl Proxy {
+    async fn send(&self, message: ProtoMessage) {
+        // Fails only once the connection is gone.
+        let _ = self.answers.send(message).await;
+    }
+
+    /// Starts a task answering the requests for one peripheral in order.
+    fn spawn_queue(self: Arc<Self>) -> mpsc::Sender<ProtoMessage> {
+        let (tx, mut rx) = mpsc::channel::<ProtoMessage>(16);
+        tokio::spawn(async move {
+            while let Some(request) = rx.recv().await {
+                self.clone().handle(request).await;
+            }
+        });
+        tx
+    }
+
+    /// Sends the free connection slots, if they are subscribed or `force`d.
+    async fn send_connections_free(&self, force: bool) {
+        if !self.subscribed.load(Ordering::SeqCst) && !force {
+            return;
+        }
+        let response = {
+            let state = self.slots.state.borrow();
+            let limit = self.backend.connection_slots();
+            BluetoothConnectionsFreeResponse {
+                free: limit.saturating_sub(state.allocated.len() as u32),
+                limit,
+                allocated: state
+                    .allocated
+                    .iter()
+                    .map(|(address, _)| *address)
+                    .collect(),
+            }
+        };
+        self.send(ProtoMessage::BluetoothConnectionsFreeResponse(response))
+            .await;
+    }
+
+    fn is_connected(&self, address: u64) -> bool {
+        self.slots
+            .state
+            .borrow()
+            .allocated
+            .contains(&(address, self.connection))
+    }
+
+    /// Allocates a slot for `address`, fails with the error reported to the client.
+    fn reserve(&self, address: u64) -> Result<(), i32> {
+        let limit = self.backend.connection_slots() as usize;
+        let mut result = Ok(());
+        self.slots.state.send_if_modified(|state| {
+            if state
+                .allocated
+                .iter()
+                .any(|(allocated, _)| *allocated == address)
+            {
+                result = Err(ERROR_GATT);
+                return false;
+            }
+            if state.allocated.len() >= limit {
+                result = Err(ERROR_NO_RESOURCES);
+                return false;
+            }
+            state.allocated.push((address, self.connection));
+            state.changed_by = self.connection;
+            true
+        });
+        result
+    }
+
+    /// Releases the slot of `address`, returns whether it was allocated.
+    fn release(&self, address: u64) -> bool {
+        self.slots.state.send_if_modified(|state| {
+            let before = state.allocated.len();
+            state
+                .allocated
+                .retain(|allocated| *allocated != (address, self.connection));
+            if state.allocated.len() == before {
+                return false;
+            }
+            state.changed_by = self.connection;
+            true
+        })
+    }
+
+    /// Releases all slots of this API connection and returns their addresses.
+    fn release_all(&self) -> Vec<u64> {
+        let mut released = Vec::new();
+        self.slots.state.send_if_modified(|state| {
+            state.allocated.retain(|(address, connection)| {
+                if *connection == self.connection {
+                    released.push(*address);
+                }
+                *connection != self.connection
+            });
+            if released.is_empty() {
+                return false;
+            }
+            state.changed_by = self.connection;
+            true
+        });
+        released
+    }
+
+    /// Reports that a peripheral disconnected, once per connection.
+    async fn disconnected(&self, address: u64, error: i32) {
+        if self.release(address) {
+            self.send_connection(address, false, 0, error).await;
+            self.send_connections_free(false).await;
+        }
+    }
+
+    async fn send_connection(&self, address: u64, connected: bool, mtu: u32, error: i32) {
+        self.send(ProtoMessage::BluetoothDeviceConnectionResponse(
+            BluetoothDeviceConnectionResponse {
+                address,
+                connected,
+                mtu,
+                error,
+            },
+        ))
+        .await;
+    }
+
+    async fn send_gatt_error(&self, address: u64, handle: u32, error: i32) {
+        self.send(ProtoMessage::BluetoothGattErrorResponse(
+            BluetoothGattErrorResponse {
+                address,
+                handle,
+                error,
+            },
+        ))
+        .await;
+    }
+
+    async fn forward_advertisements(self: Arc<Self>, raw: bool) {
+        let mut advertisements = self.backend.scan();
+        let mut batch = Vec::new();
+        let mut flush = tokio::time::interval(ADVERTISEMENT_BATCH_INTERVAL);
+        loop {
+            tokio::select! {
+                advertisement = advertisements.next() => match advertisement {
+                    Some(advertisement) if raw => {
+                        batch.push(advertisement);
+                        if batch.len() >= ADVERTISEMENT_BATCH_SIZE {
+                            self.send_advertisements(std::mem::take(&mut batch)).await;
+                        }
+                    }
+                    Some(advertisement) => {
+                        self.send(ProtoMessage::BluetoothLeAdvertisementResponse(
+                            decoded_advertisement(&advertisement),
+                        ))
+                        .await;
+                    }
+                    None => break,
+                },
+                _ = flush.tick(), if !batch.is_empty() => {
+                    self.send_advertisements(std::mem::take(&mut batch)).await;
+                }
+            }
+        }
+        if !batch.is_empty() {
+            self.send_advertisements(batch).await;
+        }
+    }
+
+    async fn send_advertisements(&self, advertisements: Vec<BluetoothLeRawAdvertisement>) {
+        self.send(ProtoMessage::BluetoothLeRawAdvertisementsResponse(
+            BluetoothLeRawAdvertisementsResponse { advertisements },
+        ))
+        .await;
+    }
+
+    async fn handle(self: Arc<Self>, request: ProtoMessage) {
+        match request {
+            ProtoMessage::BluetoothDeviceRequest(request) => self.handle_device(request).await,
+            ProtoMessage::BluetoothGattGetServicesRequest(request) => {
+                let address = request.address;
+                if !self.is_connected(address) {
+                    return self.send_gatt_error(address, 0, ERROR_NOT_CONNECTED).await;
+                }
+                match self.backend.services(address).await {
+                    Ok(services) => {
+                        // Like ESPHome, one service per message to stay below the
+                        // maximum message size.
+                        for service in services {
+                            self.send(ProtoMessage::BluetoothGattGetServicesResponse(
+                                BluetoothGattGetServicesResponse {
+                                    address,
+                                    services: vec![service],
+                                },
+                            ))
+                            .await;
+                        }
+                        self.send(ProtoMessage::BluetoothGattGetServicesDoneResponse(
+                            BluetoothGattGetServicesDoneResponse { address },
+                        ))
+                        .await;
+                    }
+                    Err(err) => self.send_gatt_error(address, 0, err.0).await,
+                }
+            }
+            ProtoMessage::BluetoothGattReadRequest(request) => {
+                let (address, handle) = (request.address, request.handle);
+                let result = match self.is_connected(address) {
+                    true => self.backend.read(address, handle).await,
+                    false => Err(BluetoothProxyError(ERROR_NOT_CONNECTED)),
+                };
+                self.send_read_result(address, handle, result).await;
+            }
+            ProtoMessage::BluetoothGattReadDescriptorRequest(request) => {
+                let (address, handle) = (request.address, request.handle);
+                let result = match self.is_connected(address) {
+                    true => self.backend.read_descriptor(address, handle).await,
+                    false => Err(BluetoothProxyError(ERROR_NOT_CONNECTED)),
+                };
+                self.send_read_result(address, handle, result).await;
+            }
+            ProtoMessage::BluetoothGattWriteRequest(request) => {
+                let (address, handle) = (request.address, request.handle);
+                let result = match self.is_connected(address) {
+                    true => {
+                        self.backend
+                            .write(address, handle, request.data, request.response)
+                            .await
+                    }
+                    false => Err(BluetoothProxyError(ERROR_NOT_CONNECTED)),
+                };
+                self.send_write_result(address, handle, result).await;
+            }
+            ProtoMessage::BluetoothGattWriteDescriptorRequest(request) => {
+                let (address, handle) = (request.address, request.handle);
+                let result = match self.is_connected(address) {
+                    true => {
+                        self.backend
+                            .write_descriptor(address, handle, request.data)
+                            .await
+                    }
+                    false => Err(BluetoothProxyError(ERROR_NOT_CONNECTED)),
+                };
+                self.send_write_result(address, handle, result).await;
+            }
+            ProtoMessage::BluetoothGattNotifyRequest(request) => {
+                let (address, handle) = (request.address, request.handle);
+                let result = match self.is_connected(address) {
+                    true => self.backend.notify(address, handle, request.enable).await,
+                    false => Err(BluetoothProxyError(ERROR_NOT_CONNECTED)),
+                };
+                match result {
+                    Ok(()) => {
+                        self.send(ProtoMessage::BluetoothGattNotifyResponse(
+                            BluetoothGattNotifyResponse { address, handle },
+                        ))
+                        .await
+                    }
+                    Err(err) => self.send_gatt_error(address, handle, err.0).await,
+                }
+            }
+            _ => {}
+        }
+    }
+
+    async fn send_read_result(
+        &self,
+        address: u64,
+        handle: u32,
+        result: Result<Vec<u8>, BluetoothProxyError>,
+    ) {
+        match result {
+            Ok(data) => {
+                self.send(ProtoMessage::BluetoothGattReadResponse(
+                    BluetoothGattReadResponse {
+                        address,
+                        handle,
+                        data,
+                    },
+                ))
+                .await
+            }
+            Err(err) => self.send_gatt_error(address, handle, err.0).await,
+        }
+    }
+
+    async fn send_write_result(
+        &self,
+        address: u64,
+        handle: u32,
+        result: Result<(), BluetoothProxyError>,
+    ) {
+        match result {
+            Ok(()) => {
+                self.send(ProtoMessage::BluetoothGattWriteResponse(
+                    BluetoothGattWriteResponse { address, handle },
+                ))
+                .await
+            }
+            Err(err) => self.send_gatt_error(address, handle, err.0).await,
+        }
+    }
+
+    async fn handle_device(&self, request: BluetoothDeviceRequest) {
+        let address = request.address;
+        let request_type = BluetoothDeviceRequestType::try_from(request.request_type);
+        match request_type {
+            Ok(
+                BluetoothDeviceRequestType::Connect
+                | BluetoothDeviceRequestType::ConnectV3WithCache
+                | BluetoothDeviceRequestType::ConnectV3WithoutCache,
+            ) => {
+                if let Err(error) = self.reserve(address) {
+                    return self.send_connection(address, false, 0, error).await;
+                }
+                self.send_connections_free(false).await;
+
+                let address_type = request.has_address_type.then_some(request.address_type);
+                let use_cache =
+                    request_type != Ok(BluetoothDeviceRequestType::ConnectV3WithoutCache);
+                match self
+                    .backend
+                    .connect(address, address_type, use_cache, self.events.clone())
+                    .await
+                {
+                    Ok(mtu) if self.is_connected(address) => {
+                        self.send_connection(address, true, mtu, 0).await
+                    }
+                    // Disconnect was requested while connecting.
+                    Ok(_) => {
+                        let _ = self.backend.disconnect(address).await;
+                    }
+                    Err(err) => self.disconnected(address, err.0).await,
+                }
+            }
+            Ok(BluetoothDeviceRequestType::Disconnect) => {
+                if self.release(address) {
+                    let _ = self.backend.disconnect(address).await;
+                    self.send_connections_free(false).await;
+                }
+                self.send_connection(address, false, 0, 0).await;
+            }
+            Ok(BluetoothDeviceRequestType::Pair) => {
+                let result = self.backend.pair(address).await;
+                self.send(ProtoMessage::BluetoothDevicePairingResponse(
+                    BluetoothDevicePairingResponse {
+                        address,
+                        paired: result.is_ok(),
+                        error: result.err().map_or(0, |err| err.0),
+                    },
+                ))
+                .await;
+            }
+            Ok(BluetoothDeviceRequestType::Unpair) => {
+                let result = self.backend.unpair(address).await;
+                self.send(ProtoMessage::BluetoothDeviceUnpairingResponse(
+                    BluetoothDeviceUnpairingResponse {
+                        address,
+                        success: result.is_ok(),
+                        error: result.err().map_or(0, |err| err.0),
+                    },
+                ))
+                .await;
+            }
+            Ok(BluetoothDeviceRequestType::ClearCache) => {
+                let result = self.backend.clear_cache(address).await;
+                self.send(ProtoMessage::BluetoothDeviceClearCacheResponse(
+                    BluetoothDeviceClearCacheResponse {
+                        address,
+                        success: result.is_ok(),
+                        error: result.err().map_or(0, |err| err.0),
+                    },
+                ))
+                .await;
+            }
+            Err(_) => debug!("Unknown Bluetooth device request {}", request.request_type),
+        }
+    }
+}
+
+/// Decodes a raw advertisement for clients without raw advertisement support.
+fn decoded_advertisement(raw: &BluetoothLeRawAdvertisement) -> BluetoothLeAdvertisementResponse {
+    let advertisement = Advertisement::from_raw(raw);
+    BluetoothLeAdvertisementResponse {
+        address: advertisement.address,
+        name: advertisement.name.unwrap_or_default().into_bytes(),
+        rssi: advertisement.rssi,
+        service_uuids: advertisement.service_uuids,
+        service_data: advertisement
+            .service_data
+            .into_iter()
+            .map(|(uuid, data)| BluetoothServiceData { uuid, data })
+            .collect(),
+        manufacturer_data: advertisement
+            .manufacturer_data
+            .into_iter()
+            .map(|(company, data)| BluetoothServiceData {
+                uuid: format!("0x{:04X}", company),
+                data,
+            })
+            .collect(),
+        address_type: advertisement.address_type,
+    }
+}
@@ -0,0 +1,363 @@
+//! A [`BluetoothProxyBackend`] with simulated peripherals instead of a radio.
+//!
+//! Peripherals are added with [`SimulatedBackend::add_peripheral`] and behave like
+//! simple GATT servers: reads return the stored value of a handle, writes replace
+//! it and [`SimulatedBackend::notify`] changes a value and notifies connected
+//! clients. The stored values can be inspected to check what a client wrote.
+
+use futures::FutureExt;
+use futures::StreamExt;
+use futures::stream::BoxStream;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc;
+use typed_builder::TypedBuilder;
+
+use crate::bluetooth_proxy::{
+    BackendFuture, BluetoothProxyBackend, BluetoothProxyError, ERROR_GATT, ERROR_INVALID_HANDLE,
+    ERROR_NOT_CONNECTED, PeripheralEvent,
+};
+use crate::proto::{BluetoothGattService, BluetoothLeRawAdvertisement};
+
+const AD_TYPE_COMPLETE_NAME: u8 = 0x09;
+
+/// A simulated BLE peripheral.
+///
+/// # Examples
+///
+/// ```rust
+/// use esphome_native_api::bluetooth_proxy::simulated::SimulatedPeripheral;
+/// use esphome_native_api::proto::{BluetoothGattCharacteristic, BluetoothGattService};
+/// use std::collections::HashMap;
+///
+/// let peripheral = SimulatedPeripheral::builder()
+///     .address(0xA4C1380D1E2F)
+///     .name("Thermometer".to_string())
+///     .services(vec![BluetoothGattService {
+///         handle: 0x10,
+///         characteristics: vec![BluetoothGattCharacteristic {
+///             handle: 0x11,
+///             ..Default::default()
+///         }],
+///         ..Default::default()
+///     }])
+///     .values(HashMap::from([(0x11, vec![21])]))
+///     .build();
+/// ```
+#[derive(TypedBuilder, Clone, Debug)]
+pub struct SimulatedPeripheral {
+    /// MAC address of the peripheral.
+    pub address: u64,
+    /// Advertised local name.
+    #[builder(default)]
+    pub name: String,
+    /// Signal strength of the advertisements in dBm.
+    #[builder(default = -60)]
+    pub rssi: i32,
+    /// MTU reported on connect.
+    #[builder(default = 247)]
+    pub mtu: u32,
+    /// GATT services of the peripheral.
+    #[builder(default)]
+    pub services: Vec<BluetoothGattService>,
+    /// Values of characteristics and descriptors by handle.
+    #[builder(default)]
+    pub values: HashMap<u32, Vec<u8>>,
+}
+
+impl SimulatedPeripheral {
+    /// The raw advertisement of the peripheral.
+    pub fn advertisement(&self) -> BluetoothLeRawAdvertisement {
+        let mut data = Vec::new();
+        if !self.name.is_empty() {
+            data.push(self.name.len() as u8 + 1);
+            data.push(AD_TYPE_COMPLETE_NAME);
+            data.extend(self.name.as_bytes());
+        }
+        BluetoothLeRawAdvertisement {
+            address: self.address,
+            rssi: self.rssi,
+            address_type: 0,
+            data,
+        }
+    }
+
+    fn has_handle(&self, handle: u32) -> bool {
+        self.services.iter().any(|service| {
+            service.characteristics.iter().any(|characteristic| {
+                characteristic.handle == handle
+                    || characteristic
+                        .descriptors
+                        .iter()
+                        .any(|descriptor| descriptor.handle == handle)
+            })
+        })
+    }
+}
+
+#[derive(Default)]
+struct State {
+    peripherals: HashMap<u64, SimulatedPeripheral>,
+    connections: HashMap<u64, mpsc::Sender<PeripheralEvent>>,
+    notifying: HashSet<(u64, u32)>,
+    paired: HashSet<u64>,
+}
+
+/// Bluetooth proxy backend with simulated peripherals.
+///
+/// Clones share the same peripherals.
+#[derive(Clone)]
+pub struct SimulatedBackend {
+    state: Arc<Mutex<State>>,
+    advertisements_tx: broadcast::Sender<BluetoothLeRawAdvertisement>,
+}
+
+impl Default for SimulatedBackend {
+    fn default() -> Self {
+        SimulatedBackend::new()
+    }
+}
+
+impl SimulatedBackend {
+    /// Creates a backend without peripherals.
+    pub fn new() -> Self {
+        SimulatedBackend {
+            state: Arc::new(Mutex::new(State::default())),
+            advertisements_tx: broadcast::channel(64).0,
+        }
+    }
+
+    /// Adds a peripheral, replacing one with the same address.
+    pub fn add_peripheral(&self, peripheral: SimulatedPeripheral) {
+        let mut state = self.state.lock().unwrap();
+        state.peripherals.insert(peripheral.address, peripheral);
+    }
+
+    /// Lets a peripheral send an advertisement to all scanners.
+    pub fn advertise(&self, address: u64) {
+        let advertisement = self
+            .state
+            .lock()
+            .unwrap()
+            .peripherals
+            .get(&address)
+            .map(SimulatedPeripheral::advertisement);
+        if let Some(advertisement) = advertisement {
+            // Fails only if nobody is scanning.
+            let _ = self.advertisements_tx.send(advertisement);
+        }
+    }
+
+    /// Changes a value and sends a notification if it is enabled.
+    pub async fn notify(&self, address: u64, handle: u32, data: Vec<u8>) {
+        let events = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(peripheral) = state.peripherals.get_mut(&address) {
+                peripheral.values.insert(handle, data.clone());
+            }
+            match state.notifying.contains(&(address, handle)) {
+                true => state.connections.get(&address).cloned(),
+                false => None,
+            }
+        };
+        if let Some(events) = events {
+            let _ = events
+                .send(PeripheralEvent::Notification {
+                    address,
+                    handle,
+                    data,
+                })
+                .await;
+        }
+    }
+
+    /// Simulates a peripheral going out of range.
+    pub async fn drop_connection(&self, address: u64) {
+        let events = self.take_connection(address);
+        if let Some(events) = events {
+            let _ = events
+                .send(PeripheralEvent::Disconnected {
+                    address,
+                    error: ERROR_GATT,
+                })
+                .await;
+        }
+    }
+
+    /// Current value of a handle of a peripheral.
+    pub fn value(&self, address: u64, handle: u32) -> Option<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        state
+            .peripherals
+            .get(&address)?
+            .values
+            .get(&handle)
+            .cloned()
+    }
+
+    /// Returns whether a client is connected to the peripheral.
+    pub fn is_connected(&self, address: u64) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .connections
+            .contains_key(&address)
+    }
+
+    /// Returns whether the peripheral is paired.
+    pub fn is_paired(&self, address: u64) -> bool {
+        self.state.lock().unwrap().paired.contains(&address)
+    }
+
+    fn take_connection(&self, address: u64) -> Option<mpsc::Sender<PeripheralEvent>> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .notifying
+            .retain(|(notifying, _)| *notifying != address);
+        state.connections.remove(&address)
+    }
+
+    /// Runs `operation` on a connected peripheral.
+    fn with_connected<T>(
+        &self,
+        address: u64,
+        operation: impl FnOnce(&mut State) -> Result<T, BluetoothProxyError>,
+    ) -> Result<T, BluetoothProxyError> {
+        let mut state = self.state.lock().unwrap();
+        if !state.connections.contains_key(&address) {
+            return Err(BluetoothProxyError(ERROR_NOT_CONNECTED));
+        }
+        operation(&mut state)
+    }
+
+    fn read_value(&self, address: u64, handle: u32) -> Result<Vec<u8>, BluetoothProxyError> {
+        self.with_connected(address, |state| {
+            let peripheral = &state.peripherals[&address];
+            match peripheral.has_handle(handle) {
+                true => Ok(peripheral.values.get(&handle).cloned().unwrap_or_default()),
+                false => Err(BluetoothProxyError(ERROR_INVALID_HANDLE)),
+            }
+        })
+    }
+
+    fn write_value(
+        &self,
+        address: u64,
+        handle: u32,
+        data: Vec<u8>,
+    ) -> Result<(), BluetoothProxyError> {
+        self.with_connected(address, |state| {
+            let peripheral = state.peripherals.get_mut(&address).unwrap();
+            match peripheral.has_handle(handle) {
+                true => {
+                    peripheral.values.insert(handle, data);
+                    Ok(())
+                }
+                false => Err(BluetoothProxyError(ERROR_INVALID_HANDLE)),
+            }
+        })
+    }
+}
+
+impl BluetoothProxyBackend for SimulatedBackend {
+    fn scan(&self) -> BoxStream<'static, BluetoothLeRawAdvertisement> {
+        let advertisements_rx = self.advertisements_tx.subscribe();
+        futures::stream::unfold(advertisements_rx, |mut advertisements_rx| async move {
+            loop {
+                match advertisements_rx.recv().await {
+                    Ok(advertisement) => return Some((advertisement, advertisements_rx)),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+        .boxed()
+    }
+
+    fn connect(
+        &self,
+        address: u64,
+        _address_type: Option<u32>,
+        _use_cache: bool,
+        events: mpsc::Sender<PeripheralEvent>,
+    ) -> BackendFuture<'_, u32> {
+        let mut state = self.state.lock().unwrap();
+        let result = match state.peripherals.get(&address) {
+            Some(peripheral) => {
+                let mtu = peripheral.mtu;
+                state.connections.insert(address, events);
+                Ok(mtu)
+            }
+            None => Err(BluetoothProxyError(ERROR_GATT)),
+        };
+        futures::future::ready(result).boxed()
+    }
+
+    fn disconnect(&self, address: u64) -> BackendFuture<'_, ()> {
+        self.take_connection(address);
+        futures::future::ready(Ok(())).boxed()
+    }
+
+    fn services(&self, address: u64) -> BackendFuture<'_, Vec<BluetoothGattService>> {
+        let result = self.with_connected(address, |state| {
+            Ok(state.peripherals[&address].services.clone())
+        });
+        futures::future::ready(result).boxed()
+    }
+
+    fn read(&self, address: u64, handle: u32) -> BackendFuture<'_, Vec<u8>> {
+        futures::future::ready(self.read_value(address, handle)).boxed()
+    }
+
+    fn write(
+        &self,
+        address: u64,
+        handle: u32,
+        data: Vec<u8>,
+        _response: bool,
+    ) -> BackendFuture<'_, ()> {
+        futures::future::ready(self.write_value(address, handle, data)).boxed()
+    }
+
+    fn read_descriptor(&self, address: u64, handle: u32) -> BackendFuture<'_, Vec<u8>> {
+        futures::future::ready(self.read_value(address, handle)).boxed()
+    }
+
+    fn write_descriptor(&self, address: u64, handle: u32, data: Vec<u8>) -> BackendFuture<'_, ()> {
+        futures::future::ready(self.write_value(address, handle, data)).boxed()
+    }
+
+    fn notify(&self, address: u64, handle: u32, enable: bool) -> BackendFuture<'_, ()> {
+        let result = self.with_connected(address, |state| {
+            if !state.peripherals[&address].has_handle(handle) {
+                return Err(BluetoothProxyError(ERROR_INVALID_HANDLE));
+            }
+            match enable {
+                true => state.notifying.insert((address, handle)),
+                false => state.notifying.remove(&(address, handle)),
+            };
+            Ok(())
+        });
+        futures::future::ready(result).boxed()
+    }
+
+    fn pair(&self, address: u64) -> BackendFuture<'_, ()> {
+        let result = self.with_connected(address, |state| {
+            state.paired.insert(address);
+            Ok(())
+        });
+        futures::future::ready(result).boxed()
+    }
+
+    fn unpair(&self, address: u64) -> BackendFuture<'_, ()> {
+        self.state.lock().unwrap().paired.remove(&address);
+        futures::future::ready(Ok(())).boxed()
+    }
+
+    fn clear_cache(&self, _address: u64) -> BackendFuture<'_, ()> {
+        // Services are never cached.
+        futures::future::ready(Ok(())).boxed()
+    }
+}
@@ -0,0 +1,306 @@
+//! Camera images split into `CameraImageResponse` chunks.
+//!
+//! A camera image is sent as a sequence of `CameraImageResponse` messages for the
+//! camera key, the last one has `done` set. A `CameraImageRequest` asks all cameras
+//! of a device for a `single` image or for a `stream` of images, so chunks of
+//! different cameras may arrive interleaved.
+//!
+//! On the client side [`ImageAssembler`] and [`CameraStream`] put the chunks back
+//! together; on the device side [`send_image`] splits a JPEG into chunks.
+//!
+//! # Examples
+//!
+//! Sending a snapshot from the device side:
+//!
+//! ```rust,no_run
+//! use esphome_native_api::camera;
+//! use esphome_native_api::esphomeapi::EspHomeApi;
+//! use esphome_native_api::parser::ProtoMessage;
+//! use tokio::net::TcpStream;
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     let stream = TcpStream::connect("192.168.1.100:6053").await?;
+//!     let api = EspHomeApi::builder().name("my-camera".to_string()).build();
+//!     let (tx, mut rx) = api.start(stream).await?;
+//!
+//!     let jpeg = std::fs::read("snapshot.jpg")?;
+//!     while let Ok(message) = rx.recv().await {
+//!         if let ProtoMessage::CameraImageRequest(_) = message {
+//!             camera::send_image(&tx, 0x1234, &jpeg, camera::DEFAULT_CHUNK_SIZE).await?;
+//!         }
+//!     }
+//!     Ok(())
+//! }
+//! ```
+
+use log::warn;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use crate::parser::ProtoMessage;
+use crate::proto::{CameraImageRequest, CameraImageResponse};
+
+/// Chunk size ESPHome devices use for camera images.
+pub const DEFAULT_CHUNK_SIZE: usize = 1024;
+
+/// Devices stop streaming after a while, so an active stream renews its request.
+const STREAM_RENEW_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A complete camera image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CameraFrame {
+    /// Key of the camera entity.
+    pub key: u32,
+    /// The image, usually a JPEG.
+    pub data: Vec<u8>,
+}
+
+/// Reassembles images from `CameraImageResponse` chunks, separately per camera.
+#[derive(Debug, Default)]
+pub struct ImageAssembler {
+    buffers: HashMap<u32, Vec<u8>>,
+}
+
+impl ImageAssembler {
+    /// Adds a chunk and returns the image once its last chunk arrived.
+    pub fn push(&mut self, chunk: CameraImageResponse) -> Option<CameraFrame> {
+        let buffer = self.buffers.entry(chunk.key).or_default();
+        buffer.extend_from_slice(&chunk.data);
+        if !chunk.done {
+            return None;
+        }
+        let data = self.buffers.remove(&chunk.key).unwrap_or_default();
+        Some(CameraFrame {
+            key: chunk.key,
+            data,
+        })
+    }
+}
+
+/// Assembles the next complete image of one camera for a snapshot.
+pub(crate) struct Snapshot {
+    key: u32,
+    assembler: ImageAssembler,
+    /// Whether the next chunk of the camera starts an image, otherwise the chunks up
+    /// to the end of the current image are skipped.
+    at_image_start: bool,
+}
+
+impl Snapshot {
+    pub(crate) fn new(key: u32, at_image_start: bool) -> Self {
+        Snapshot {
+            key,
+            assembler: ImageAssembler::default(),
+            at_image_start,
+        }
+    }
+
+    /// Adds a chunk and returns the image once it is complete.
+    pub(crate) fn push(&mut self, chunk: CameraImageResponse) -> Option<Vec<u8>> {
+        if chunk.key != self.key {
+            return None;
+        }
+        if !self.at_image_start {
+            self.at_image_start = chunk.done;
+            return None;
+        }
+        self.assembler.push(chunk).map(|frame| frame.data)
+    }
+
+    /// Drops the image in progress after chunks were missed and waits for the next.
+    pub(crate) fn missed(&mut self) {
+        self.assembler = ImageAssembler::default();
+        self.at_image_start = false;
+    }
+}
+
+/// Splits an image into `CameraImageResponse` chunks of at most `chunk_size` bytes.
+///
+/// An empty image results in a single empty chunk with `done` set.
+pub fn chunk_image(key: u32, image: &[u8], chunk_size: usize) -> Vec<CameraImageResponse> {
+    if image.is_empty() {
+        return vec![CameraImageResponse {
+            key,
+            done: true,
+            ..Default::default()
+        }];
+    }
+    let chunk_count = image.len().div_ceil(chunk_size.max(1));
+    image
+        .chunks(chunk_size.max(1))
+        .enumerate()
+        .map(|(index, data)| CameraImageResponse {
+            key,
+            data: data.to_vec(),
+            done: index + 1 == chunk_count,
+            ..Default::default()
+        })
+        .collect()
+}
+
+/// Sends an image as `CameraImageResponse` chunks.
+pub async fn send_image(
+    tx: &mpsc::Sender<ProtoMessage>,
+    key: u32,
+    image: &[u8],
+    chunk_size: usize,
+) -> Result<(), mpsc::error::SendError<ProtoMessage>> {
+    for chunk in chunk_image(key, image, chunk_size) {
+        tx.send(ProtoMessage::CameraImageResponse(chunk)).await?;
+    }
+    Ok(())
+}
+
+/// Stream of complete camera images.
+pub struct CameraStream {
+    tx: mpsc::Sender<ProtoMessage>,
+    incoming: broadcast::Receiver<ProtoMessage>,
+    key: Option<u32>,
+    assembler: ImageAssembler,
+    last_request: Instant,
+    streams: Arc<AtomicUsize>,
+}
+
+impl CameraStream {
+    pub(crate) fn new(
+        tx: mpsc::Sender<ProtoMessage>,
+        incoming: broadcast::Receiver<ProtoMessage>,
+        key: Option<u32>,
+        streams: Arc<AtomicUsize>,
+    ) -> Self {
+        streams.fetch_add(1, Ordering::SeqCst);
+        CameraStream {
+            tx,
+            incoming,
+            key,
+            assembler: ImageAssembler::default(),
+            last_request: Instant::now(),
+            streams,
+        }
+    }
+
+    /// Waits for the next complete image.
+    ///
+    /// Returns `None` once the connection is closed.
+    pub async fn recv(&mut self) -> Option<CameraFrame> {
+        loop {
+            let renew_at = self.last_request + STREAM_RENEW_INTERVAL;
+            let message = tokio::select! {
+                message = self.incoming.recv() => message,
+                _ = tokio::time::sleep_until(renew_at) => {
+                    self.tx.send(stream_request()).await.ok()?;
+                    self.last_request = Instant::now();
+                    continue;
+                }
+            };
+            match message {
+                Ok(ProtoMessage::CameraImageResponse(chunk)) => {
+                    if self.key.is_some_and(|key| key != chunk.key) {
+                        continue;
+                    }
+                    if let Some(frame) = self.assembler.push(chunk) {
+                        return Some(frame);
+                    }
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    // The current images are incomplete now.
+                    warn!("Camera stream missed {} messages", skipped);
+                    self.assembler = ImageAssembler::default();
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+impl Drop for CameraStream {
+    fn drop(&mut self) {
+        self.streams.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub(crate) fn single_request() -> ProtoMessage {
+    ProtoMessage::CameraImageRequest(CameraImageRequest {
+        single: true,
+        stream: false,
+    })
+}
+
+pub(crate) fn stream_request() -> ProtoMessage {
+    ProtoMessage::CameraImageRequest(CameraImageRequest {
+        single: false,
+        stream: true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_image_splits_and_marks_last_chunk() {
+        let image: Vec<u8> = (0..=255).collect();
+        let chunks = chunk_image(7, &image, 100);
+
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].data.len(), 100);
+        assert_eq!(chunks[2].data.len(), 56);
+        assert!(!chunks[0].done && !chunks[1].done && chunks[2].done);
+        assert!(chunks.iter().all(|chunk| chunk.key == 7));
+
+        let empty = chunk_image(7, &[], 100);
+        assert_eq!(empty.len(), 1);
+        assert!(empty[0].done);
+    }
+
+    #[test]
+    fn assembler_handles_interleaved_cameras() {
+        let first: Vec<u8> = vec![1; 2500];
+        let second: Vec<u8> = vec![2; 1500];
+        let mut first_chunks = chunk_image(1, &first, 1024).into_iter();
+        let mut second_chunks = chunk_image(2, &second, 1024).into_iter();
+
+        let mut assembler = ImageAssembler::default();
+        assert!(assembler.push(first_chunks.next().unwrap()).is_none());
+        assert!(assembler.push(second_chunks.next().unwrap()).is_none());
+        assert!(assembler.push(first_chunks.next().unwrap()).is_none());
+
+        let frame = assembler.push(second_chunks.next().unwrap()).unwrap();
+        assert_eq!(frame.key, 2);
+        assert_eq!(frame.data, second);
+
+        let frame = assembler.push(first_chunks.next().unwrap()).unwrap();
+        assert_eq!(frame.key, 1);
+        assert_eq!(frame.data, first);
+    }
+
+    #[test]
+    fn snapshot_skips_images_with_missed_chunks() {
+        let first: Vec<u8> = vec![1; 2500];
+        let second: Vec<u8> = vec![2; 1500];
+        let mut chunks = chunk_image(1, &first, 1024).into_iter();
+
+        let mut snapshot = Snapshot::new(1, true);
+        assert!(snapshot.push(chunks.next().unwrap()).is_none());
+        // The second chunk was missed, the rest of the first image is skipped.
+        chunks.next();
+        snapshot.missed();
+        assert!(snapshot.push(chunks.next().unwrap()).is_none());
+
+        let mut image = None;
+        for chunk in chunk_image(2, &[0xFF; 10], 1024)
+            .into_iter()
+            .chain(chunk_image(1, &second, 1024))
+        {
+            image = snapshot.push(chunk).or(image);
+        }
+        assert_eq!(image, Some(second));
+    }
+}
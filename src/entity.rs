@@ -0,0 +1,144 @@
+//! Entity domains and states shared by the device and the client side.
+//!
+//! An entity belongs to a [`Domain`], e.g. `sensor`, and its state is sent with the
+//! state response of that domain. [`EntityState`] holds any of these responses, the
+//! device side publishes them and the client side caches them.
+
+use crate::parser::ProtoMessage;
+use crate::proto::{
+    AlarmControlPanelStateResponse, BinarySensorStateResponse, ClimateStateResponse,
+    CoverStateResponse, DateStateResponse, DateTimeStateResponse, EventResponse, FanStateResponse,
+    LightStateResponse, LockStateResponse, MediaPlayerStateResponse, NumberStateResponse,
+    SelectStateResponse, SensorStateResponse, SwitchStateResponse, TextSensorStateResponse,
+    TextStateResponse, TimeStateResponse, UpdateStateResponse, ValveStateResponse,
+};
+
+macro_rules! domains {
+    ($($variant:ident => $domain:literal,)*) => {
+        /// Entity domains as used by ESPHome and Home Assistant.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        pub enum Domain {
+            $(
+                #[doc = concat!("The `", $domain, "` domain")]
+                $variant,
+            )*
+        }
+
+        impl Domain {
+            /// Returns the domain name, e.g. `binary_sensor`.
+            pub fn as_str(&self) -> &'static str {
+                match self {
+                    $(Domain::$variant => $domain,)*
+                }
+            }
+        }
+    };
+}
+
+domains!(
+    AlarmControlPanel => "alarm_control_panel",
+    BinarySensor => "binary_sensor",
+    Button => "button",
+    Camera => "camera",
+    Climate => "climate",
+    Cover => "cover",
+    Date => "date",
+    DateTime => "datetime",
+    Event => "event",
+    Fan => "fan",
+    Light => "light",
+    Lock => "lock",
+    MediaPlayer => "media_player",
+    Number => "number",
+    Select => "select",
+    Sensor => "sensor",
+    Switch => "switch",
+    Text => "text",
+    TextSensor => "text_sensor",
+    Time => "time",
+    Update => "update",
+    Valve => "valve",
+);
+
+macro_rules! state_mappings {
+    ($($variant:ident($response:ident),)*) => {
+        /// The latest state of an entity, holding the original state response.
+        #[derive(Clone, Debug, PartialEq)]
+        pub enum EntityState {
+            $(
+                #[doc = concat!("State sent as `", stringify!($response), "`")]
+                $variant($response),
+            )*
+        }
+
+        impl EntityState {
+            /// Converts a state response into an entity state.
+            ///
+            /// Returns `None` for all other messages.
+            pub fn from_message(message: ProtoMessage) -> Option<Self> {
+                match message {
+                    $(ProtoMessage::$response(response) => Some(EntityState::$variant(response)),)*
+                    _ => None,
+                }
+            }
+
+            /// The key of the entity this state belongs to.
+            pub fn key(&self) -> u32 {
+                match self {
+                    $(EntityState::$variant(response) => response.key,)*
+                }
+            }
+
+            pub(crate) fn set_key(&mut self, key: u32) {
+                match self {
+                    $(EntityState::$variant(response) => response.key = key,)*
+                }
+            }
+
+            /// The domain of the entity this state belongs to.
+            pub fn domain(&self) -> Domain {
+                match self {
+                    $(EntityState::$variant(_) => Domain::$variant,)*
+                }
+            }
+
+            /// Converts the entity state back into its state response.
+            pub fn into_message(self) -> ProtoMessage {
+                match self {
+                    $(EntityState::$variant(response) => ProtoMessage::$response(response),)*
+                }
+            }
+        }
+
+        $(
+            impl From<$response> for EntityState {
+                fn from(response: $response) -> Self {
+                    EntityState::$variant(response)
+                }
+            }
+        )*
+    };
+}
+
+state_mappings!(
+    AlarmControlPanel(AlarmControlPanelStateResponse),
+    BinarySensor(BinarySensorStateResponse),
+    Climate(ClimateStateResponse),
+    Cover(CoverStateResponse),
+    Date(DateStateResponse),
+    DateTime(DateTimeStateResponse),
+    Event(EventResponse),
+    Fan(FanStateResponse),
+    Light(LightStateResponse),
+    Lock(LockStateResponse),
+    MediaPlayer(MediaPlayerStateResponse),
+    Number(NumberStateResponse),
+    Select(SelectStateResponse),
+    Sensor(SensorStateResponse),
+    Switch(SwitchStateResponse),
+    Text(TextStateResponse),
+    TextSensor(TextSensorStateResponse),
+    Time(TimeStateResponse),
+    Update(UpdateStateResponse),
+    Valve(ValveStateResponse),
+);
@@ -51,6 +51,7 @@ use log::debug;
 use log::error;
 use log::info;
 use log::trace;
+use log::warn;
 use noise_protocol::CipherState;
 use noise_protocol::ErrorKind;
 use noise_protocol::HandshakeState;
@@ -58,25 +59,36 @@ use noise_protocol::patterns::noise_nn_psk0;
 use noise_rust_crypto::ChaCha20Poly1305;
 use noise_rust_crypto::Sha256;
 use noise_rust_crypto::X25519;
+use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::sync::Mutex;
 use tokio::sync::broadcast;
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
+use tokio::sync::watch;
 use tokio_stream::StreamExt;
 use tokio_util::codec::FramedRead;
 use tokio_util::codec::FramedWrite;
+use tokio_util::sync::CancellationToken;
 use typed_builder::TypedBuilder;
 
+use crate::bluetooth_proxy::{self, BluetoothProxyBackend};
 use crate::frame::FrameCodec;
+use crate::homeassistant::{self, HomeAssistant};
+use crate::keepalive::{Keepalive, KeepaliveAction, KeepaliveTimer};
+use crate::mdns::MdnsService;
 use crate::packet_encrypted;
 use crate::packet_plaintext;
 use crate::parser::ProtoMessage;
 use crate::proto::{
     self, AuthenticationResponse, DeviceInfoResponse, DisconnectResponse, HelloResponse,
-    PingResponse,
+    PingRequest, PingResponse,
 };
+use crate::registry::Registry;
+use crate::time_sync::{self, TimeSync};
+use crate::user_services::UserService;
+use crate::voice_assistant::{self, VoiceAssistant};
 
 async fn write_error_and_disconnect<W>(mut writer: FramedWrite<W, FrameCodec>, message: &str)
 where
@@ -94,6 +106,12 @@ where
 
 const ERROR_ONLY_ENCRYPTED: &str = "Only key encryption is enabled";
 const ERROR_HANDSHAKE_MAC_FAILURE: &str = "Handshake MAC failure";
+const ERROR_CLOSED_DURING_HANDSHAKE: &str = "Connection closed during handshake";
+
+/// Noise protocol of encrypted connections, advertised over mDNS.
+const NOISE_PROTOCOL_NAME: &str = "Noise_NNpsk0_25519_ChaChaPoly_SHA256";
+/// Platform advertised over mDNS, ESPHome uses `host` for devices running on an OS.
+const MDNS_PLATFORM: &str = "host";
 
 /// Low-level ESPHome native API client.
 ///
@@ -117,6 +135,11 @@ const ERROR_HANDSHAKE_MAC_FAILURE: &str = "Handshake MAC failure";
 /// - `manufacturer`: Device manufacturer (optional)
 /// - `suggested_area`: Suggested area for the device (optional)
 /// - `bluetooth_mac_address`: Bluetooth MAC address (optional)
+/// - `keepalive`: Ping interval and timeout (default: 20s / 90s, `keepalive_opt(None)` disables it)
+/// - `bluetooth_proxy`: Backend answering the Bluetooth proxy requests (optional)
+/// - `voice_assistant`: Voice assistant answering the voice assistant requests (optional)
+/// - `time_sync`: Time source synced with the time of the client (optional)
+/// - `services`: User-defined services callable by the client (default: none)
 ///
 /// # Examples
 ///
@@ -177,6 +200,38 @@ pub struct EspHomeApi {
     legacy_voice_assistant_version: u32,
     #[builder(default = 0)]
     voice_assistant_feature_flags: u32,
+
+    /// Keepalive of the connection, like ESPHome pings after 20s and closes after 90s.
+    #[builder(default = Some(Keepalive::default()), setter(strip_option(fallback=keepalive_opt)))]
+    keepalive: Option<Keepalive>,
+
+    /// Backend of the Bluetooth proxy, its feature flags replace `bluetooth_proxy_feature_flags`.
+    #[builder(default = None, setter(strip_option(fallback=bluetooth_proxy_opt)))]
+    bluetooth_proxy: Option<Arc<dyn BluetoothProxyBackend>>,
+
+    /// Connection slots of the Bluetooth proxy, shared by all connections.
+    #[builder(default, setter(skip))]
+    bluetooth_proxy_slots: Arc<bluetooth_proxy::Slots>,
+
+    /// Voice assistant, its feature flags replace `voice_assistant_feature_flags`.
+    #[builder(default = None, setter(strip_option(fallback=voice_assistant_opt)))]
+    voice_assistant: Option<VoiceAssistant>,
+
+    /// Time source, requests the time from every client after its hello.
+    #[builder(default = None, setter(strip_option(fallback=time_sync_opt)))]
+    time_sync: Option<TimeSync>,
+
+    /// User-defined services, listed before the entities of every enumeration.
+    #[builder(default)]
+    services: Vec<UserService>,
+
+    /// Shared by all connections started from this API and its clones.
+    #[builder(default, setter(skip))]
+    homeassistant: Arc<HomeAssistant>,
+
+    /// Live connections started from this API and its clones.
+    #[builder(default, setter(skip))]
+    pub(crate) connections: Arc<Registry>,
 }
 
 /// Handles the ESPHome API protocol with encryption support.
@@ -229,6 +284,26 @@ impl EspHomeApi {
         ),
         Box<dyn std::error::Error>,
     >
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (_, messages_tx, messages_rx) = self.start_registered(stream).await?;
+        Ok((messages_tx, messages_rx))
+    }
+
+    /// Like [`EspHomeApi::start`], also returns the id of the connection in
+    /// `connections`.
+    pub(crate) async fn start_registered<S>(
+        &self,
+        stream: S,
+    ) -> Result<
+        (
+            u64,
+            mpsc::Sender<ProtoMessage>,
+            broadcast::Receiver<ProtoMessage>,
+        ),
+        Box<dyn std::error::Error>,
+    >
     where
         S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
     {
@@ -251,11 +326,17 @@ impl EspHomeApi {
             webserver_port: 0,
             // See https://github.com/esphome/aioesphomeapi/blob/c1fee2f4eaff84d13ca71996bb272c28b82314fc/aioesphomeapi/model.py#L154
             legacy_bluetooth_proxy_version: self.legacy_bluetooth_proxy_version,
-            bluetooth_proxy_feature_flags: self.bluetooth_proxy_feature_flags,
+            bluetooth_proxy_feature_flags: match &self.bluetooth_proxy {
+                Some(bluetooth_proxy) => bluetooth_proxy.feature_flags(),
+                None => self.bluetooth_proxy_feature_flags,
+            },
             manufacturer: self.manufacturer.clone().unwrap_or_default(),
             friendly_name: self.friendly_name.clone().unwrap_or(self.name.clone()),
             legacy_voice_assistant_version: self.legacy_voice_assistant_version,
-            voice_assistant_feature_flags: self.voice_assistant_feature_flags,
+            voice_assistant_feature_flags: match &self.voice_assistant {
+                Some(voice_assistant) => voice_assistant.feature_flags(),
+                None => self.voice_assistant_feature_flags,
+            },
             suggested_area: self.suggested_area.clone().unwrap_or_default(),
             bluetooth_mac_address: self.bluetooth_mac_address.clone().unwrap_or_default(),
             areas: vec![],
@@ -328,16 +409,18 @@ impl EspHomeApi {
                 return Err("No encryption key set, but encrypted communication requested.".into());
             }
 
-            let frame_noise_hello = reader.next().await.unwrap().unwrap();
+            let frame_noise_hello = reader.next().await.ok_or(ERROR_CLOSED_DURING_HANDSHAKE)??;
             debug!("Frame 1: {:02X?}", &frame_noise_hello);
 
             let message_server_hello =
                 packet_encrypted::generate_server_hello_frame(self.name.clone(), self.mac.clone());
 
-            writer.send(message_server_hello.clone()).await.unwrap();
-            writer.flush().await.unwrap();
+            writer.send(message_server_hello.clone()).await?;
+            writer.flush().await?;
 
-            let frame_handshake_request = reader.next().await.unwrap().unwrap();
+            // The client may abort here, e.g. because it expected a different device.
+            let frame_handshake_request =
+                reader.next().await.ok_or(ERROR_CLOSED_DURING_HANDSHAKE)??;
             debug!("Frame 2: {:02X?}", &frame_handshake_request);
 
             // Similar to https://github.com/esphome/aioesphomeapi/blob/60bcd1698dd622aeac6f4b5ec448bab0e3467c4f/aioesphomeapi/_frame_helper/noise.py#L248C17-L255
@@ -393,10 +476,12 @@ impl EspHomeApi {
 
         // Asynchronously wait for an inbound socket.
         let (cancellation_write_tx, mut cancellation_write_rx) = oneshot::channel();
+        // Stops both loops when the connection is closed from this side.
+        let closing = CancellationToken::new();
 
         // Write Loop
         let encrypt_cypher_for_write = encrypt_cypher;
-        tokio::spawn(async move {
+        tokio::spawn(closing.clone().run_until_cancelled_owned(async move {
             loop {
                 let answer_message: ProtoMessage;
 
@@ -449,91 +534,334 @@ impl EspHomeApi {
                     }
                 }
             }
-        });
+        }));
 
         // Clone all necessary data before spawning the task
         let answer_messages_tx_clone = answer_messages_tx.clone();
+        let keepalive = self.keepalive;
+        let bluetooth_proxy_tx = self.bluetooth_proxy.clone().map(|backend| {
+            bluetooth_proxy::spawn(
+                backend,
+                self.bluetooth_proxy_slots.clone(),
+                answer_messages_tx.clone(),
+            )
+        });
+        let voice_assistant_tx = self.voice_assistant.clone().map(|voice_assistant| {
+            voice_assistant::spawn(voice_assistant, answer_messages_tx.clone())
+        });
+        let time_sync_tx = self
+            .time_sync
+            .clone()
+            .map(|time_sync| time_sync::spawn(time_sync, answer_messages_tx.clone()));
+        let services = self.services.clone();
+        let homeassistant = self.homeassistant.clone();
+        let connections = self.connections.clone();
+        let connection_id = connections.connect(answer_messages_tx.clone(), closing.clone());
         // Read Loop
         tokio::spawn(async move {
-            loop {
-                let next = reader.next().await;
-                if next.is_none() {
-                    info!("Read loop stopped because stream finished");
-                    // If sending fails, the write loop is probably already closed
-                    let _ = cancellation_write_tx.send("read loop finished");
-                    break;
-                }
-                let frame = next.unwrap().unwrap();
-                trace!("TCP Receive: {:02X?}", &frame);
-
-                let message;
-                if encrypted {
-                    let mut decrypt_cipher_changer = decrypt_cypher.lock().await;
-                    message = packet_encrypted::packet_to_message(
-                        &frame,
-                        &mut *decrypt_cipher_changer.as_mut().unwrap(),
-                    )
-                    .unwrap();
-                } else {
-                    message = packet_plaintext::packet_to_message(&frame).unwrap();
-                }
-
-                // Authenticated Messages
-                match &message {
-                    ProtoMessage::DisconnectRequest(disconnect_request) => {
-                        debug!("DisconnectRequest: {:?}", disconnect_request);
-                        let response_message = DisconnectResponse {};
-                        answer_messages_tx_clone
-                            .send(ProtoMessage::DisconnectResponse(response_message))
-                            .await
-                            .unwrap();
-                        continue;
-                    }
-                    ProtoMessage::PingRequest(ping_request) => {
-                        debug!("PingRequest: {:?}", ping_request);
-                        let response_message = PingResponse {};
-                        answer_messages_tx_clone
-                            .send(ProtoMessage::PingResponse(response_message))
-                            .await
-                            .unwrap();
+            let read = async {
+                let mut keepalive_timer = KeepaliveTimer::new(keepalive);
+                loop {
+                    let next = tokio::select! {
+                        next = reader.next() => next,
+                        action = keepalive_timer.next_action() => match action {
+                            KeepaliveAction::Ping => {
+                                debug!("Sending keepalive PingRequest");
+                                let _ = answer_messages_tx_clone
+                                    .send(ProtoMessage::PingRequest(PingRequest {}))
+                                    .await;
+                                continue;
+                            }
+                            KeepaliveAction::TimedOut => {
+                                info!("Read loop stopped because keepalive timed out");
+                                let _ = cancellation_write_tx.send("keepalive timed out");
+                                break;
+                            }
+                        },
+                    };
+                    keepalive_timer.received();
+                    if next.is_none() {
+                        info!("Read loop stopped because stream finished");
+                        // If sending fails, the write loop is probably already closed
+                        let _ = cancellation_write_tx.send("read loop finished");
+                        break;
                     }
-                    ProtoMessage::DeviceInfoRequest(device_info_request) => {
-                        debug!("DeviceInfoRequest: {:?}", device_info_request);
-                        answer_messages_tx_clone
-                            .send(ProtoMessage::DeviceInfoResponse(device_info.clone()))
-                            .await
-                            .unwrap();
+                    let frame = next.unwrap().unwrap();
+                    trace!("TCP Receive: {:02X?}", &frame);
+
+                    let message;
+                    if encrypted {
+                        let mut decrypt_cipher_changer = decrypt_cypher.lock().await;
+                        message = packet_encrypted::packet_to_message(
+                            &frame,
+                            &mut *decrypt_cipher_changer.as_mut().unwrap(),
+                        )
+                        .unwrap();
+                    } else {
+                        message = packet_plaintext::packet_to_message(&frame).unwrap();
                     }
-                    ProtoMessage::HelloRequest(hello_request) => {
-                        debug!("HelloRequest: {:?}", hello_request);
 
-                        answer_messages_tx_clone
-                            .send(ProtoMessage::HelloResponse(hello_response.clone()))
-                            .await
-                            .unwrap();
-                    }
-                    ProtoMessage::AuthenticationRequest(authentication_request) => {
-                        debug!("AuthenticationRequest: {:?}", authentication_request);
-
-                        if authentication_request.password != "" {
-                            info!("Password Authentication is not supported");
-                        } else {
-                            let response_message = AuthenticationResponse {
-                                invalid_password: false,
-                            };
+                    // Authenticated Messages
+                    match &message {
+                        ProtoMessage::DisconnectRequest(disconnect_request) => {
+                            debug!("DisconnectRequest: {:?}", disconnect_request);
+                            let response_message = DisconnectResponse {};
                             answer_messages_tx_clone
-                                .send(ProtoMessage::AuthenticationResponse(response_message))
+                                .send(ProtoMessage::DisconnectResponse(response_message))
                                 .await
                                 .unwrap();
+                            continue;
+                        }
+                        ProtoMessage::PingRequest(ping_request) => {
+                            debug!("PingRequest: {:?}", ping_request);
+                            let response_message = PingResponse {};
+                            answer_messages_tx_clone
+                                .send(ProtoMessage::PingResponse(response_message))
+                                .await
+                                .unwrap();
+                        }
+                        ProtoMessage::PingResponse(_) => {
+                            debug!("PingResponse received");
+                            // The keepalive timer was reset above, the response may also
+                            // answer a ping sent by the application.
+                            let _ = outgoing_messages_tx.send(message.clone());
+                        }
+                        ProtoMessage::DeviceInfoRequest(device_info_request) => {
+                            debug!("DeviceInfoRequest: {:?}", device_info_request);
+                            answer_messages_tx_clone
+                                .send(ProtoMessage::DeviceInfoResponse(device_info.clone()))
+                                .await
+                                .unwrap();
+                        }
+                        ProtoMessage::HelloRequest(hello_request) => {
+                            debug!("HelloRequest: {:?}", hello_request);
+
+                            answer_messages_tx_clone
+                                .send(ProtoMessage::HelloResponse(hello_response.clone()))
+                                .await
+                                .unwrap();
+                            // The time can be requested once the client said hello.
+                            if let Some(time_sync_tx) = &time_sync_tx {
+                                let _ = time_sync_tx.send(message.clone()).await;
+                            }
+                        }
+                        ProtoMessage::AuthenticationRequest(authentication_request) => {
+                            debug!("AuthenticationRequest: {:?}", authentication_request);
+
+                            if authentication_request.password != "" {
+                                info!("Password Authentication is not supported");
+                            } else {
+                                let response_message = AuthenticationResponse {
+                                    invalid_password: false,
+                                };
+                                answer_messages_tx_clone
+                                    .send(ProtoMessage::AuthenticationResponse(response_message))
+                                    .await
+                                    .unwrap();
+                            }
+                        }
+                        ProtoMessage::SubscribeHomeassistantServicesRequest(request) => {
+                            debug!("SubscribeHomeassistantServicesRequest: {:?}", request);
+                            connections.subscribe(connection_id, Vec::new(), |subscriptions| {
+                                subscriptions.homeassistant_actions = true
+                            });
+                        }
+                        ProtoMessage::SubscribeHomeAssistantStatesRequest(request) => {
+                            debug!("SubscribeHomeAssistantStatesRequest: {:?}", request);
+                            homeassistant.subscribe_states(&connections, connection_id);
+                        }
+                        ProtoMessage::HomeAssistantStateResponse(state) => {
+                            debug!("HomeAssistantStateResponse: {:?}", state);
+                            homeassistant.state_received(state.clone());
+                        }
+                        ProtoMessage::ListEntitiesRequest(_) => {
+                            // Services are answered first, the entities and the final
+                            // ListEntitiesDoneResponse are sent by the application.
+                            for service in &services {
+                                let _ = answer_messages_tx_clone
+                                    .send(ProtoMessage::ListEntitiesServicesResponse(
+                                        service.list_response(),
+                                    ))
+                                    .await;
+                            }
+                            outgoing_messages_tx.send(message.clone()).unwrap();
+                        }
+                        ProtoMessage::ExecuteServiceRequest(request) => {
+                            debug!("ExecuteServiceRequest: {:?}", request);
+                            if let Some(service) =
+                                services.iter().find(|service| service.key() == request.key)
+                            {
+                                match service.decode(request) {
+                                    Ok(args) => {
+                                        tokio::spawn(service.call(args));
+                                    }
+                                    Err(err) => {
+                                        warn!("Invalid call of service {}: {}", service.name(), err)
+                                    }
+                                }
+                            } else {
+                                // Services not registered here are left to the application.
+                                outgoing_messages_tx.send(message.clone()).unwrap();
+                            }
+                        }
+                        message
+                            if bluetooth_proxy_tx.is_some()
+                                && bluetooth_proxy::is_request(message) =>
+                        {
+                            if let Some(bluetooth_proxy_tx) = &bluetooth_proxy_tx {
+                                let _ = bluetooth_proxy_tx.send(message.clone()).await;
+                            }
+                        }
+                        message
+                            if voice_assistant_tx.is_some()
+                                && voice_assistant::is_request(message) =>
+                        {
+                            if let Some(voice_assistant_tx) = &voice_assistant_tx {
+                                let _ = voice_assistant_tx.send(message.clone()).await;
+                            }
+                        }
+                        message if time_sync_tx.is_some() && time_sync::is_request(message) => {
+                            if let Some(time_sync_tx) = &time_sync_tx {
+                                let _ = time_sync_tx.send(message.clone()).await;
+                            }
+                        }
+                        message => {
+                            outgoing_messages_tx.send(message.clone()).unwrap();
                         }
-                    }
-                    message => {
-                        outgoing_messages_tx.send(message.clone()).unwrap();
                     }
                 }
+            };
+            if closing.run_until_cancelled(read).await.is_none() {
+                info!("Read loop stopped because the connection was closed");
             }
+            connections.disconnected(connection_id);
         });
 
-        Ok((answer_messages_tx.clone(), outgoing_messages_rx))
+        Ok((
+            connection_id,
+            answer_messages_tx.clone(),
+            outgoing_messages_rx,
+        ))
+    }
+
+    /// Calls a Home Assistant action, or fires an event if `is_event` is set.
+    ///
+    /// `service` is the action, e.g. `light.turn_on`, or the event type, e.g.
+    /// `esphome.tag_scanned`. Values of `data_template` are templates rendered by
+    /// Home Assistant with `variables`.
+    ///
+    /// The action is sent to all connections of this API and its clones that sent a
+    /// `SubscribeHomeassistantServicesRequest`. Returns the number of connections
+    /// it was sent to.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use esphome_native_api::esphomeapi::EspHomeApi;
+    /// # use std::collections::HashMap;
+    /// # async fn example(api: EspHomeApi) {
+    /// let data = HashMap::from([("entity_id".to_string(), "light.kitchen".to_string())]);
+    /// api.call_homeassistant_action(
+    ///     "light.turn_on",
+    ///     data,
+    ///     HashMap::new(),
+    ///     HashMap::new(),
+    ///     false,
+    /// )
+    /// .await;
+    /// # }
+    /// ```
+    pub async fn call_homeassistant_action(
+        &self,
+        service: &str,
+        data: HashMap<String, String>,
+        data_template: HashMap<String, String>,
+        variables: HashMap<String, String>,
+        is_event: bool,
+    ) -> usize {
+        let action = homeassistant::action_request(
+            service.to_string(),
+            data,
+            data_template,
+            variables,
+            is_event,
+        );
+        self.homeassistant.call_action(&self.connections, action)
+    }
+
+    /// Imports the state of a Home Assistant entity, or one of its attributes.
+    ///
+    /// The entity is requested from all connections of this API and its clones that
+    /// sent a `SubscribeHomeAssistantStatesRequest`, now or later. The returned
+    /// receiver holds `None` until Home Assistant sent the state and is updated on
+    /// every change. Importing the same entity and attribute again returns another
+    /// receiver of the same state.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use esphome_native_api::esphomeapi::EspHomeApi;
+    /// # async fn example(api: EspHomeApi) {
+    /// let mut sun = api.import_homeassistant_state("sun.sun", None).await;
+    /// while sun.changed().await.is_ok() {
+    ///     println!("Sun is {:?}", *sun.borrow());
+    /// }
+    /// # }
+    /// ```
+    pub async fn import_homeassistant_state(
+        &self,
+        entity_id: &str,
+        attribute: Option<&str>,
+    ) -> watch::Receiver<Option<String>> {
+        self.homeassistant.import_state(
+            &self.connections,
+            entity_id.to_string(),
+            attribute.unwrap_or_default().to_string(),
+        )
+    }
+
+    /// The mDNS service advertising this API on `port`, see [`crate::mdns::MdnsResponder`].
+    ///
+    /// Like ESPHome, the TXT record carries the friendly name, ESPHome version,
+    /// platform, MAC address, project and encryption of the device, so Home Assistant
+    /// can show the device before connecting to it.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use esphome_native_api::esphomeapi::EspHomeApi;
+    /// let api = EspHomeApi::builder()
+    ///     .name("bedroom-light".to_string())
+    ///     .mac("AA:BB:CC:DD:EE:FF".to_string())
+    ///     .build();
+    /// let service = api.mdns_service(6053);
+    /// assert_eq!(service.instance_name(), "bedroom-light._esphomelib._tcp.local");
+    /// assert!(service.txt.contains(&("mac".to_string(), "aabbccddeeff".to_string())));
+    /// ```
+    pub fn mdns_service(&self, port: u16) -> MdnsService {
+        let mut service = MdnsService {
+            instance: self.name.clone(),
+            port,
+            txt: Vec::new(),
+        };
+        service.set_txt(
+            "friendly_name",
+            self.friendly_name.as_deref().unwrap_or(&self.name),
+        );
+        service.set_txt("version", proto::VERSION);
+        service.set_txt("platform", MDNS_PLATFORM);
+        if let Some(mac) = &self.mac {
+            service.set_txt("mac", &mac.replace(':', "").to_lowercase());
+        }
+        if let Some(project_name) = &self.project_name {
+            service.set_txt("project_name", project_name);
+        }
+        if let Some(project_version) = &self.project_version {
+            service.set_txt("project_version", project_version);
+        }
+        if self.encryption_key.is_some() {
+            service.set_txt("api_encryption", NOISE_PROTOCOL_NAME);
+        }
+        service
     }
 }
@@ -0,0 +1,1073 @@
+//! ESPHome native API client implementation.
+//!
+//! This module provides [`EspHomeClient`], which plays the Home Assistant side of the
+//! protocol: it connects to an ESPHome device, performs the hello exchange, requests
+//! the device information and afterwards exposes the same [`ProtoMessage`] channels
+//! as [`crate::esphomeapi::EspHomeApi`].
+//!
+//! If an encryption key is configured, the client runs the initiator side of the
+//! `Noise_NNpsk0_25519_ChaChaPoly_SHA256` handshake before the hello exchange.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! use esphome_native_api::esphomeclient::EspHomeClient;
+//! use tokio::net::TcpStream;
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     let stream = TcpStream::connect("192.168.1.100:6053").await?;
+//!
+//!     let client = EspHomeClient::builder()
+//!         .client_info("my-client".to_string())
+//!         .build();
+//!
+//!     let connection = client.start(stream).await?;
+//!     println!("Connected to {}", connection.device_info_response().name);
+//!
+//!     let mut rx = connection.subscribe();
+//!     while let Ok(message) = rx.recv().await {
+//!         println!("Received: {:?}", message);
+//!     }
+//!     Ok(())
+//! }
+//! ```
+
+pub mod advertisements;
+pub mod bluetooth;
+pub mod commands;
+pub mod entities;
+pub mod logs;
+pub mod reconnect;
+pub mod states;
+pub mod voice_assistant;
+
+use base64::prelude::*;
+use futures::sink::SinkExt;
+use log::debug;
+use log::error;
+use log::info;
+use log::trace;
+use log::warn;
+use noise_protocol::CipherState;
+use noise_protocol::ErrorKind;
+use noise_protocol::HandshakeState;
+use noise_protocol::patterns::noise_nn_psk0;
+use noise_rust_crypto::ChaCha20Poly1305;
+use noise_rust_crypto::Sha256;
+use noise_rust_crypto::X25519;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::time::SystemTime;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+use tokio::sync::watch;
+use tokio_stream::StreamExt;
+use tokio_util::codec::FramedRead;
+use tokio_util::codec::FramedWrite;
+use typed_builder::TypedBuilder;
+
+use crate::camera::{self, CameraStream};
+use crate::esphomeclient::advertisements::{Advertisement, AdvertisementStream};
+use crate::esphomeclient::bluetooth::BluetoothDevice;
+use crate::esphomeclient::entities::EntityCatalog;
+use crate::esphomeclient::logs::LogStream;
+use crate::esphomeclient::states::StateCache;
+use crate::esphomeclient::voice_assistant::VoiceAssistantSubscription;
+use crate::frame::FrameCodec;
+use crate::keepalive::{Keepalive, KeepaliveAction, KeepaliveTimer};
+use crate::packet_encrypted;
+use crate::packet_plaintext;
+use crate::parser::ProtoMessage;
+use crate::proto::{
+    DeviceInfoRequest, DeviceInfoResponse, DisconnectRequest, DisconnectResponse,
+    ExecuteServiceRequest, GetTimeResponse, HelloRequest, HelloResponse, ListEntitiesRequest,
+    ListEntitiesServicesResponse, LogLevel, PingRequest, PingResponse, SubscribeLogsRequest,
+    SubscribeStatesRequest,
+};
+use crate::request;
+use crate::request::RequestError;
+use crate::time_sync;
+use crate::user_services::{self, ServiceError, ServiceValue};
+
+const ERROR_HANDSHAKE_MAC_FAILURE: &str = "Handshake MAC failure";
+
+/// Errors that can occur while connecting to or talking with an ESPHome device.
+#[derive(Debug)]
+pub enum ClientError {
+    /// The underlying stream failed.
+    Io(std::io::Error),
+    /// The device closed the connection.
+    ConnectionClosed,
+    /// The device only accepts encrypted connections.
+    EncryptionRequired,
+    /// An encryption key is configured, but the device only accepts plaintext connections.
+    EncryptionNotSupported,
+    /// The configured encryption key is not a base64 encoded 32 byte key.
+    InvalidEncryptionKey,
+    /// The device rejected the encryption key.
+    HandshakeMacFailure,
+    /// The noise handshake failed for another reason.
+    Handshake(String),
+    /// The device reported a different name than expected.
+    NameMismatch {
+        /// Name the client expected
+        expected: String,
+        /// Name reported by the device
+        received: String,
+    },
+    /// The device reported a different MAC address than expected.
+    MacMismatch {
+        /// MAC address the client expected
+        expected: String,
+        /// MAC address reported by the device
+        received: String,
+    },
+    /// An encrypted message could not be decrypted.
+    Decryption,
+    /// The device answered with an API version this client cannot talk to.
+    UnsupportedApiVersion {
+        /// Major API version reported by the device
+        major: u32,
+        /// Minor API version reported by the device
+        minor: u32,
+    },
+    /// The device sent data that does not follow the protocol.
+    Protocol(String),
+    /// The device did not answer a request in time.
+    Timeout,
+    /// A Bluetooth proxy could not connect to a peripheral or lost the connection.
+    BluetoothConnection {
+        /// Address of the peripheral
+        address: u64,
+        /// Error code reported by the proxy
+        error: i32,
+    },
+    /// A GATT operation on a peripheral failed.
+    Gatt {
+        /// Address of the peripheral
+        address: u64,
+        /// Handle of the characteristic or descriptor
+        handle: u32,
+        /// Error code reported by the proxy
+        error: i32,
+    },
+    /// The device does not offer a service with this name.
+    UnknownService(String),
+    /// The arguments of a service call do not match the schema listed by the device.
+    ServiceArguments(ServiceError),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Io(err) => write!(f, "I/O error: {}", err),
+            ClientError::ConnectionClosed => write!(f, "Connection closed"),
+            ClientError::EncryptionRequired => {
+                write!(f, "Device requires an encrypted connection")
+            }
+            ClientError::EncryptionNotSupported => {
+                write!(f, "Device does not support encrypted connections")
+            }
+            ClientError::InvalidEncryptionKey => write!(f, "Invalid encryption key"),
+            ClientError::HandshakeMacFailure => write!(f, "{}", ERROR_HANDSHAKE_MAC_FAILURE),
+            ClientError::Handshake(message) => write!(f, "Handshake failed: {}", message),
+            ClientError::Decryption => write!(f, "Error during decryption"),
+            ClientError::NameMismatch { expected, received } => write!(
+                f,
+                "Expected device name {}, but connected to {}",
+                expected, received
+            ),
+            ClientError::MacMismatch { expected, received } => write!(
+                f,
+                "Expected device MAC {}, but connected to {}",
+                expected, received
+            ),
+            ClientError::UnsupportedApiVersion { major, minor } => {
+                write!(f, "Unsupported API version {}.{}", major, minor)
+            }
+            ClientError::Protocol(message) => write!(f, "Protocol error: {}", message),
+            ClientError::Timeout => write!(f, "Request timed out"),
+            ClientError::BluetoothConnection { address, error } => write!(
+                f,
+                "Bluetooth device {:012X} not connected (error {})",
+                address, error
+            ),
+            ClientError::Gatt {
+                address,
+                handle,
+                error,
+            } => write!(
+                f,
+                "GATT error {} on handle {} of Bluetooth device {:012X}",
+                error, handle, address
+            ),
+            ClientError::UnknownService(name) => write!(f, "Unknown service {}", name),
+            ClientError::ServiceArguments(err) => write!(f, "Invalid service arguments: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ClientError {
+    fn from(err: std::io::Error) -> Self {
+        ClientError::Io(err)
+    }
+}
+
+impl From<RequestError> for ClientError {
+    fn from(err: RequestError) -> Self {
+        match err {
+            RequestError::Timeout => ClientError::Timeout,
+            RequestError::ConnectionClosed => ClientError::ConnectionClosed,
+        }
+    }
+}
+
+/// ESPHome native API client.
+///
+/// `EspHomeClient` connects to an ESPHome device the same way Home Assistant does.
+/// It sends the `HelloRequest`, validates the `HelloResponse` and fetches the
+/// `DeviceInfoResponse` before handing out a [`ClientConnection`].
+///
+/// # Builder Options
+///
+/// - `client_info`: Client identification string (default: "Rust: esphome-native-api")
+/// - `encryption_key`: Base64-encoded encryption key (optional, enables encryption)
+/// - `expected_name`: Device name the connection must be made to (optional)
+/// - `expected_mac`: Device MAC address the connection must be made to (optional)
+/// - `api_version_major`: API version major number (default: 1)
+/// - `api_version_minor`: API version minor number (default: 10)
+/// - `keepalive`: Ping interval and timeout (default: 20s / 90s, `keepalive_opt(None)` disables it)
+/// - `request_timeout`: Timeout of the request helpers on [`ClientConnection`] (default: 10s)
+/// - `timezone`: POSIX TZ string sent with the time when the device requests it (optional)
+///
+/// # Examples
+///
+/// ```rust
+/// use esphome_native_api::esphomeclient::EspHomeClient;
+///
+/// let client = EspHomeClient::builder()
+///     .client_info("dashboard".to_string())
+///     .build();
+/// ```
+#[derive(TypedBuilder, Clone)]
+pub struct EspHomeClient {
+    #[builder(default="Rust: esphome-native-api".to_string())]
+    client_info: String,
+
+    #[builder(default = None, setter(strip_option(fallback=encryption_key_opt)))]
+    encryption_key: Option<String>,
+
+    #[builder(default = None, setter(strip_option(fallback=expected_name_opt)))]
+    expected_name: Option<String>,
+    #[builder(default = None, setter(strip_option(fallback=expected_mac_opt)))]
+    expected_mac: Option<String>,
+
+    #[builder(default = 1)]
+    api_version_major: u32,
+    #[builder(default = 10)]
+    api_version_minor: u32,
+
+    /// Keepalive of the connection, like ESPHome pings after 20s and closes after 90s.
+    #[builder(default = Some(Keepalive::default()), setter(strip_option(fallback=keepalive_opt)))]
+    keepalive: Option<Keepalive>,
+
+    #[builder(default = Duration::from_secs(10))]
+    request_timeout: Duration,
+
+    /// Sent with the local time in answers to `GetTimeRequest`, e.g. `CET-1CEST,M3.5.0,M10.5.0/3`.
+    #[builder(default = None, setter(strip_option(fallback=timezone_opt)))]
+    timezone: Option<String>,
+}
+
+impl EspHomeClient {
+    /// Connects to an ESPHome device over an established stream.
+    ///
+    /// This method performs the complete connection setup:
+    /// 1. Performing the encryption handshake if an encryption key is configured
+    /// 2. Sending the `HelloRequest` and validating the `HelloResponse`
+    /// 3. Requesting the device information
+    /// 4. Spawning the read and write loops
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if:
+    /// - The stream fails or is closed during the setup
+    /// - The device requires encryption, but no key was provided
+    /// - The encryption handshake fails
+    /// - The device does not match the expected name or MAC address
+    /// - The device reports an incompatible major API version
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use esphome_native_api::esphomeclient::EspHomeClient;
+    /// # use tokio::net::TcpStream;
+    /// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+    /// let stream = TcpStream::connect("192.168.1.100:6053").await?;
+    /// let client = EspHomeClient::builder().build();
+    /// let connection = client.start(stream).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn start<S>(&self, stream: S) -> Result<ClientConnection, ClientError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let encrypted = self.encryption_key.is_some();
+        let (stream_read, stream_write) = tokio::io::split(stream);
+        let mut stream_read = BufReader::new(stream_read);
+        let mut writer = FramedWrite::new(stream_write, FrameCodec::new(encrypted));
+
+        let hello_request = ProtoMessage::HelloRequest(HelloRequest {
+            client_info: self.client_info.clone(),
+            api_version_major: self.api_version_major,
+            api_version_minor: self.api_version_minor,
+        });
+
+        let (mut reader, mut encrypt_cipher, mut decrypt_cipher) = match &self.encryption_key {
+            Some(encryption_key) => {
+                let noise_psk = decode_encryption_key(encryption_key)?;
+
+                // Stage 1: Noise hello
+                trace!("Init Connection: Noise hello");
+                writer.send(Vec::new()).await?;
+
+                let peeked_bytes = stream_read.fill_buf().await?;
+                if peeked_bytes.is_empty() {
+                    return Err(ClientError::ConnectionClosed);
+                }
+                trace!("TCP Peeked: {:02X?}", &peeked_bytes[0..1]);
+                if peeked_bytes[0] != 1 {
+                    return Err(ClientError::EncryptionNotSupported);
+                }
+                let mut reader = FramedRead::new(stream_read, FrameCodec::new(true));
+
+                // Stage 2: Handshake
+                let (encrypt_cipher, decrypt_cipher) = self
+                    .noise_handshake(&noise_psk, &mut reader, &mut writer)
+                    .await?;
+                let mut encrypt_cipher = Some(encrypt_cipher);
+
+                send_message(&mut writer, &hello_request, encrypt_cipher.as_mut()).await?;
+                (reader, encrypt_cipher, Some(decrypt_cipher))
+            }
+            None => {
+                send_message(&mut writer, &hello_request, None).await?;
+
+                // An encrypted device answers a plaintext hello with an encrypted frame.
+                let peeked_bytes = stream_read.fill_buf().await?;
+                if peeked_bytes.is_empty() {
+                    return Err(ClientError::ConnectionClosed);
+                }
+                trace!("TCP Peeked: {:02X?}", &peeked_bytes[0..1]);
+                if peeked_bytes[0] != 0 {
+                    return Err(ClientError::EncryptionRequired);
+                }
+
+                (
+                    FramedRead::new(stream_read, FrameCodec::new(false)),
+                    None,
+                    None,
+                )
+            }
+        };
+
+        let hello_response = loop {
+            match read_message(&mut reader, decrypt_cipher.as_mut()).await? {
+                ProtoMessage::HelloResponse(hello_response) => break hello_response,
+                message => debug!("Ignoring message before hello: {:?}", message),
+            }
+        };
+        debug!("HelloResponse: {:?}", hello_response);
+        self.verify_device(&hello_response.name, None)?;
+
+        if hello_response.api_version_major != self.api_version_major {
+            return Err(ClientError::UnsupportedApiVersion {
+                major: hello_response.api_version_major,
+                minor: hello_response.api_version_minor,
+            });
+        }
+
+        send_message(
+            &mut writer,
+            &ProtoMessage::DeviceInfoRequest(DeviceInfoRequest {}),
+            encrypt_cipher.as_mut(),
+        )
+        .await?;
+        let device_info = loop {
+            match read_message(&mut reader, decrypt_cipher.as_mut()).await? {
+                ProtoMessage::DeviceInfoResponse(device_info) => break device_info,
+                message => debug!("Ignoring message before device info: {:?}", message),
+            }
+        };
+        debug!("DeviceInfoResponse: {:?}", device_info);
+        self.verify_device(&device_info.name, Some(&device_info.mac_address))?;
+
+        debug!("Initialization done.");
+
+        let (messages_tx, messages_rx) = mpsc::channel::<ProtoMessage>(16);
+        let (incoming_messages_tx, _) = broadcast::channel::<ProtoMessage>(256);
+        let (closed_tx, closed_rx) = watch::channel(false);
+        let (cancellation_write_tx, cancellation_write_rx) = oneshot::channel();
+
+        tokio::spawn(write_loop(
+            writer,
+            encrypt_cipher,
+            messages_rx,
+            cancellation_write_rx,
+        ));
+        tokio::spawn(read_loop(
+            reader,
+            decrypt_cipher,
+            messages_tx.clone(),
+            incoming_messages_tx.clone(),
+            cancellation_write_tx,
+            closed_tx,
+            self.clone(),
+        ));
+
+        Ok(ClientConnection {
+            messages_tx,
+            incoming_messages_tx: incoming_messages_tx.downgrade(),
+            closed_rx,
+            hello_response,
+            device_info,
+            request_timeout: self.request_timeout,
+            services: Arc::default(),
+            advertisement_streams: Arc::default(),
+            camera_streams: Arc::default(),
+            log_level: Arc::default(),
+            device_address: None,
+        })
+    }
+
+    /// Like [`EspHomeClient::start`], for a TCP stream the connection also knows the
+    /// address of the device.
+    pub async fn start_tcp(&self, stream: TcpStream) -> Result<ClientConnection, ClientError> {
+        let device_address = stream.peer_addr()?.ip();
+        let mut connection = self.start(stream).await?;
+        connection.device_address = Some(device_address);
+        Ok(connection)
+    }
+
+    /// Runs the initiator side of the noise handshake and returns the encrypt and decrypt
+    /// cipher states.
+    ///
+    /// Similar to https://github.com/esphome/aioesphomeapi/blob/60bcd1698dd622aeac6f4b5ec448bab0e3467c4f/aioesphomeapi/_frame_helper/noise.py
+    async fn noise_handshake<R, W>(
+        &self,
+        noise_psk: &[u8],
+        reader: &mut FramedRead<R, FrameCodec>,
+        writer: &mut FramedWrite<W, FrameCodec>,
+    ) -> Result<(CipherState<ChaCha20Poly1305>, CipherState<ChaCha20Poly1305>), ClientError>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let frame_server_hello = reader.next().await.ok_or(ClientError::ConnectionClosed)??;
+        debug!("Server hello: {:02X?}", &frame_server_hello);
+        let (name, mac) = packet_encrypted::parse_server_hello_frame(&frame_server_hello)
+            .map_err(|err| ClientError::Handshake(err.to_string()))?;
+        debug!("Server name: {}, mac: {:?}", name, mac);
+        self.verify_device(&name, mac.as_deref())?;
+
+        let mut handshake_state: HandshakeState<X25519, ChaCha20Poly1305, Sha256> =
+            HandshakeState::new(
+                noise_nn_psk0(),
+                true,
+                b"NoiseAPIInit\0\0",
+                None,
+                None,
+                None,
+                None,
+            );
+        handshake_state.push_psk(noise_psk);
+
+        let out = handshake_state
+            .write_message_vec(b"")
+            .map_err(|err| ClientError::Handshake(err.to_string()))?;
+        let mut message_handshake = vec![0];
+        message_handshake.extend(out);
+        debug!("Sending handshake");
+        writer.send(message_handshake).await?;
+
+        let frame_handshake_response =
+            reader.next().await.ok_or(ClientError::ConnectionClosed)??;
+        debug!("Handshake response: {:02X?}", &frame_handshake_response);
+        match frame_handshake_response.first() {
+            Some(0) => {}
+            Some(_) => {
+                let message = String::from_utf8_lossy(&frame_handshake_response[1..]).into_owned();
+                if message == ERROR_HANDSHAKE_MAC_FAILURE {
+                    return Err(ClientError::HandshakeMacFailure);
+                }
+                return Err(ClientError::Handshake(message));
+            }
+            None => {
+                return Err(ClientError::Handshake(
+                    "Empty handshake response".to_string(),
+                ));
+            }
+        }
+
+        // Ignore message type byte
+        if let Err(err) = handshake_state.read_message_vec(&frame_handshake_response[1..]) {
+            return Err(match err.kind() {
+                ErrorKind::Decryption => ClientError::HandshakeMacFailure,
+                _ => ClientError::Handshake(err.to_string()),
+            });
+        }
+
+        let (encrypt_cipher, decrypt_cipher) = handshake_state.get_ciphers();
+        Ok((encrypt_cipher, decrypt_cipher))
+    }
+
+    /// Checks the reported device identity against the expected name and MAC address.
+    ///
+    /// Devices that do not report a MAC address are accepted.
+    fn verify_device(&self, name: &str, mac: Option<&str>) -> Result<(), ClientError> {
+        if let Some(expected_name) = self.expected_name.as_ref().filter(|e| *e != name) {
+            return Err(ClientError::NameMismatch {
+                expected: expected_name.clone(),
+                received: name.to_string(),
+            });
+        }
+        match (&self.expected_mac, mac.filter(|mac| !mac.is_empty())) {
+            (Some(expected_mac), Some(mac))
+                if normalize_mac(expected_mac) != normalize_mac(mac) =>
+            {
+                Err(ClientError::MacMismatch {
+                    expected: expected_mac.clone(),
+                    received: mac.to_string(),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Handle to an established client connection.
+///
+/// The handle is cheap to clone; all clones share the same underlying connection.
+#[derive(Clone)]
+pub struct ClientConnection {
+    messages_tx: mpsc::Sender<ProtoMessage>,
+    incoming_messages_tx: broadcast::WeakSender<ProtoMessage>,
+    closed_rx: watch::Receiver<bool>,
+    hello_response: HelloResponse,
+    device_info: DeviceInfoResponse,
+    request_timeout: Duration,
+    /// Services of the last entity listing, the schema of [`ClientConnection::execute_service`].
+    services: Arc<Mutex<Option<Vec<ListEntitiesServicesResponse>>>>,
+    /// Number of open [`AdvertisementStream`]s, the last one unsubscribes.
+    advertisement_streams: Arc<AtomicUsize>,
+    /// Number of open [`CameraStream`]s.
+    camera_streams: Arc<AtomicUsize>,
+    /// Most verbose log level requested, the device keeps only the last request.
+    log_level: Arc<AtomicI32>,
+    /// Address of the device, known for connections of [`EspHomeClient::start_tcp`].
+    device_address: Option<IpAddr>,
+}
+
+impl ClientConnection {
+    /// Returns a sender for messages to the device.
+    pub fn sender(&self) -> mpsc::Sender<ProtoMessage> {
+        self.messages_tx.clone()
+    }
+
+    /// Returns a new receiver for all messages sent by the device.
+    ///
+    /// Only messages received after subscribing are delivered. The receiver reports
+    /// [`broadcast::error::RecvError::Closed`] once the connection is gone.
+    pub fn subscribe(&self) -> broadcast::Receiver<ProtoMessage> {
+        match self.incoming_messages_tx.upgrade() {
+            Some(incoming_messages_tx) => incoming_messages_tx.subscribe(),
+            None => broadcast::channel(1).1,
+        }
+    }
+
+    /// Sends a message to the device.
+    pub async fn send(&self, message: ProtoMessage) -> Result<(), ClientError> {
+        self.messages_tx
+            .send(message)
+            .await
+            .map_err(|_| ClientError::ConnectionClosed)
+    }
+
+    /// Sends an entity command, e.g. one built with [`commands::light`].
+    pub async fn command(&self, command: impl Into<ProtoMessage>) -> Result<(), ClientError> {
+        self.send(command.into()).await
+    }
+
+    /// The `HelloResponse` the device answered with during the connection setup.
+    pub fn hello_response(&self) -> &HelloResponse {
+        &self.hello_response
+    }
+
+    /// Address of the device, if the connection was started with
+    /// [`EspHomeClient::start_tcp`].
+    pub fn device_address(&self) -> Option<IpAddr> {
+        self.device_address
+    }
+
+    /// The `DeviceInfoResponse` fetched during the connection setup.
+    pub fn device_info_response(&self) -> &DeviceInfoResponse {
+        &self.device_info
+    }
+
+    /// Returns `true` once the connection has been closed.
+    pub fn is_closed(&self) -> bool {
+        *self.closed_rx.borrow()
+    }
+
+    /// Waits until the connection has been closed.
+    pub async fn closed(&self) {
+        let mut closed_rx = self.closed_rx.clone();
+        // An error means the read loop is gone, which also means the connection is closed.
+        let _ = closed_rx.wait_for(|closed| *closed).await;
+    }
+
+    /// Sends a request and resolves with the first response `extract` accepts.
+    ///
+    /// Fails with [`ClientError::Timeout`] if no matching response arrives within the
+    /// configured `request_timeout`.
+    pub async fn request<T, F>(&self, message: ProtoMessage, extract: F) -> Result<T, ClientError>
+    where
+        F: FnMut(ProtoMessage) -> Option<T>,
+    {
+        Ok(request::request(
+            &self.messages_tx,
+            self.subscribe(),
+            message,
+            self.request_timeout,
+            extract,
+        )
+        .await?)
+    }
+
+    /// Pings the device and resolves with the round trip time.
+    pub async fn ping(&self) -> Result<Duration, ClientError> {
+        Ok(request::ping(&self.messages_tx, self.subscribe(), self.request_timeout).await?)
+    }
+
+    /// Requests the current device information.
+    ///
+    /// Unlike [`ClientConnection::device_info_response`] this asks the device again.
+    pub async fn device_info(&self) -> Result<DeviceInfoResponse, ClientError> {
+        Ok(request::device_info(&self.messages_tx, self.subscribe(), self.request_timeout).await?)
+    }
+
+    /// Requests the current time from the peer.
+    pub async fn get_time(&self) -> Result<GetTimeResponse, ClientError> {
+        Ok(request::get_time(&self.messages_tx, self.subscribe(), self.request_timeout).await?)
+    }
+
+    /// Requests all entities from the device and collects them into an [`EntityCatalog`].
+    ///
+    /// Resolves once the device sends `ListEntitiesDoneResponse`. Fails with
+    /// [`ClientError::Timeout`] if the listing takes longer than `request_timeout`.
+    pub async fn list_entities(&self) -> Result<EntityCatalog, ClientError> {
+        let mut incoming = self.subscribe();
+        self.send(ProtoMessage::ListEntitiesRequest(ListEntitiesRequest {}))
+            .await?;
+
+        let mut catalog = EntityCatalog::default();
+        let collect = async {
+            loop {
+                match incoming.recv().await {
+                    Ok(ProtoMessage::ListEntitiesDoneResponse(_)) => return Ok(()),
+                    Ok(message) => {
+                        catalog.insert_message(message);
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        return Err(ClientError::Protocol(format!(
+                            "Missed {} entity messages",
+                            skipped
+                        )));
+                    }
+                    Err(broadcast::error::RecvError::Closed) => {
+                        return Err(ClientError::ConnectionClosed);
+                    }
+                }
+            }
+        };
+        tokio::time::timeout(self.request_timeout, collect)
+            .await
+            .map_err(|_| ClientError::Timeout)??;
+        *self.services.lock().unwrap() = Some(catalog.services().to_vec());
+        Ok(catalog)
+    }
+
+    /// Calls a user-defined service of the device.
+    ///
+    /// The arguments are checked against the schema of the last
+    /// [`ClientConnection::list_entities`], the entities are listed first if they
+    /// haven't been yet.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use esphome_native_api::esphomeclient::ClientConnection;
+    /// # async fn example(connection: ClientConnection) -> Result<(), Box<dyn std::error::Error>> {
+    /// connection
+    ///     .execute_service("play_tone", &[("frequency", 440.into()), ("duration", 0.5f32.into())])
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn execute_service(
+        &self,
+        name: &str,
+        args: &[(&str, ServiceValue)],
+    ) -> Result<(), ClientError> {
+        let cached = self.services.lock().unwrap().clone();
+        let services = match cached {
+            Some(services) => services,
+            None => self.list_entities().await?.services().to_vec(),
+        };
+        let service = services
+            .iter()
+            .find(|service| service.name == name)
+            .ok_or_else(|| ClientError::UnknownService(name.to_string()))?;
+        let args = user_services::encode_arguments(service, args)
+            .map_err(ClientError::ServiceArguments)?;
+        self.send(ProtoMessage::ExecuteServiceRequest(ExecuteServiceRequest {
+            key: service.key,
+            args,
+            ..Default::default()
+        }))
+        .await
+    }
+
+    /// Subscribes to the states of all entities and keeps them in a [`StateCache`].
+    ///
+    /// The cache is updated in the background until the connection closes.
+    pub async fn subscribe_states(&self) -> Result<StateCache, ClientError> {
+        let cache = StateCache::default();
+        // Subscribe before sending the request to not miss the initial states.
+        tokio::spawn(cache.clone().run(self.subscribe()));
+        self.send(ProtoMessage::SubscribeStatesRequest(
+            SubscribeStatesRequest {},
+        ))
+        .await?;
+        Ok(cache)
+    }
+
+    /// Subscribes to the device logs up to `level`.
+    ///
+    /// With `dump_config` the device additionally logs its configuration once. The
+    /// device sends the logs up to the most verbose level of all subscriptions, every
+    /// stream only yields the records up to its own level.
+    pub async fn subscribe_logs(
+        &self,
+        level: LogLevel,
+        dump_config: bool,
+    ) -> Result<LogStream, ClientError> {
+        let logs = LogStream::new(self.subscribe(), level);
+        let subscribed_level = self.log_level.fetch_max(level.into(), Ordering::SeqCst);
+        // A less verbose request would downgrade the streams already open.
+        if subscribed_level < level as i32 || dump_config {
+            self.send(ProtoMessage::SubscribeLogsRequest(SubscribeLogsRequest {
+                level: subscribed_level.max(level.into()),
+                dump_config,
+            }))
+            .await?;
+        }
+        Ok(logs)
+    }
+
+    /// Requests a single image of all cameras and returns the one of camera `key`.
+    ///
+    /// While a [`CameraStream`] is open the device may be in the middle of an
+    /// image, so chunks are only collected after its last chunk.
+    ///
+    /// Fails with [`ClientError::Timeout`] if the image is not complete within the
+    /// configured `request_timeout`.
+    pub async fn camera_snapshot(&self, key: u32) -> Result<Vec<u8>, ClientError> {
+        // With a stream open, the first chunks may belong to an image streamed before.
+        let mut snapshot =
+            camera::Snapshot::new(key, self.camera_streams.load(Ordering::SeqCst) == 0);
+        let mut incoming = self.subscribe();
+        self.send(camera::single_request()).await?;
+        let image = async {
+            loop {
+                match incoming.recv().await {
+                    Ok(ProtoMessage::CameraImageResponse(chunk)) => {
+                        if let Some(image) = snapshot.push(chunk) {
+                            return Ok(image);
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        // The image in progress is incomplete, ask for another one.
+                        warn!("Camera snapshot missed {} messages", skipped);
+                        snapshot.missed();
+                        self.send(camera::single_request()).await?;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => {
+                        return Err(ClientError::ConnectionClosed);
+                    }
+                }
+            }
+        };
+        tokio::time::timeout(self.request_timeout, image)
+            .await
+            .map_err(|_| ClientError::Timeout)?
+    }
+
+    /// Starts streaming images of the cameras.
+    ///
+    /// With `key` only images of that camera are yielded, otherwise images of all
+    /// cameras of the device.
+    pub async fn camera_stream(&self, key: Option<u32>) -> Result<CameraStream, ClientError> {
+        let stream = CameraStream::new(
+            self.sender(),
+            self.subscribe(),
+            key,
+            self.camera_streams.clone(),
+        );
+        self.send(camera::stream_request()).await?;
+        Ok(stream)
+    }
+
+    /// Connects to a BLE peripheral through the Bluetooth proxy of the device.
+    ///
+    /// `address` is the MAC address of the peripheral as a number, e.g.
+    /// `0xA4C1380D1E2F` for `A4:C1:38:0D:1E:2F`.
+    pub async fn bluetooth_connect(&self, address: u64) -> Result<BluetoothDevice, ClientError> {
+        BluetoothDevice::connect(self, address, None).await
+    }
+
+    /// Connects to the BLE peripheral that sent `advertisement` through the Bluetooth
+    /// proxy of the device.
+    ///
+    /// Unlike [`ClientConnection::bluetooth_connect`] the address type of the
+    /// advertisement is sent along, which peripherals with a random address need.
+    pub async fn bluetooth_connect_advertised(
+        &self,
+        advertisement: &Advertisement,
+    ) -> Result<BluetoothDevice, ClientError> {
+        BluetoothDevice::connect(
+            self,
+            advertisement.address,
+            Some(advertisement.address_type),
+        )
+        .await
+    }
+
+    /// Subscribes to the BLE advertisements the Bluetooth proxy of the device receives.
+    ///
+    /// Dropping the last of the returned streams unsubscribes again.
+    pub async fn subscribe_bluetooth_advertisements(
+        &self,
+    ) -> Result<AdvertisementStream, ClientError> {
+        let advertisements = AdvertisementStream::new(
+            self.sender(),
+            self.subscribe(),
+            self.advertisement_streams.clone(),
+        );
+        self.send(advertisements::subscribe_request()).await?;
+        Ok(advertisements)
+    }
+
+    /// Subscribes to the voice assistant of the device to run its pipelines.
+    ///
+    /// Audio is streamed in API messages if the device offers them, otherwise over
+    /// UDP. Dropping the returned subscription unsubscribes again.
+    pub async fn subscribe_voice_assistant(
+        &self,
+    ) -> Result<VoiceAssistantSubscription, ClientError> {
+        VoiceAssistantSubscription::subscribe(self).await
+    }
+
+    /// Asks the device to close the connection.
+    pub async fn disconnect(&self) -> Result<(), ClientError> {
+        self.send(ProtoMessage::DisconnectRequest(DisconnectRequest {}))
+            .await
+    }
+}
+
+fn normalize_mac(mac: &str) -> String {
+    mac.chars()
+        .filter(|c| c.is_ascii_hexdigit())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+fn decode_encryption_key(encryption_key: &str) -> Result<Vec<u8>, ClientError> {
+    match BASE64_STANDARD.decode(encryption_key) {
+        Ok(noise_psk) if noise_psk.len() == 32 => Ok(noise_psk),
+        _ => Err(ClientError::InvalidEncryptionKey),
+    }
+}
+
+async fn send_message<W>(
+    writer: &mut FramedWrite<W, FrameCodec>,
+    message: &ProtoMessage,
+    encrypt_cipher: Option<&mut CipherState<ChaCha20Poly1305>>,
+) -> Result<(), ClientError>
+where
+    W: AsyncWrite + Unpin,
+{
+    let packet = match encrypt_cipher {
+        Some(encrypt_cipher) => packet_encrypted::message_to_packet(message, encrypt_cipher),
+        None => packet_plaintext::message_to_packet(message),
+    }
+    .map_err(|err| ClientError::Protocol(err.to_string()))?;
+    writer.send(packet).await?;
+    Ok(())
+}
+
+async fn read_message<R>(
+    reader: &mut FramedRead<R, FrameCodec>,
+    decrypt_cipher: Option<&mut CipherState<ChaCha20Poly1305>>,
+) -> Result<ProtoMessage, ClientError>
+where
+    R: AsyncRead + Unpin,
+{
+    let frame = reader.next().await.ok_or(ClientError::ConnectionClosed)??;
+    trace!("TCP Receive: {:02X?}", &frame);
+    match decrypt_cipher {
+        Some(decrypt_cipher) => {
+            let decrypted_frame = decrypt_cipher
+                .decrypt_vec(&frame)
+                .map_err(|_| ClientError::Decryption)?;
+            packet_encrypted::decrypted_packet_to_message(&decrypted_frame)
+        }
+        None => packet_plaintext::packet_to_message(&frame),
+    }
+    .map_err(|err| ClientError::Protocol(err.to_string()))
+}
+
+async fn write_loop<W>(
+    mut writer: FramedWrite<W, FrameCodec>,
+    mut encrypt_cipher: Option<CipherState<ChaCha20Poly1305>>,
+    mut messages_rx: mpsc::Receiver<ProtoMessage>,
+    mut cancellation_write_rx: oneshot::Receiver<&'static str>,
+) where
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let message = tokio::select! {
+            biased; // Poll cancellation_write_rx first
+            cancel_message = &mut cancellation_write_rx => {
+                debug!("Write loop received cancellation signal ({:?}), exiting.", cancel_message);
+                break;
+            }
+            message = messages_rx.recv() => match message {
+                Some(message) => message,
+                None => break,
+            },
+        };
+
+        debug!("Send message: {:?}", message);
+        if let Err(err) = send_message(&mut writer, &message, encrypt_cipher.as_mut()).await {
+            error!("Failed to send message: {}", err);
+            break;
+        }
+
+        if matches!(message, ProtoMessage::DisconnectResponse(_)) {
+            debug!("Disconnecting");
+            break;
+        }
+    }
+
+    let mut stream_write = writer.into_inner();
+    if let Err(err) = stream_write.shutdown().await {
+        debug!("failed to shutdown socket: {:?}", err);
+    }
+}
+
+async fn read_loop<R>(
+    mut reader: FramedRead<R, FrameCodec>,
+    mut decrypt_cipher: Option<CipherState<ChaCha20Poly1305>>,
+    messages_tx: mpsc::Sender<ProtoMessage>,
+    incoming_messages_tx: broadcast::Sender<ProtoMessage>,
+    cancellation_write_tx: oneshot::Sender<&'static str>,
+    closed_tx: watch::Sender<bool>,
+    client: EspHomeClient,
+) where
+    R: AsyncRead + Unpin,
+{
+    let mut keepalive_timer = KeepaliveTimer::new(client.keepalive);
+    loop {
+        let result = tokio::select! {
+            result = read_message(&mut reader, decrypt_cipher.as_mut()) => result,
+            action = keepalive_timer.next_action() => match action {
+                KeepaliveAction::Ping => {
+                    let _ = messages_tx.send(ProtoMessage::PingRequest(PingRequest {})).await;
+                    continue;
+                }
+                KeepaliveAction::TimedOut => {
+                    info!("Read loop stopped: keepalive timed out");
+                    break;
+                }
+            },
+        };
+        let message = match result {
+            Ok(message) => {
+                keepalive_timer.received();
+                message
+            }
+            Err(ClientError::Protocol(err)) => {
+                keepalive_timer.received();
+                // Newer devices may send messages this crate does not know yet.
+                debug!("Skipping undecodable message: {}", err);
+                continue;
+            }
+            Err(err) => {
+                info!("Read loop stopped: {}", err);
+                break;
+            }
+        };
+
+        match message {
+            ProtoMessage::PingRequest(_) => {
+                let _ = messages_tx
+                    .send(ProtoMessage::PingResponse(PingResponse {}))
+                    .await;
+            }
+            ProtoMessage::GetTimeRequest(_) => {
+                let response = time_sync::time_response(SystemTime::now(), client.timezone.clone());
+                let _ = messages_tx
+                    .send(ProtoMessage::GetTimeResponse(response))
+                    .await;
+            }
+            ProtoMessage::DisconnectRequest(_) => {
+                debug!("Device requested disconnect");
+                // The write loop shuts the stream down after sending the response.
+                let _ = messages_tx
+                    .send(ProtoMessage::DisconnectResponse(DisconnectResponse {}))
+                    .await;
+            }
+            ProtoMessage::DisconnectResponse(_) => {
+                debug!("Device confirmed disconnect");
+                break;
+            }
+            message => {
+                // Nobody listening is not an error for the connection.
+                let _ = incoming_messages_tx.send(message);
+            }
+        }
+    }
+
+    // If sending fails, the write loop is probably already closed
+    let _ = cancellation_write_tx.send("read loop finished");
+    let _ = closed_tx.send(true);
+}
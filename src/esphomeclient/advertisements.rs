@@ -0,0 +1,376 @@
+//! BLE advertisements received by an ESPHome Bluetooth proxy.
+//!
+//! Proxies forward advertisements either decoded, as `BluetoothLeAdvertisementResponse`,
+//! or in batches of raw advertising data, as `BluetoothLeRawAdvertisementsResponse`.
+//! An [`AdvertisementStream`] requests raw advertisements, decodes the AD structures
+//! and yields one [`Advertisement`] per received advertisement in both cases.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! # use esphome_native_api::esphomeclient::EspHomeClient;
+//! # use tokio::net::TcpStream;
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! let stream = TcpStream::connect("192.168.1.100:6053").await?;
+//! let connection = EspHomeClient::builder().build().start(stream).await?;
+//!
+//! let mut advertisements = connection.subscribe_bluetooth_advertisements().await?;
+//! while let Some(advertisement) = advertisements.recv().await {
+//!     println!(
+//!         "{:012X} {:?} {} dBm",
+//!         advertisement.address, advertisement.name, advertisement.rssi
+//!     );
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use log::warn;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::broadcast;
+use tokio::sync::mpsc;
+
+use crate::esphomeclient::bluetooth::uuid_to_string;
+use crate::parser::ProtoMessage;
+use crate::proto::{
+    BluetoothLeAdvertisementResponse, BluetoothLeRawAdvertisement,
+    SubscribeBluetoothLeAdvertisementsRequest, UnsubscribeBluetoothLeAdvertisementsRequest,
+};
+
+/// Subscription flag asking the proxy for raw advertisements.
+const FLAG_RAW_ADVERTISEMENTS: u32 = 1;
+
+const AD_TYPE_UUID16_INCOMPLETE: u8 = 0x02;
+const AD_TYPE_UUID16_COMPLETE: u8 = 0x03;
+const AD_TYPE_UUID32_INCOMPLETE: u8 = 0x04;
+const AD_TYPE_UUID32_COMPLETE: u8 = 0x05;
+const AD_TYPE_UUID128_INCOMPLETE: u8 = 0x06;
+const AD_TYPE_UUID128_COMPLETE: u8 = 0x07;
+const AD_TYPE_SHORT_NAME: u8 = 0x08;
+const AD_TYPE_COMPLETE_NAME: u8 = 0x09;
+const AD_TYPE_TX_POWER: u8 = 0x0A;
+const AD_TYPE_SERVICE_DATA_UUID16: u8 = 0x16;
+const AD_TYPE_SERVICE_DATA_UUID32: u8 = 0x20;
+const AD_TYPE_SERVICE_DATA_UUID128: u8 = 0x21;
+const AD_TYPE_MANUFACTURER_DATA: u8 = 0xFF;
+
+/// A decoded BLE advertisement.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Advertisement {
+    /// MAC address of the advertiser.
+    pub address: u64,
+    /// Address type of the advertiser, 0 for public and 1 for random addresses.
+    pub address_type: u32,
+    /// Signal strength in dBm.
+    pub rssi: i32,
+    /// Local name, the complete one if both are advertised.
+    pub name: Option<String>,
+    /// Advertised TX power in dBm.
+    pub tx_power: Option<i8>,
+    /// Advertised service UUIDs.
+    pub service_uuids: Vec<String>,
+    /// Service data by service UUID.
+    pub service_data: HashMap<String, Vec<u8>>,
+    /// Manufacturer data by company identifier.
+    pub manufacturer_data: HashMap<u16, Vec<u8>>,
+}
+
+impl Advertisement {
+    /// Decodes a raw advertisement of a `BluetoothLeRawAdvertisementsResponse`.
+    ///
+    /// Malformed AD structures end the decoding; everything before them is kept.
+    pub fn from_raw(raw: &BluetoothLeRawAdvertisement) -> Self {
+        let mut advertisement = Advertisement {
+            address: raw.address,
+            address_type: raw.address_type,
+            rssi: raw.rssi,
+            ..Default::default()
+        };
+
+        let mut data = raw.data.as_slice();
+        while let [length, rest @ ..] = data {
+            let length = *length as usize;
+            if length == 0 || rest.len() < length {
+                break;
+            }
+            let (structure, remaining) = rest.split_at(length);
+            advertisement.add_structure(structure[0], &structure[1..]);
+            data = remaining;
+        }
+        advertisement
+    }
+
+    /// Converts an advertisement a proxy already decoded.
+    pub fn from_response(response: &BluetoothLeAdvertisementResponse) -> Self {
+        Advertisement {
+            address: response.address,
+            address_type: response.address_type,
+            rssi: response.rssi,
+            name: (!response.name.is_empty())
+                .then(|| String::from_utf8_lossy(&response.name).into_owned()),
+            tx_power: None,
+            service_uuids: response.service_uuids.clone(),
+            service_data: response
+                .service_data
+                .iter()
+                .map(|data| (data.uuid.clone(), data.data.clone()))
+                .collect(),
+            manufacturer_data: response
+                .manufacturer_data
+                .iter()
+                .filter_map(|data| {
+                    let company = data.uuid.trim_start_matches("0x");
+                    Some((u16::from_str_radix(company, 16).ok()?, data.data.clone()))
+                })
+                .collect(),
+        }
+    }
+
+    fn add_structure(&mut self, ad_type: u8, data: &[u8]) {
+        match ad_type {
+            AD_TYPE_UUID16_INCOMPLETE | AD_TYPE_UUID16_COMPLETE => {
+                self.service_uuids
+                    .extend(data.chunks_exact(2).map(uuid_from_le_bytes));
+            }
+            AD_TYPE_UUID32_INCOMPLETE | AD_TYPE_UUID32_COMPLETE => {
+                self.service_uuids
+                    .extend(data.chunks_exact(4).map(uuid_from_le_bytes));
+            }
+            AD_TYPE_UUID128_INCOMPLETE | AD_TYPE_UUID128_COMPLETE => {
+                self.service_uuids
+                    .extend(data.chunks_exact(16).map(uuid_from_le_bytes));
+            }
+            AD_TYPE_SHORT_NAME if self.name.is_none() => {
+                self.name = Some(String::from_utf8_lossy(data).into_owned());
+            }
+            AD_TYPE_COMPLETE_NAME => {
+                self.name = Some(String::from_utf8_lossy(data).into_owned());
+            }
+            AD_TYPE_TX_POWER => {
+                if let [tx_power] = data {
+                    self.tx_power = Some(*tx_power as i8);
+                }
+            }
+            AD_TYPE_SERVICE_DATA_UUID16 => self.add_service_data(data, 2),
+            AD_TYPE_SERVICE_DATA_UUID32 => self.add_service_data(data, 4),
+            AD_TYPE_SERVICE_DATA_UUID128 => self.add_service_data(data, 16),
+            AD_TYPE_MANUFACTURER_DATA => {
+                if let [low, high, payload @ ..] = data {
+                    let company = u16::from_le_bytes([*low, *high]);
+                    self.manufacturer_data.insert(company, payload.to_vec());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn add_service_data(&mut self, data: &[u8], uuid_length: usize) {
+        if data.len() >= uuid_length {
+            let (uuid, payload) = data.split_at(uuid_length);
+            self.service_data
+                .insert(uuid_from_le_bytes(uuid), payload.to_vec());
+        }
+    }
+}
+
+/// Formats a 16, 32 or 128 bit UUID sent in little endian byte order.
+fn uuid_from_le_bytes(bytes: &[u8]) -> String {
+    let mut be = bytes.to_vec();
+    be.reverse();
+    match be.len() {
+        16 => {
+            let high = u64::from_be_bytes(be[..8].try_into().unwrap());
+            let low = u64::from_be_bytes(be[8..].try_into().unwrap());
+            uuid_to_string(&[high, low], 0)
+        }
+        _ => {
+            let short_uuid = be
+                .iter()
+                .fold(0u32, |uuid, byte| (uuid << 8) | *byte as u32);
+            uuid_to_string(&[], short_uuid)
+        }
+    }
+}
+
+/// Stream of BLE advertisements received by a Bluetooth proxy.
+///
+/// Dropping the last stream of a connection unsubscribes from the advertisements.
+pub struct AdvertisementStream {
+    tx: mpsc::Sender<ProtoMessage>,
+    incoming: broadcast::Receiver<ProtoMessage>,
+    pending: VecDeque<Advertisement>,
+    /// Open streams of the connection.
+    streams: Arc<AtomicUsize>,
+}
+
+impl AdvertisementStream {
+    pub(crate) fn new(
+        tx: mpsc::Sender<ProtoMessage>,
+        incoming: broadcast::Receiver<ProtoMessage>,
+        streams: Arc<AtomicUsize>,
+    ) -> Self {
+        streams.fetch_add(1, Ordering::SeqCst);
+        AdvertisementStream {
+            tx,
+            incoming,
+            pending: VecDeque::new(),
+            streams,
+        }
+    }
+
+    /// Waits for the next advertisement.
+    ///
+    /// Returns `None` once the connection is closed.
+    pub async fn recv(&mut self) -> Option<Advertisement> {
+        loop {
+            if let Some(advertisement) = self.pending.pop_front() {
+                return Some(advertisement);
+            }
+            match self.incoming.recv().await {
+                Ok(ProtoMessage::BluetoothLeRawAdvertisementsResponse(response)) => {
+                    self.pending
+                        .extend(response.advertisements.iter().map(Advertisement::from_raw));
+                }
+                Ok(ProtoMessage::BluetoothLeAdvertisementResponse(response)) => {
+                    return Some(Advertisement::from_response(&response));
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("Advertisement stream missed {} messages", skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+impl Drop for AdvertisementStream {
+    fn drop(&mut self) {
+        if self.streams.fetch_sub(1, Ordering::SeqCst) > 1 {
+            return;
+        }
+        let unsubscribe = ProtoMessage::UnsubscribeBluetoothLeAdvertisementsRequest(
+            UnsubscribeBluetoothLeAdvertisementsRequest {},
+        );
+        match self.tx.try_send(unsubscribe) {
+            Ok(()) | Err(mpsc::error::TrySendError::Closed(_)) => {}
+            Err(mpsc::error::TrySendError::Full(unsubscribe)) => {
+                // Drop can't wait for capacity, so a task sends it later.
+                if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+                    let tx = self.tx.clone();
+                    runtime.spawn(async move { tx.send(unsubscribe).await });
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn subscribe_request() -> ProtoMessage {
+    ProtoMessage::SubscribeBluetoothLeAdvertisementsRequest(
+        SubscribeBluetoothLeAdvertisementsRequest {
+            flags: FLAG_RAW_ADVERTISEMENTS,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_raw_advertisement() {
+        let raw = BluetoothLeRawAdvertisement {
+            address: 0xA4C1380D1E2F,
+            rssi: -60,
+            address_type: 0,
+            data: vec![
+                0x02, 0x01, 0x06, // flags
+                0x05, 0x08, b'L', b'Y', b'W', b'S', // short name
+                0x03, 0x03, 0x1A, 0x18, // 16 bit service UUID 0x181A
+                0x02, 0x0A, 0xF4, // TX power -12 dBm
+                0x05, 0x16, 0x1A, 0x18, 0x12, 0x34, // service data of 0x181A
+                0x05, 0xFF, 0x4C, 0x00, 0x02, 0x15, // Apple manufacturer data
+            ],
+        };
+
+        let advertisement = Advertisement::from_raw(&raw);
+        assert_eq!(advertisement.address, 0xA4C1380D1E2F);
+        assert_eq!(advertisement.rssi, -60);
+        assert_eq!(advertisement.name.as_deref(), Some("LYWS"));
+        assert_eq!(advertisement.tx_power, Some(-12));
+        assert_eq!(
+            advertisement.service_uuids,
+            vec!["0000181a-0000-1000-8000-00805f9b34fb"]
+        );
+        assert_eq!(
+            advertisement.service_data["0000181a-0000-1000-8000-00805f9b34fb"],
+            vec![0x12, 0x34]
+        );
+        assert_eq!(advertisement.manufacturer_data[&0x004C], vec![0x02, 0x15]);
+    }
+
+    #[test]
+    fn decodes_128_bit_uuids_and_stops_at_malformed_data() {
+        let mut uuid: Vec<u8> = vec![
+            0x6E, 0x40, 0x00, 0x01, 0xB5, 0xA3, 0xF3, 0x93, 0xE0, 0xA9, 0xE5, 0x0E, 0x24, 0xDC,
+            0xCA, 0x9E,
+        ];
+        uuid.reverse();
+        let mut data = vec![0x11, 0x07];
+        data.extend(uuid);
+        data.extend([0x09, 0x09, b'x']);
+
+        let advertisement = Advertisement::from_raw(&BluetoothLeRawAdvertisement {
+            data,
+            ..Default::default()
+        });
+        assert_eq!(
+            advertisement.service_uuids,
+            vec!["6e400001-b5a3-f393-e0a9-e50e24dcca9e"]
+        );
+        assert_eq!(advertisement.name, None);
+    }
+
+    #[tokio::test]
+    async fn stream_unpacks_batches_and_unsubscribes_on_drop() {
+        use crate::proto::BluetoothLeRawAdvertisementsResponse;
+
+        let (tx, mut rx) = mpsc::channel(4);
+        let (incoming_tx, incoming) = broadcast::channel(4);
+        let streams = Arc::new(AtomicUsize::new(0));
+        let mut advertisements = AdvertisementStream::new(tx, incoming, streams.clone());
+        let other =
+            AdvertisementStream::new(advertisements.tx.clone(), incoming_tx.subscribe(), streams);
+
+        incoming_tx
+            .send(ProtoMessage::BluetoothLeRawAdvertisementsResponse(
+                BluetoothLeRawAdvertisementsResponse {
+                    advertisements: vec![
+                        BluetoothLeRawAdvertisement {
+                            address: 1,
+                            ..Default::default()
+                        },
+                        BluetoothLeRawAdvertisement {
+                            address: 2,
+                            ..Default::default()
+                        },
+                    ],
+                },
+            ))
+            .unwrap();
+        assert_eq!(advertisements.recv().await.unwrap().address, 1);
+        assert_eq!(advertisements.recv().await.unwrap().address, 2);
+
+        // Only the last stream unsubscribes.
+        drop(other);
+        assert!(rx.try_recv().is_err());
+        drop(advertisements);
+        assert!(matches!(
+            rx.recv().await,
+            Some(ProtoMessage::UnsubscribeBluetoothLeAdvertisementsRequest(_))
+        ));
+    }
+}
@@ -0,0 +1,422 @@
+//! GATT client on top of an ESPHome Bluetooth proxy.
+//!
+//! A device with the `bluetooth_proxy` component connects to BLE peripherals on
+//! behalf of the client. Every request carries the address of the peripheral and
+//! the answers arrive as separate messages, so [`BluetoothDevice`] matches them by
+//! address and handle and turns `BluetoothGattErrorResponse`s into
+//! [`ClientError::Gatt`].
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! # use esphome_native_api::esphomeclient::EspHomeClient;
+//! # use esphome_native_api::esphomeclient::bluetooth::uuid_to_string;
+//! # use tokio::net::TcpStream;
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! let stream = TcpStream::connect("192.168.1.100:6053").await?;
+//! let connection = EspHomeClient::builder().build().start(stream).await?;
+//!
+//! let device = connection.bluetooth_connect(0xA4C1380D1E2F).await?;
+//! for service in device.services().await? {
+//!     for characteristic in &service.characteristics {
+//!         let uuid = uuid_to_string(&characteristic.uuid, characteristic.short_uuid);
+//!         println!("{} handle {}", uuid, characteristic.handle);
+//!     }
+//! }
+//!
+//! let mut notifications = device.notify(0x0021).await?;
+//! while let Some(data) = notifications.recv().await {
+//!     println!("{:02X?}", data);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use log::warn;
+use tokio::sync::broadcast;
+
+use crate::bluetooth_proxy::FEATURE_REMOTE_CACHING;
+use crate::esphomeclient::{ClientConnection, ClientError};
+use crate::parser::ProtoMessage;
+use crate::proto::{
+    BluetoothDeviceRequest, BluetoothDeviceRequestType, BluetoothGattGetServicesRequest,
+    BluetoothGattNotifyRequest, BluetoothGattReadDescriptorRequest, BluetoothGattReadRequest,
+    BluetoothGattService, BluetoothGattWriteDescriptorRequest, BluetoothGattWriteRequest,
+};
+
+/// A BLE peripheral connected through a Bluetooth proxy.
+///
+/// All operations fail with [`ClientError::BluetoothConnection`] once the proxy
+/// reports that the peripheral disconnected.
+#[derive(Clone)]
+pub struct BluetoothDevice {
+    connection: ClientConnection,
+    address: u64,
+    mtu: u32,
+}
+
+impl BluetoothDevice {
+    pub(crate) async fn connect(
+        connection: &ClientConnection,
+        address: u64,
+        address_type: Option<u32>,
+    ) -> Result<Self, ClientError> {
+        let feature_flags = connection
+            .device_info_response()
+            .bluetooth_proxy_feature_flags;
+        let request = ProtoMessage::BluetoothDeviceRequest(connect_request(
+            address,
+            address_type,
+            feature_flags,
+        ));
+        let mtu = connection
+            .request(request, |message| match message {
+                ProtoMessage::BluetoothDeviceConnectionResponse(response)
+                    if response.address == address =>
+                {
+                    Some(match response.connected {
+                        true => Ok(response.mtu),
+                        false => Err(ClientError::BluetoothConnection {
+                            address,
+                            error: response.error,
+                        }),
+                    })
+                }
+                _ => None,
+            })
+            .await??;
+        Ok(BluetoothDevice {
+            connection: connection.clone(),
+            address,
+            mtu,
+        })
+    }
+
+    /// Address of the peripheral.
+    pub fn address(&self) -> u64 {
+        self.address
+    }
+
+    /// MTU negotiated by the proxy.
+    pub fn mtu(&self) -> u32 {
+        self.mtu
+    }
+
+    /// Discovers the GATT services of the peripheral.
+    pub async fn services(&self) -> Result<Vec<BluetoothGattService>, ClientError> {
+        let address = self.address;
+        let mut services = Vec::new();
+        let request =
+            ProtoMessage::BluetoothGattGetServicesRequest(BluetoothGattGetServicesRequest {
+                address,
+            });
+        self.request(request, None, |message| match message {
+            ProtoMessage::BluetoothGattGetServicesResponse(response)
+                if response.address == address =>
+            {
+                services.extend(response.services);
+                None
+            }
+            ProtoMessage::BluetoothGattGetServicesDoneResponse(response)
+                if response.address == address =>
+            {
+                Some(())
+            }
+            _ => None,
+        })
+        .await?;
+        Ok(services)
+    }
+
+    /// Reads the value of a characteristic.
+    pub async fn read(&self, handle: u32) -> Result<Vec<u8>, ClientError> {
+        let address = self.address;
+        let request =
+            ProtoMessage::BluetoothGattReadRequest(BluetoothGattReadRequest { address, handle });
+        self.request(request, Some(handle), |message| match message {
+            ProtoMessage::BluetoothGattReadResponse(response)
+                if response.address == address && response.handle == handle =>
+            {
+                Some(response.data)
+            }
+            _ => None,
+        })
+        .await
+    }
+
+    /// Writes the value of a characteristic.
+    ///
+    /// With `response` the write is acknowledged by the peripheral and this waits for
+    /// the acknowledgement, otherwise it returns once the request is sent.
+    pub async fn write(
+        &self,
+        handle: u32,
+        data: Vec<u8>,
+        response: bool,
+    ) -> Result<(), ClientError> {
+        let request = ProtoMessage::BluetoothGattWriteRequest(BluetoothGattWriteRequest {
+            address: self.address,
+            handle,
+            response,
+            data,
+        });
+        if !response {
+            return self.connection.send(request).await;
+        }
+        self.request_write(request, handle).await
+    }
+
+    /// Reads the value of a descriptor.
+    pub async fn read_descriptor(&self, handle: u32) -> Result<Vec<u8>, ClientError> {
+        let address = self.address;
+        let request =
+            ProtoMessage::BluetoothGattReadDescriptorRequest(BluetoothGattReadDescriptorRequest {
+                address,
+                handle,
+            });
+        self.request(request, Some(handle), |message| match message {
+            ProtoMessage::BluetoothGattReadResponse(response)
+                if response.address == address && response.handle == handle =>
+            {
+                Some(response.data)
+            }
+            _ => None,
+        })
+        .await
+    }
+
+    /// Writes the value of a descriptor.
+    pub async fn write_descriptor(&self, handle: u32, data: Vec<u8>) -> Result<(), ClientError> {
+        let request = ProtoMessage::BluetoothGattWriteDescriptorRequest(
+            BluetoothGattWriteDescriptorRequest {
+                address: self.address,
+                handle,
+                data,
+            },
+        );
+        self.request_write(request, handle).await
+    }
+
+    /// Enables notifications of a characteristic and returns them as a stream.
+    ///
+    /// The proxy writes the client characteristic configuration descriptor itself.
+    pub async fn notify(&self, handle: u32) -> Result<Notifications, ClientError> {
+        // Subscribe before enabling to not miss the first notification.
+        let notifications = Notifications {
+            incoming: self.connection.subscribe(),
+            address: self.address,
+            handle,
+        };
+        self.set_notify(handle, true).await?;
+        Ok(notifications)
+    }
+
+    /// Disables notifications of a characteristic.
+    pub async fn stop_notify(&self, handle: u32) -> Result<(), ClientError> {
+        self.set_notify(handle, false).await
+    }
+
+    /// Disconnects from the peripheral.
+    pub async fn disconnect(&self) -> Result<(), ClientError> {
+        let address = self.address;
+        let request = ProtoMessage::BluetoothDeviceRequest(BluetoothDeviceRequest {
+            address,
+            request_type: BluetoothDeviceRequestType::Disconnect.into(),
+            ..Default::default()
+        });
+        self.connection
+            .request(request, |message| match message {
+                ProtoMessage::BluetoothDeviceConnectionResponse(response)
+                    if response.address == address && !response.connected =>
+                {
+                    Some(())
+                }
+                _ => None,
+            })
+            .await
+    }
+
+    async fn set_notify(&self, handle: u32, enable: bool) -> Result<(), ClientError> {
+        let address = self.address;
+        let request = ProtoMessage::BluetoothGattNotifyRequest(BluetoothGattNotifyRequest {
+            address,
+            handle,
+            enable,
+        });
+        self.request(request, Some(handle), |message| match message {
+            ProtoMessage::BluetoothGattNotifyResponse(response)
+                if response.address == address && response.handle == handle =>
+            {
+                Some(())
+            }
+            _ => None,
+        })
+        .await
+    }
+
+    async fn request_write(&self, request: ProtoMessage, handle: u32) -> Result<(), ClientError> {
+        let address = self.address;
+        self.request(request, Some(handle), |message| match message {
+            ProtoMessage::BluetoothGattWriteResponse(response)
+                if response.address == address && response.handle == handle =>
+            {
+                Some(())
+            }
+            _ => None,
+        })
+        .await
+    }
+
+    /// Sends a GATT request and waits for the response `extract` accepts.
+    ///
+    /// Errors for `handle` and disconnects of the peripheral end the request early.
+    /// Errors for service discovery are reported without a handle.
+    async fn request<T, F>(
+        &self,
+        request: ProtoMessage,
+        handle: Option<u32>,
+        mut extract: F,
+    ) -> Result<T, ClientError>
+    where
+        F: FnMut(ProtoMessage) -> Option<T>,
+    {
+        let address = self.address;
+        self.connection
+            .request(request, |message| match message {
+                ProtoMessage::BluetoothGattErrorResponse(response)
+                    if response.address == address
+                        && handle.is_none_or(|handle| handle == response.handle) =>
+                {
+                    Some(Err(ClientError::Gatt {
+                        address,
+                        handle: response.handle,
+                        error: response.error,
+                    }))
+                }
+                ProtoMessage::BluetoothDeviceConnectionResponse(response)
+                    if response.address == address && !response.connected =>
+                {
+                    Some(Err(ClientError::BluetoothConnection {
+                        address,
+                        error: response.error,
+                    }))
+                }
+                message => extract(message).map(Ok),
+            })
+            .await?
+    }
+}
+
+/// Stream of notifications of a characteristic.
+pub struct Notifications {
+    incoming: broadcast::Receiver<ProtoMessage>,
+    address: u64,
+    handle: u32,
+}
+
+impl Notifications {
+    /// Waits for the next notification.
+    ///
+    /// Returns `None` once the peripheral or the connection to the proxy is gone.
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        loop {
+            match self.incoming.recv().await {
+                Ok(ProtoMessage::BluetoothGattNotifyDataResponse(response))
+                    if response.address == self.address && response.handle == self.handle =>
+                {
+                    return Some(response.data);
+                }
+                Ok(ProtoMessage::BluetoothDeviceConnectionResponse(response))
+                    if response.address == self.address && !response.connected =>
+                {
+                    return None;
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("Notification stream missed {} messages", skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+/// Formats the UUID of a service, characteristic or descriptor.
+///
+/// Proxies send UUIDs either as two 64 bit halves or, for 16 bit UUIDs of newer
+/// proxies, as `short_uuid`.
+pub fn uuid_to_string(uuid: &[u64], short_uuid: u32) -> String {
+    let (high, low) = match uuid {
+        [high, low] => (*high, *low),
+        // Expand 16 and 32 bit UUIDs with the Bluetooth base UUID.
+        _ => (
+            ((short_uuid as u64) << 32) | 0x0000_1000,
+            0x8000_0080_5F9B_34FB,
+        ),
+    };
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        high >> 32,
+        (high >> 16) & 0xFFFF,
+        high & 0xFFFF,
+        low >> 48,
+        low & 0xFFFF_FFFF_FFFF
+    )
+}
+
+/// The connect request for a proxy with `feature_flags`.
+///
+/// Proxies with remote caching understand the V3 connect requests and may use their
+/// cache of the GATT services, older ones only the legacy request.
+fn connect_request(
+    address: u64,
+    address_type: Option<u32>,
+    feature_flags: u32,
+) -> BluetoothDeviceRequest {
+    let request_type = if feature_flags & FEATURE_REMOTE_CACHING != 0 {
+        BluetoothDeviceRequestType::ConnectV3WithCache
+    } else {
+        BluetoothDeviceRequestType::Connect
+    };
+    BluetoothDeviceRequest {
+        address,
+        request_type: request_type.into(),
+        has_address_type: address_type.is_some(),
+        address_type: address_type.unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connect_request_follows_the_proxy_features() {
+        let request = connect_request(0xA4C1380D1E2F, Some(1), FEATURE_REMOTE_CACHING);
+        assert_eq!(
+            request.request_type,
+            BluetoothDeviceRequestType::ConnectV3WithCache as i32
+        );
+        assert!(request.has_address_type);
+        assert_eq!(request.address_type, 1);
+
+        let request = connect_request(0xA4C1380D1E2F, None, 0);
+        assert_eq!(
+            request.request_type,
+            BluetoothDeviceRequestType::Connect as i32
+        );
+        assert!(!request.has_address_type);
+    }
+
+    #[test]
+    fn formats_full_and_short_uuids() {
+        assert_eq!(
+            uuid_to_string(&[0x6E40_0001_B5A3_F393, 0xE0A9_E50E_24DC_CA9E], 0),
+            "6e400001-b5a3-f393-e0a9-e50e24dcca9e"
+        );
+        assert_eq!(
+            uuid_to_string(&[], 0x180F),
+            "0000180f-0000-1000-8000-00805f9b34fb"
+        );
+    }
+}
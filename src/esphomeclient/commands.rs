@@ -0,0 +1,762 @@
+//! Typed builders for entity commands.
+//!
+//! Every controllable domain has a function taking the entity key and returning a
+//! builder. The builders set the `has_*` flags of the proto request for every value
+//! that is given, so only the given values are changed on the device. A builder
+//! converts into a [`ProtoMessage`] and can be passed to
+//! [`crate::esphomeclient::ClientConnection::command`].
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! # use esphome_native_api::esphomeclient::EspHomeClient;
+//! # use esphome_native_api::esphomeclient::commands::{cover, light};
+//! # use tokio::net::TcpStream;
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! let stream = TcpStream::connect("192.168.1.100:6053").await?;
+//! let connection = EspHomeClient::builder().build().start(stream).await?;
+//!
+//! connection
+//!     .command(light(0x1234).turn_on().brightness(0.5).color_temperature(300.0).transition(2.0))
+//!     .await?;
+//! connection.command(cover(0x5678).position(0.3)).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::parser::ProtoMessage;
+use crate::proto::{
+    AlarmControlPanelCommandRequest, AlarmControlPanelStateCommand, ButtonCommandRequest,
+    ClimateCommandRequest, ClimateFanMode, ClimateMode, ClimatePreset, ClimateSwingMode, ColorMode,
+    CoverCommandRequest, DateCommandRequest, DateTimeCommandRequest, FanCommandRequest,
+    FanDirection, LightCommandRequest, LockCommand, LockCommandRequest, MediaPlayerCommand,
+    MediaPlayerCommandRequest, NumberCommandRequest, SelectCommandRequest, SwitchCommandRequest,
+    TextCommandRequest, TimeCommandRequest, UpdateCommand, UpdateCommandRequest,
+    ValveCommandRequest,
+};
+
+macro_rules! command_builder {
+    ($(#[$doc:meta])* $builder:ident($request:ident) => $function:ident) => {
+        $(#[$doc])*
+        #[derive(Clone, Debug, PartialEq)]
+        pub struct $builder {
+            request: $request,
+        }
+
+        #[doc = concat!("Starts a `", stringify!($request), "` for the entity with the given key.")]
+        pub fn $function(key: u32) -> $builder {
+            $builder {
+                request: $request {
+                    key,
+                    ..Default::default()
+                },
+            }
+        }
+
+        impl $builder {
+            /// Addresses the entity of a sub-device.
+            pub fn device_id(mut self, device_id: u32) -> Self {
+                self.request.device_id = device_id;
+                self
+            }
+
+            /// Returns the proto request.
+            pub fn build(self) -> $request {
+                self.request
+            }
+        }
+
+        impl From<$builder> for ProtoMessage {
+            fn from(builder: $builder) -> Self {
+                ProtoMessage::$request(builder.request)
+            }
+        }
+    };
+}
+
+command_builder!(
+    /// Command for a `light` entity.
+    LightCommandBuilder(LightCommandRequest) => light
+);
+
+impl LightCommandBuilder {
+    /// Turns the light on.
+    pub fn turn_on(self) -> Self {
+        self.state(true)
+    }
+
+    /// Turns the light off.
+    pub fn turn_off(self) -> Self {
+        self.state(false)
+    }
+
+    /// Sets the on/off state.
+    pub fn state(mut self, state: bool) -> Self {
+        self.request.has_state = true;
+        self.request.state = state;
+        self
+    }
+
+    /// Sets the master brightness from `0.0` to `1.0`.
+    pub fn brightness(mut self, brightness: f32) -> Self {
+        self.request.has_brightness = true;
+        self.request.brightness = brightness;
+        self
+    }
+
+    /// Sets the color mode, e.g. `ColorMode::Rgb`.
+    pub fn color_mode(mut self, color_mode: ColorMode) -> Self {
+        self.request.has_color_mode = true;
+        self.request.color_mode = color_mode.into();
+        self
+    }
+
+    /// Sets the brightness of the color channels from `0.0` to `1.0`.
+    pub fn color_brightness(mut self, color_brightness: f32) -> Self {
+        self.request.has_color_brightness = true;
+        self.request.color_brightness = color_brightness;
+        self
+    }
+
+    /// Sets the RGB color, each channel from `0.0` to `1.0`.
+    pub fn rgb(mut self, red: f32, green: f32, blue: f32) -> Self {
+        self.request.has_rgb = true;
+        self.request.red = red;
+        self.request.green = green;
+        self.request.blue = blue;
+        self
+    }
+
+    /// Sets the white channel from `0.0` to `1.0`.
+    pub fn white(mut self, white: f32) -> Self {
+        self.request.has_white = true;
+        self.request.white = white;
+        self
+    }
+
+    /// Sets the color temperature in mireds.
+    pub fn color_temperature(mut self, mireds: f32) -> Self {
+        self.request.has_color_temperature = true;
+        self.request.color_temperature = mireds;
+        self
+    }
+
+    /// Sets the cold white channel from `0.0` to `1.0`.
+    pub fn cold_white(mut self, cold_white: f32) -> Self {
+        self.request.has_cold_white = true;
+        self.request.cold_white = cold_white;
+        self
+    }
+
+    /// Sets the warm white channel from `0.0` to `1.0`.
+    pub fn warm_white(mut self, warm_white: f32) -> Self {
+        self.request.has_warm_white = true;
+        self.request.warm_white = warm_white;
+        self
+    }
+
+    /// Sets the transition length in seconds.
+    pub fn transition(mut self, seconds: f32) -> Self {
+        self.request.has_transition_length = true;
+        self.request.transition_length = seconds_to_millis(seconds);
+        self
+    }
+
+    /// Flashes the light for the given number of seconds.
+    pub fn flash(mut self, seconds: f32) -> Self {
+        self.request.has_flash_length = true;
+        self.request.flash_length = seconds_to_millis(seconds);
+        self
+    }
+
+    /// Starts an effect by name.
+    pub fn effect(mut self, effect: impl Into<String>) -> Self {
+        self.request.has_effect = true;
+        self.request.effect = effect.into();
+        self
+    }
+}
+
+command_builder!(
+    /// Command for a `switch` entity.
+    SwitchCommandBuilder(SwitchCommandRequest) => switch
+);
+
+impl SwitchCommandBuilder {
+    /// Turns the switch on.
+    pub fn turn_on(self) -> Self {
+        self.state(true)
+    }
+
+    /// Turns the switch off.
+    pub fn turn_off(self) -> Self {
+        self.state(false)
+    }
+
+    /// Sets the state.
+    pub fn state(mut self, state: bool) -> Self {
+        self.request.state = state;
+        self
+    }
+}
+
+command_builder!(
+    /// Command for a `cover` entity.
+    CoverCommandBuilder(CoverCommandRequest) => cover
+);
+
+impl CoverCommandBuilder {
+    /// Opens the cover completely.
+    pub fn open(self) -> Self {
+        self.position(1.0)
+    }
+
+    /// Closes the cover completely.
+    pub fn close(self) -> Self {
+        self.position(0.0)
+    }
+
+    /// Stops the current movement.
+    pub fn stop(mut self) -> Self {
+        self.request.stop = true;
+        self
+    }
+
+    /// Moves the cover to a position from `0.0` (closed) to `1.0` (open).
+    pub fn position(mut self, position: f32) -> Self {
+        self.request.has_position = true;
+        self.request.position = position;
+        self
+    }
+
+    /// Sets the tilt from `0.0` to `1.0`.
+    pub fn tilt(mut self, tilt: f32) -> Self {
+        self.request.has_tilt = true;
+        self.request.tilt = tilt;
+        self
+    }
+}
+
+command_builder!(
+    /// Command for a `fan` entity.
+    FanCommandBuilder(FanCommandRequest) => fan
+);
+
+impl FanCommandBuilder {
+    /// Turns the fan on.
+    pub fn turn_on(self) -> Self {
+        self.state(true)
+    }
+
+    /// Turns the fan off.
+    pub fn turn_off(self) -> Self {
+        self.state(false)
+    }
+
+    /// Sets the on/off state.
+    pub fn state(mut self, state: bool) -> Self {
+        self.request.has_state = true;
+        self.request.state = state;
+        self
+    }
+
+    /// Sets the speed level, from `1` to the `supported_speed_count` of the fan.
+    pub fn speed_level(mut self, speed_level: i32) -> Self {
+        self.request.has_speed_level = true;
+        self.request.speed_level = speed_level;
+        self
+    }
+
+    /// Turns oscillation on or off.
+    pub fn oscillating(mut self, oscillating: bool) -> Self {
+        self.request.has_oscillating = true;
+        self.request.oscillating = oscillating;
+        self
+    }
+
+    /// Sets the direction, e.g. `FanDirection::Reverse`.
+    pub fn direction(mut self, direction: FanDirection) -> Self {
+        self.request.has_direction = true;
+        self.request.direction = direction.into();
+        self
+    }
+
+    /// Activates a preset mode by name.
+    pub fn preset_mode(mut self, preset_mode: impl Into<String>) -> Self {
+        self.request.has_preset_mode = true;
+        self.request.preset_mode = preset_mode.into();
+        self
+    }
+}
+
+command_builder!(
+    /// Command for a `climate` entity.
+    ClimateCommandBuilder(ClimateCommandRequest) => climate
+);
+
+impl ClimateCommandBuilder {
+    /// Sets the mode, e.g. `ClimateMode::Heat`.
+    pub fn mode(mut self, mode: ClimateMode) -> Self {
+        self.request.has_mode = true;
+        self.request.mode = mode.into();
+        self
+    }
+
+    /// Sets the target temperature.
+    pub fn target_temperature(mut self, temperature: f32) -> Self {
+        self.request.has_target_temperature = true;
+        self.request.target_temperature = temperature;
+        self
+    }
+
+    /// Sets the lower target temperature of a two-point climate.
+    pub fn target_temperature_low(mut self, temperature: f32) -> Self {
+        self.request.has_target_temperature_low = true;
+        self.request.target_temperature_low = temperature;
+        self
+    }
+
+    /// Sets the upper target temperature of a two-point climate.
+    pub fn target_temperature_high(mut self, temperature: f32) -> Self {
+        self.request.has_target_temperature_high = true;
+        self.request.target_temperature_high = temperature;
+        self
+    }
+
+    /// Sets the target humidity in percent.
+    pub fn target_humidity(mut self, humidity: f32) -> Self {
+        self.request.has_target_humidity = true;
+        self.request.target_humidity = humidity;
+        self
+    }
+
+    /// Sets one of the predefined fan modes, e.g. `ClimateFanMode::ClimateFanAuto`.
+    pub fn fan_mode(mut self, fan_mode: ClimateFanMode) -> Self {
+        self.request.has_fan_mode = true;
+        self.request.fan_mode = fan_mode.into();
+        self
+    }
+
+    /// Sets a custom fan mode by name.
+    pub fn custom_fan_mode(mut self, fan_mode: impl Into<String>) -> Self {
+        self.request.has_custom_fan_mode = true;
+        self.request.custom_fan_mode = fan_mode.into();
+        self
+    }
+
+    /// Sets the swing mode, e.g. `ClimateSwingMode::ClimateSwingVertical`.
+    pub fn swing_mode(mut self, swing_mode: ClimateSwingMode) -> Self {
+        self.request.has_swing_mode = true;
+        self.request.swing_mode = swing_mode.into();
+        self
+    }
+
+    /// Sets one of the predefined presets, e.g. `ClimatePreset::Eco`.
+    pub fn preset(mut self, preset: ClimatePreset) -> Self {
+        self.request.has_preset = true;
+        self.request.preset = preset.into();
+        self
+    }
+
+    /// Sets a custom preset by name.
+    pub fn custom_preset(mut self, preset: impl Into<String>) -> Self {
+        self.request.has_custom_preset = true;
+        self.request.custom_preset = preset.into();
+        self
+    }
+}
+
+command_builder!(
+    /// Command for a `valve` entity.
+    ValveCommandBuilder(ValveCommandRequest) => valve
+);
+
+impl ValveCommandBuilder {
+    /// Opens the valve completely.
+    pub fn open(self) -> Self {
+        self.position(1.0)
+    }
+
+    /// Closes the valve completely.
+    pub fn close(self) -> Self {
+        self.position(0.0)
+    }
+
+    /// Stops the current movement.
+    pub fn stop(mut self) -> Self {
+        self.request.stop = true;
+        self
+    }
+
+    /// Moves the valve to a position from `0.0` (closed) to `1.0` (open).
+    pub fn position(mut self, position: f32) -> Self {
+        self.request.has_position = true;
+        self.request.position = position;
+        self
+    }
+}
+
+command_builder!(
+    /// Command for a `lock` entity.
+    ///
+    /// Without calling one of the actions the lock is unlocked.
+    LockCommandBuilder(LockCommandRequest) => lock
+);
+
+impl LockCommandBuilder {
+    /// Locks the lock.
+    pub fn lock(mut self) -> Self {
+        self.request.command = LockCommand::LockLock.into();
+        self
+    }
+
+    /// Unlocks the lock.
+    pub fn unlock(mut self) -> Self {
+        self.request.command = LockCommand::LockUnlock.into();
+        self
+    }
+
+    /// Opens the door, if the lock supports it.
+    pub fn open(mut self) -> Self {
+        self.request.command = LockCommand::LockOpen.into();
+        self
+    }
+
+    /// Sets the code required by the lock.
+    pub fn code(mut self, code: impl Into<String>) -> Self {
+        self.request.has_code = true;
+        self.request.code = code.into();
+        self
+    }
+}
+
+command_builder!(
+    /// Command for a `number` entity.
+    NumberCommandBuilder(NumberCommandRequest) => number
+);
+
+impl NumberCommandBuilder {
+    /// Sets the value.
+    pub fn value(mut self, value: f32) -> Self {
+        self.request.state = value;
+        self
+    }
+}
+
+command_builder!(
+    /// Command for a `select` entity.
+    SelectCommandBuilder(SelectCommandRequest) => select
+);
+
+impl SelectCommandBuilder {
+    /// Selects an option.
+    pub fn option(mut self, option: impl Into<String>) -> Self {
+        self.request.state = option.into();
+        self
+    }
+}
+
+command_builder!(
+    /// Command for a `text` entity.
+    TextCommandBuilder(TextCommandRequest) => text
+);
+
+impl TextCommandBuilder {
+    /// Sets the text.
+    pub fn value(mut self, value: impl Into<String>) -> Self {
+        self.request.state = value.into();
+        self
+    }
+}
+
+command_builder!(
+    /// Command for a `date` entity.
+    DateCommandBuilder(DateCommandRequest) => date
+);
+
+impl DateCommandBuilder {
+    /// Sets the date.
+    pub fn date(mut self, year: u32, month: u32, day: u32) -> Self {
+        self.request.year = year;
+        self.request.month = month;
+        self.request.day = day;
+        self
+    }
+}
+
+command_builder!(
+    /// Command for a `time` entity.
+    TimeCommandBuilder(TimeCommandRequest) => time
+);
+
+impl TimeCommandBuilder {
+    /// Sets the time of day.
+    pub fn time(mut self, hour: u32, minute: u32, second: u32) -> Self {
+        self.request.hour = hour;
+        self.request.minute = minute;
+        self.request.second = second;
+        self
+    }
+}
+
+command_builder!(
+    /// Command for a `datetime` entity.
+    DateTimeCommandBuilder(DateTimeCommandRequest) => datetime
+);
+
+impl DateTimeCommandBuilder {
+    /// Sets the point in time as seconds since the unix epoch.
+    pub fn epoch_seconds(mut self, epoch_seconds: u32) -> Self {
+        self.request.epoch_seconds = epoch_seconds;
+        self
+    }
+}
+
+command_builder!(
+    /// Command for a `button` entity, sending it presses the button.
+    ButtonCommandBuilder(ButtonCommandRequest) => button
+);
+
+command_builder!(
+    /// Command for a `media_player` entity.
+    MediaPlayerCommandBuilder(MediaPlayerCommandRequest) => media_player
+);
+
+impl MediaPlayerCommandBuilder {
+    /// Starts or resumes playback.
+    pub fn play(self) -> Self {
+        self.command(MediaPlayerCommand::Play)
+    }
+
+    /// Pauses playback.
+    pub fn pause(self) -> Self {
+        self.command(MediaPlayerCommand::Pause)
+    }
+
+    /// Stops playback.
+    pub fn stop(self) -> Self {
+        self.command(MediaPlayerCommand::Stop)
+    }
+
+    /// Mutes the player.
+    pub fn mute(self) -> Self {
+        self.command(MediaPlayerCommand::Mute)
+    }
+
+    /// Unmutes the player.
+    pub fn unmute(self) -> Self {
+        self.command(MediaPlayerCommand::Unmute)
+    }
+
+    /// Sends any other media player command.
+    pub fn command(mut self, command: MediaPlayerCommand) -> Self {
+        self.request.has_command = true;
+        self.request.command = command.into();
+        self
+    }
+
+    /// Sets the volume from `0.0` to `1.0`.
+    pub fn volume(mut self, volume: f32) -> Self {
+        self.request.has_volume = true;
+        self.request.volume = volume;
+        self
+    }
+
+    /// Plays the media at the given URL.
+    pub fn media_url(mut self, media_url: impl Into<String>) -> Self {
+        self.request.has_media_url = true;
+        self.request.media_url = media_url.into();
+        self
+    }
+
+    /// Plays the media as an announcement, interrupting the current playback.
+    pub fn announcement(mut self, announcement: bool) -> Self {
+        self.request.has_announcement = true;
+        self.request.announcement = announcement;
+        self
+    }
+}
+
+command_builder!(
+    /// Command for an `alarm_control_panel` entity.
+    ///
+    /// Without calling one of the actions the panel is disarmed.
+    AlarmControlPanelCommandBuilder(AlarmControlPanelCommandRequest) => alarm_control_panel
+);
+
+impl AlarmControlPanelCommandBuilder {
+    /// Disarms the panel.
+    pub fn disarm(mut self) -> Self {
+        self.request.command = AlarmControlPanelStateCommand::AlarmControlPanelDisarm.into();
+        self
+    }
+
+    /// Arms the panel in away mode.
+    pub fn arm_away(mut self) -> Self {
+        self.request.command = AlarmControlPanelStateCommand::AlarmControlPanelArmAway.into();
+        self
+    }
+
+    /// Arms the panel in home mode.
+    pub fn arm_home(mut self) -> Self {
+        self.request.command = AlarmControlPanelStateCommand::AlarmControlPanelArmHome.into();
+        self
+    }
+
+    /// Arms the panel in night mode.
+    pub fn arm_night(mut self) -> Self {
+        self.request.command = AlarmControlPanelStateCommand::AlarmControlPanelArmNight.into();
+        self
+    }
+
+    /// Arms the panel in vacation mode.
+    pub fn arm_vacation(mut self) -> Self {
+        self.request.command = AlarmControlPanelStateCommand::AlarmControlPanelArmVacation.into();
+        self
+    }
+
+    /// Arms the panel with custom bypass.
+    pub fn arm_custom_bypass(mut self) -> Self {
+        self.request.command =
+            AlarmControlPanelStateCommand::AlarmControlPanelArmCustomBypass.into();
+        self
+    }
+
+    /// Triggers the alarm.
+    pub fn trigger(mut self) -> Self {
+        self.request.command = AlarmControlPanelStateCommand::AlarmControlPanelTrigger.into();
+        self
+    }
+
+    /// Sets the code required by the panel.
+    pub fn code(mut self, code: impl Into<String>) -> Self {
+        self.request.code = code.into();
+        self
+    }
+}
+
+command_builder!(
+    /// Command for an `update` entity.
+    UpdateCommandBuilder(UpdateCommandRequest) => update
+);
+
+impl UpdateCommandBuilder {
+    /// Installs the available update.
+    pub fn install(mut self) -> Self {
+        self.request.command = UpdateCommand::Update.into();
+        self
+    }
+
+    /// Checks for a new update.
+    pub fn check(mut self) -> Self {
+        self.request.command = UpdateCommand::Check.into();
+        self
+    }
+}
+
+fn seconds_to_millis(seconds: f32) -> u32 {
+    (seconds.max(0.0) * 1000.0).round() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn light_command_sets_only_given_flags() {
+        let request = light(1)
+            .turn_on()
+            .brightness(0.5)
+            .color_temperature(300.0)
+            .transition(2.0)
+            .build();
+
+        assert_eq!(request.key, 1);
+        assert!(request.has_state && request.state);
+        assert!(request.has_brightness);
+        assert_eq!(request.brightness, 0.5);
+        assert!(request.has_color_temperature);
+        assert_eq!(request.color_temperature, 300.0);
+        assert!(request.has_transition_length);
+        assert_eq!(request.transition_length, 2000);
+        assert!(!request.has_rgb);
+        assert!(!request.has_effect);
+        assert!(!request.has_color_mode);
+    }
+
+    #[test]
+    fn light_command_color() {
+        let request = light(1)
+            .color_mode(ColorMode::Rgb)
+            .rgb(1.0, 0.0, 0.5)
+            .build();
+
+        assert!(request.has_color_mode);
+        assert_eq!(request.color_mode, ColorMode::Rgb as i32);
+        assert!(request.has_rgb);
+        assert_eq!(request.blue, 0.5);
+        assert!(!request.has_state);
+    }
+
+    #[test]
+    fn cover_and_valve_commands() {
+        let request = cover(2).open().tilt(0.25).build();
+        assert!(request.has_position);
+        assert_eq!(request.position, 1.0);
+        assert!(request.has_tilt);
+        assert!(!request.stop);
+
+        let request = valve(3).stop().build();
+        assert!(request.stop);
+        assert!(!request.has_position);
+    }
+
+    #[test]
+    fn climate_command() {
+        let request = climate(4)
+            .mode(ClimateMode::Heat)
+            .target_temperature(21.5)
+            .preset(ClimatePreset::Eco)
+            .build();
+        assert!(request.has_mode);
+        assert_eq!(request.mode, ClimateMode::Heat as i32);
+        assert!(request.has_target_temperature);
+        assert!(request.has_preset);
+        assert_eq!(request.preset, ClimatePreset::Eco as i32);
+        assert!(!request.has_target_temperature_low);
+        assert!(!request.has_fan_mode);
+    }
+
+    #[test]
+    fn lock_and_alarm_control_panel_commands() {
+        let request = lock(5).open().code("1234").build();
+        assert_eq!(request.command, LockCommand::LockOpen as i32);
+        assert!(request.has_code);
+
+        let request = alarm_control_panel(6).arm_night().code("0000").build();
+        assert_eq!(
+            request.command,
+            AlarmControlPanelStateCommand::AlarmControlPanelArmNight as i32
+        );
+        assert_eq!(request.code, "0000");
+    }
+
+    #[test]
+    fn commands_convert_into_messages() {
+        let message: ProtoMessage = switch(7).turn_on().device_id(2).into();
+        match message {
+            ProtoMessage::SwitchCommandRequest(request) => {
+                assert_eq!(request.key, 7);
+                assert!(request.state);
+                assert_eq!(request.device_id, 2);
+            }
+            message => panic!("Unexpected message: {:?}", message),
+        }
+
+        let message: ProtoMessage = button(8).into();
+        assert!(matches!(
+            message,
+            ProtoMessage::ButtonCommandRequest(ButtonCommandRequest { key: 8, .. })
+        ));
+    }
+}
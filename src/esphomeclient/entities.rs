@@ -0,0 +1,295 @@
+//! Typed entity catalog built from `ListEntities*Response` messages.
+//!
+//! After a `ListEntitiesRequest` the device announces every entity with a domain
+//! specific response, terminated by `ListEntitiesDoneResponse`. The [`EntityCatalog`]
+//! collects these responses and makes them available by key, by object id and by
+//! domain.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! # use esphome_native_api::esphomeclient::EspHomeClient;
+//! # use tokio::net::TcpStream;
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! let stream = TcpStream::connect("192.168.1.100:6053").await?;
+//! let connection = EspHomeClient::builder().build().start(stream).await?;
+//!
+//! let catalog = connection.list_entities().await?;
+//! for sensor in catalog.sensors() {
+//!     println!("{} ({})", sensor.name, sensor.unit_of_measurement);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+
+pub use crate::entity::Domain;
+use crate::parser::ProtoMessage;
+use crate::proto::{
+    ListEntitiesAlarmControlPanelResponse, ListEntitiesBinarySensorResponse,
+    ListEntitiesButtonResponse, ListEntitiesCameraResponse, ListEntitiesClimateResponse,
+    ListEntitiesCoverResponse, ListEntitiesDateResponse, ListEntitiesDateTimeResponse,
+    ListEntitiesEventResponse, ListEntitiesFanResponse, ListEntitiesLightResponse,
+    ListEntitiesLockResponse, ListEntitiesMediaPlayerResponse, ListEntitiesNumberResponse,
+    ListEntitiesSelectResponse, ListEntitiesSensorResponse, ListEntitiesServicesResponse,
+    ListEntitiesSwitchResponse, ListEntitiesTextResponse, ListEntitiesTextSensorResponse,
+    ListEntitiesTimeResponse, ListEntitiesUpdateResponse, ListEntitiesValveResponse,
+};
+
+macro_rules! entity_mappings {
+    ($($variant:ident($response:ident) => $domain:literal, $accessor:ident;)*) => {
+        /// An entity announced by the device, holding the original list entities response.
+        #[derive(Clone, Debug)]
+        pub enum EntityInfo {
+            $(
+                #[doc = concat!("Entity of the `", $domain, "` domain")]
+                $variant($response),
+            )*
+        }
+
+        impl EntityInfo {
+            /// Converts a list entities response into an entity.
+            ///
+            /// Returns `None` for all other messages.
+            pub fn from_message(message: ProtoMessage) -> Option<Self> {
+                match message {
+                    $(ProtoMessage::$response(response) => Some(EntityInfo::$variant(response)),)*
+                    _ => None,
+                }
+            }
+
+            /// The domain of the entity.
+            pub fn domain(&self) -> Domain {
+                match self {
+                    $(EntityInfo::$variant(_) => Domain::$variant,)*
+                }
+            }
+
+            /// The key used to address the entity in state and command messages.
+            pub fn key(&self) -> u32 {
+                match self {
+                    $(EntityInfo::$variant(response) => response.key,)*
+                }
+            }
+
+            /// The object id of the entity.
+            pub fn object_id(&self) -> &str {
+                match self {
+                    $(EntityInfo::$variant(response) => &response.object_id,)*
+                }
+            }
+
+            /// The display name of the entity.
+            pub fn name(&self) -> &str {
+                match self {
+                    $(EntityInfo::$variant(response) => &response.name,)*
+                }
+            }
+        }
+
+        impl EntityCatalog {
+            $(
+                #[doc = concat!("Iterates over all entities of the `", $domain, "` domain.")]
+                pub fn $accessor(&self) -> impl Iterator<Item = &$response> {
+                    self.entities.values().filter_map(|entity| match entity {
+                        EntityInfo::$variant(response) => Some(response),
+                        _ => None,
+                    })
+                }
+            )*
+        }
+    };
+}
+
+entity_mappings!(
+    AlarmControlPanel(ListEntitiesAlarmControlPanelResponse) => "alarm_control_panel", alarm_control_panels;
+    BinarySensor(ListEntitiesBinarySensorResponse) => "binary_sensor", binary_sensors;
+    Button(ListEntitiesButtonResponse) => "button", buttons;
+    Camera(ListEntitiesCameraResponse) => "camera", cameras;
+    Climate(ListEntitiesClimateResponse) => "climate", climates;
+    Cover(ListEntitiesCoverResponse) => "cover", covers;
+    Date(ListEntitiesDateResponse) => "date", dates;
+    DateTime(ListEntitiesDateTimeResponse) => "datetime", datetimes;
+    Event(ListEntitiesEventResponse) => "event", events;
+    Fan(ListEntitiesFanResponse) => "fan", fans;
+    Light(ListEntitiesLightResponse) => "light", lights;
+    Lock(ListEntitiesLockResponse) => "lock", locks;
+    MediaPlayer(ListEntitiesMediaPlayerResponse) => "media_player", media_players;
+    Number(ListEntitiesNumberResponse) => "number", numbers;
+    Select(ListEntitiesSelectResponse) => "select", selects;
+    Sensor(ListEntitiesSensorResponse) => "sensor", sensors;
+    Switch(ListEntitiesSwitchResponse) => "switch", switches;
+    Text(ListEntitiesTextResponse) => "text", texts;
+    TextSensor(ListEntitiesTextSensorResponse) => "text_sensor", text_sensors;
+    Time(ListEntitiesTimeResponse) => "time", times;
+    Update(ListEntitiesUpdateResponse) => "update", updates;
+    Valve(ListEntitiesValveResponse) => "valve", valves;
+);
+
+/// All entities and user-defined services announced by a device.
+#[derive(Clone, Debug, Default)]
+pub struct EntityCatalog {
+    entities: BTreeMap<u32, EntityInfo>,
+    keys_by_object_id: HashMap<String, u32>,
+    services: Vec<ListEntitiesServicesResponse>,
+}
+
+impl EntityCatalog {
+    /// Adds a list entities message to the catalog.
+    ///
+    /// Returns `false` if the message is neither an entity nor a service announcement.
+    pub fn insert_message(&mut self, message: ProtoMessage) -> bool {
+        if let ProtoMessage::ListEntitiesServicesResponse(service) = message {
+            self.services.push(service);
+            return true;
+        }
+        match EntityInfo::from_message(message) {
+            Some(entity) => {
+                self.insert(entity);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Adds an entity to the catalog, replacing any entity with the same key.
+    pub fn insert(&mut self, entity: EntityInfo) {
+        if let Some(replaced) = self.entities.get(&entity.key())
+            && self.keys_by_object_id.get(replaced.object_id()) == Some(&entity.key())
+        {
+            self.keys_by_object_id.remove(replaced.object_id());
+        }
+        self.keys_by_object_id
+            .insert(entity.object_id().to_string(), entity.key());
+        self.entities.insert(entity.key(), entity);
+    }
+
+    /// Looks up an entity by its key.
+    pub fn get(&self, key: u32) -> Option<&EntityInfo> {
+        self.entities.get(&key)
+    }
+
+    /// Looks up an entity by its object id.
+    pub fn get_by_object_id(&self, object_id: &str) -> Option<&EntityInfo> {
+        self.keys_by_object_id
+            .get(object_id)
+            .and_then(|key| self.entities.get(key))
+    }
+
+    /// Iterates over all entities ordered by key.
+    pub fn iter(&self) -> impl Iterator<Item = &EntityInfo> {
+        self.entities.values()
+    }
+
+    /// Iterates over all entities of a domain.
+    pub fn by_domain(&self, domain: Domain) -> impl Iterator<Item = &EntityInfo> {
+        self.entities
+            .values()
+            .filter(move |entity| entity.domain() == domain)
+    }
+
+    /// The user-defined services announced by the device.
+    pub fn services(&self) -> &[ListEntitiesServicesResponse] {
+        &self.services
+    }
+
+    /// Number of entities in the catalog, not counting services.
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    /// Returns `true` if the catalog contains no entities.
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proto::ListEntitiesDoneResponse;
+
+    use super::*;
+
+    fn sensor(key: u32, object_id: &str) -> ProtoMessage {
+        ProtoMessage::ListEntitiesSensorResponse(ListEntitiesSensorResponse {
+            key,
+            object_id: object_id.to_string(),
+            name: object_id.to_string(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn catalog_lookup_by_key_and_object_id() {
+        let mut catalog = EntityCatalog::default();
+        assert!(catalog.insert_message(sensor(1, "temperature")));
+        assert!(
+            catalog.insert_message(ProtoMessage::ListEntitiesSwitchResponse(
+                ListEntitiesSwitchResponse {
+                    key: 2,
+                    object_id: "relay".to_string(),
+                    ..Default::default()
+                }
+            ))
+        );
+
+        assert_eq!(catalog.len(), 2);
+        assert_eq!(catalog.get(1).unwrap().object_id(), "temperature");
+        assert_eq!(catalog.get_by_object_id("relay").unwrap().key(), 2);
+        assert_eq!(
+            catalog.get_by_object_id("relay").unwrap().domain(),
+            Domain::Switch
+        );
+        assert!(catalog.get(3).is_none());
+    }
+
+    #[test]
+    fn catalog_replaces_object_id_of_replaced_entity() {
+        let mut catalog = EntityCatalog::default();
+        catalog.insert_message(sensor(1, "temperature"));
+        catalog.insert_message(sensor(1, "outdoor_temperature"));
+
+        assert_eq!(catalog.len(), 1);
+        assert!(catalog.get_by_object_id("temperature").is_none());
+        assert_eq!(
+            catalog
+                .get_by_object_id("outdoor_temperature")
+                .unwrap()
+                .key(),
+            1
+        );
+    }
+
+    #[test]
+    fn catalog_groups_by_domain() {
+        let mut catalog = EntityCatalog::default();
+        catalog.insert_message(sensor(1, "temperature"));
+        catalog.insert_message(sensor(2, "humidity"));
+        catalog.insert_message(ProtoMessage::ListEntitiesButtonResponse(
+            ListEntitiesButtonResponse {
+                key: 3,
+                object_id: "restart".to_string(),
+                ..Default::default()
+            },
+        ));
+
+        assert_eq!(catalog.sensors().count(), 2);
+        assert_eq!(catalog.buttons().count(), 1);
+        assert_eq!(catalog.lights().count(), 0);
+        assert_eq!(catalog.by_domain(Domain::Sensor).count(), 2);
+        assert_eq!(Domain::BinarySensor.as_str(), "binary_sensor");
+    }
+
+    #[test]
+    fn catalog_ignores_other_messages() {
+        let mut catalog = EntityCatalog::default();
+        assert!(
+            !catalog.insert_message(ProtoMessage::ListEntitiesDoneResponse(
+                ListEntitiesDoneResponse {}
+            ))
+        );
+        assert!(catalog.is_empty());
+    }
+}
@@ -0,0 +1,236 @@
+//! Log streaming with structured log records.
+//!
+//! ESPHome sends every log line as a `SubscribeLogsResponse` containing the colored
+//! text printed on the serial console, e.g.
+//! `\x1b[0;32m[I][sensor:093]: 'Temperature': Sending state 21.5\x1b[0m`.
+//! A [`LogStream`] filters these responses out of the incoming messages and parses
+//! them into [`LogRecord`]s.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! # use esphome_native_api::esphomeclient::EspHomeClient;
+//! # use esphome_native_api::proto::LogLevel;
+//! # use tokio::net::TcpStream;
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! let stream = TcpStream::connect("192.168.1.100:6053").await?;
+//! let connection = EspHomeClient::builder().build().start(stream).await?;
+//!
+//! let mut logs = connection.subscribe_logs(LogLevel::Debug, true).await?;
+//! while let Some(record) = logs.recv().await {
+//!     println!("{:?} {}: {}", record.level, record.tag.unwrap_or_default(), record.message);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use log::warn;
+use tokio::sync::broadcast;
+
+use crate::parser::ProtoMessage;
+use crate::proto::{LogLevel, SubscribeLogsResponse};
+
+/// A single parsed log line of a device.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogRecord {
+    /// Level of the log line.
+    pub level: LogLevel,
+    /// Component that logged the line, e.g. `sensor`.
+    pub tag: Option<String>,
+    /// Source line of the log call.
+    pub line: Option<u32>,
+    /// The message without level, tag and color codes.
+    pub message: String,
+}
+
+impl LogRecord {
+    /// Parses a `SubscribeLogsResponse`.
+    pub fn from_response(response: &SubscribeLogsResponse) -> Self {
+        let level = LogLevel::try_from(response.level).unwrap_or(LogLevel::None);
+        LogRecord::parse(level, &String::from_utf8_lossy(&response.message))
+    }
+
+    /// Parses a log line as printed by ESPHome, with or without color codes.
+    ///
+    /// Lines that do not follow the `[L][tag:line]: message` format are kept as message
+    /// without tag and line. If `level` is [`LogLevel::None`], the level is taken from
+    /// the line.
+    pub fn parse(level: LogLevel, text: &str) -> Self {
+        let text = strip_ansi(text);
+        let text = text.trim_end_matches(['\r', '\n']);
+
+        match parse_prefix(text) {
+            Some((line_level, tag, line, message)) => LogRecord {
+                level: match level {
+                    LogLevel::None => line_level.unwrap_or(LogLevel::None),
+                    level => level,
+                },
+                tag: Some(tag.to_string()),
+                line,
+                message: message.to_string(),
+            },
+            None => LogRecord {
+                level,
+                tag: None,
+                line: None,
+                message: text.to_string(),
+            },
+        }
+    }
+}
+
+/// Splits `[L][tag:line]: message` into its parts.
+fn parse_prefix(text: &str) -> Option<(Option<LogLevel>, &str, Option<u32>, &str)> {
+    let (level, rest) = text.strip_prefix('[')?.split_once(']')?;
+    let (source, mut rest) = rest.strip_prefix('[')?.split_once(']')?;
+    // Newer versions may append further bracketed fields, e.g. the task name.
+    while let Some((_, remaining)) = rest.strip_prefix('[').and_then(|r| r.split_once(']')) {
+        rest = remaining;
+    }
+    let message = rest.strip_prefix(':')?;
+    let message = message.strip_prefix(' ').unwrap_or(message);
+
+    let (tag, line) = match source.rsplit_once(':') {
+        Some((tag, line)) => match line.parse() {
+            Ok(line) => (tag, Some(line)),
+            Err(_) => (source, None),
+        },
+        None => (source, None),
+    };
+    Some((level_from_letter(level), tag, line, message))
+}
+
+fn level_from_letter(letter: &str) -> Option<LogLevel> {
+    match letter {
+        "E" => Some(LogLevel::Error),
+        "W" => Some(LogLevel::Warn),
+        "I" => Some(LogLevel::Info),
+        "C" => Some(LogLevel::Config),
+        "D" => Some(LogLevel::Debug),
+        "V" => Some(LogLevel::Verbose),
+        "VV" => Some(LogLevel::VeryVerbose),
+        _ => None,
+    }
+}
+
+/// Removes ANSI escape sequences, like the color codes ESPHome adds to log lines.
+pub fn strip_ansi(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            stripped.push(c);
+            continue;
+        }
+        // Control sequences run until a final byte in `@`..=`~`, all other escape
+        // sequences are two characters long.
+        if chars.next() == Some('[') {
+            for c in chars.by_ref() {
+                if ('@'..='~').contains(&c) {
+                    break;
+                }
+            }
+        }
+    }
+    stripped
+}
+
+/// Stream of log records of a device.
+///
+/// Only records up to the requested level are yielded, even if another subscription
+/// on the same connection requested more verbose logs.
+pub struct LogStream {
+    incoming: broadcast::Receiver<ProtoMessage>,
+    level: LogLevel,
+}
+
+impl LogStream {
+    pub(crate) fn new(incoming: broadcast::Receiver<ProtoMessage>, level: LogLevel) -> Self {
+        LogStream { incoming, level }
+    }
+
+    /// Waits for the next log record.
+    ///
+    /// Returns `None` once the connection is closed.
+    pub async fn recv(&mut self) -> Option<LogRecord> {
+        loop {
+            match self.incoming.recv().await {
+                Ok(ProtoMessage::SubscribeLogsResponse(response)) => {
+                    let record = LogRecord::from_response(&response);
+                    if record.level <= self.level {
+                        return Some(record);
+                    }
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("Log stream missed {} messages", skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_colored_log_line() {
+        let record = LogRecord::parse(
+            LogLevel::Info,
+            "\x1b[0;32m[I][sensor:093]: 'Temperature': Sending state 21.50000 °C\x1b[0m",
+        );
+        assert_eq!(record.level, LogLevel::Info);
+        assert_eq!(record.tag.as_deref(), Some("sensor"));
+        assert_eq!(record.line, Some(93));
+        assert_eq!(record.message, "'Temperature': Sending state 21.50000 °C");
+    }
+
+    #[test]
+    fn parses_level_from_line_and_extra_fields() {
+        let record = LogRecord::parse(LogLevel::None, "[VV][api.service:042][loopTask]: Hello");
+        assert_eq!(record.level, LogLevel::VeryVerbose);
+        assert_eq!(record.tag.as_deref(), Some("api.service"));
+        assert_eq!(record.line, Some(42));
+        assert_eq!(record.message, "Hello");
+    }
+
+    #[test]
+    fn keeps_unstructured_lines() {
+        let record = LogRecord::parse(LogLevel::Config, "  Update Interval: 60.0s\n");
+        assert_eq!(record.level, LogLevel::Config);
+        assert_eq!(record.tag, None);
+        assert_eq!(record.line, None);
+        assert_eq!(record.message, "  Update Interval: 60.0s");
+    }
+
+    #[test]
+    fn strips_ansi_sequences() {
+        assert_eq!(strip_ansi("\x1b[1;31mError\x1b[0m done"), "Error done");
+        assert_eq!(strip_ansi("plain"), "plain");
+    }
+
+    #[tokio::test]
+    async fn log_stream_filters_by_level() {
+        let (tx, rx) = broadcast::channel(8);
+        let mut logs = LogStream::new(rx, LogLevel::Info);
+
+        for (level, text) in [
+            (LogLevel::Debug, "[D][a:1]: debug"),
+            (LogLevel::Warn, "[W][b:2]: warning"),
+        ] {
+            tx.send(ProtoMessage::SubscribeLogsResponse(SubscribeLogsResponse {
+                level: level.into(),
+                message: text.as_bytes().to_vec(),
+            }))
+            .unwrap();
+        }
+        drop(tx);
+
+        let record = logs.recv().await.unwrap();
+        assert_eq!(record.level, LogLevel::Warn);
+        assert_eq!(record.message, "warning");
+        assert!(logs.recv().await.is_none());
+    }
+}
@@ -0,0 +1,583 @@
+//! Managed client connection that survives device reboots and network outages.
+//!
+//! A [`ManagedConnection`] keeps connecting to the device in the background. After
+//! every (re)connect it redoes the hello and noise handshake, lists the entities
+//! again and re-sends all subscriptions that were made through the handle, e.g.
+//! `SubscribeStatesRequest` or `SubscribeLogsRequest`. Failed attempts and dropped
+//! connections are retried with exponential [`Backoff`], the delay starts over once a
+//! connection stayed up for the maximum delay.
+//!
+//! The handle stays valid across reconnects: receivers returned by
+//! [`ManagedConnection::subscribe`] keep receiving messages from every new
+//! connection, and [`ManagedConnection::events`] reports the connection state.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! # use esphome_native_api::esphomeclient::EspHomeClient;
+//! # use esphome_native_api::esphomeclient::reconnect::{Backoff, ConnectionEvent, ManagedConnection};
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! let client = EspHomeClient::builder().build();
+//! let connection =
+//!     ManagedConnection::connect_tcp(client, Backoff::default(), "192.168.1.100:6053");
+//!
+//! let states = connection.subscribe_states().await?;
+//! let mut events = connection.events();
+//! while let Ok(event) = events.recv().await {
+//!     if let ConnectionEvent::Connected { name } = event {
+//!         println!("Connected to {}, {} states known", name, states.states().len());
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use log::debug;
+use log::warn;
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast;
+use tokio::sync::watch;
+use typed_builder::TypedBuilder;
+
+use crate::esphomeclient::ClientConnection;
+use crate::esphomeclient::ClientError;
+use crate::esphomeclient::EspHomeClient;
+use crate::esphomeclient::entities::EntityCatalog;
+use crate::esphomeclient::logs::LogStream;
+use crate::esphomeclient::states::StateCache;
+use crate::parser::ProtoMessage;
+use crate::proto::{LogLevel, SubscribeLogsRequest, SubscribeStatesRequest};
+
+/// Exponential backoff between reconnect attempts.
+#[derive(TypedBuilder, Clone, Debug)]
+pub struct Backoff {
+    /// Delay after the first failed attempt.
+    #[builder(default = Duration::from_secs(1))]
+    initial: Duration,
+    /// Upper bound for the delay.
+    #[builder(default = Duration::from_secs(60))]
+    max: Duration,
+    /// Factor the delay grows by with every failed attempt.
+    #[builder(default = 2.0)]
+    multiplier: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::builder().build()
+    }
+}
+
+impl Backoff {
+    /// Returns the delay before the reconnect attempt following `attempt` failed ones.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.powi(attempt.min(i32::MAX as u32) as i32);
+        Duration::try_from_secs_f64(self.initial.as_secs_f64() * factor)
+            .unwrap_or(self.max)
+            .min(self.max)
+    }
+}
+
+/// Current state of a [`ManagedConnection`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// A connection attempt is in progress.
+    Connecting,
+    /// The device is connected and all subscriptions have been re-sent.
+    Connected,
+    /// The connection was lost or the last attempt failed; waiting for the next attempt.
+    Disconnected,
+    /// The managed connection has been closed and will not reconnect.
+    Closed,
+}
+
+/// Connection state changes reported by [`ManagedConnection::events`].
+#[derive(Clone, Debug)]
+pub enum ConnectionEvent {
+    /// A connection has been established.
+    Connected {
+        /// Name of the device as reported in the `DeviceInfoResponse`.
+        name: String,
+    },
+    /// A connection attempt failed.
+    ConnectFailed {
+        /// Description of the error.
+        error: String,
+        /// Delay until the next attempt.
+        retry_in: Duration,
+    },
+    /// An established connection was lost.
+    Disconnected,
+    /// The managed connection has been closed.
+    Closed,
+}
+
+struct Inner {
+    current: RwLock<Option<ClientConnection>>,
+    entities: RwLock<EntityCatalog>,
+    subscriptions: Mutex<Vec<ProtoMessage>>,
+    states: StateCache,
+    incoming_tx: broadcast::Sender<ProtoMessage>,
+    events_tx: broadcast::Sender<ConnectionEvent>,
+    state_tx: watch::Sender<ConnectionState>,
+}
+
+impl Inner {
+    fn set_state(&self, state: ConnectionState) {
+        self.state_tx.send_replace(state);
+    }
+
+    fn emit(&self, event: ConnectionEvent) {
+        debug!("Managed connection event: {:?}", event);
+        // Nobody listening is fine.
+        let _ = self.events_tx.send(event);
+    }
+}
+
+/// Stops the background task once the last handle is dropped.
+struct ShutdownGuard {
+    shutdown_tx: watch::Sender<bool>,
+}
+
+impl Drop for ShutdownGuard {
+    fn drop(&mut self) {
+        self.shutdown_tx.send_replace(true);
+    }
+}
+
+/// Stable handle to a device connection that reconnects automatically.
+///
+/// The handle is cheap to clone. Reconnecting stops once [`ManagedConnection::close`]
+/// is called or the last handle is dropped.
+#[derive(Clone)]
+pub struct ManagedConnection {
+    inner: Arc<Inner>,
+    guard: Arc<ShutdownGuard>,
+}
+
+impl ManagedConnection {
+    /// Starts a managed connection to a device reachable via TCP.
+    pub fn connect_tcp(
+        client: EspHomeClient,
+        backoff: Backoff,
+        address: impl Into<String>,
+    ) -> ManagedConnection {
+        let address = address.into();
+        ManagedConnection::connect(client, backoff, move || {
+            let address = address.clone();
+            async move { TcpStream::connect(address).await }
+        })
+    }
+
+    /// Starts a managed connection using `connector` to open a new stream for every attempt.
+    pub fn connect<C, Fut, S>(
+        client: EspHomeClient,
+        backoff: Backoff,
+        connector: C,
+    ) -> ManagedConnection
+    where
+        C: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = io::Result<S>> + Send + 'static,
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (incoming_tx, _) = broadcast::channel(256);
+        let (events_tx, _) = broadcast::channel(16);
+        let (state_tx, _) = watch::channel(ConnectionState::Connecting);
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+        let inner = Arc::new(Inner {
+            current: RwLock::new(None),
+            entities: RwLock::new(EntityCatalog::default()),
+            subscriptions: Mutex::new(Vec::new()),
+            states: StateCache::default(),
+            incoming_tx,
+            events_tx,
+            state_tx,
+        });
+
+        tokio::spawn(supervise(
+            inner.clone(),
+            client,
+            backoff,
+            connector,
+            shutdown_rx,
+        ));
+
+        ManagedConnection {
+            inner,
+            guard: Arc::new(ShutdownGuard { shutdown_tx }),
+        }
+    }
+
+    /// The current connection state.
+    pub fn state(&self) -> ConnectionState {
+        *self.inner.state_tx.borrow()
+    }
+
+    /// Returns a receiver that always holds the current connection state.
+    pub fn state_changes(&self) -> watch::Receiver<ConnectionState> {
+        self.inner.state_tx.subscribe()
+    }
+
+    /// Returns a receiver for connection events.
+    pub fn events(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.inner.events_tx.subscribe()
+    }
+
+    /// Waits until the device is connected.
+    ///
+    /// Fails with [`ClientError::ConnectionClosed`] if the managed connection gets closed.
+    pub async fn connected(&self) -> Result<(), ClientError> {
+        let mut state_rx = self.state_changes();
+        let state = *state_rx
+            .wait_for(|state| matches!(state, ConnectionState::Connected | ConnectionState::Closed))
+            .await
+            .map_err(|_| ClientError::ConnectionClosed)?;
+        match state {
+            ConnectionState::Connected => Ok(()),
+            _ => Err(ClientError::ConnectionClosed),
+        }
+    }
+
+    /// The currently established connection, if any.
+    pub fn connection(&self) -> Option<ClientConnection> {
+        self.inner.current.read().unwrap().clone()
+    }
+
+    /// The entities listed after the most recent connect.
+    pub fn entities(&self) -> EntityCatalog {
+        self.inner.entities.read().unwrap().clone()
+    }
+
+    /// Returns a new receiver for all messages sent by the device.
+    ///
+    /// Unlike [`ClientConnection::subscribe`] the receiver keeps working across reconnects.
+    pub fn subscribe(&self) -> broadcast::Receiver<ProtoMessage> {
+        self.inner.incoming_tx.subscribe()
+    }
+
+    /// Sends a message to the device.
+    ///
+    /// Subscription requests are remembered and re-sent after every reconnect. They
+    /// are accepted while disconnected, all other messages fail with
+    /// [`ClientError::ConnectionClosed`].
+    pub async fn send(&self, message: ProtoMessage) -> Result<(), ClientError> {
+        // Remember and read the connection under one lock, so a concurrent reconnect
+        // either replays this subscription or we send it ourselves.
+        let (remembered, connection) = {
+            let mut subscriptions = self.inner.subscriptions.lock().unwrap();
+            (
+                remember_subscription(&mut subscriptions, &message),
+                self.connection(),
+            )
+        };
+        match connection {
+            Some(connection) => connection.send(message).await,
+            None if remembered => Ok(()),
+            None => Err(ClientError::ConnectionClosed),
+        }
+    }
+
+    /// Sends an entity command, e.g. one built with [`crate::esphomeclient::commands::light`].
+    pub async fn command(&self, command: impl Into<ProtoMessage>) -> Result<(), ClientError> {
+        self.send(command.into()).await
+    }
+
+    /// Subscribes to the states of all entities.
+    ///
+    /// The returned cache is shared by all calls and kept up to date across reconnects.
+    pub async fn subscribe_states(&self) -> Result<StateCache, ClientError> {
+        self.send(ProtoMessage::SubscribeStatesRequest(
+            SubscribeStatesRequest {},
+        ))
+        .await?;
+        Ok(self.inner.states.clone())
+    }
+
+    /// Subscribes to the device logs up to `level`.
+    ///
+    /// The subscription is renewed after every reconnect; with `dump_config` the
+    /// device logs its configuration again each time. The device sends the logs up to
+    /// the most verbose level of all subscriptions, every stream only yields the
+    /// records up to its own level.
+    pub async fn subscribe_logs(
+        &self,
+        level: LogLevel,
+        dump_config: bool,
+    ) -> Result<LogStream, ClientError> {
+        let logs = LogStream::new(self.subscribe(), level);
+        let subscribed_level = self
+            .inner
+            .subscriptions
+            .lock()
+            .unwrap()
+            .iter()
+            .find_map(|subscription| match subscription {
+                ProtoMessage::SubscribeLogsRequest(request) => Some(request.level),
+                _ => None,
+            })
+            .unwrap_or_default();
+        self.send(ProtoMessage::SubscribeLogsRequest(SubscribeLogsRequest {
+            level: subscribed_level.max(level.into()),
+            dump_config,
+        }))
+        .await?;
+        Ok(logs)
+    }
+
+    /// Stops reconnecting and closes the current connection.
+    pub async fn close(&self) {
+        self.guard.shutdown_tx.send_replace(true);
+        let mut state_rx = self.state_changes();
+        let _ = state_rx
+            .wait_for(|state| *state == ConnectionState::Closed)
+            .await;
+    }
+}
+
+/// Stores subscription requests for replay after a reconnect.
+///
+/// Returns `true` if the message changes the remembered subscriptions.
+fn remember_subscription(subscriptions: &mut Vec<ProtoMessage>, message: &ProtoMessage) -> bool {
+    match message {
+        ProtoMessage::SubscribeStatesRequest(_)
+        | ProtoMessage::SubscribeLogsRequest(_)
+        | ProtoMessage::SubscribeHomeassistantServicesRequest(_)
+        | ProtoMessage::SubscribeHomeAssistantStatesRequest(_)
+        | ProtoMessage::SubscribeBluetoothLeAdvertisementsRequest(_)
+        | ProtoMessage::SubscribeBluetoothConnectionsFreeRequest(_)
+        | ProtoMessage::SubscribeVoiceAssistantRequest(_) => {
+            // A newer request of the same kind replaces the previous one.
+            let kind = std::mem::discriminant(message);
+            subscriptions.retain(|subscription| std::mem::discriminant(subscription) != kind);
+            subscriptions.push(message.clone());
+            true
+        }
+        ProtoMessage::UnsubscribeBluetoothLeAdvertisementsRequest(_) => {
+            subscriptions.retain(|subscription| {
+                !matches!(
+                    subscription,
+                    ProtoMessage::SubscribeBluetoothLeAdvertisementsRequest(_)
+                )
+            });
+            true
+        }
+        _ => false,
+    }
+}
+
+async fn shutdown_requested(shutdown_rx: &mut watch::Receiver<bool>) {
+    // An error means all handles are gone, which also requests a shutdown.
+    let _ = shutdown_rx.wait_for(|shutdown| *shutdown).await;
+}
+
+async fn supervise<C, Fut, S>(
+    inner: Arc<Inner>,
+    client: EspHomeClient,
+    backoff: Backoff,
+    connector: C,
+    mut shutdown_rx: watch::Receiver<bool>,
+) where
+    C: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = io::Result<S>> + Send + 'static,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut attempt: u32 = 0;
+    loop {
+        inner.set_state(ConnectionState::Connecting);
+        let result = tokio::select! {
+            result = establish(&inner, &client, &connector) => result,
+            _ = shutdown_requested(&mut shutdown_rx) => break,
+        };
+
+        match result {
+            Ok((connection, incoming)) => {
+                let connected_at = tokio::time::Instant::now();
+                inner.set_state(ConnectionState::Connected);
+                inner.emit(ConnectionEvent::Connected {
+                    name: connection.device_info_response().name.clone(),
+                });
+
+                let shutdown = tokio::select! {
+                    _ = forward(&inner, incoming) => false,
+                    _ = shutdown_requested(&mut shutdown_rx) => true,
+                };
+                if shutdown {
+                    break;
+                }
+
+                *inner.current.write().unwrap() = None;
+                inner.set_state(ConnectionState::Disconnected);
+                inner.emit(ConnectionEvent::Disconnected);
+
+                // A device that keeps dropping connections is retried like a failing one.
+                if connected_at.elapsed() >= backoff.max {
+                    attempt = 0;
+                }
+                let retry_in = backoff.delay(attempt);
+                attempt = attempt.saturating_add(1);
+                debug!("Reconnecting in {:?}", retry_in);
+                tokio::select! {
+                    _ = tokio::time::sleep(retry_in) => {}
+                    _ = shutdown_requested(&mut shutdown_rx) => break,
+                }
+            }
+            Err(err) => {
+                let retry_in = backoff.delay(attempt);
+                attempt = attempt.saturating_add(1);
+                inner.set_state(ConnectionState::Disconnected);
+                inner.emit(ConnectionEvent::ConnectFailed {
+                    error: err.to_string(),
+                    retry_in,
+                });
+                tokio::select! {
+                    _ = tokio::time::sleep(retry_in) => {}
+                    _ = shutdown_requested(&mut shutdown_rx) => break,
+                }
+            }
+        }
+    }
+
+    let connection = inner.current.write().unwrap().take();
+    if let Some(connection) = connection {
+        let _ = connection.disconnect().await;
+    }
+    inner.set_state(ConnectionState::Closed);
+    inner.emit(ConnectionEvent::Closed);
+}
+
+/// Connects, lists the entities and replays the subscriptions.
+async fn establish<C, Fut, S>(
+    inner: &Inner,
+    client: &EspHomeClient,
+    connector: &C,
+) -> Result<(ClientConnection, broadcast::Receiver<ProtoMessage>), ClientError>
+where
+    C: Fn() -> Fut,
+    Fut: Future<Output = io::Result<S>>,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let stream = connector().await?;
+    let connection = client.start(stream).await?;
+    let entities = connection.list_entities().await?;
+    *inner.entities.write().unwrap() = entities;
+
+    let incoming = connection.subscribe();
+    let subscriptions = {
+        let subscriptions = inner.subscriptions.lock().unwrap();
+        *inner.current.write().unwrap() = Some(connection.clone());
+        subscriptions.clone()
+    };
+    for subscription in subscriptions {
+        connection.send(subscription).await?;
+    }
+    Ok((connection, incoming))
+}
+
+/// Forwards messages of one connection to the stable receivers until it closes.
+async fn forward(inner: &Inner, mut incoming: broadcast::Receiver<ProtoMessage>) {
+    loop {
+        match incoming.recv().await {
+            Ok(message) => {
+                inner.states.update(message.clone());
+                // Nobody listening is fine.
+                let _ = inner.incoming_tx.send(message);
+            }
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!("Managed connection missed {} messages", skipped);
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proto::{
+        PingRequest, SubscribeLogsRequest, UnsubscribeBluetoothLeAdvertisementsRequest,
+    };
+
+    use super::*;
+
+    #[test]
+    fn backoff_grows_until_max() {
+        let backoff = Backoff::builder()
+            .initial(Duration::from_millis(100))
+            .max(Duration::from_secs(1))
+            .build();
+        assert_eq!(backoff.delay(0), Duration::from_millis(100));
+        assert_eq!(backoff.delay(1), Duration::from_millis(200));
+        assert_eq!(backoff.delay(3), Duration::from_millis(800));
+        assert_eq!(backoff.delay(4), Duration::from_secs(1));
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn log_subscription_keeps_the_most_verbose_level() {
+        let managed = ManagedConnection::connect(
+            EspHomeClient::builder().build(),
+            Backoff::default(),
+            || async { Err::<tokio::io::DuplexStream, _>(io::Error::other("offline")) },
+        );
+        managed
+            .subscribe_logs(LogLevel::Debug, false)
+            .await
+            .unwrap();
+        managed.subscribe_logs(LogLevel::Info, true).await.unwrap();
+
+        let subscriptions = managed.inner.subscriptions.lock().unwrap();
+        assert!(matches!(
+            subscriptions.as_slice(),
+            [ProtoMessage::SubscribeLogsRequest(SubscribeLogsRequest { level, dump_config: true })]
+                if *level == LogLevel::Debug as i32
+        ));
+    }
+
+    #[test]
+    fn subscriptions_are_replaced_and_removed() {
+        let mut subscriptions = Vec::new();
+        assert!(remember_subscription(
+            &mut subscriptions,
+            &ProtoMessage::SubscribeLogsRequest(SubscribeLogsRequest {
+                level: 1,
+                dump_config: false,
+            })
+        ));
+        assert!(remember_subscription(
+            &mut subscriptions,
+            &ProtoMessage::SubscribeLogsRequest(SubscribeLogsRequest {
+                level: 5,
+                dump_config: false,
+            })
+        ));
+        assert!(remember_subscription(
+            &mut subscriptions,
+            &ProtoMessage::SubscribeBluetoothLeAdvertisementsRequest(Default::default())
+        ));
+        assert_eq!(subscriptions.len(), 2);
+
+        assert!(remember_subscription(
+            &mut subscriptions,
+            &ProtoMessage::UnsubscribeBluetoothLeAdvertisementsRequest(
+                UnsubscribeBluetoothLeAdvertisementsRequest {}
+            )
+        ));
+        assert!(!remember_subscription(
+            &mut subscriptions,
+            &ProtoMessage::PingRequest(PingRequest {})
+        ));
+
+        assert_eq!(subscriptions.len(), 1);
+        assert!(matches!(
+            subscriptions[0],
+            ProtoMessage::SubscribeLogsRequest(SubscribeLogsRequest { level: 5, .. })
+        ));
+    }
+}
@@ -0,0 +1,201 @@
+//! Live state cache fed by `SubscribeStatesRequest`.
+//!
+//! After subscribing, the device sends the current state of every entity and keeps
+//! sending updates whenever a state changes. The [`StateCache`] keeps the latest
+//! state per entity key, so it can be queried synchronously, and notifies about
+//! every received state.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! # use esphome_native_api::esphomeclient::EspHomeClient;
+//! # use tokio::net::TcpStream;
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! let stream = TcpStream::connect("192.168.1.100:6053").await?;
+//! let connection = EspHomeClient::builder().build().start(stream).await?;
+//!
+//! let states = connection.subscribe_states().await?;
+//! let mut temperature = states.watch(0x1234);
+//! while temperature.changed().await.is_ok() {
+//!     println!("Temperature: {:?}", *temperature.borrow());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use log::warn;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use tokio::sync::broadcast;
+use tokio::sync::watch;
+
+pub use crate::entity::EntityState;
+use crate::parser::ProtoMessage;
+
+/// Latest state of every entity of a device.
+///
+/// The cache is cheap to clone; all clones share the same states.
+#[derive(Clone)]
+pub struct StateCache {
+    states: Arc<RwLock<HashMap<u32, EntityState>>>,
+    watchers: Arc<Mutex<HashMap<u32, watch::Sender<Option<EntityState>>>>>,
+    changes_tx: broadcast::Sender<EntityState>,
+}
+
+impl Default for StateCache {
+    fn default() -> Self {
+        let (changes_tx, _) = broadcast::channel(256);
+        StateCache {
+            states: Arc::new(RwLock::new(HashMap::new())),
+            watchers: Arc::new(Mutex::new(HashMap::new())),
+            changes_tx,
+        }
+    }
+}
+
+impl StateCache {
+    /// Returns the latest known state of an entity.
+    pub fn state_of(&self, key: u32) -> Option<EntityState> {
+        self.states.read().unwrap().get(&key).cloned()
+    }
+
+    /// Returns a snapshot of all known states.
+    pub fn states(&self) -> HashMap<u32, EntityState> {
+        self.states.read().unwrap().clone()
+    }
+
+    /// Watches the state of a single entity.
+    ///
+    /// The receiver starts with the latest known state and is notified about every
+    /// state the device sends for this entity.
+    pub fn watch(&self, key: u32) -> watch::Receiver<Option<EntityState>> {
+        let mut watchers = self.watchers.lock().unwrap();
+        match watchers.get(&key) {
+            Some(watcher) => watcher.subscribe(),
+            None => {
+                let (watcher, receiver) = watch::channel(self.state_of(key));
+                watchers.insert(key, watcher);
+                receiver
+            }
+        }
+    }
+
+    /// Returns a receiver for every state the device sends.
+    pub fn changes(&self) -> broadcast::Receiver<EntityState> {
+        self.changes_tx.subscribe()
+    }
+
+    /// Stores the state contained in a message and notifies watchers.
+    ///
+    /// Returns `false` if the message is not a state response.
+    pub fn update(&self, message: ProtoMessage) -> bool {
+        let Some(state) = EntityState::from_message(message) else {
+            return false;
+        };
+        let key = state.key();
+
+        self.states.write().unwrap().insert(key, state.clone());
+
+        {
+            let mut watchers = self.watchers.lock().unwrap();
+            if let Some(watcher) = watchers.get(&key) {
+                if watcher.receiver_count() == 0 {
+                    watchers.remove(&key);
+                } else {
+                    watcher.send_replace(Some(state.clone()));
+                }
+            }
+        }
+
+        // Nobody listening is fine.
+        let _ = self.changes_tx.send(state);
+        true
+    }
+
+    /// Feeds the cache from a connection until the connection closes.
+    pub(crate) async fn run(self, mut incoming: broadcast::Receiver<ProtoMessage>) {
+        loop {
+            match incoming.recv().await {
+                Ok(message) => {
+                    self.update(message);
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("State cache missed {} messages", skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proto::{ListEntitiesDoneResponse, SensorStateResponse};
+
+    use super::*;
+
+    fn sensor_state(key: u32, state: f32) -> ProtoMessage {
+        ProtoMessage::SensorStateResponse(SensorStateResponse {
+            key,
+            state,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn state_of_returns_latest_state() {
+        let cache = StateCache::default();
+        assert!(cache.state_of(1).is_none());
+
+        assert!(cache.update(sensor_state(1, 20.0)));
+        assert!(cache.update(sensor_state(1, 21.0)));
+
+        match cache.state_of(1) {
+            Some(EntityState::Sensor(state)) => assert_eq!(state.state, 21.0),
+            state => panic!("Unexpected state: {:?}", state),
+        }
+        assert_eq!(cache.states().len(), 1);
+    }
+
+    #[test]
+    fn update_ignores_other_messages() {
+        let cache = StateCache::default();
+        assert!(!cache.update(ProtoMessage::ListEntitiesDoneResponse(
+            ListEntitiesDoneResponse {}
+        )));
+        assert!(cache.states().is_empty());
+    }
+
+    #[tokio::test]
+    async fn watch_is_notified_for_its_key_only() {
+        let cache = StateCache::default();
+        cache.update(sensor_state(1, 20.0));
+
+        let mut watcher = cache.watch(1);
+        assert!(matches!(*watcher.borrow(), Some(EntityState::Sensor(_))));
+
+        cache.update(sensor_state(2, 5.0));
+        assert!(!watcher.has_changed().unwrap());
+
+        cache.update(sensor_state(1, 22.0));
+        watcher.changed().await.unwrap();
+        match &*watcher.borrow() {
+            Some(EntityState::Sensor(state)) => assert_eq!(state.state, 22.0),
+            state => panic!("Unexpected state: {:?}", state),
+        }
+    }
+
+    #[tokio::test]
+    async fn changes_receives_every_state() {
+        let cache = StateCache::default();
+        let mut changes = cache.changes();
+
+        cache.update(sensor_state(1, 20.0));
+        cache.update(sensor_state(1, 20.0));
+
+        assert_eq!(changes.recv().await.unwrap().key(), 1);
+        assert_eq!(changes.recv().await.unwrap().key(), 1);
+    }
+}
@@ -0,0 +1,320 @@
+//! Voice assistant pipelines of ESPHome voice satellites.
+//!
+//! Home Assistant runs the assist pipeline for a satellite: the device asks for a
+//! pipeline with a `VoiceAssistantRequest`, streams the recorded voice command and
+//! plays the spoken answer. A [`VoiceAssistantSubscription`] receives these requests
+//! and picks the audio transport from the feature flags of the device:
+//! `VoiceAssistantAudio` messages if the device offers them, UDP otherwise.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! # use esphome_native_api::esphomeclient::EspHomeClient;
+//! # use esphome_native_api::voice_assistant::PipelineEventType;
+//! # use tokio::net::TcpStream;
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! let stream = TcpStream::connect("192.168.1.100:6053").await?;
+//! let connection = EspHomeClient::builder().build().start(stream).await?;
+//!
+//! let mut voice_assistant = connection.subscribe_voice_assistant().await?;
+//! while let Some(request) = voice_assistant.recv_request().await {
+//!     if !request.start {
+//!         continue;
+//!     }
+//!     voice_assistant.accept().await?;
+//!     let mut command = Vec::new();
+//!     while let Some(chunk) = voice_assistant.recv_audio().await? {
+//!         command.extend(chunk);
+//!     }
+//!     voice_assistant.send_event(PipelineEventType::RunEnd, &[]).await?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use log::{debug, warn};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::net::UdpSocket;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc;
+
+use crate::esphomeclient::{ClientConnection, ClientError};
+use crate::parser::ProtoMessage;
+use crate::proto::{
+    SubscribeVoiceAssistantRequest, VoiceAssistantAudio, VoiceAssistantEventData,
+    VoiceAssistantEventResponse, VoiceAssistantRequest, VoiceAssistantResponse,
+};
+use crate::voice_assistant::{FEATURE_API_AUDIO, PipelineEventType, SUBSCRIBE_API_AUDIO};
+
+/// Largest audio datagram received over UDP.
+const MAX_DATAGRAM_SIZE: usize = 4096;
+
+/// How audio is streamed between device and client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioTransport {
+    /// In `VoiceAssistantAudio` messages of the API connection.
+    Api,
+    /// In datagrams to and from a UDP port of the client.
+    Udp,
+}
+
+/// Subscription to the voice assistant of a device.
+///
+/// Dropping the subscription unsubscribes again.
+pub struct VoiceAssistantSubscription {
+    connection: ClientConnection,
+    incoming: broadcast::Receiver<ProtoMessage>,
+    udp: Option<UdpSocket>,
+    /// Address of the device, only its datagrams are accepted.
+    device: Option<IpAddr>,
+    /// Where the device streams from, answers are sent back there.
+    peer: Option<SocketAddr>,
+    ended: bool,
+}
+
+impl VoiceAssistantSubscription {
+    pub(crate) async fn subscribe(connection: &ClientConnection) -> Result<Self, ClientError> {
+        let feature_flags = connection
+            .device_info_response()
+            .voice_assistant_feature_flags;
+        let udp = match feature_flags & FEATURE_API_AUDIO {
+            0 => Some(
+                UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+                    .await
+                    .map_err(ClientError::Io)?,
+            ),
+            _ => None,
+        };
+        let subscription = VoiceAssistantSubscription {
+            connection: connection.clone(),
+            incoming: connection.subscribe(),
+            udp,
+            device: connection.device_address(),
+            peer: None,
+            ended: false,
+        };
+        let flags = match subscription.transport() {
+            AudioTransport::Api => SUBSCRIBE_API_AUDIO,
+            AudioTransport::Udp => 0,
+        };
+        connection
+            .send(ProtoMessage::SubscribeVoiceAssistantRequest(
+                SubscribeVoiceAssistantRequest {
+                    subscribe: true,
+                    flags,
+                },
+            ))
+            .await?;
+        Ok(subscription)
+    }
+
+    /// The transport audio is streamed with.
+    pub fn transport(&self) -> AudioTransport {
+        match self.udp {
+            Some(_) => AudioTransport::Udp,
+            None => AudioTransport::Api,
+        }
+    }
+
+    /// Waits for the device to start or stop a pipeline.
+    ///
+    /// Returns `None` once the connection is closed.
+    pub async fn recv_request(&mut self) -> Option<VoiceAssistantRequest> {
+        loop {
+            match self.incoming.recv().await {
+                Ok(ProtoMessage::VoiceAssistantRequest(request)) => return Some(request),
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("Voice assistant subscription missed {} messages", skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// Accepts the pipeline the device asked for, the device starts streaming.
+    pub async fn accept(&mut self) -> Result<(), ClientError> {
+        let port = match &self.udp {
+            Some(socket) => socket.local_addr().map_err(ClientError::Io)?.port() as u32,
+            None => 0,
+        };
+        self.ended = false;
+        self.connection
+            .send(ProtoMessage::VoiceAssistantResponse(
+                VoiceAssistantResponse { port, error: false },
+            ))
+            .await
+    }
+
+    /// Rejects the pipeline the device asked for.
+    pub async fn reject(&self) -> Result<(), ClientError> {
+        self.connection
+            .send(ProtoMessage::VoiceAssistantResponse(
+                VoiceAssistantResponse {
+                    port: 0,
+                    error: true,
+                },
+            ))
+            .await
+    }
+
+    /// Reports the progress of the pipeline to the device.
+    pub async fn send_event(
+        &self,
+        event_type: PipelineEventType,
+        data: &[(&str, &str)],
+    ) -> Result<(), ClientError> {
+        let data = data
+            .iter()
+            .map(|(name, value)| VoiceAssistantEventData {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+            .collect();
+        self.connection
+            .send(ProtoMessage::VoiceAssistantEventResponse(
+                VoiceAssistantEventResponse {
+                    event_type: event_type.into(),
+                    data,
+                },
+            ))
+            .await
+    }
+
+    /// Waits for the next chunk of the recorded voice command.
+    ///
+    /// Returns `None` after the last chunk or once the device stopped the pipeline.
+    pub async fn recv_audio(&mut self) -> Result<Option<Vec<u8>>, ClientError> {
+        if self.ended {
+            return Ok(None);
+        }
+        loop {
+            let message = match &self.udp {
+                Some(socket) => {
+                    let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+                    tokio::select! {
+                        received = socket.recv_from(&mut buffer) => {
+                            let (length, peer) = received.map_err(ClientError::Io)?;
+                            if !accepts(self.device, self.peer, peer) {
+                                debug!("Ignoring voice assistant audio from {}", peer);
+                                continue;
+                            }
+                            self.peer = Some(peer);
+                            buffer.truncate(length);
+                            // An empty datagram ends the audio.
+                            self.ended = buffer.is_empty();
+                            return Ok((!self.ended).then_some(buffer));
+                        }
+                        message = self.incoming.recv() => message,
+                    }
+                }
+                None => self.incoming.recv().await,
+            };
+            match message {
+                Ok(ProtoMessage::VoiceAssistantAudio(audio)) if self.udp.is_none() => {
+                    self.ended = audio.end;
+                    if !audio.data.is_empty() {
+                        return Ok(Some(audio.data));
+                    }
+                    if audio.end {
+                        return Ok(None);
+                    }
+                }
+                Ok(ProtoMessage::VoiceAssistantRequest(request)) if !request.start => {
+                    self.ended = true;
+                    return Ok(None);
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("Voice assistant subscription missed {} messages", skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => {
+                    return Err(ClientError::ConnectionClosed);
+                }
+            }
+        }
+    }
+
+    /// Streams a chunk of the spoken answer to the device.
+    ///
+    /// Over UDP the answer can only be sent after the device streamed audio.
+    pub async fn send_audio(&self, data: Vec<u8>) -> Result<(), ClientError> {
+        self.send_answer(data, false).await
+    }
+
+    /// Tells the device that the spoken answer is complete.
+    pub async fn end_audio(&self) -> Result<(), ClientError> {
+        self.send_answer(vec![], true).await
+    }
+
+    async fn send_answer(&self, data: Vec<u8>, end: bool) -> Result<(), ClientError> {
+        match &self.udp {
+            Some(socket) => {
+                let peer = self.peer.ok_or_else(|| {
+                    ClientError::Protocol("No audio received over UDP yet".to_string())
+                })?;
+                socket
+                    .send_to(&data, peer)
+                    .await
+                    .map(|_| ())
+                    .map_err(ClientError::Io)
+            }
+            None => {
+                self.connection
+                    .send(ProtoMessage::VoiceAssistantAudio(VoiceAssistantAudio {
+                        data,
+                        end,
+                    }))
+                    .await
+            }
+        }
+    }
+}
+
+impl Drop for VoiceAssistantSubscription {
+    fn drop(&mut self) {
+        let unsubscribe =
+            ProtoMessage::SubscribeVoiceAssistantRequest(SubscribeVoiceAssistantRequest {
+                subscribe: false,
+                flags: 0,
+            });
+        let tx = self.connection.sender();
+        match tx.try_send(unsubscribe) {
+            Ok(()) | Err(mpsc::error::TrySendError::Closed(_)) => {}
+            Err(mpsc::error::TrySendError::Full(unsubscribe)) => {
+                // Drop can't wait for capacity, so a task sends it later.
+                if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+                    runtime.spawn(async move { tx.send(unsubscribe).await });
+                }
+            }
+        }
+    }
+}
+
+/// Returns whether a datagram from `peer` is audio of the device.
+///
+/// Without the address of the device the first sender is taken as the device.
+fn accepts(device: Option<IpAddr>, known_peer: Option<SocketAddr>, peer: SocketAddr) -> bool {
+    match (device, known_peer) {
+        (Some(device), _) => peer.ip().to_canonical() == device.to_canonical(),
+        (None, Some(known_peer)) => peer == known_peer,
+        (None, None) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_only_datagrams_of_the_device() {
+        let device: SocketAddr = "192.168.1.100:5000".parse().unwrap();
+        let other: SocketAddr = "192.168.1.200:5000".parse().unwrap();
+
+        assert!(accepts(Some(device.ip()), None, device));
+        assert!(!accepts(Some(device.ip()), None, other));
+        assert!(accepts(None, None, other));
+        assert!(accepts(None, Some(device), device));
+        assert!(!accepts(None, Some(device), other));
+    }
+}
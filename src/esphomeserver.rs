@@ -8,24 +8,40 @@
 //! # Examples
 //!
 //! ```rust,no_run
-//! use esphome_native_api::esphomeserver::{EspHomeServer, Entity, BinarySensor};
-//! use tokio::net::TcpStream;
+//! use esphome_native_api::esphomeserver::{BinarySensor, EspHomeServer, Sensor};
+//! use esphome_native_api::proto::SensorStateClass;
 //!
 //! #[tokio::main]
 //! async fn main() -> Result<(), Box<dyn std::error::Error>> {
-//!     let stream = TcpStream::connect("192.168.1.100:6053").await?;
-//!     
 //!     let mut server = EspHomeServer::builder()
 //!         .name("my-server".to_string())
 //!         .build();
 //!     
 //!     // Add entities
-//!     let sensor = Entity::BinarySensor(BinarySensor {
+//!     let door = BinarySensor {
 //!         object_id: "door_sensor".to_string(),
-//!     });
-//!     server.add_entity("door_sensor", sensor);
+//!         name: "Door".to_string(),
+//!         device_class: "door".to_string(),
+//!         ..Default::default()
+//!     };
+//!     server.add_entity("door_sensor", door.into())?;
+//!     let temperature = Sensor {
+//!         object_id: "temperature".to_string(),
+//!         name: "Temperature".to_string(),
+//!         unit_of_measurement: "°C".to_string(),
+//!         accuracy_decimals: 1,
+//!         state_class: SensorStateClass::Measurement,
+//!         ..Default::default()
+//!     };
+//!     server.add_entity("temperature", temperature.into())?;
 //!     
-//!     let (tx, mut rx) = server.start(stream).await?;
+//!     // Serve all clients, e.g. Home Assistant, until Ctrl+C is pressed
+//!     let shutdown = server.shutdown_handle();
+//!     tokio::spawn(async move {
+//!         tokio::signal::ctrl_c().await.unwrap();
+//!         shutdown.cancel();
+//!     });
+//!     server.serve("0.0.0.0:6053").await?;
 //!     
 //!     Ok(())
 //! }
@@ -35,6 +51,8 @@
 
 use log::debug;
 use log::error;
+use log::info;
+use log::warn;
 use noise_protocol::CipherState;
 use noise_protocol::HandshakeState;
 use noise_rust_crypto::ChaCha20Poly1305;
@@ -43,16 +61,41 @@ use noise_rust_crypto::X25519;
 use std::collections::HashMap;
 use std::str;
 use std::sync::Arc;
+use std::sync::OnceLock;
 use std::sync::atomic::AtomicBool;
-use tokio::net::TcpStream;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, ToSocketAddrs};
 use tokio::sync::Mutex;
+use tokio::sync::Semaphore;
 use tokio::sync::broadcast;
 use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
 use typed_builder::TypedBuilder;
 
+mod logs;
+mod state_store;
+
+use state_store::StateStore;
+
+use crate::entity::{Domain, EntityState};
 use crate::esphomeapi::EspHomeApi;
+use crate::hash::hash_fnv1;
+use crate::mdns::{MdnsConfig, MdnsResponder};
 use crate::parser::ProtoMessage;
-use crate::proto::ListEntitiesDoneResponse;
+use crate::proto::{
+    ClimateMode, ColorMode, EntityCategory, ListEntitiesAlarmControlPanelResponse,
+    ListEntitiesBinarySensorResponse, ListEntitiesButtonResponse, ListEntitiesCameraResponse,
+    ListEntitiesClimateResponse, ListEntitiesCoverResponse, ListEntitiesDateResponse,
+    ListEntitiesDateTimeResponse, ListEntitiesDoneResponse, ListEntitiesEventResponse,
+    ListEntitiesFanResponse, ListEntitiesLightResponse, ListEntitiesLockResponse,
+    ListEntitiesMediaPlayerResponse, ListEntitiesNumberResponse, ListEntitiesSelectResponse,
+    ListEntitiesSensorResponse, ListEntitiesSwitchResponse, ListEntitiesTextResponse,
+    ListEntitiesTextSensorResponse, ListEntitiesTimeResponse, ListEntitiesUpdateResponse,
+    ListEntitiesValveResponse, LogLevel, MediaPlayerSupportedFormat, NumberMode, SensorStateClass,
+    TextMode,
+};
 
 /// High-level ESPHome server implementation.
 ///
@@ -82,8 +125,17 @@ pub struct EspHomeServer {
     pub(crate) components_by_key: HashMap<u32, Entity>,
     #[builder(default=HashMap::new(), setter(skip))]
     pub(crate) components_key_id: HashMap<String, u32>,
-    #[builder(default = 0, setter(skip))]
-    pub(crate) current_key: u32,
+    /// Entity states, shared by all connections started from this server.
+    #[builder(default, setter(skip))]
+    pub(crate) states: Arc<StateStore>,
+    /// API shared by all connections, created on first use.
+    #[builder(default, setter(skip))]
+    api: OnceLock<EspHomeApi>,
+    /// Messages of the clients accepted by `serve_listener`.
+    #[builder(default = broadcast::channel(64).0, setter(skip))]
+    incoming_messages_tx: broadcast::Sender<ProtoMessage>,
+    #[builder(default, setter(skip))]
+    shutdown: CancellationToken,
 
     #[builder(via_mutators, default=Arc::new(AtomicBool::new(false)))]
     pub(crate) encrypted_api: Arc<AtomicBool>,
@@ -129,13 +181,31 @@ pub struct EspHomeServer {
     suggested_area: Option<String>,
     #[builder(default = None, setter(strip_option))]
     bluetooth_mac_address: Option<String>,
+    #[builder(default = None, setter(strip_option))]
+    project_name: Option<String>,
+    #[builder(default = None, setter(strip_option))]
+    project_version: Option<String>,
+
+    /// Maximum number of clients served at the same time by `serve_listener`.
+    #[builder(default = 8)]
+    max_connections: usize,
+
+    /// Advertises `serve_listener` over mDNS, disabled by default.
+    #[builder(default = None, setter(strip_option))]
+    mdns: Option<MdnsConfig>,
 }
 
+/// How long a shut down server waits for its clients to disconnect.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the server waits before accepting again after accepting failed.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
 /// Easier version of the API abstraction.
 ///
 /// Manages entity keys internally.
 impl EspHomeServer {
-    /// Starts the ESPHome server and begins communication over the provided TCP stream.
+    /// Starts the ESPHome server and begins communication over the provided stream.
     ///
     /// This method initializes the underlying [`EspHomeApi`], establishes the connection,
     /// and spawns a background task to handle message routing between the API and
@@ -143,7 +213,7 @@ impl EspHomeServer {
     ///
     /// # Arguments
     ///
-    /// * `tcp_stream` - An established TCP connection to an ESPHome device
+    /// * `stream` - An established connection to a client, e.g. a `TcpStream`
     ///
     /// # Returns
     ///
@@ -168,84 +238,179 @@ impl EspHomeServer {
     /// # Ok(())
     /// # }
     /// ```
-    pub async fn start(
-        &mut self,
-        tcp_stream: TcpStream,
+    pub async fn start<S>(
+        &self,
+        stream: S,
     ) -> Result<
         (
             mpsc::Sender<ProtoMessage>,
             broadcast::Receiver<ProtoMessage>,
         ),
         Box<dyn std::error::Error>,
-    > {
-        let server = EspHomeApi::builder()
-            .api_version_major(self.api_version_major)
-            .api_version_minor(self.api_version_minor)
-            // .password(self.password.or_else())
-            .server_info(self.server_info.clone())
-            .name(self.name.clone())
-            // .friendly_name(self.friendly_name)
-            // .bluetooth_mac_address(self.bluetooth_mac_address)
-            // .mac(self.mac)
-            // .manufacturer(self.manufacturer)
-            // .model(self.model)
-            // .suggested_area(self.suggested_area)
-            .build();
-        let (messages_tx, mut messages_rx) = server.start(tcp_stream).await?;
+    >
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
         let (outgoing_messages_tx, outgoing_messages_rx) = broadcast::channel::<ProtoMessage>(16);
-        let api_components_clone = self.components_by_key.clone();
-        // let messages_tx_clone = messages_tx.clone();
-
-        tokio::spawn(async move {
-            loop {
-                messages_rx.recv().await.map_or_else(
-                    |e| {
-                        error!("Error receiving message: {:?}", e);
-                        // Handle the error, maybe log it or break the loop
-                    },
-                    |message| {
-                        // Process the received message
-                        debug!("Received message: {:?}", message);
-
-                        match message {
-                            ProtoMessage::ListEntitiesRequest(list_entities_request) => {
-                                debug!("ListEntitiesRequest: {:?}", list_entities_request);
-
-                                for _sensor in api_components_clone.values() {
-                                    // TODO: Handle the different entity types
-                                    // outgoing_messages_tx.send(sensor.clone()).unwrap();
-                                }
-                                outgoing_messages_tx
-                                    .send(ProtoMessage::ListEntitiesDoneResponse(
-                                        ListEntitiesDoneResponse {},
-                                    ))
-                                    .unwrap();
-                            }
-                            other_message => {
-                                // Forward the message to the outgoing channel
-                                if let Err(e) = outgoing_messages_tx.send(other_message) {
-                                    error!("Error sending message to outgoing channel: {:?}", e);
-                                }
-                            }
-                        }
-                    },
-                );
+        let messages_tx = start_connection(
+            self.api(),
+            self.components_by_key.clone(),
+            self.states.clone(),
+            outgoing_messages_tx,
+            stream,
+        )
+        .await?;
+        Ok((messages_tx, outgoing_messages_rx))
+    }
+
+    /// Binds a TCP listener to `addr` and serves all clients connecting to it.
+    ///
+    /// See [`EspHomeServer::serve_listener`].
+    pub async fn serve(&self, addr: impl ToSocketAddrs) -> Result<(), Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind(addr).await?;
+        self.serve_listener(listener).await
+    }
+
+    /// Accepts clients from `listener` until the server is shut down.
+    ///
+    /// Every client is served by its own task, all of them share the registered
+    /// entities and their states. Clients beyond `max_connections` are disconnected
+    /// right away. Messages of the clients that aren't answered by the server are
+    /// available from [`EspHomeServer::subscribe`].
+    ///
+    /// With the `mdns` option the server is advertised over mDNS while serving, so Home
+    /// Assistant discovers it.
+    ///
+    /// Once the [`EspHomeServer::shutdown_handle`] is cancelled, no more clients are
+    /// accepted, the mDNS advertisement is withdrawn, all connected clients are asked
+    /// to disconnect and the method returns after they did. Clients that didn't
+    /// disconnect within a timeout are closed.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use esphome_native_api::esphomeserver::EspHomeServer;
+    /// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+    /// let server = EspHomeServer::builder().name("server".to_string()).build();
+    /// let shutdown = server.shutdown_handle();
+    /// tokio::spawn(async move {
+    ///     tokio::signal::ctrl_c().await.unwrap();
+    ///     shutdown.cancel();
+    /// });
+    /// server.serve("0.0.0.0:6053").await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn serve_listener(
+        &self,
+        listener: TcpListener,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mdns = match &self.mdns {
+            Some(config) => {
+                let service = self.api().mdns_service(listener.local_addr()?.port());
+                Some(MdnsResponder::start(config.clone(), service).await?)
             }
-        });
+            None => None,
+        };
 
-        Ok((messages_tx.clone(), outgoing_messages_rx))
+        let permits = Arc::new(Semaphore::new(self.max_connections));
+        let mut tasks = JoinSet::new();
+        loop {
+            let accepted = tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                accepted = listener.accept() => accepted,
+            };
+            let (stream, address) = match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    error!("Failed to accept connection: {:?}", err);
+                    // Errors like running out of file descriptors persist for a while.
+                    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            };
+            let Ok(permit) = permits.clone().try_acquire_owned() else {
+                warn!(
+                    "Rejected connection from {}, {} clients are connected",
+                    address, self.max_connections
+                );
+                continue;
+            };
+            debug!("Accepted connection from {}", address);
+
+            let api = self.api().clone();
+            let entities = self.components_by_key.clone();
+            let states = self.states.clone();
+            let incoming_messages_tx = self.incoming_messages_tx.clone();
+            tasks.spawn(async move {
+                let messages_tx =
+                    match start_connection(&api, entities, states, incoming_messages_tx, stream)
+                        .await
+                    {
+                        Ok(messages_tx) => messages_tx,
+                        Err(err) => {
+                            warn!("Connection from {} failed: {}", address, err);
+                            return;
+                        }
+                    };
+                messages_tx.closed().await;
+                debug!("Connection from {} closed", address);
+                drop(permit);
+            });
+            // Forget the tasks of closed connections.
+            while tasks.try_join_next().is_some() {}
+        }
+
+        if let Some(mdns) = mdns {
+            mdns.stop().await;
+        }
+        info!(
+            "Shutting down, disconnecting {} clients",
+            self.api().connections.len()
+        );
+        self.api().connections.disconnect_all();
+        let closed = async { while tasks.join_next().await.is_some() {} };
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, closed)
+            .await
+            .is_err()
+        {
+            warn!("Clients did not disconnect in time");
+            self.api().connections.close_all();
+            tasks.abort_all();
+        }
+        Ok(())
+    }
+
+    /// Returns the handle that shuts down [`EspHomeServer::serve_listener`].
+    pub fn shutdown_handle(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// Returns a new receiver for the messages of all clients accepted by
+    /// [`EspHomeServer::serve_listener`], e.g. entity commands.
+    ///
+    /// Messages answered by the server itself are not included, except for
+    /// subscriptions.
+    pub fn subscribe(&self) -> broadcast::Receiver<ProtoMessage> {
+        self.incoming_messages_tx.subscribe()
     }
 
     /// Adds an entity to the server's internal registry.
     ///
-    /// Each entity is assigned a unique key that is managed internally. The entity
-    /// can be referenced by its string identifier in subsequent operations.
+    /// Like ESPHome, the key of the entity is the hash of its object id; an empty
+    /// object id is replaced with `entity_id`. The entity can be referenced by its
+    /// string identifier in subsequent operations. Adding an entity with the same
+    /// identifier replaces the previous one and forgets its state.
     ///
     /// # Arguments
     ///
     /// * `entity_id` - A unique string identifier for the entity
     /// * `entity` - The entity to register
     ///
+    /// # Errors
+    ///
+    /// Returns an error if another entity has the same key, i.e. the same object id.
+    ///
     /// # Examples
     ///
     /// ```rust
@@ -253,35 +418,561 @@ impl EspHomeServer {
     /// let mut server = EspHomeServer::builder().name("server".to_string()).build();
     /// let sensor = Entity::BinarySensor(BinarySensor {
     ///     object_id: "motion_sensor".to_string(),
+    ///     device_class: "motion".to_string(),
+    ///     ..Default::default()
     /// });
-    /// server.add_entity("motion", sensor);
+    /// server.add_entity("motion", sensor).unwrap();
+    /// assert!(server.key("motion").is_some());
+    /// ```
+    pub fn add_entity(
+        &mut self,
+        entity_id: &str,
+        mut entity: Entity,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if entity.object_id().is_empty() {
+            entity.set_object_id(entity_id);
+        }
+        let key = hash_fnv1(&entity.object_id().to_string());
+        if let Some((other_id, _)) = self
+            .components_key_id
+            .iter()
+            .find(|(other_id, other_key)| **other_key == key && *other_id != entity_id)
+        {
+            return Err(format!(
+                "Entity {} has the same object id {} as {}",
+                entity_id,
+                entity.object_id(),
+                other_id
+            )
+            .into());
+        }
+        if let Some(previous_key) = self.components_key_id.insert(entity_id.to_string(), key) {
+            self.components_by_key.remove(&previous_key);
+            self.states.forget_state(previous_key);
+        }
+        self.components_by_key.insert(key, entity);
+        Ok(())
+    }
+
+    /// The key of the entity registered as `entity_id`.
+    pub fn key(&self, entity_id: &str) -> Option<u32> {
+        self.components_key_id.get(entity_id).copied()
+    }
+
+    /// Publishes the state of the entity registered as `entity_id`.
+    ///
+    /// The state is kept as the last known state of the entity, which is sent to
+    /// every client right after its `SubscribeStatesRequest`, and is sent to all
+    /// clients that already subscribed. The key of `state` is set by the server.
+    /// Returns the number of clients the state was sent to.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if no entity is registered as `entity_id` or if the state
+    /// belongs to another domain than the entity.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use esphome_native_api::esphomeserver::{EspHomeServer, Sensor};
+    /// # use esphome_native_api::proto::SensorStateResponse;
+    /// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+    /// let mut server = EspHomeServer::builder().name("server".to_string()).build();
+    /// server.add_entity("temperature", Sensor::default().into())?;
+    ///
+    /// let state = SensorStateResponse {
+    ///     state: 21.5,
+    ///     ..Default::default()
+    /// };
+    /// server.publish_state("temperature", state).await?;
+    /// # Ok(())
+    /// # }
     /// ```
-    pub fn add_entity(&mut self, entity_id: &str, entity: Entity) {
-        self.components_key_id
-            .insert(entity_id.to_string(), self.current_key);
-        self.components_by_key.insert(self.current_key, entity);
+    pub async fn publish_state(
+        &self,
+        entity_id: &str,
+        state: impl Into<EntityState>,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        let mut state = state.into();
+        let key = self
+            .key(entity_id)
+            .ok_or_else(|| format!("Unknown entity {}", entity_id))?;
+        let domain = self
+            .components_by_key
+            .get(&key)
+            .ok_or_else(|| format!("Unknown entity {}", entity_id))?
+            .domain();
+        if state.domain() != domain {
+            return Err(format!(
+                "Entity {} of domain {} can't have a {} state",
+                entity_id,
+                domain.as_str(),
+                state.domain().as_str()
+            )
+            .into());
+        }
+        state.set_key(key);
+        Ok(self.states.publish_state(&self.api().connections, state))
+    }
 
-        self.current_key += 1;
+    /// The last published state of the entity registered as `entity_id`.
+    pub fn state(&self, entity_id: &str) -> Option<EntityState> {
+        self.states.state(self.key(entity_id)?)
+    }
+
+    /// Sends a log line to all clients that subscribed to logs up to `level`.
+    ///
+    /// The line is formatted like ESPHome does, e.g. `[I][tag]: message`. Returns
+    /// the number of clients the line was sent to.
+    pub async fn log(&self, level: LogLevel, tag: &str, message: &str) -> usize {
+        logs::log(&self.api().connections, level, tag, message)
+    }
+
+    /// Calls a Home Assistant action, or fires an event if `is_event` is set.
+    ///
+    /// The action is sent to all clients that subscribed to actions, see
+    /// [`EspHomeApi::call_homeassistant_action`]. Returns the number of clients it
+    /// was sent to.
+    pub async fn call_homeassistant_action(
+        &self,
+        service: &str,
+        data: HashMap<String, String>,
+        data_template: HashMap<String, String>,
+        variables: HashMap<String, String>,
+        is_event: bool,
+    ) -> usize {
+        self.api()
+            .call_homeassistant_action(service, data, data_template, variables, is_event)
+            .await
+    }
+
+    /// Number of clients currently connected.
+    pub fn connection_count(&self) -> usize {
+        self.api().connections.len()
+    }
+
+    /// The API shared by all connections of this server.
+    fn api(&self) -> &EspHomeApi {
+        self.api.get_or_init(|| {
+            EspHomeApi::builder()
+                .api_version_major(self.api_version_major)
+                .api_version_minor(self.api_version_minor)
+                // .password(self.password.or_else())
+                .server_info(self.server_info.clone())
+                .name(self.name.clone())
+                .encryption_key_opt(self.encryption_key.clone())
+                .friendly_name_opt(self.friendly_name.clone())
+                .bluetooth_mac_address_opt(self.bluetooth_mac_address.clone())
+                .mac_opt(self.mac.clone())
+                .manufacturer_opt(self.manufacturer.clone())
+                .model_opt(self.model.clone())
+                .suggested_area_opt(self.suggested_area.clone())
+                .project_name_opt(self.project_name.clone())
+                .project_version_opt(self.project_version.clone())
+                .build()
+        })
     }
 }
 
-/// Represents different types of entities supported by ESPHome.
+/// Starts a connection and answers the requests the server handles itself.
 ///
-/// This enum contains all entity types that can be registered with the server.
-/// Currently, only binary sensors are implemented, but this will expand to include
-/// other entity types like switches, lights, sensors, etc.
-#[derive(Clone, Debug)]
-pub enum Entity {
-    /// A binary sensor entity (on/off state)
-    BinarySensor(BinarySensor),
+/// All other messages are sent to `outgoing_messages_tx`.
+async fn start_connection<S>(
+    api: &EspHomeApi,
+    entities: HashMap<u32, Entity>,
+    states: Arc<StateStore>,
+    outgoing_messages_tx: broadcast::Sender<ProtoMessage>,
+    stream: S,
+) -> Result<mpsc::Sender<ProtoMessage>, Box<dyn std::error::Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (connection_id, messages_tx, mut messages_rx) = api.start_registered(stream).await?;
+    let registry = api.connections.clone();
+
+    let messages_tx_clone = messages_tx.clone();
+
+    tokio::spawn(async move {
+        loop {
+            let message = match messages_rx.recv().await {
+                Ok(message) => message,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    error!("Missed {} messages", skipped);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            debug!("Received message: {:?}", message);
+
+            match message {
+                ProtoMessage::SubscribeStatesRequest(subscribe_states_request) => {
+                    debug!("SubscribeStatesRequest: {:?}", subscribe_states_request);
+                    states.subscribe_states(&registry, connection_id);
+                    // The application still learns about the subscription.
+                    let _ = outgoing_messages_tx.send(ProtoMessage::SubscribeStatesRequest(
+                        subscribe_states_request,
+                    ));
+                }
+                ProtoMessage::SubscribeLogsRequest(subscribe_logs_request) => {
+                    debug!("SubscribeLogsRequest: {:?}", subscribe_logs_request);
+                    let level =
+                        LogLevel::try_from(subscribe_logs_request.level).unwrap_or(LogLevel::None);
+                    logs::subscribe_logs(&registry, connection_id, level);
+                    let _ = outgoing_messages_tx
+                        .send(ProtoMessage::SubscribeLogsRequest(subscribe_logs_request));
+                }
+                ProtoMessage::ListEntitiesRequest(list_entities_request) => {
+                    debug!("ListEntitiesRequest: {:?}", list_entities_request);
+
+                    for (key, entity) in &entities {
+                        let _ = messages_tx_clone.send(entity.list_response(*key)).await;
+                    }
+                    let _ = messages_tx_clone
+                        .send(ProtoMessage::ListEntitiesDoneResponse(
+                            ListEntitiesDoneResponse {},
+                        ))
+                        .await;
+                }
+                other_message => {
+                    // Forward the message to the outgoing channel
+                    if let Err(e) = outgoing_messages_tx.send(other_message) {
+                        error!("Error sending message to outgoing channel: {:?}", e);
+                    }
+                }
+            }
+        }
+    });
+
+    Ok(messages_tx)
 }
 
-/// Represents a binary sensor entity.
-///
-/// Binary sensors report a simple on/off or true/false state, such as
-/// door/window sensors, motion detectors, or binary switches.
-#[derive(Clone, Debug)]
-pub struct BinarySensor {
-    /// The unique object identifier for this binary sensor
-    pub object_id: String,
+/// Conversion of entity configuration fields into their protobuf representation.
+trait ToProto {
+    type Proto;
+
+    fn to_proto(&self) -> Self::Proto;
+}
+
+macro_rules! proto_as_is {
+    ($($type:ty),* $(,)?) => {
+        $(
+            impl ToProto for $type {
+                type Proto = $type;
+
+                fn to_proto(&self) -> Self::Proto {
+                    self.clone()
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! proto_enumerations {
+    ($($type:ty),* $(,)?) => {
+        $(
+            impl ToProto for $type {
+                type Proto = i32;
+
+                fn to_proto(&self) -> Self::Proto {
+                    (*self).into()
+                }
+            }
+
+            impl ToProto for Vec<$type> {
+                type Proto = Vec<i32>;
+
+                fn to_proto(&self) -> Self::Proto {
+                    self.iter().map(|value| (*value).into()).collect()
+                }
+            }
+        )*
+    };
+}
+
+proto_as_is!(
+    bool,
+    i32,
+    u32,
+    f32,
+    String,
+    Vec<String>,
+    Vec<MediaPlayerSupportedFormat>,
+);
+proto_enumerations!(
+    ClimateMode,
+    ColorMode,
+    EntityCategory,
+    NumberMode,
+    SensorStateClass,
+    TextMode,
+);
+
+macro_rules! entities {
+    ($(
+        $(#[$meta:meta])*
+        $variant:ident($response:ident) => $domain:ident {
+            $($(#[$field_meta:meta])* $field:ident: $type:ty,)*
+        }
+    )*) => {
+        /// Represents the different types of entities supported by ESPHome.
+        ///
+        /// This enum contains all entity types that can be registered with the server,
+        /// each with its typed configuration.
+        #[derive(Clone, Debug)]
+        pub enum Entity {
+            $(
+                $(#[$meta])*
+                $variant($variant),
+            )*
+        }
+
+        impl Entity {
+            /// The domain of the entity.
+            pub fn domain(&self) -> Domain {
+                match self {
+                    $(Entity::$variant(_) => Domain::$domain,)*
+                }
+            }
+
+            /// The object id of the entity.
+            pub fn object_id(&self) -> &str {
+                match self {
+                    $(Entity::$variant(entity) => &entity.object_id,)*
+                }
+            }
+
+            pub(crate) fn set_object_id(&mut self, object_id: &str) {
+                match self {
+                    $(Entity::$variant(entity) => entity.object_id = object_id.to_string(),)*
+                }
+            }
+
+            /// The `ListEntities*Response` announcing the entity with `key`.
+            pub fn list_response(&self, key: u32) -> ProtoMessage {
+                match self {
+                    $(Entity::$variant(entity) => ProtoMessage::$response($response {
+                        object_id: entity.object_id.clone(),
+                        key,
+                        name: entity.name.clone(),
+                        icon: entity.icon.clone(),
+                        disabled_by_default: entity.disabled_by_default,
+                        entity_category: entity.entity_category.to_proto(),
+                        $($field: entity.$field.to_proto(),)*
+                        ..Default::default()
+                    }),)*
+                }
+            }
+        }
+
+        $(
+            $(#[$meta])*
+            #[derive(Clone, Debug, Default)]
+            pub struct $variant {
+                /// The unique object identifier, the key of the entity is derived from it
+                pub object_id: String,
+                /// The display name, empty to use the name of the device
+                pub name: String,
+                /// Material Design icon, e.g. `mdi:thermometer`
+                pub icon: String,
+                /// Whether Home Assistant adds the entity disabled
+                pub disabled_by_default: bool,
+                /// Marks configuration and diagnostic entities
+                pub entity_category: EntityCategory,
+                $(
+                    $(#[$field_meta])*
+                    pub $field: $type,
+                )*
+            }
+
+            impl From<$variant> for Entity {
+                fn from(entity: $variant) -> Self {
+                    Entity::$variant(entity)
+                }
+            }
+        )*
+    };
 }
+
+entities!(
+    /// An alarm control panel that can be armed and disarmed.
+    AlarmControlPanel(ListEntitiesAlarmControlPanelResponse) => AlarmControlPanel {
+        /// Bitmask of the supported arming modes
+        supported_features: u32,
+        /// Whether a code is needed to disarm
+        requires_code: bool,
+        /// Whether a code is needed to arm
+        requires_code_to_arm: bool,
+    }
+    /// A binary sensor entity (on/off state).
+    ///
+    /// Binary sensors report a simple on/off or true/false state, such as
+    /// door/window sensors, motion detectors, or binary switches.
+    BinarySensor(ListEntitiesBinarySensorResponse) => BinarySensor {
+        /// Home Assistant device class, e.g. `door` or `motion`
+        device_class: String,
+        /// Whether the sensor reports the connection status of the device
+        is_status_binary_sensor: bool,
+    }
+    /// A button that triggers an action when pressed.
+    Button(ListEntitiesButtonResponse) => Button {
+        /// Home Assistant device class, e.g. `restart`
+        device_class: String,
+    }
+    /// A camera that streams images.
+    Camera(ListEntitiesCameraResponse) => Camera {}
+    /// A climate device like a thermostat or an air conditioner.
+    Climate(ListEntitiesClimateResponse) => Climate {
+        /// Whether the current temperature is reported
+        supports_current_temperature: bool,
+        /// Whether a low and a high target temperature can be set
+        supports_two_point_target_temperature: bool,
+        /// The supported modes
+        supported_modes: Vec<ClimateMode>,
+        /// Lowest temperature offered in the UI
+        visual_min_temperature: f32,
+        /// Highest temperature offered in the UI
+        visual_max_temperature: f32,
+        /// Step of the target temperature in the UI
+        visual_target_temperature_step: f32,
+    }
+    /// A cover like a blind, a shutter or a garage door.
+    Cover(ListEntitiesCoverResponse) => Cover {
+        /// Whether the state is assumed instead of reported
+        assumed_state: bool,
+        /// Whether the cover can be moved to a position
+        supports_position: bool,
+        /// Whether the cover can be tilted
+        supports_tilt: bool,
+        /// Whether the cover can be stopped while moving
+        supports_stop: bool,
+        /// Home Assistant device class, e.g. `garage`
+        device_class: String,
+    }
+    /// A date that can be set.
+    Date(ListEntitiesDateResponse) => Date {}
+    /// A date and time that can be set.
+    DateTime(ListEntitiesDateTimeResponse) => DateTime {}
+    /// An event entity that fires events like button presses.
+    Event(ListEntitiesEventResponse) => Event {
+        /// Home Assistant device class, e.g. `button`
+        device_class: String,
+        /// Types of the events that are fired
+        event_types: Vec<String>,
+    }
+    /// A fan.
+    Fan(ListEntitiesFanResponse) => Fan {
+        /// Whether the fan can oscillate
+        supports_oscillation: bool,
+        /// Whether the speed can be set
+        supports_speed: bool,
+        /// Whether the direction can be changed
+        supports_direction: bool,
+        /// Number of speed levels
+        supported_speed_count: i32,
+        /// The supported preset modes
+        supported_preset_modes: Vec<String>,
+    }
+    /// A light.
+    Light(ListEntitiesLightResponse) => Light {
+        /// The supported color modes
+        supported_color_modes: Vec<ColorMode>,
+        /// Lowest color temperature in mireds
+        min_mireds: f32,
+        /// Highest color temperature in mireds
+        max_mireds: f32,
+        /// Names of the available effects
+        effects: Vec<String>,
+    }
+    /// A lock.
+    Lock(ListEntitiesLockResponse) => Lock {
+        /// Whether the state is assumed instead of reported
+        assumed_state: bool,
+        /// Whether the lock can open the door
+        supports_open: bool,
+        /// Whether a code is needed
+        requires_code: bool,
+        /// Regular expression the code must match
+        code_format: String,
+    }
+    /// A media player.
+    MediaPlayer(ListEntitiesMediaPlayerResponse) => MediaPlayer {
+        /// Whether the playback can be paused
+        supports_pause: bool,
+        /// The supported audio formats
+        supported_formats: Vec<MediaPlayerSupportedFormat>,
+    }
+    /// A number that can be set within a range.
+    Number(ListEntitiesNumberResponse) => Number {
+        /// Smallest value
+        min_value: f32,
+        /// Largest value
+        max_value: f32,
+        /// Step between values
+        step: f32,
+        /// Unit of the value, e.g. `°C`
+        unit_of_measurement: String,
+        /// How the number is shown in the UI
+        mode: NumberMode,
+        /// Home Assistant device class, e.g. `temperature`
+        device_class: String,
+    }
+    /// A selection from a list of options.
+    Select(ListEntitiesSelectResponse) => Select {
+        /// The selectable options
+        options: Vec<String>,
+    }
+    /// A sensor reporting a numeric value.
+    Sensor(ListEntitiesSensorResponse) => Sensor {
+        /// Unit of the value, e.g. `°C`
+        unit_of_measurement: String,
+        /// Number of decimals shown
+        accuracy_decimals: i32,
+        /// Whether every value is recorded, even if it didn't change
+        force_update: bool,
+        /// Home Assistant device class, e.g. `temperature`
+        device_class: String,
+        /// Home Assistant state class, used for statistics
+        state_class: SensorStateClass,
+    }
+    /// A switch.
+    Switch(ListEntitiesSwitchResponse) => Switch {
+        /// Whether the state is assumed instead of reported
+        assumed_state: bool,
+        /// Home Assistant device class, e.g. `outlet`
+        device_class: String,
+    }
+    /// A text that can be set.
+    Text(ListEntitiesTextResponse) => Text {
+        /// Minimum length of the text
+        min_length: u32,
+        /// Maximum length of the text
+        max_length: u32,
+        /// Regular expression the text must match
+        pattern: String,
+        /// How the text is shown in the UI
+        mode: TextMode,
+    }
+    /// A sensor reporting a text.
+    TextSensor(ListEntitiesTextSensorResponse) => TextSensor {
+        /// Home Assistant device class, e.g. `timestamp`
+        device_class: String,
+    }
+    /// A time that can be set.
+    Time(ListEntitiesTimeResponse) => Time {}
+    /// A firmware update.
+    Update(ListEntitiesUpdateResponse) => Update {
+        /// Home Assistant device class, e.g. `firmware`
+        device_class: String,
+    }
+    /// A valve.
+    Valve(ListEntitiesValveResponse) => Valve {
+        /// Home Assistant device class, e.g. `water`
+        device_class: String,
+        /// Whether the state is assumed instead of reported
+        assumed_state: bool,
+        /// Whether the valve can be moved to a position
+        supports_position: bool,
+        /// Whether the valve can be stopped while moving
+        supports_stop: bool,
+    }
+);
@@ -0,0 +1,81 @@
+//! Log lines of an [`crate::esphomeserver::EspHomeServer`].
+//!
+//! Log lines are sent to the connections that subscribed with `SubscribeLogsRequest`
+//! up to their level, formatted like ESPHome does.
+
+use crate::parser::ProtoMessage;
+use crate::proto::{LogLevel, SubscribeLogsResponse};
+use crate::registry::Registry;
+
+/// Subscribes a connection to the log lines up to `level`.
+pub(crate) fn subscribe_logs(connections: &Registry, id: u64, level: LogLevel) {
+    connections.subscribe(id, Vec::new(), |subscriptions| {
+        subscriptions.log_level = Some(level)
+    });
+}
+
+/// Sends a log line to all connections subscribed up to its level.
+///
+/// Returns the number of connections the line was sent to.
+pub(crate) fn log(connections: &Registry, level: LogLevel, tag: &str, message: &str) -> usize {
+    let line = format!("[{}][{}]: {}", level_letter(level), tag, message);
+    let response = SubscribeLogsResponse {
+        level: level.into(),
+        message: line.into_bytes(),
+    };
+    connections.send_to(
+        |subscriptions| {
+            subscriptions
+                .log_level
+                .is_some_and(|subscribed| level <= subscribed)
+        },
+        ProtoMessage::SubscribeLogsResponse(response),
+    )
+}
+
+/// The letter ESPHome prints for a log level, e.g. `I` for info.
+fn level_letter(level: LogLevel) -> &'static str {
+    match level {
+        LogLevel::None => "",
+        LogLevel::Error => "E",
+        LogLevel::Warn => "W",
+        LogLevel::Info => "I",
+        LogLevel::Config => "C",
+        LogLevel::Debug => "D",
+        LogLevel::Verbose => "V",
+        LogLevel::VeryVerbose => "VV",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+    use tokio_util::sync::CancellationToken;
+
+    #[tokio::test]
+    async fn sends_logs_up_to_the_subscribed_level() {
+        let registry = Registry::default();
+        let (info_tx, mut info_rx) = mpsc::channel(4);
+        let (debug_tx, mut debug_rx) = mpsc::channel(4);
+        let (unsubscribed_tx, mut unsubscribed_rx) = mpsc::channel(4);
+        let info = registry.connect(info_tx, CancellationToken::new());
+        let debug = registry.connect(debug_tx, CancellationToken::new());
+        registry.connect(unsubscribed_tx, CancellationToken::new());
+        subscribe_logs(&registry, info, LogLevel::Info);
+        subscribe_logs(&registry, debug, LogLevel::Debug);
+
+        assert_eq!(log(&registry, LogLevel::Debug, "sensor", "Reading"), 1);
+        assert_eq!(log(&registry, LogLevel::Warn, "wifi", "Weak signal"), 2);
+
+        let Some(ProtoMessage::SubscribeLogsResponse(line)) = debug_rx.recv().await else {
+            panic!("log line not sent");
+        };
+        assert_eq!(line.message, b"[D][sensor]: Reading");
+        let Some(ProtoMessage::SubscribeLogsResponse(line)) = info_rx.recv().await else {
+            panic!("log line not sent");
+        };
+        assert_eq!(line.level, LogLevel::Warn as i32);
+        assert!(unsubscribed_rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,171 @@
+//! Entity states of an [`crate::esphomeserver::EspHomeServer`].
+//!
+//! Like a real ESPHome device the server keeps the last known state of every entity
+//! and sends it as soon as a client sends `SubscribeStatesRequest`, afterwards every
+//! published state is sent to the subscribed connections only. The connections are
+//! kept in the [`Registry`] of the API of the server.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::entity::EntityState;
+use crate::registry::Registry;
+
+/// States shared by all connections of a server.
+#[derive(Default)]
+pub(crate) struct StateStore {
+    states: Mutex<HashMap<u32, EntityState>>,
+}
+
+impl StateStore {
+    /// The last known state of the entity with `key`.
+    pub(crate) fn state(&self, key: u32) -> Option<EntityState> {
+        self.states.lock().unwrap().get(&key).cloned()
+    }
+
+    /// Forgets the state of an entity that was replaced.
+    pub(crate) fn forget_state(&self, key: u32) {
+        self.states.lock().unwrap().remove(&key);
+    }
+
+    /// Subscribes a connection to the states and sends it all known states.
+    pub(crate) fn subscribe_states(&self, connections: &Registry, id: u64) {
+        // The dump is queued while holding the lock, so no state published meanwhile
+        // is queued before it.
+        let states = self.states.lock().unwrap();
+        let dump = states
+            .values()
+            .map(|state| state.clone().into_message())
+            .collect();
+        connections.subscribe(id, dump, |subscriptions| subscriptions.states = true);
+    }
+
+    /// Stores a state and sends it to all subscribed connections.
+    ///
+    /// Returns the number of connections the state was sent to.
+    pub(crate) fn publish_state(&self, connections: &Registry, state: EntityState) -> usize {
+        // Sent while holding the lock, so a connection subscribing meanwhile gets
+        // the state either in its dump or queued after it.
+        let mut states = self.states.lock().unwrap();
+        states.insert(state.key(), state.clone());
+        connections.send_to(|subscriptions| subscriptions.states, state.into_message())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::ProtoMessage;
+    use crate::proto::{SensorStateResponse, SwitchStateResponse};
+    use tokio::sync::mpsc;
+    use tokio_util::sync::CancellationToken;
+
+    #[tokio::test]
+    async fn dumps_states_on_subscription() {
+        let store = StateStore::default();
+        let registry = Registry::default();
+        store.publish_state(
+            &registry,
+            SensorStateResponse {
+                key: 1,
+                state: 20.0,
+                ..Default::default()
+            }
+            .into(),
+        );
+        store.publish_state(
+            &registry,
+            SensorStateResponse {
+                key: 1,
+                state: 21.5,
+                ..Default::default()
+            }
+            .into(),
+        );
+
+        let (answers_tx, mut answers_rx) = mpsc::channel(4);
+        let id = registry.connect(answers_tx, CancellationToken::new());
+        store.subscribe_states(&registry, id);
+        let Some(ProtoMessage::SensorStateResponse(dumped)) = answers_rx.recv().await else {
+            panic!("state not dumped");
+        };
+        assert_eq!(dumped.state, 21.5);
+        assert!(answers_rx.try_recv().is_err());
+
+        let switch: EntityState = SwitchStateResponse {
+            key: 2,
+            state: true,
+            ..Default::default()
+        }
+        .into();
+        assert_eq!(store.publish_state(&registry, switch.clone()), 1);
+        let Some(ProtoMessage::SwitchStateResponse(sent)) = answers_rx.recv().await else {
+            panic!("state not sent");
+        };
+        assert!(sent.state);
+        assert_eq!(store.state(2), Some(switch));
+
+        drop(answers_rx);
+        registry.disconnected(id);
+        assert_eq!(
+            store.publish_state(
+                &registry,
+                SensorStateResponse {
+                    key: 1,
+                    ..Default::default()
+                }
+                .into()
+            ),
+            0
+        );
+        assert_eq!(registry.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn queues_states_published_while_dumping_after_the_dump() {
+        let store = StateStore::default();
+        let registry = Registry::default();
+        // More states than a queue takes from the subscribers.
+        let count = 200;
+        for key in 0..count {
+            store.publish_state(
+                &registry,
+                SensorStateResponse {
+                    key,
+                    state: 20.0,
+                    ..Default::default()
+                }
+                .into(),
+            );
+        }
+
+        let (answers_tx, mut answers_rx) = mpsc::channel(1);
+        let closing = CancellationToken::new();
+        let id = registry.connect(answers_tx, closing.clone());
+        let subscribing = async { store.subscribe_states(&registry, id) };
+        let publishing = async {
+            store.publish_state(
+                &registry,
+                SensorStateResponse {
+                    key: 0,
+                    state: 21.5,
+                    ..Default::default()
+                }
+                .into(),
+            )
+        };
+        let ((), published) = tokio::join!(subscribing, publishing);
+        assert_eq!(published, 1);
+
+        let mut states = HashMap::new();
+        for _ in 0..=count {
+            let Some(ProtoMessage::SensorStateResponse(state)) = answers_rx.recv().await else {
+                panic!("state not sent");
+            };
+            states.insert(state.key, state.state);
+        }
+        assert_eq!(states.len(), count as usize);
+        assert_eq!(states[&0], 21.5);
+        assert!(!closing.is_cancelled());
+    }
+}
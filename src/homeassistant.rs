@@ -0,0 +1,196 @@
+//! Home Assistant services used by the device side.
+//!
+//! Home Assistant subscribes to the actions of a device with
+//! `SubscribeHomeassistantServicesRequest`. Afterwards the device can call actions,
+//! fire events and report scanned tags with `HomeassistantActionRequest` messages,
+//! see [`crate::esphomeapi::EspHomeApi::call_homeassistant_action`].
+//!
+//! Entity states are imported the other way round: after
+//! `SubscribeHomeAssistantStatesRequest` the device announces every registered
+//! entity and attribute with `SubscribeHomeAssistantStateResponse`, and Home
+//! Assistant answers with a `HomeAssistantStateResponse` on every change, see
+//! [`crate::esphomeapi::EspHomeApi::import_homeassistant_state`].
+
+use log::debug;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::watch;
+
+use crate::parser::{HomeassistantActionRequest, ProtoMessage};
+use crate::proto::{
+    HomeAssistantStateResponse, HomeassistantServiceMap, SubscribeHomeAssistantStateResponse,
+};
+use crate::registry::Registry;
+
+/// Entity id and attribute, the attribute is empty for the state itself.
+type StateKey = (String, String);
+
+/// State shared by all connections of an API.
+///
+/// The subscribed connections are kept in the [`Registry`] of the API.
+#[derive(Default)]
+pub(crate) struct HomeAssistant {
+    states: Mutex<HashMap<StateKey, watch::Sender<Option<String>>>>,
+}
+
+impl HomeAssistant {
+    /// Sends an action to all subscribed connections and returns their number.
+    pub(crate) fn call_action(
+        &self,
+        connections: &Registry,
+        action: HomeassistantActionRequest,
+    ) -> usize {
+        connections.send_to(
+            |subscriptions| subscriptions.homeassistant_actions,
+            ProtoMessage::HomeassistantActionRequest(action),
+        )
+    }
+
+    /// Registers a state, connections that already subscribed are asked for it.
+    pub(crate) fn import_state(
+        &self,
+        connections: &Registry,
+        entity_id: String,
+        attribute: String,
+    ) -> watch::Receiver<Option<String>> {
+        let key = (entity_id, attribute);
+        let mut states = self.states.lock().unwrap();
+        if let Some(state_tx) = states.get(&key) {
+            return state_tx.subscribe();
+        }
+        let (state_tx, state_rx) = watch::channel(None);
+        // Requested while holding the lock, so a connection subscribing meanwhile
+        // is asked only once.
+        connections.send_to(
+            |subscriptions| subscriptions.homeassistant_states,
+            state_request(&key),
+        );
+        states.insert(key, state_tx);
+        state_rx
+    }
+
+    /// Subscribes a connection and asks it for all registered states.
+    pub(crate) fn subscribe_states(&self, connections: &Registry, id: u64) {
+        let states = self.states.lock().unwrap();
+        let requests: Vec<ProtoMessage> = states.keys().map(state_request).collect();
+        connections.subscribe(id, requests, |subscriptions| {
+            subscriptions.homeassistant_states = true
+        });
+    }
+
+    /// Publishes a state sent by Home Assistant.
+    pub(crate) fn state_received(&self, state: HomeAssistantStateResponse) {
+        let key = (state.entity_id, state.attribute);
+        match self.states.lock().unwrap().get(&key) {
+            Some(state_tx) => {
+                state_tx.send_replace(Some(state.state));
+            }
+            None => debug!("Received state of unknown entity {:?}", key),
+        }
+    }
+}
+
+fn state_request((entity_id, attribute): &StateKey) -> ProtoMessage {
+    ProtoMessage::SubscribeHomeAssistantStateResponse(SubscribeHomeAssistantStateResponse {
+        entity_id: entity_id.clone(),
+        attribute: attribute.clone(),
+        once: false,
+    })
+}
+
+/// Builds an action request, `service` is the action or, with `is_event`, the event.
+pub(crate) fn action_request(
+    service: String,
+    data: HashMap<String, String>,
+    data_template: HashMap<String, String>,
+    variables: HashMap<String, String>,
+    is_event: bool,
+) -> HomeassistantActionRequest {
+    HomeassistantActionRequest {
+        service,
+        data: service_map(data),
+        data_template: service_map(data_template),
+        variables: service_map(variables),
+        is_event,
+        ..Default::default()
+    }
+}
+
+fn service_map(map: HashMap<String, String>) -> Vec<HomeassistantServiceMap> {
+    let mut entries: Vec<HomeassistantServiceMap> = map
+        .into_iter()
+        .map(|(key, value)| HomeassistantServiceMap { key, value })
+        .collect();
+    // Sorted so that calls with the same arguments produce the same message.
+    entries.sort_by(|a, b| a.key.cmp(&b.key));
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+    use tokio_util::sync::CancellationToken;
+
+    #[tokio::test]
+    async fn calls_only_subscribed_connections() {
+        let homeassistant = HomeAssistant::default();
+        let connections = Registry::default();
+        let (subscribed_tx, mut subscribed_rx) = mpsc::channel(1);
+        let (unsubscribed_tx, mut unsubscribed_rx) = mpsc::channel(1);
+        let subscribed = connections.connect(subscribed_tx, CancellationToken::new());
+        connections.connect(unsubscribed_tx, CancellationToken::new());
+        connections.subscribe(subscribed, Vec::new(), |subscriptions| {
+            subscriptions.homeassistant_actions = true
+        });
+
+        let action = action_request(
+            "light.turn_on".to_string(),
+            HashMap::from([
+                ("entity_id".to_string(), "light.kitchen".to_string()),
+                ("brightness".to_string(), "128".to_string()),
+            ]),
+            HashMap::new(),
+            HashMap::new(),
+            false,
+        );
+        assert_eq!(homeassistant.call_action(&connections, action), 1);
+
+        let Some(ProtoMessage::HomeassistantActionRequest(received)) = subscribed_rx.recv().await
+        else {
+            panic!("action not received");
+        };
+        assert_eq!(received.service, "light.turn_on");
+        assert_eq!(received.data[0].key, "brightness");
+        assert_eq!(received.data[1].value, "light.kitchen");
+        assert!(unsubscribed_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn imports_states() {
+        let homeassistant = HomeAssistant::default();
+        let connections = Registry::default();
+        let sun = homeassistant.import_state(&connections, "sun.sun".to_string(), String::new());
+        let again = homeassistant.import_state(&connections, "sun.sun".to_string(), String::new());
+        assert_eq!(*sun.borrow(), None);
+
+        let (answers_tx, mut answers_rx) = mpsc::channel(4);
+        let id = connections.connect(answers_tx, CancellationToken::new());
+        homeassistant.subscribe_states(&connections, id);
+        let Some(ProtoMessage::SubscribeHomeAssistantStateResponse(request)) =
+            answers_rx.recv().await
+        else {
+            panic!("state not requested");
+        };
+        assert_eq!(request.entity_id, "sun.sun");
+        assert!(answers_rx.try_recv().is_err());
+
+        homeassistant.state_received(HomeAssistantStateResponse {
+            entity_id: "sun.sun".to_string(),
+            state: "above_horizon".to_string(),
+            attribute: String::new(),
+        });
+        assert_eq!(sun.borrow().as_deref(), Some("above_horizon"));
+        assert_eq!(again.borrow().as_deref(), Some("above_horizon"));
+    }
+}
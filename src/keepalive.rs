@@ -0,0 +1,137 @@
+//! Keepalive handling shared by the device and the client side.
+//!
+//! Like ESPHome itself, a connection sends a `PingRequest` once it has been idle for
+//! the keepalive interval and is torn down when nothing at all was received within
+//! the keepalive timeout. This detects half-open connections long before the
+//! operating system gives up on them.
+
+use std::time::Duration;
+use tokio::time::Instant;
+use typed_builder::TypedBuilder;
+
+/// Keepalive settings of a connection.
+///
+/// # Examples
+///
+/// ```rust
+/// use esphome_native_api::keepalive::Keepalive;
+/// use std::time::Duration;
+///
+/// let keepalive = Keepalive::builder()
+///     .interval(Duration::from_secs(10))
+///     .timeout(Duration::from_secs(45))
+///     .build();
+/// ```
+#[derive(TypedBuilder, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Keepalive {
+    /// Idle time after which a `PingRequest` is sent.
+    #[builder(default = Duration::from_secs(20))]
+    pub interval: Duration,
+    /// Time without any received message after which the connection is closed.
+    #[builder(default = Duration::from_secs(90))]
+    pub timeout: Duration,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Keepalive::builder().build()
+    }
+}
+
+/// What the read loop has to do once the keepalive timer fires.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum KeepaliveAction {
+    /// Send a `PingRequest` to the peer.
+    Ping,
+    /// The peer has been silent for too long; close the connection.
+    TimedOut,
+}
+
+/// Tracks the last activity of a connection.
+pub(crate) struct KeepaliveTimer {
+    keepalive: Option<Keepalive>,
+    last_received: Instant,
+    last_ping: Instant,
+}
+
+impl KeepaliveTimer {
+    pub(crate) fn new(keepalive: Option<Keepalive>) -> Self {
+        let now = Instant::now();
+        KeepaliveTimer {
+            keepalive,
+            last_received: now,
+            last_ping: now,
+        }
+    }
+
+    /// Records that a message was received from the peer.
+    pub(crate) fn received(&mut self) {
+        self.last_received = Instant::now();
+    }
+
+    /// Waits until the next keepalive action is due.
+    ///
+    /// Never resolves if keepalive is disabled. The future is cancel safe, so it can
+    /// be raced against reading the next message.
+    pub(crate) async fn next_action(&mut self) -> KeepaliveAction {
+        let Some(keepalive) = self.keepalive else {
+            return std::future::pending().await;
+        };
+
+        let ping_at = self.last_received.max(self.last_ping) + keepalive.interval;
+        let timeout_at = self.last_received + keepalive.timeout;
+        if timeout_at <= ping_at {
+            tokio::time::sleep_until(timeout_at).await;
+            KeepaliveAction::TimedOut
+        } else {
+            tokio::time::sleep_until(ping_at).await;
+            self.last_ping = Instant::now();
+            KeepaliveAction::Ping
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keepalive() -> Option<Keepalive> {
+        Some(
+            Keepalive::builder()
+                .interval(Duration::from_secs(10))
+                .timeout(Duration::from_secs(25))
+                .build(),
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pings_when_idle_and_times_out() {
+        let start = Instant::now();
+        let mut timer = KeepaliveTimer::new(keepalive());
+
+        assert_eq!(timer.next_action().await, KeepaliveAction::Ping);
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+        assert_eq!(timer.next_action().await, KeepaliveAction::Ping);
+        assert_eq!(start.elapsed(), Duration::from_secs(20));
+        assert_eq!(timer.next_action().await, KeepaliveAction::TimedOut);
+        assert_eq!(start.elapsed(), Duration::from_secs(25));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn received_messages_postpone_pings() {
+        let start = Instant::now();
+        let mut timer = KeepaliveTimer::new(keepalive());
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        timer.received();
+        assert_eq!(timer.next_action().await, KeepaliveAction::Ping);
+        assert_eq!(start.elapsed(), Duration::from_secs(15));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn disabled_keepalive_never_fires() {
+        let mut timer = KeepaliveTimer::new(None);
+        let action = tokio::time::timeout(Duration::from_secs(3600), timer.next_action()).await;
+        assert!(action.is_err());
+    }
+}
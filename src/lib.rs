@@ -4,16 +4,42 @@
 
 pub mod proto;
 
+#[cfg(feature = "std")]
+pub mod bluetooth_proxy;
+#[cfg(feature = "std")]
+pub mod camera;
+#[cfg(feature = "std")]
+pub mod entity;
 #[cfg(feature = "std")]
 pub mod esphomeapi;
 #[cfg(feature = "std")]
+pub mod esphomeclient;
+#[cfg(feature = "std")]
 pub mod esphomeserver;
 #[cfg(feature = "std")]
 mod frame;
 #[cfg(feature = "std")]
+mod homeassistant;
+#[cfg(feature = "std")]
+pub mod keepalive;
+#[cfg(feature = "std")]
+pub mod mdns;
+#[cfg(feature = "std")]
 mod packet_plaintext;
 #[cfg(feature = "std")]
 pub mod parser;
+#[cfg(feature = "std")]
+mod registry;
+#[cfg(feature = "std")]
+pub mod request;
+#[cfg(feature = "std")]
+pub mod time_sync;
+#[cfg(feature = "std")]
+pub mod user_services;
+#[cfg(feature = "std")]
+mod versions;
+#[cfg(feature = "std")]
+pub mod voice_assistant;
 // #[cfg(feature = "std")]
 #[cfg(feature = "std")]
 mod packet_encrypted;
@@ -0,0 +1,856 @@
+//! mDNS advertisement of the native API.
+//!
+//! Home Assistant discovers ESPHome devices by browsing for `_esphomelib._tcp`
+//! services. [`MdnsResponder`] answers these queries for a single device with the
+//! records of RFC 6762 and RFC 6763: a PTR record pointing to the service instance
+//! of the device, its SRV and TXT records and the A records of its host. The TXT
+//! record carries the device metadata shown during discovery, see
+//! [`crate::esphomeapi::EspHomeApi::mdns_service`].
+//!
+//! The responder announces the service when started and says goodbye when stopped,
+//! so clients learn about the device without polling. Conflict probing is not done,
+//! the device name has to be unique in the network.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! use esphome_native_api::esphomeapi::EspHomeApi;
+//! use esphome_native_api::mdns::{MdnsConfig, MdnsResponder};
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     let api = EspHomeApi::builder()
+//!         .name("bedroom-light".to_string())
+//!         .mac("AA:BB:CC:DD:EE:FF".to_string())
+//!         .build();
+//!
+//!     let responder = MdnsResponder::start(MdnsConfig::default(), api.mdns_service(6053)).await?;
+//!     // Serve the API on port 6053 ...
+//!     responder.stop().await;
+//!     Ok(())
+//! }
+//! ```
+
+use log::{debug, trace, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket as StdUdpSocket};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use typed_builder::TypedBuilder;
+
+/// Multicast group of mDNS.
+pub const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+/// Port of mDNS.
+pub const MDNS_PORT: u16 = 5353;
+/// Service type ESPHome devices are advertised with.
+pub const SERVICE_TYPE: &str = "_esphomelib._tcp.local";
+
+/// Browsed to enumerate all service types of a network, see RFC 6763 section 9.
+const SERVICE_TYPE_ENUMERATION: &str = "_services._dns-sd._udp.local";
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Set in the class of unique records of multicast responses.
+const CACHE_FLUSH: u16 = 0x8000;
+/// Set in the class of questions that prefer a unicast response.
+const UNICAST_RESPONSE: u16 = 0x8000;
+/// Response flag and authoritative answer flag.
+const RESPONSE_FLAGS: u16 = 0x8400;
+
+/// TTL of the records naming the host, as recommended by RFC 6762.
+const HOST_TTL: u32 = 120;
+/// TTL of all other records, as recommended by RFC 6762.
+const SERVICE_TTL: u32 = 4500;
+/// Maximum TTL of the records of a legacy unicast response.
+const LEGACY_TTL: u32 = 10;
+/// Number of announcements sent when starting, see RFC 6762 section 8.3.
+const ANNOUNCEMENTS: u32 = 2;
+/// Delay between the announcements sent when starting.
+const ANNOUNCEMENT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Network settings of a [`MdnsResponder`].
+///
+/// # Examples
+///
+/// ```rust
+/// use esphome_native_api::mdns::MdnsConfig;
+/// use std::net::Ipv4Addr;
+///
+/// let config = MdnsConfig::builder()
+///     .interface(Ipv4Addr::new(192, 168, 1, 20))
+///     .txt(vec![("board".to_string(), "raspberrypi".to_string())])
+///     .build();
+/// ```
+#[derive(TypedBuilder, Clone, Debug)]
+pub struct MdnsConfig {
+    /// Port the responder listens on, anything but 5353 is only useful for tests.
+    #[builder(default = MDNS_PORT)]
+    port: u16,
+    /// Address of the interface multicast is received and sent on, unspecified lets
+    /// the system choose.
+    #[builder(default = Ipv4Addr::UNSPECIFIED)]
+    interface: Ipv4Addr,
+    /// Addresses advertised for the host, defaults to the address of `interface`.
+    #[builder(default)]
+    addresses: Vec<Ipv4Addr>,
+    /// TXT entries added to those of the service, e.g. `board`, replacing entries
+    /// with the same key.
+    #[builder(default)]
+    txt: Vec<(String, String)>,
+}
+
+impl Default for MdnsConfig {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+/// Service instance advertised by a [`MdnsResponder`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct MdnsService {
+    /// Name of the instance and its host, the device name.
+    pub instance: String,
+    /// Port of the native API.
+    pub port: u16,
+    /// Entries of the TXT record.
+    pub txt: Vec<(String, String)>,
+}
+
+impl MdnsService {
+    /// Sets a TXT entry, replacing an entry with the same key.
+    pub fn set_txt(&mut self, key: &str, value: &str) {
+        match self.txt.iter_mut().find(|(existing, _)| existing == key) {
+            Some((_, existing)) => *existing = value.to_string(),
+            None => self.txt.push((key.to_string(), value.to_string())),
+        }
+    }
+
+    /// Full name of the service instance, e.g. `bedroom-light._esphomelib._tcp.local`.
+    pub fn instance_name(&self) -> String {
+        format!("{}.{}", self.instance, SERVICE_TYPE)
+    }
+
+    /// Name of the host, e.g. `bedroom-light.local`.
+    pub fn host_name(&self) -> String {
+        format!("{}.local", self.instance)
+    }
+}
+
+/// Answers mDNS queries for a [`MdnsService`] until stopped.
+///
+/// Dropping the responder stops it as well, but without waiting for the goodbye to be
+/// sent.
+pub struct MdnsResponder {
+    shutdown: CancellationToken,
+    task: Option<JoinHandle<()>>,
+}
+
+impl MdnsResponder {
+    /// Joins the mDNS group, announces `service` and answers queries in a background task.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the socket cannot be bound or the group cannot be joined.
+    pub async fn start(config: MdnsConfig, mut service: MdnsService) -> io::Result<Self> {
+        for (key, value) in &config.txt {
+            service.set_txt(key, value);
+        }
+        let addresses = if config.addresses.is_empty() {
+            vec![local_address(config.interface)?]
+        } else {
+            config.addresses.clone()
+        };
+        let socket = bind(&config)?;
+        debug!(
+            "Advertising {} on {:?} port {}",
+            service.instance_name(),
+            addresses,
+            service.port
+        );
+
+        let records = Records { service, addresses };
+        let shutdown = CancellationToken::new();
+        let task = tokio::spawn(respond(socket, config.port, records, shutdown.clone()));
+        Ok(Self {
+            shutdown,
+            task: Some(task),
+        })
+    }
+
+    /// Stops answering queries after telling the clients that the service is gone.
+    pub async fn stop(mut self) {
+        self.shutdown.cancel();
+        if let Some(task) = self.task.take() {
+            let _ = task.await;
+        }
+    }
+}
+
+impl Drop for MdnsResponder {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+
+/// Binds a socket shared with other responders of the host and joins the mDNS group.
+fn bind(config: &MdnsConfig) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, config.port).into())?;
+    socket.join_multicast_v4(&MDNS_GROUP, &config.interface)?;
+    socket.set_multicast_if_v4(&config.interface)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_multicast_ttl_v4(255)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// The address of `interface`, or of the interface multicast is routed to.
+fn local_address(interface: Ipv4Addr) -> io::Result<Ipv4Addr> {
+    if !interface.is_unspecified() {
+        return Ok(interface);
+    }
+    // Connecting a UDP socket sends nothing, it only picks the route.
+    let socket = StdUdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect((MDNS_GROUP, MDNS_PORT))?;
+    match socket.local_addr()? {
+        SocketAddr::V4(address) => Ok(*address.ip()),
+        SocketAddr::V6(_) => Err(io::Error::other("no IPv4 address")),
+    }
+}
+
+async fn respond(socket: UdpSocket, port: u16, records: Records, shutdown: CancellationToken) {
+    let group = SocketAddr::from((MDNS_GROUP, port));
+
+    // Announced twice, while already answering queries.
+    let mut announcements = 0;
+    let announcement = tokio::time::sleep(Duration::ZERO);
+    tokio::pin!(announcement);
+
+    let mut buffer = [0; 9000];
+    loop {
+        let received = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = &mut announcement, if announcements < ANNOUNCEMENTS => {
+                let message = encode_message(0, &[], &records.all(), &[], false);
+                if let Err(err) = socket.send_to(&message, group).await {
+                    warn!("Failed to announce {}: {}", records.service.instance, err);
+                }
+                announcements += 1;
+                announcement
+                    .as_mut()
+                    .reset(tokio::time::Instant::now() + ANNOUNCEMENT_INTERVAL);
+                continue;
+            }
+            received = socket.recv_from(&mut buffer) => received,
+        };
+        let (length, source) = match received {
+            Ok(received) => received,
+            Err(err) => {
+                warn!("Failed to receive mDNS message: {}", err);
+                continue;
+            }
+        };
+        let Some(query) = Query::parse(&buffer[..length]) else {
+            trace!("Ignoring mDNS message from {}", source);
+            continue;
+        };
+        // Queries not sent from the mDNS port come from simple resolvers, RFC 6762 section 6.7.
+        let legacy = source.port() != port;
+        let Some(response) = records.answer(&query, legacy) else {
+            continue;
+        };
+        let destination = if legacy || query.unicast_response() {
+            source
+        } else {
+            group
+        };
+        trace!("Answering mDNS query from {} to {}", source, destination);
+        if let Err(err) = socket.send_to(&response, destination).await {
+            warn!("Failed to answer mDNS query from {}: {}", source, err);
+        }
+    }
+
+    let goodbye: Vec<Record> = records
+        .all()
+        .into_iter()
+        .map(|record| Record { ttl: 0, ..record })
+        .collect();
+    let message = encode_message(0, &[], &goodbye, &[], false);
+    if let Err(err) = socket.send_to(&message, group).await {
+        warn!(
+            "Failed to say goodbye for {}: {}",
+            records.service.instance, err
+        );
+    }
+}
+
+/// The records of a service and its host.
+struct Records {
+    service: MdnsService,
+    addresses: Vec<Ipv4Addr>,
+}
+
+impl Records {
+    fn ptr(&self) -> Record {
+        Record {
+            name: SERVICE_TYPE.to_string(),
+            ttl: SERVICE_TTL,
+            unique: false,
+            data: RecordData::Ptr(self.service.instance_name()),
+        }
+    }
+
+    fn service_type(&self) -> Record {
+        Record {
+            name: SERVICE_TYPE_ENUMERATION.to_string(),
+            ttl: SERVICE_TTL,
+            unique: false,
+            data: RecordData::Ptr(SERVICE_TYPE.to_string()),
+        }
+    }
+
+    fn srv(&self) -> Record {
+        Record {
+            name: self.service.instance_name(),
+            ttl: HOST_TTL,
+            unique: true,
+            data: RecordData::Srv {
+                port: self.service.port,
+                target: self.service.host_name(),
+            },
+        }
+    }
+
+    fn txt(&self) -> Record {
+        Record {
+            name: self.service.instance_name(),
+            ttl: SERVICE_TTL,
+            unique: true,
+            data: RecordData::Txt(
+                self.service
+                    .txt
+                    .iter()
+                    .map(|(key, value)| format!("{}={}", key, value))
+                    .collect(),
+            ),
+        }
+    }
+
+    fn a(&self) -> Vec<Record> {
+        self.addresses
+            .iter()
+            .map(|address| Record {
+                name: self.service.host_name(),
+                ttl: HOST_TTL,
+                unique: true,
+                data: RecordData::A(*address),
+            })
+            .collect()
+    }
+
+    /// All records, as announced when starting and withdrawn when stopping.
+    fn all(&self) -> Vec<Record> {
+        let mut records = vec![self.ptr(), self.srv(), self.txt()];
+        records.extend(self.a());
+        records
+    }
+
+    /// The response to `query`, `None` if none of its questions is about the service.
+    fn answer(&self, query: &Query, legacy: bool) -> Option<Vec<u8>> {
+        let instance_name = self.service.instance_name();
+        let host_name = self.service.host_name();
+        let mut answers = Vec::new();
+        let mut additionals = Vec::new();
+        for question in &query.questions {
+            let asks = |record_type| {
+                question.record_type == record_type || question.record_type == TYPE_ANY
+            };
+            if question.name.eq_ignore_ascii_case(SERVICE_TYPE) && asks(TYPE_PTR) {
+                answers.push(self.ptr());
+                additionals.extend([self.srv(), self.txt()]);
+                additionals.extend(self.a());
+            } else if question.name.eq_ignore_ascii_case(SERVICE_TYPE_ENUMERATION) && asks(TYPE_PTR)
+            {
+                answers.push(self.service_type());
+            } else if question.name.eq_ignore_ascii_case(&instance_name) {
+                if asks(TYPE_SRV) {
+                    answers.push(self.srv());
+                }
+                if asks(TYPE_TXT) {
+                    answers.push(self.txt());
+                }
+                additionals.extend(self.a());
+            } else if question.name.eq_ignore_ascii_case(&host_name) && asks(TYPE_A) {
+                answers.extend(self.a());
+            }
+        }
+        if answers.is_empty() {
+            return None;
+        }
+        answers.dedup();
+        additionals.retain(|record| !answers.contains(record));
+        additionals.dedup();
+
+        if legacy {
+            let answers = legacy_records(answers);
+            let additionals = legacy_records(additionals);
+            Some(encode_message(
+                query.id,
+                &query.questions,
+                &answers,
+                &additionals,
+                true,
+            ))
+        } else {
+            Some(encode_message(0, &[], &answers, &additionals, false))
+        }
+    }
+}
+
+/// Limits the TTL of records for a legacy unicast response.
+fn legacy_records(records: Vec<Record>) -> Vec<Record> {
+    records
+        .into_iter()
+        .map(|record| Record {
+            ttl: record.ttl.min(LEGACY_TTL),
+            ..record
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Record {
+    name: String,
+    ttl: u32,
+    /// Unique records are flagged to flush the caches of the clients.
+    unique: bool,
+    data: RecordData,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum RecordData {
+    A(Ipv4Addr),
+    Ptr(String),
+    Srv { port: u16, target: String },
+    Txt(Vec<String>),
+}
+
+impl RecordData {
+    fn record_type(&self) -> u16 {
+        match self {
+            RecordData::A(_) => TYPE_A,
+            RecordData::Ptr(_) => TYPE_PTR,
+            RecordData::Srv { .. } => TYPE_SRV,
+            RecordData::Txt(_) => TYPE_TXT,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Question {
+    name: String,
+    record_type: u16,
+    class: u16,
+}
+
+/// The questions of a received query.
+#[derive(Debug)]
+struct Query {
+    id: u16,
+    questions: Vec<Question>,
+}
+
+impl Query {
+    /// Parses a query, `None` for responses and malformed messages.
+    fn parse(message: &[u8]) -> Option<Self> {
+        let mut reader = Reader::new(message);
+        let id = reader.u16()?;
+        let flags = reader.u16()?;
+        if flags & 0x8000 != 0 {
+            return None;
+        }
+        let question_count = reader.u16()?;
+        // Known answers and authority records are not needed to answer.
+        reader.skip(6)?;
+        let mut questions = Vec::new();
+        for _ in 0..question_count {
+            questions.push(Question {
+                name: reader.name()?,
+                record_type: reader.u16()?,
+                class: reader.u16()?,
+            });
+        }
+        Some(Self { id, questions })
+    }
+
+    /// Whether all questions prefer a unicast response.
+    fn unicast_response(&self) -> bool {
+        self.questions
+            .iter()
+            .all(|question| question.class & UNICAST_RESPONSE != 0)
+    }
+}
+
+/// Reads the fields of a DNS message.
+struct Reader<'a> {
+    message: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(message: &'a [u8]) -> Self {
+        Self {
+            message,
+            position: 0,
+        }
+    }
+
+    fn bytes(&mut self, length: usize) -> Option<&'a [u8]> {
+        let bytes = self
+            .message
+            .get(self.position..self.position.checked_add(length)?)?;
+        self.position += length;
+        Some(bytes)
+    }
+
+    fn skip(&mut self, length: usize) -> Option<()> {
+        self.bytes(length).map(|_| ())
+    }
+
+    #[cfg(test)]
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    #[cfg(test)]
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4)
+            .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Reads a name, following compression pointers.
+    fn name(&mut self) -> Option<String> {
+        let mut labels = Vec::new();
+        let mut position = self.position;
+        let mut end = None;
+        // Every pointer has to point backwards, which ends loops.
+        let mut limit = position;
+        loop {
+            let length = *self.message.get(position)? as usize;
+            match length & 0xC0 {
+                0x00 if length == 0 => {
+                    position += 1;
+                    break;
+                }
+                0x00 => {
+                    let label = self.message.get(position + 1..position + 1 + length)?;
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                    position += 1 + length;
+                }
+                0xC0 => {
+                    let low = *self.message.get(position + 1)? as usize;
+                    let target = ((length & 0x3F) << 8) | low;
+                    if target >= limit {
+                        return None;
+                    }
+                    end.get_or_insert(position + 2);
+                    limit = target;
+                    position = target;
+                }
+                _ => return None,
+            }
+        }
+        self.position = end.unwrap_or(position);
+        Some(labels.join("."))
+    }
+}
+
+fn encode_name(buffer: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        buffer.push(label.len() as u8);
+        buffer.extend_from_slice(label);
+    }
+    buffer.push(0);
+}
+
+fn encode_record(buffer: &mut Vec<u8>, record: &Record, legacy: bool) {
+    encode_name(buffer, &record.name);
+    buffer.extend_from_slice(&record.data.record_type().to_be_bytes());
+    let class = if record.unique && !legacy {
+        CLASS_IN | CACHE_FLUSH
+    } else {
+        CLASS_IN
+    };
+    buffer.extend_from_slice(&class.to_be_bytes());
+    buffer.extend_from_slice(&record.ttl.to_be_bytes());
+
+    let mut data = Vec::new();
+    match &record.data {
+        RecordData::A(address) => data.extend_from_slice(&address.octets()),
+        RecordData::Ptr(target) => encode_name(&mut data, target),
+        RecordData::Srv { port, target } => {
+            // Priority and weight.
+            data.extend_from_slice(&[0, 0, 0, 0]);
+            data.extend_from_slice(&port.to_be_bytes());
+            encode_name(&mut data, target);
+        }
+        RecordData::Txt(entries) => {
+            for entry in entries {
+                let entry = &entry.as_bytes()[..entry.len().min(255)];
+                data.push(entry.len() as u8);
+                data.extend_from_slice(entry);
+            }
+            // A TXT record without entries holds a single empty string.
+            if entries.is_empty() {
+                data.push(0);
+            }
+        }
+    }
+    buffer.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(&data);
+}
+
+fn encode_message(
+    id: u16,
+    questions: &[Question],
+    answers: &[Record],
+    additionals: &[Record],
+    legacy: bool,
+) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    for field in [
+        id,
+        RESPONSE_FLAGS,
+        questions.len() as u16,
+        answers.len() as u16,
+        0,
+        additionals.len() as u16,
+    ] {
+        buffer.extend_from_slice(&field.to_be_bytes());
+    }
+    for question in questions {
+        encode_name(&mut buffer, &question.name);
+        buffer.extend_from_slice(&question.record_type.to_be_bytes());
+        buffer.extend_from_slice(&(question.class & !UNICAST_RESPONSE).to_be_bytes());
+    }
+    for record in answers.iter().chain(additionals) {
+        encode_record(&mut buffer, record, legacy);
+    }
+    buffer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::timeout;
+
+    /// A record of a received response, with its data left undecoded.
+    struct Received {
+        name: String,
+        record_type: u16,
+        class: u16,
+        ttl: u32,
+        data: Vec<u8>,
+    }
+
+    fn parse_response(message: &[u8]) -> (u16, Vec<Received>) {
+        let mut reader = Reader::new(message);
+        let id = reader.u16().unwrap();
+        assert_eq!(reader.u16().unwrap(), RESPONSE_FLAGS);
+        let question_count = reader.u16().unwrap();
+        let answer_count = reader.u16().unwrap();
+        let authority_count = reader.u16().unwrap();
+        let additional_count = reader.u16().unwrap();
+        for _ in 0..question_count {
+            reader.name().unwrap();
+            reader.skip(4).unwrap();
+        }
+        let mut records = Vec::new();
+        for _ in 0..answer_count + authority_count + additional_count {
+            let name = reader.name().unwrap();
+            let record_type = reader.u16().unwrap();
+            let class = reader.u16().unwrap();
+            let ttl = reader.u32().unwrap();
+            let length = reader.u16().unwrap() as usize;
+            let data = reader.bytes(length).unwrap().to_vec();
+            records.push(Received {
+                name,
+                record_type,
+                class,
+                ttl,
+                data,
+            });
+        }
+        (id, records)
+    }
+
+    /// A UDP port that was free a moment ago, so parallel tests don't share one.
+    fn free_port() -> u16 {
+        std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    fn query(id: u16, name: &str, record_type: u16) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        for field in [id, 0, 1, 0, 0, 0] {
+            buffer.extend_from_slice(&field.to_be_bytes());
+        }
+        encode_name(&mut buffer, name);
+        buffer.extend_from_slice(&record_type.to_be_bytes());
+        buffer.extend_from_slice(&CLASS_IN.to_be_bytes());
+        buffer
+    }
+
+    fn service() -> MdnsService {
+        MdnsService {
+            instance: "test-device".to_string(),
+            port: 6053,
+            txt: vec![
+                ("friendly_name".to_string(), "Test Device".to_string()),
+                ("mac".to_string(), "aabbccddeeff".to_string()),
+            ],
+        }
+    }
+
+    #[test]
+    fn parses_compressed_names() {
+        let mut message = query(7, SERVICE_TYPE, TYPE_PTR);
+        // Second question pointing to the `_tcp.local` suffix of the first one.
+        message[5] = 2;
+        message.extend_from_slice(&[4, b'h', b'o', b's', b't', 0xC0, 24]);
+        message.extend_from_slice(&TYPE_A.to_be_bytes());
+        message.extend_from_slice(&(CLASS_IN | UNICAST_RESPONSE).to_be_bytes());
+
+        let query = Query::parse(&message).unwrap();
+        assert_eq!(query.id, 7);
+        assert_eq!(query.questions[0].name, SERVICE_TYPE);
+        assert_eq!(query.questions[1].name, "host._tcp.local");
+        assert_eq!(query.questions[1].record_type, TYPE_A);
+        assert!(!query.unicast_response());
+
+        // A pointer to itself is rejected.
+        let mut looping = message.clone();
+        let end = looping.len();
+        looping[end - 5] = 40;
+        assert!(Query::parse(&looping).is_none());
+        assert!(Query::parse(&message[..message.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn answers_only_questions_about_the_service() {
+        let records = Records {
+            service: service(),
+            addresses: vec![Ipv4Addr::new(192, 168, 1, 20)],
+        };
+        let other = Query::parse(&query(1, "_http._tcp.local", TYPE_PTR)).unwrap();
+        assert!(records.answer(&other, false).is_none());
+
+        let host = Query::parse(&query(1, "TEST-DEVICE.local", TYPE_ANY)).unwrap();
+        let (_, answered) = parse_response(&records.answer(&host, false).unwrap());
+        assert_eq!(answered.len(), 1);
+        assert_eq!(answered[0].record_type, TYPE_A);
+        assert_eq!(answered[0].class, CLASS_IN | CACHE_FLUSH);
+        assert_eq!(answered[0].data, [192, 168, 1, 20]);
+
+        let types = Query::parse(&query(1, SERVICE_TYPE_ENUMERATION, TYPE_PTR)).unwrap();
+        let (_, answered) = parse_response(&records.answer(&types, false).unwrap());
+        let mut target = Vec::new();
+        encode_name(&mut target, SERVICE_TYPE);
+        assert_eq!(answered[0].data, target);
+    }
+
+    #[tokio::test]
+    async fn answers_queries_over_loopback_multicast() {
+        let port = free_port();
+        let config = MdnsConfig::builder()
+            .port(port)
+            .interface(Ipv4Addr::LOCALHOST)
+            .txt(vec![("board".to_string(), "test-board".to_string())])
+            .build();
+        let responder = MdnsResponder::start(config, service()).await.unwrap();
+
+        // Sent from another port than the mDNS port, the response is a legacy unicast one.
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        socket.set_multicast_if_v4(&Ipv4Addr::LOCALHOST).unwrap();
+        socket.set_multicast_loop_v4(true).unwrap();
+        socket.set_nonblocking(true).unwrap();
+        socket
+            .bind(&SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0).into())
+            .unwrap();
+        let socket = UdpSocket::from_std(socket.into()).unwrap();
+        socket
+            .send_to(&query(42, SERVICE_TYPE, TYPE_PTR), (MDNS_GROUP, port))
+            .await
+            .unwrap();
+
+        let mut buffer = [0; 9000];
+        let (length, _) = timeout(Duration::from_secs(5), socket.recv_from(&mut buffer))
+            .await
+            .expect("no response in time")
+            .unwrap();
+        let (id, records) = parse_response(&buffer[..length]);
+        assert_eq!(id, 42);
+
+        let ptr = &records[0];
+        assert_eq!(ptr.name, SERVICE_TYPE);
+        assert_eq!(ptr.record_type, TYPE_PTR);
+        assert_eq!(ptr.ttl, LEGACY_TTL);
+        let mut instance = Vec::new();
+        encode_name(&mut instance, "test-device._esphomelib._tcp.local");
+        assert_eq!(ptr.data, instance);
+
+        let srv = records
+            .iter()
+            .find(|record| record.record_type == TYPE_SRV)
+            .unwrap();
+        assert_eq!(srv.class, CLASS_IN);
+        assert_eq!(srv.data[4..6], 6053u16.to_be_bytes());
+
+        let txt = records
+            .iter()
+            .find(|record| record.record_type == TYPE_TXT)
+            .unwrap();
+        let mut entries = Vec::new();
+        let mut reader = Reader::new(&txt.data);
+        while let Some(length) = reader.u8() {
+            entries
+                .push(String::from_utf8(reader.bytes(length as usize).unwrap().to_vec()).unwrap());
+        }
+        assert_eq!(
+            entries,
+            [
+                "friendly_name=Test Device",
+                "mac=aabbccddeeff",
+                "board=test-board"
+            ]
+        );
+
+        let a = records
+            .iter()
+            .find(|record| record.record_type == TYPE_A)
+            .unwrap();
+        assert_eq!(a.name, "test-device.local");
+        assert_eq!(a.data, [127, 0, 0, 1]);
+
+        responder.stop().await;
+    }
+}
@@ -22,18 +22,49 @@ pub(crate) fn generate_server_hello_frame(name: String, mac: Option<String>) ->
     message_server_hello
 }
 
+/// Splits a server hello frame into the device name and the optional MAC address.
+pub(crate) fn parse_server_hello_frame(
+    frame: &[u8],
+) -> Result<(String, Option<String>), &'static str> {
+    match frame.first() {
+        Some(1) => {}
+        Some(_) => return Err("Unknown encryption protocol in server hello"),
+        None => return Err("Empty server hello"),
+    }
+
+    let mut parts = frame[1..]
+        .split(|byte| *byte == 0)
+        .map(|part| String::from_utf8_lossy(part).into_owned());
+    let name = parts.next().unwrap_or_default();
+    let mac = parts.next().filter(|mac| !mac.is_empty());
+
+    Ok((name, mac))
+}
+
 pub(crate) fn packet_to_message(
     buffer: &[u8],
     cipher_decrypt: &mut CipherState<ChaCha20Poly1305>,
 ) -> Result<ProtoMessage, Box<dyn std::error::Error>> {
-    let decrypted_message_frame = cipher_decrypt.decrypt_vec(buffer).unwrap(); // "Error during decryption".to_string()
+    let decrypted_message_frame = cipher_decrypt
+        .decrypt_vec(buffer)
+        .map_err(|_| "Error during decryption")?;
+
+    decrypted_packet_to_message(&decrypted_message_frame)
+}
+
+pub(crate) fn decrypted_packet_to_message(
+    decrypted_message_frame: &[u8],
+) -> Result<ProtoMessage, Box<dyn std::error::Error>> {
+    if decrypted_message_frame.len() < 4 {
+        return Err("Decrypted message is too short".into());
+    }
 
     let message_type = BigEndian::read_u16(&decrypted_message_frame[0..2]) as usize;
     let packet_content = &decrypted_message_frame[4..];
     debug!("Message type: {}", message_type);
     debug!("Message: {:?}", packet_content);
 
-    Ok(parser::parse_proto_message(message_type, packet_content).unwrap())
+    Ok(parser::parse_proto_message(message_type, packet_content)?)
 }
 
 pub(crate) fn message_to_packet(
@@ -103,4 +134,30 @@ mod tests {
             _ => panic!("Expected HelloResponse message"),
         }
     }
+
+    #[test]
+    fn test_parse_server_hello_frame() {
+        let frame =
+            generate_server_hello_frame("test_device".to_string(), Some("AA:BB".to_string()));
+
+        let (name, mac) = parse_server_hello_frame(&frame).unwrap();
+
+        assert_eq!(name, "test_device");
+        assert_eq!(mac, Some("AA:BB".to_string()));
+    }
+
+    #[test]
+    fn test_parse_server_hello_frame_without_mac() {
+        let frame = generate_server_hello_frame("test_device".to_string(), None);
+
+        let (name, mac) = parse_server_hello_frame(&frame).unwrap();
+
+        assert_eq!(name, "test_device");
+        assert_eq!(mac, None);
+    }
+
+    #[test]
+    fn test_parse_server_hello_frame_unknown_protocol() {
+        assert!(parse_server_hello_frame(&[2, b'a', 0]).is_err());
+    }
 }
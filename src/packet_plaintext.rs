@@ -8,11 +8,12 @@ pub(crate) fn packet_to_message(buffer: &[u8]) -> Result<ProtoMessage, Box<dyn s
     let packet_content = &buffer[1..];
     debug!("Message type: {}", message_type);
     debug!("Message: {:02X?}", packet_content);
-    Ok(parser::parse_proto_message(message_type, packet_content).unwrap())
+    Ok(parser::parse_proto_message(message_type, packet_content)?)
 }
 
-
-pub(crate) fn message_to_packet(message: &ProtoMessage) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+pub(crate) fn message_to_packet(
+    message: &ProtoMessage,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
     let response_content = parser::proto_to_vec(message)?;
     let message_type = parser::message_to_num(message)?;
     let message_bit: Vec<u8> = vec![message_type];
@@ -44,6 +44,16 @@ use crate::proto::{
     VoiceAssistantEventResponse, VoiceAssistantRequest, VoiceAssistantResponse,
     VoiceAssistantSetConfiguration, VoiceAssistantTimerEventResponse,
 };
+
+// Message 35 is called `HomeassistantServiceResponse` before ESPHome 2025.8.0.
+crate::versions::before_2025_8! {
+    {
+        pub use crate::proto::HomeassistantServiceResponse as HomeassistantActionRequest;
+    } else {
+        pub use crate::proto::HomeassistantActionRequest;
+    }
+}
+
 macro_rules! proto_message_mappings {
     ($($type_id:expr => $struct:ident),* $(,)?) => {
         #[doc(hidden)]
@@ -127,7 +137,7 @@ proto_message_mappings!(
     32 => LightCommandRequest,
     33 => SwitchCommandRequest,
     34 => SubscribeHomeassistantServicesRequest,
-    // 35 => HomeassistantServiceResponse,
+    35 => HomeassistantActionRequest,
     36 => GetTimeRequest,
     37 => GetTimeResponse,
     38 => SubscribeHomeAssistantStatesRequest,
@@ -0,0 +1,233 @@
+//! Live connections of an [`crate::esphomeapi::EspHomeApi`] and their subscriptions.
+//!
+//! Every connection started from an API is registered here until it closes, so
+//! messages the device sends on its own, like Home Assistant actions, entity states
+//! and log lines, reach all connections that subscribed to them.
+//!
+//! Every connection has its own queue in front of the connection's messages. Messages
+//! are put into the queue without waiting, so a client that doesn't read its messages
+//! can't hold up the others. Once its queue is full, further log lines are dropped for
+//! that client only. Any other message closes the connection instead, a client missing
+//! a state would keep showing a stale one, after reconnecting it gets all states again.
+
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use crate::parser::ProtoMessage;
+use crate::proto::{DisconnectRequest, LogLevel};
+
+/// Messages sent to subscribers queued for a connection before it counts as full.
+const QUEUE_SIZE: usize = 64;
+
+/// Messages a connection subscribed to.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Subscriptions {
+    /// Home Assistant actions, after `SubscribeHomeassistantServicesRequest`.
+    pub(crate) homeassistant_actions: bool,
+    /// Imported Home Assistant states, after `SubscribeHomeAssistantStatesRequest`.
+    pub(crate) homeassistant_states: bool,
+    /// Entity states, after `SubscribeStatesRequest`.
+    pub(crate) states: bool,
+    /// Log lines up to this level, after `SubscribeLogsRequest`.
+    pub(crate) log_level: Option<LogLevel>,
+}
+
+/// A queued message, `true` if it counts towards [`QUEUE_SIZE`].
+type Queued = (ProtoMessage, bool);
+
+/// A live connection and its subscriptions.
+struct Connection {
+    queue: mpsc::UnboundedSender<Queued>,
+    /// Queued messages that count towards [`QUEUE_SIZE`].
+    pending: Arc<AtomicUsize>,
+    closing: CancellationToken,
+    subscriptions: Subscriptions,
+}
+
+/// Connections shared by all connections of an API and its clones.
+#[derive(Default)]
+pub(crate) struct Registry {
+    next_connection_id: AtomicU64,
+    connections: Mutex<HashMap<u64, Connection>>,
+}
+
+impl Registry {
+    /// Registers a new connection sending its messages to `answers` and returns its id.
+    ///
+    /// The connection stops reading and writing once `closing` is cancelled.
+    pub(crate) fn connect(
+        &self,
+        answers: mpsc::Sender<ProtoMessage>,
+        closing: CancellationToken,
+    ) -> u64 {
+        let id = self.next_connection_id.fetch_add(1, Ordering::Relaxed);
+        let (queue, mut queued) = mpsc::unbounded_channel::<Queued>();
+        let pending = Arc::new(AtomicUsize::new(0));
+        let forwarded = pending.clone();
+        tokio::spawn(async move {
+            while let Some((message, counted)) = queued.recv().await {
+                if counted {
+                    forwarded.fetch_sub(1, Ordering::Relaxed);
+                }
+                if answers.send(message).await.is_err() {
+                    break;
+                }
+            }
+        });
+        self.connections.lock().unwrap().insert(
+            id,
+            Connection {
+                queue,
+                pending,
+                closing,
+                subscriptions: Subscriptions::default(),
+            },
+        );
+        id
+    }
+
+    /// Forgets a closed connection.
+    pub(crate) fn disconnected(&self, id: u64) {
+        self.connections.lock().unwrap().remove(&id);
+    }
+
+    /// Asks all connections to disconnect, they are forgotten once closed.
+    pub(crate) fn disconnect_all(&self) {
+        self.send_to(
+            |_| true,
+            ProtoMessage::DisconnectRequest(DisconnectRequest {}),
+        );
+    }
+
+    /// Closes all connections right away, without asking them to disconnect.
+    pub(crate) fn close_all(&self) {
+        for connection in self.connections.lock().unwrap().values() {
+            connection.closing.cancel();
+        }
+    }
+
+    /// Number of live connections.
+    pub(crate) fn len(&self) -> usize {
+        self.connections
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|connection| !connection.queue.is_closed())
+            .count()
+    }
+
+    /// Changes the subscriptions of a connection and queues `dump` for it.
+    ///
+    /// The dump is queued before the new subscriptions apply, so every message sent
+    /// to the subscribers afterwards arrives after it. Returns `false` if the
+    /// connection is gone.
+    pub(crate) fn subscribe<F>(&self, id: u64, dump: Vec<ProtoMessage>, subscribe: F) -> bool
+    where
+        F: FnOnce(&mut Subscriptions),
+    {
+        let mut connections = self.connections.lock().unwrap();
+        let Some(connection) = connections.get_mut(&id) else {
+            return false;
+        };
+        for message in dump {
+            // Fails only once the connection is gone.
+            let _ = connection.queue.send((message, false));
+        }
+        subscribe(&mut connection.subscriptions);
+        true
+    }
+
+    /// Queues a message for all connections with matching subscriptions.
+    ///
+    /// Returns the number of connections the message was queued for.
+    pub(crate) fn send_to<F>(&self, subscribed: F, message: ProtoMessage) -> usize
+    where
+        F: Fn(&Subscriptions) -> bool,
+    {
+        let mut connections = self.connections.lock().unwrap();
+        let mut delivered = 0;
+        connections.retain(|id, connection| {
+            if !subscribed(&connection.subscriptions) {
+                return true;
+            }
+            if connection.pending.load(Ordering::Relaxed) >= QUEUE_SIZE {
+                if matches!(message, ProtoMessage::SubscribeLogsResponse(_)) {
+                    warn!("Connection {} is not reading, dropped a log line", id);
+                    return true;
+                }
+                warn!("Connection {} is not reading, closing it", id);
+                connection.closing.cancel();
+                return false;
+            }
+            match connection.queue.send((message.clone(), true)) {
+                Ok(()) => {
+                    connection.pending.fetch_add(1, Ordering::Relaxed);
+                    delivered += 1;
+                    true
+                }
+                Err(_) => {
+                    debug!("Connection {} closed before the message was sent", id);
+                    false
+                }
+            }
+        });
+        delivered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::{LogLevel, SensorStateResponse, SubscribeLogsResponse};
+
+    fn subscribe_all(registry: &Registry, id: u64) -> bool {
+        registry.subscribe(id, Vec::new(), |subscriptions| {
+            subscriptions.states = true;
+            subscriptions.log_level = Some(LogLevel::VeryVerbose)
+        })
+    }
+
+    #[tokio::test]
+    async fn slow_connections_do_not_block_others() {
+        let registry = Registry::default();
+        let (slow_tx, _slow_rx) = mpsc::channel(1);
+        let (fast_tx, mut fast_rx) = mpsc::channel(1);
+        let (closed_tx, closed_rx) = mpsc::channel(1);
+        drop(closed_rx);
+        let slow_closing = CancellationToken::new();
+        let slow = registry.connect(slow_tx, slow_closing.clone());
+        let fast = registry.connect(fast_tx, CancellationToken::new());
+        let closed = registry.connect(closed_tx, CancellationToken::new());
+        assert_ne!(slow, fast);
+        for id in [slow, fast, closed] {
+            subscribe_all(&registry, id);
+        }
+        let unsubscribed = registry.connect(mpsc::channel(1).0, CancellationToken::new());
+
+        let line = ProtoMessage::SubscribeLogsResponse(SubscribeLogsResponse::default());
+        let all = |subscriptions: &Subscriptions| subscriptions.states;
+        for _ in 0..QUEUE_SIZE * 2 {
+            registry.send_to(all, line.clone());
+            assert!(matches!(
+                fast_rx.recv().await,
+                Some(ProtoMessage::SubscribeLogsResponse(_))
+            ));
+        }
+        // Log lines are dropped for the slow connection, its queue is full.
+        assert_eq!(registry.send_to(all, line), 1);
+        assert!(!slow_closing.is_cancelled());
+        assert!(!subscribe_all(&registry, closed));
+        assert!(registry.subscribe(unsubscribed, Vec::new(), |_| {}));
+
+        // A state isn't dropped, the slow connection is closed instead.
+        let state = ProtoMessage::SensorStateResponse(SensorStateResponse::default());
+        assert_eq!(registry.send_to(all, state), 1);
+        assert!(slow_closing.is_cancelled());
+        assert!(!subscribe_all(&registry, slow));
+        assert!(subscribe_all(&registry, fast));
+    }
+}
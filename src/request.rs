@@ -0,0 +1,208 @@
+//! Request/response correlation on top of the message channels.
+//!
+//! Both [`crate::esphomeapi::EspHomeApi::start`] and
+//! [`crate::esphomeclient::ClientConnection`] expose a `mpsc::Sender` for outgoing
+//! messages and a `broadcast::Receiver` for incoming ones. The helpers in this module
+//! send a request and resolve with the first matching response, or fail after a
+//! timeout.
+//!
+//! # Examples
+//!
+//! Asking Home Assistant for the current time from the device side:
+//!
+//! ```rust,no_run
+//! use esphome_native_api::esphomeapi::EspHomeApi;
+//! use esphome_native_api::request;
+//! use std::time::Duration;
+//! use tokio::net::TcpStream;
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     let stream = TcpStream::connect("192.168.1.100:6053").await?;
+//!     let api = EspHomeApi::builder().name("my-device".to_string()).build();
+//!     let (tx, rx) = api.start(stream).await?;
+//!
+//!     let time = request::get_time(&tx, rx.resubscribe(), Duration::from_secs(5)).await?;
+//!     println!("Home Assistant time: {}", time.epoch_seconds);
+//!     Ok(())
+//! }
+//! ```
+
+use std::fmt;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc;
+
+use crate::parser::ProtoMessage;
+use crate::proto::{
+    DeviceInfoRequest, DeviceInfoResponse, GetTimeRequest, GetTimeResponse, PingRequest,
+    PingResponse,
+};
+
+/// Errors of a request that did not get a response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestError {
+    /// No matching response arrived in time.
+    Timeout,
+    /// The connection was closed before a response arrived.
+    ConnectionClosed,
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::Timeout => write!(f, "Request timed out"),
+            RequestError::ConnectionClosed => write!(f, "Connection closed"),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+/// Sends `message` and waits for the first incoming message `extract` accepts.
+///
+/// `incoming` has to be subscribed before calling this function, otherwise a fast
+/// response may be missed. Messages `extract` rejects are skipped.
+pub async fn request<T, F>(
+    tx: &mpsc::Sender<ProtoMessage>,
+    mut incoming: broadcast::Receiver<ProtoMessage>,
+    message: ProtoMessage,
+    timeout: Duration,
+    mut extract: F,
+) -> Result<T, RequestError>
+where
+    F: FnMut(ProtoMessage) -> Option<T>,
+{
+    tx.send(message)
+        .await
+        .map_err(|_| RequestError::ConnectionClosed)?;
+
+    let response = async {
+        loop {
+            match incoming.recv().await {
+                Ok(message) => {
+                    if let Some(response) = extract(message) {
+                        return Ok(response);
+                    }
+                }
+                // The response may still come; otherwise the timeout fires.
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => {
+                    return Err(RequestError::ConnectionClosed);
+                }
+            }
+        }
+    };
+    tokio::time::timeout(timeout, response)
+        .await
+        .map_err(|_| RequestError::Timeout)?
+}
+
+/// Sends a `PingRequest` and resolves with the round trip time.
+pub async fn ping(
+    tx: &mpsc::Sender<ProtoMessage>,
+    incoming: broadcast::Receiver<ProtoMessage>,
+    timeout: Duration,
+) -> Result<Duration, RequestError> {
+    let start = tokio::time::Instant::now();
+    request(
+        tx,
+        incoming,
+        ProtoMessage::PingRequest(PingRequest {}),
+        timeout,
+        |message| match message {
+            ProtoMessage::PingResponse(PingResponse {}) => Some(()),
+            _ => None,
+        },
+    )
+    .await?;
+    Ok(start.elapsed())
+}
+
+/// Sends a `DeviceInfoRequest` and resolves with the `DeviceInfoResponse`.
+pub async fn device_info(
+    tx: &mpsc::Sender<ProtoMessage>,
+    incoming: broadcast::Receiver<ProtoMessage>,
+    timeout: Duration,
+) -> Result<DeviceInfoResponse, RequestError> {
+    request(
+        tx,
+        incoming,
+        ProtoMessage::DeviceInfoRequest(DeviceInfoRequest {}),
+        timeout,
+        |message| match message {
+            ProtoMessage::DeviceInfoResponse(response) => Some(response),
+            _ => None,
+        },
+    )
+    .await
+}
+
+/// Sends a `GetTimeRequest` and resolves with the `GetTimeResponse`.
+pub async fn get_time(
+    tx: &mpsc::Sender<ProtoMessage>,
+    incoming: broadcast::Receiver<ProtoMessage>,
+    timeout: Duration,
+) -> Result<GetTimeResponse, RequestError> {
+    request(
+        tx,
+        incoming,
+        ProtoMessage::GetTimeRequest(GetTimeRequest {}),
+        timeout,
+        |message| match message {
+            ProtoMessage::GetTimeResponse(response) => Some(response),
+            _ => None,
+        },
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn request_skips_unrelated_messages() {
+        let (tx, mut rx) = mpsc::channel(4);
+        let (incoming_tx, incoming) = broadcast::channel(4);
+
+        tokio::spawn(async move {
+            while let Some(message) = rx.recv().await {
+                assert!(matches!(message, ProtoMessage::GetTimeRequest(_)));
+                incoming_tx
+                    .send(ProtoMessage::PingResponse(PingResponse {}))
+                    .unwrap();
+                incoming_tx
+                    .send(ProtoMessage::GetTimeResponse(GetTimeResponse {
+                        epoch_seconds: 42,
+                        ..Default::default()
+                    }))
+                    .unwrap();
+            }
+        });
+
+        let time = get_time(&tx, incoming, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(time.epoch_seconds, 42);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn request_times_out() {
+        let (tx, _rx) = mpsc::channel(4);
+        let (_incoming_tx, incoming) = broadcast::channel(4);
+
+        let result = ping(&tx, incoming, Duration::from_secs(5)).await;
+        assert_eq!(result, Err(RequestError::Timeout));
+    }
+
+    #[tokio::test]
+    async fn request_fails_on_closed_connection() {
+        let (tx, _rx) = mpsc::channel(4);
+        let (incoming_tx, incoming) = broadcast::channel::<ProtoMessage>(4);
+        drop(incoming_tx);
+
+        let result = device_info(&tx, incoming, Duration::from_secs(5)).await;
+        assert_eq!(result.err(), Some(RequestError::ConnectionClosed));
+    }
+}
@@ -0,0 +1,237 @@
+//! Time synchronisation with Home Assistant on the device side.
+//!
+//! Devices without a real time clock ask Home Assistant for the time with a
+//! `GetTimeRequest`. A [`TimeSync`] passed to [`crate::esphomeapi::EspHomeApi`]
+//! requests the time once a client said hello and again every `resync_interval`,
+//! and exposes the last synced time. Newer protocol versions also carry the
+//! timezone of Home Assistant as POSIX TZ string.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! use esphome_native_api::esphomeapi::EspHomeApi;
+//! use esphome_native_api::time_sync::TimeSync;
+//! use tokio::net::TcpListener;
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     let time_sync = TimeSync::builder().build();
+//!     let api = EspHomeApi::builder()
+//!         .name("clock".to_string())
+//!         .time_sync(time_sync.clone())
+//!         .build();
+//!     let listener = TcpListener::bind("0.0.0.0:6053").await?;
+//!     let (stream, _) = listener.accept().await?;
+//!     let (_tx, _rx) = api.start(stream).await?;
+//!
+//!     let time = time_sync.synced().await;
+//!     println!("It is {:?} in {:?}", time.now(), time.timezone);
+//!     Ok(())
+//! }
+//! ```
+
+use log::debug;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+use typed_builder::TypedBuilder;
+
+use crate::parser::ProtoMessage;
+use crate::proto::{GetTimeRequest, GetTimeResponse};
+
+/// Default interval the time is requested again in, the same as ESPHome uses.
+pub const DEFAULT_RESYNC_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Time received from Home Assistant.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyncedTime {
+    /// Seconds since the Unix epoch when the time was received.
+    pub epoch_seconds: u32,
+    /// POSIX TZ string of Home Assistant, if the protocol version carries it.
+    pub timezone: Option<String>,
+    /// When the time was received.
+    pub received: Instant,
+}
+
+impl SyncedTime {
+    /// The current time, advanced by the monotonic clock since the sync.
+    pub fn now(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.epoch_seconds as u64) + self.received.elapsed()
+    }
+}
+
+/// Time source synced with Home Assistant.
+///
+/// Clones share the synced time, so one clone can be passed to
+/// [`crate::esphomeapi::EspHomeApi`] while another one is read.
+#[derive(TypedBuilder, Clone)]
+pub struct TimeSync {
+    /// Interval the time is requested again in.
+    #[builder(default = DEFAULT_RESYNC_INTERVAL)]
+    resync_interval: Duration,
+    #[builder(default = Arc::new(watch::channel(None).0), setter(skip))]
+    time_tx: Arc<watch::Sender<Option<SyncedTime>>>,
+}
+
+impl TimeSync {
+    /// The last synced time, `None` before the first sync.
+    pub fn time(&self) -> Option<SyncedTime> {
+        self.time_tx.borrow().clone()
+    }
+
+    /// The current time, `None` before the first sync.
+    pub fn now(&self) -> Option<SystemTime> {
+        self.time_tx.borrow().as_ref().map(SyncedTime::now)
+    }
+
+    /// Returns a receiver that is updated on every sync.
+    pub fn subscribe(&self) -> watch::Receiver<Option<SyncedTime>> {
+        self.time_tx.subscribe()
+    }
+
+    /// Waits for the first sync and returns the synced time.
+    pub async fn synced(&self) -> SyncedTime {
+        let mut time_rx = self.time_tx.subscribe();
+        // The sender lives in `self`, so waiting can't fail.
+        let time = time_rx.wait_for(Option::is_some).await.unwrap();
+        time.clone().unwrap()
+    }
+
+    fn update(&self, response: GetTimeResponse) {
+        let time = SyncedTime {
+            epoch_seconds: response.epoch_seconds,
+            timezone: timezone(&response),
+            received: Instant::now(),
+        };
+        debug!("Time synced: {:?}", time);
+        self.time_tx.send_replace(Some(time));
+    }
+}
+
+/// Returns whether the time sync of a connection handles `message`.
+pub(crate) fn is_request(message: &ProtoMessage) -> bool {
+    matches!(message, ProtoMessage::GetTimeResponse(_))
+}
+
+/// Starts syncing the time over one API connection.
+///
+/// Responses are passed through the returned sender; the `HelloRequest` of the
+/// client starts the syncing.
+pub(crate) fn spawn(
+    time_sync: TimeSync,
+    answers: mpsc::Sender<ProtoMessage>,
+) -> mpsc::Sender<ProtoMessage> {
+    let (requests_tx, mut requests_rx) = mpsc::channel::<ProtoMessage>(4);
+
+    tokio::spawn(async move {
+        let mut next_sync: Option<Instant> = None;
+        loop {
+            tokio::select! {
+                request = requests_rx.recv() => match request {
+                    Some(ProtoMessage::HelloRequest(_)) if next_sync.is_none() => {
+                        next_sync = Some(Instant::now());
+                    }
+                    Some(ProtoMessage::GetTimeResponse(response)) => time_sync.update(response),
+                    Some(_) => {}
+                    None => break,
+                },
+                _ = sleep_until(next_sync) => {
+                    let request = ProtoMessage::GetTimeRequest(GetTimeRequest {});
+                    if answers.send(request).await.is_err() {
+                        break;
+                    }
+                    next_sync = Some(Instant::now() + time_sync.resync_interval);
+                }
+            }
+        }
+        debug!("Time sync stopped");
+    });
+
+    requests_tx
+}
+
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Builds the answer to a `GetTimeRequest`.
+pub(crate) fn time_response(time: SystemTime, timezone: Option<String>) -> GetTimeResponse {
+    let epoch_seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs() as u32)
+        .unwrap_or_default();
+    let mut response = GetTimeResponse {
+        epoch_seconds,
+        ..Default::default()
+    };
+    set_timezone(&mut response, timezone);
+    response
+}
+
+// `GetTimeResponse` carries the timezone since ESPHome 2025.11.0.
+crate::versions::before_2025_11! {
+    {
+        fn timezone(_response: &GetTimeResponse) -> Option<String> {
+            None
+        }
+
+        fn set_timezone(_response: &mut GetTimeResponse, _timezone: Option<String>) {}
+    } else {
+        fn timezone(response: &GetTimeResponse) -> Option<String> {
+            Some(response.timezone.clone()).filter(|timezone| !timezone.is_empty())
+        }
+
+        fn set_timezone(response: &mut GetTimeResponse, timezone: Option<String>) {
+            response.timezone = timezone.unwrap_or_default();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_time_after_hello_and_resyncs() {
+        let time_sync = TimeSync::builder()
+            .resync_interval(Duration::from_secs(60))
+            .build();
+        let (answers_tx, mut answers_rx) = mpsc::channel(4);
+        let requests_tx = spawn(time_sync.clone(), answers_tx);
+
+        tokio::time::sleep(Duration::from_secs(120)).await;
+        assert!(answers_rx.try_recv().is_err());
+
+        requests_tx
+            .send(ProtoMessage::HelloRequest(Default::default()))
+            .await
+            .unwrap();
+        assert!(matches!(
+            answers_rx.recv().await,
+            Some(ProtoMessage::GetTimeRequest(_))
+        ));
+        requests_tx
+            .send(ProtoMessage::GetTimeResponse(time_response(
+                UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+                None,
+            )))
+            .await
+            .unwrap();
+        let time = time_sync.synced().await;
+        assert_eq!(time.epoch_seconds, 1_700_000_000);
+
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        assert_eq!(
+            time_sync.now(),
+            Some(UNIX_EPOCH + Duration::from_secs(1_700_000_030))
+        );
+        assert!(matches!(
+            answers_rx.recv().await,
+            Some(ProtoMessage::GetTimeRequest(_))
+        ));
+    }
+}
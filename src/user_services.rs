@@ -0,0 +1,457 @@
+//! User-defined services of the device side.
+//!
+//! A device can offer services with typed arguments that Home Assistant shows as
+//! actions. Services are passed to [`crate::esphomeapi::EspHomeApi`], which lists
+//! them during the entity enumeration and calls the handler of a service with the
+//! decoded arguments on every `ExecuteServiceRequest`.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use esphome_native_api::esphomeapi::EspHomeApi;
+//! use esphome_native_api::proto::ServiceArgType;
+//! use esphome_native_api::user_services::UserService;
+//!
+//! let play_tone = UserService::new(
+//!     "play_tone",
+//!     &[("frequency", ServiceArgType::Int), ("duration", ServiceArgType::Float)],
+//!     |args| async move {
+//!         println!("Playing {:?} Hz", args["frequency"].as_int());
+//!     },
+//! );
+//! let api = EspHomeApi::builder()
+//!     .name("buzzer".to_string())
+//!     .services(vec![play_tone])
+//!     .build();
+//! ```
+
+use futures::FutureExt;
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+
+use crate::hash::hash_fnv1;
+use crate::proto::{
+    ExecuteServiceArgument, ExecuteServiceRequest, ListEntitiesServicesArgument,
+    ListEntitiesServicesResponse, ServiceArgType,
+};
+
+/// Value of a service argument.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ServiceValue {
+    /// A boolean.
+    Bool(bool),
+    /// An integer.
+    Int(i32),
+    /// A float.
+    Float(f32),
+    /// A string.
+    String(String),
+    /// A list of booleans.
+    BoolArray(Vec<bool>),
+    /// A list of integers.
+    IntArray(Vec<i32>),
+    /// A list of floats.
+    FloatArray(Vec<f32>),
+    /// A list of strings.
+    StringArray(Vec<String>),
+}
+
+impl ServiceValue {
+    /// The argument type of the value.
+    pub fn arg_type(&self) -> ServiceArgType {
+        match self {
+            ServiceValue::Bool(_) => ServiceArgType::Bool,
+            ServiceValue::Int(_) => ServiceArgType::Int,
+            ServiceValue::Float(_) => ServiceArgType::Float,
+            ServiceValue::String(_) => ServiceArgType::String,
+            ServiceValue::BoolArray(_) => ServiceArgType::BoolArray,
+            ServiceValue::IntArray(_) => ServiceArgType::IntArray,
+            ServiceValue::FloatArray(_) => ServiceArgType::FloatArray,
+            ServiceValue::StringArray(_) => ServiceArgType::StringArray,
+        }
+    }
+
+    /// The value if it is a boolean.
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            ServiceValue::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// The value if it is an integer.
+    pub fn as_int(&self) -> Option<i32> {
+        match self {
+            ServiceValue::Int(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// The value if it is a float.
+    pub fn as_float(&self) -> Option<f32> {
+        match self {
+            ServiceValue::Float(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// The value if it is a string.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            ServiceValue::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Decodes an argument sent for an argument of type `arg_type`.
+    pub fn from_argument(argument: &ExecuteServiceArgument, arg_type: ServiceArgType) -> Self {
+        match arg_type {
+            ServiceArgType::Bool => ServiceValue::Bool(argument.bool_),
+            // Like ESPHome, the legacy field wins if a client sets it.
+            ServiceArgType::Int => match argument.legacy_int {
+                0 => ServiceValue::Int(argument.int_),
+                legacy_int => ServiceValue::Int(legacy_int),
+            },
+            ServiceArgType::Float => ServiceValue::Float(argument.float_),
+            ServiceArgType::String => ServiceValue::String(argument.string_.clone()),
+            ServiceArgType::BoolArray => ServiceValue::BoolArray(argument.bool_array.clone()),
+            ServiceArgType::IntArray => ServiceValue::IntArray(argument.int_array.clone()),
+            ServiceArgType::FloatArray => ServiceValue::FloatArray(argument.float_array.clone()),
+            ServiceArgType::StringArray => ServiceValue::StringArray(argument.string_array.clone()),
+        }
+    }
+
+    /// Encodes the value as argument of an `ExecuteServiceRequest`.
+    pub fn to_argument(&self) -> ExecuteServiceArgument {
+        match self.clone() {
+            ServiceValue::Bool(bool_) => ExecuteServiceArgument {
+                bool_,
+                ..Default::default()
+            },
+            // Devices before API 1.3 only read the legacy field.
+            ServiceValue::Int(int_) => ExecuteServiceArgument {
+                int_,
+                legacy_int: int_,
+                ..Default::default()
+            },
+            ServiceValue::Float(float_) => ExecuteServiceArgument {
+                float_,
+                ..Default::default()
+            },
+            ServiceValue::String(string_) => ExecuteServiceArgument {
+                string_,
+                ..Default::default()
+            },
+            ServiceValue::BoolArray(bool_array) => ExecuteServiceArgument {
+                bool_array,
+                ..Default::default()
+            },
+            ServiceValue::IntArray(int_array) => ExecuteServiceArgument {
+                int_array,
+                ..Default::default()
+            },
+            ServiceValue::FloatArray(float_array) => ExecuteServiceArgument {
+                float_array,
+                ..Default::default()
+            },
+            ServiceValue::StringArray(string_array) => ExecuteServiceArgument {
+                string_array,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+macro_rules! service_value_from {
+    ($($type:ty => $variant:ident),* $(,)?) => {
+        $(
+            impl From<$type> for ServiceValue {
+                fn from(value: $type) -> Self {
+                    ServiceValue::$variant(value.into())
+                }
+            }
+        )*
+    };
+}
+
+service_value_from!(
+    bool => Bool,
+    i32 => Int,
+    f32 => Float,
+    String => String,
+    &str => String,
+    Vec<bool> => BoolArray,
+    Vec<i32> => IntArray,
+    Vec<f32> => FloatArray,
+    Vec<String> => StringArray,
+);
+
+/// Errors of decoding the arguments of a service call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServiceError {
+    /// The number of arguments differs from the schema.
+    ArgumentCount {
+        /// Number of arguments of the schema
+        expected: usize,
+        /// Number of arguments sent
+        received: usize,
+    },
+    /// An argument of the schema is missing.
+    MissingArgument(String),
+    /// An argument is not part of the schema.
+    UnknownArgument(String),
+    /// An argument has a different type than in the schema.
+    ArgumentType {
+        /// Name of the argument
+        name: String,
+        /// Type of the argument in the schema
+        expected: ServiceArgType,
+    },
+    /// An argument of the schema has a type this crate does not know.
+    UnknownArgumentType {
+        /// Name of the argument
+        name: String,
+        /// Type of the argument in the schema
+        arg_type: i32,
+    },
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServiceError::ArgumentCount { expected, received } => {
+                write!(f, "Expected {} arguments, received {}", expected, received)
+            }
+            ServiceError::MissingArgument(name) => write!(f, "Missing argument {}", name),
+            ServiceError::UnknownArgument(name) => write!(f, "Unknown argument {}", name),
+            ServiceError::ArgumentType { name, expected } => {
+                write!(f, "Argument {} must be {:?}", name, expected)
+            }
+            ServiceError::UnknownArgumentType { name, arg_type } => {
+                write!(f, "Argument {} has unknown type {}", name, arg_type)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ServiceError {}
+
+/// Handler of a service, called with the arguments by name.
+pub type ServiceHandler =
+    Arc<dyn Fn(HashMap<String, ServiceValue>) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// A user-defined service of a device.
+#[derive(Clone)]
+pub struct UserService {
+    name: String,
+    key: u32,
+    args: Vec<(String, ServiceArgType)>,
+    handler: ServiceHandler,
+}
+
+impl fmt::Debug for UserService {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UserService")
+            .field("name", &self.name)
+            .field("key", &self.key)
+            .field("args", &self.args)
+            .finish_non_exhaustive()
+    }
+}
+
+impl UserService {
+    /// Creates a service with the arguments `args` in order.
+    ///
+    /// The key of the service is derived from its name like ESPHome does.
+    pub fn new<F, Fut>(name: &str, args: &[(&str, ServiceArgType)], handler: F) -> Self
+    where
+        F: Fn(HashMap<String, ServiceValue>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        UserService {
+            name: name.to_string(),
+            key: hash_fnv1(&name.to_string()),
+            args: args
+                .iter()
+                .map(|(name, arg_type)| (name.to_string(), *arg_type))
+                .collect(),
+            handler: Arc::new(move |args| handler(args).boxed()),
+        }
+    }
+
+    /// Name of the service.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Key of the service.
+    pub fn key(&self) -> u32 {
+        self.key
+    }
+
+    pub(crate) fn list_response(&self) -> ListEntitiesServicesResponse {
+        ListEntitiesServicesResponse {
+            name: self.name.clone(),
+            key: self.key,
+            args: self
+                .args
+                .iter()
+                .map(|(name, arg_type)| ListEntitiesServicesArgument {
+                    name: name.clone(),
+                    r#type: *arg_type as i32,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Decodes the arguments of a call, which are sent in the order of the schema.
+    pub(crate) fn decode(
+        &self,
+        request: &ExecuteServiceRequest,
+    ) -> Result<HashMap<String, ServiceValue>, ServiceError> {
+        if request.args.len() != self.args.len() {
+            return Err(ServiceError::ArgumentCount {
+                expected: self.args.len(),
+                received: request.args.len(),
+            });
+        }
+        Ok(self
+            .args
+            .iter()
+            .zip(&request.args)
+            .map(|((name, arg_type), argument)| {
+                (
+                    name.clone(),
+                    ServiceValue::from_argument(argument, *arg_type),
+                )
+            })
+            .collect())
+    }
+
+    pub(crate) fn call(&self, args: HashMap<String, ServiceValue>) -> BoxFuture<'static, ()> {
+        (self.handler)(args)
+    }
+}
+
+/// Encodes the arguments of a call in the order of the listed schema.
+pub fn encode_arguments(
+    service: &ListEntitiesServicesResponse,
+    args: &[(&str, ServiceValue)],
+) -> Result<Vec<ExecuteServiceArgument>, ServiceError> {
+    if let Some((name, _)) = args
+        .iter()
+        .find(|(name, _)| !service.args.iter().any(|arg| arg.name == *name))
+    {
+        return Err(ServiceError::UnknownArgument(name.to_string()));
+    }
+    service
+        .args
+        .iter()
+        .map(|arg| {
+            let (_, value) = args
+                .iter()
+                .find(|(name, _)| *name == arg.name)
+                .ok_or_else(|| ServiceError::MissingArgument(arg.name.clone()))?;
+            let expected = ServiceArgType::try_from(arg.r#type).map_err(|_| {
+                ServiceError::UnknownArgumentType {
+                    name: arg.name.clone(),
+                    arg_type: arg.r#type,
+                }
+            })?;
+            match value.arg_type() == expected {
+                true => Ok(value.to_argument()),
+                false => Err(ServiceError::ArgumentType {
+                    name: arg.name.clone(),
+                    expected,
+                }),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_and_decodes_arguments() {
+        let service = UserService::new(
+            "set_schedule",
+            &[
+                ("enabled", ServiceArgType::Bool),
+                ("hours", ServiceArgType::IntArray),
+                ("label", ServiceArgType::String),
+            ],
+            |_| async {},
+        );
+        let listed = service.list_response();
+        assert_eq!(listed.key, hash_fnv1(&"set_schedule".to_string()));
+
+        let args = encode_arguments(
+            &listed,
+            &[
+                ("label", "Weekdays".into()),
+                ("enabled", true.into()),
+                ("hours", vec![7, 18].into()),
+            ],
+        )
+        .unwrap();
+        let request = ExecuteServiceRequest {
+            key: listed.key,
+            args,
+            ..Default::default()
+        };
+        let decoded = service.decode(&request).unwrap();
+        assert_eq!(decoded["enabled"], ServiceValue::Bool(true));
+        assert_eq!(decoded["hours"], ServiceValue::IntArray(vec![7, 18]));
+        assert_eq!(decoded["label"].as_str(), Some("Weekdays"));
+
+        assert_eq!(
+            encode_arguments(&listed, &[("enabled", true.into())]),
+            Err(ServiceError::MissingArgument("hours".to_string()))
+        );
+        assert_eq!(
+            encode_arguments(
+                &listed,
+                &[
+                    ("label", 1.into()),
+                    ("enabled", true.into()),
+                    ("hours", vec![7].into()),
+                ],
+            ),
+            Err(ServiceError::ArgumentType {
+                name: "label".to_string(),
+                expected: ServiceArgType::String,
+            })
+        );
+        assert!(matches!(
+            service.decode(&ExecuteServiceRequest {
+                key: listed.key,
+                args: vec![],
+                ..Default::default()
+            }),
+            Err(ServiceError::ArgumentCount { .. })
+        ));
+
+        let mut unknown = listed.clone();
+        unknown.args[0].r#type = 42;
+        assert_eq!(
+            encode_arguments(
+                &unknown,
+                &[
+                    ("label", "Weekdays".into()),
+                    ("enabled", true.into()),
+                    ("hours", vec![7, 18].into()),
+                ],
+            ),
+            Err(ServiceError::UnknownArgumentType {
+                name: "enabled".to_string(),
+                arg_type: 42,
+            })
+        );
+    }
+}
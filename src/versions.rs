@@ -0,0 +1,98 @@
+//! Items that differ between the ESPHome versions selected by the `version_*` features.
+
+/// Expands the first items if any of the `features` is enabled, the others otherwise.
+macro_rules! cfg_any {
+    ($features:tt { $($matching:item)* } else { $($other:item)* }) => {
+        $( #[cfg(any $features)] $matching )*
+        $( #[cfg(not(any $features))] $other )*
+    };
+}
+
+/// Selects items for ESPHome versions before 2025.8.0 or since then.
+///
+/// ```ignore
+/// before_2025_8! {
+///     { /* before 2025.8.0 */ } else { /* since 2025.8.0 */ }
+/// }
+/// ```
+macro_rules! before_2025_8 {
+    ($($items:tt)*) => {
+        $crate::versions::cfg_any! {
+            (
+                feature = "version_2025_2_1",
+                feature = "version_2025_2_2",
+                feature = "version_2025_3_0",
+                feature = "version_2025_3_1",
+                feature = "version_2025_3_2",
+                feature = "version_2025_3_3",
+                feature = "version_2025_4_0",
+                feature = "version_2025_4_1",
+                feature = "version_2025_4_2",
+                feature = "version_2025_5_0",
+                feature = "version_2025_5_1",
+                feature = "version_2025_5_2",
+                feature = "version_2025_6_0",
+                feature = "version_2025_6_1",
+                feature = "version_2025_6_2",
+                feature = "version_2025_6_3",
+                feature = "version_2025_7_0",
+                feature = "version_2025_7_1",
+                feature = "version_2025_7_2",
+                feature = "version_2025_7_3",
+                feature = "version_2025_7_4",
+                feature = "version_2025_7_5",
+            )
+            $($items)*
+        }
+    };
+}
+
+/// Selects items for ESPHome versions before 2025.11.0 or since then.
+macro_rules! before_2025_11 {
+    ($($items:tt)*) => {
+        $crate::versions::cfg_any! {
+            (
+                feature = "version_2025_2_1",
+                feature = "version_2025_2_2",
+                feature = "version_2025_3_0",
+                feature = "version_2025_3_1",
+                feature = "version_2025_3_2",
+                feature = "version_2025_3_3",
+                feature = "version_2025_4_0",
+                feature = "version_2025_4_1",
+                feature = "version_2025_4_2",
+                feature = "version_2025_5_0",
+                feature = "version_2025_5_1",
+                feature = "version_2025_5_2",
+                feature = "version_2025_6_0",
+                feature = "version_2025_6_1",
+                feature = "version_2025_6_2",
+                feature = "version_2025_6_3",
+                feature = "version_2025_7_0",
+                feature = "version_2025_7_1",
+                feature = "version_2025_7_2",
+                feature = "version_2025_7_3",
+                feature = "version_2025_7_4",
+                feature = "version_2025_7_5",
+                feature = "version_2025_8_0",
+                feature = "version_2025_8_1",
+                feature = "version_2025_8_2",
+                feature = "version_2025_8_3",
+                feature = "version_2025_8_4",
+                feature = "version_2025_9_0",
+                feature = "version_2025_9_1",
+                feature = "version_2025_9_2",
+                feature = "version_2025_9_3",
+                feature = "version_2025_10_0",
+                feature = "version_2025_10_1",
+                feature = "version_2025_10_2",
+                feature = "version_2025_10_3",
+                feature = "version_2025_10_4",
+                feature = "version_2025_10_5",
+            )
+            $($items)*
+        }
+    };
+}
+
+pub(crate) use {before_2025_8, before_2025_11, cfg_any};
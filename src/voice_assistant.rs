@@ -0,0 +1,962 @@
+//! Voice assistant satellite on the device side.
+//!
+//! A voice satellite records a voice command, streams it to the Home Assistant
+//! assist pipeline and plays the spoken answer. [`VoiceAssistant`] implements the
+//! protocol on top of an [`AudioDevice`] and is passed to
+//! [`crate::esphomeapi::EspHomeApi`]: it answers the subscription, configuration and
+//! announcement messages of Home Assistant, streams the microphone while a pipeline
+//! listens and plays the text-to-speech audio.
+//!
+//! Audio is either streamed in `VoiceAssistantAudio` messages or, if Home Assistant
+//! answers the pipeline start with a port, over UDP. The device offers the API
+//! transport with the [`FEATURE_API_AUDIO`] flag and UDP if a `udp_host` is
+//! configured; Home Assistant picks one of them when it subscribes and accepts a
+//! pipeline.
+//!
+//! [`file::FileAudio`] is an audio device reading and writing raw PCM files, e.g.
+//! for tests.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! use esphome_native_api::esphomeapi::EspHomeApi;
+//! use esphome_native_api::voice_assistant::file::FileAudio;
+//! use esphome_native_api::voice_assistant::{VoiceAssistant, VoiceEvent};
+//! use std::sync::Arc;
+//! use tokio::net::TcpListener;
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     let audio = FileAudio::builder()
+//!         .input("command.raw".into())
+//!         .output("answer.raw".into())
+//!         .build();
+//!     let voice_assistant = VoiceAssistant::builder().audio(Arc::new(audio)).build();
+//!
+//!     let api = EspHomeApi::builder()
+//!         .name("voice-satellite".to_string())
+//!         .voice_assistant(voice_assistant.clone())
+//!         .build();
+//!     let listener = TcpListener::bind("0.0.0.0:6053").await?;
+//!     let (stream, _) = listener.accept().await?;
+//!     let (_tx, _rx) = api.start(stream).await?;
+//!
+//!     let mut events = voice_assistant.events();
+//!     voice_assistant.subscribed().await;
+//!     voice_assistant.start_pipeline(None).await?;
+//!     while let Ok(event) = events.recv().await {
+//!         println!("{:?}", event);
+//!     }
+//!     Ok(())
+//! }
+//! ```
+
+pub mod file;
+
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tokio::time::Instant;
+use tokio_stream::StreamExt;
+use typed_builder::TypedBuilder;
+
+use crate::parser::ProtoMessage;
+use crate::proto::{
+    VoiceAssistantAnnounceFinished, VoiceAssistantAnnounceRequest, VoiceAssistantAudio,
+    VoiceAssistantConfigurationResponse, VoiceAssistantEvent, VoiceAssistantEventResponse,
+    VoiceAssistantRequest, VoiceAssistantTimerEvent, VoiceAssistantTimerEventResponse,
+    VoiceAssistantWakeWord,
+};
+
+/// The device is a voice assistant.
+pub const FEATURE_VOICE_ASSISTANT: u32 = 1 << 0;
+/// The device plays the answers itself.
+pub const FEATURE_SPEAKER: u32 = 1 << 1;
+/// The device streams audio in `VoiceAssistantAudio` messages.
+pub const FEATURE_API_AUDIO: u32 = 1 << 2;
+/// The device handles timers.
+pub const FEATURE_TIMERS: u32 = 1 << 3;
+/// The device plays announcements.
+pub const FEATURE_ANNOUNCE: u32 = 1 << 4;
+/// The device starts a conversation after an announcement.
+pub const FEATURE_START_CONVERSATION: u32 = 1 << 5;
+
+/// Subscription flag of clients accepting `VoiceAssistantAudio` messages.
+pub const SUBSCRIBE_API_AUDIO: u32 = 1;
+
+/// Home Assistant detects the end of the voice command.
+pub const REQUEST_USE_VAD: u32 = 1 << 0;
+/// Home Assistant detects the wake word.
+pub const REQUEST_USE_WAKE_WORD: u32 = 1 << 1;
+
+/// Time Home Assistant has to accept a pipeline.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest audio datagram received over UDP.
+const MAX_DATAGRAM_SIZE: usize = 4096;
+
+/// Errors of starting a pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VoiceAssistantError {
+    /// No client subscribed to the voice assistant.
+    NotSubscribed,
+    /// A pipeline is already running.
+    Busy,
+    /// The client could not start the pipeline.
+    Rejected,
+    /// The client did not answer in time.
+    Timeout,
+    /// The client asked for an audio transport the device does not offer.
+    UnsupportedTransport,
+}
+
+impl fmt::Display for VoiceAssistantError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VoiceAssistantError::NotSubscribed => write!(f, "No client subscribed"),
+            VoiceAssistantError::Busy => write!(f, "Pipeline already running"),
+            VoiceAssistantError::Rejected => write!(f, "Pipeline rejected"),
+            VoiceAssistantError::Timeout => write!(f, "Pipeline not started in time"),
+            VoiceAssistantError::UnsupportedTransport => {
+                write!(f, "Audio transport not supported")
+            }
+        }
+    }
+}
+
+impl std::error::Error for VoiceAssistantError {}
+
+/// Microphone and speaker of a voice satellite.
+///
+/// Audio is raw 16 bit little endian mono PCM with 16 kHz.
+pub trait AudioDevice: Send + Sync + 'static {
+    /// Starts recording and returns the recorded audio in chunks.
+    ///
+    /// Recording stops when the stream is dropped.
+    fn microphone(&self) -> BoxStream<'static, Vec<u8>>;
+
+    /// Plays a chunk of streamed audio.
+    fn play(&self, data: Vec<u8>) -> BoxFuture<'_, ()>;
+
+    /// Called after the last chunk of streamed audio.
+    fn end_of_stream(&self) -> BoxFuture<'_, ()>;
+
+    /// Plays a media file, e.g. an announcement or a text-to-speech answer.
+    fn play_media(&self, url: String) -> BoxFuture<'_, std::io::Result<()>>;
+}
+
+/// Event types of a pipeline run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipelineEventType {
+    /// The pipeline failed, `code` and `message` are in the data.
+    Error,
+    /// The pipeline started.
+    RunStart,
+    /// The pipeline finished.
+    RunEnd,
+    /// Speech-to-text started.
+    SttStart,
+    /// Speech-to-text finished, the text is in the data.
+    SttEnd,
+    /// Intent recognition started.
+    IntentStart,
+    /// Intent recognition finished.
+    IntentEnd,
+    /// Text-to-speech started.
+    TtsStart,
+    /// Text-to-speech finished, the `url` of the answer is in the data.
+    TtsEnd,
+    /// Wake word detection started.
+    WakeWordStart,
+    /// The wake word was detected.
+    WakeWordEnd,
+    /// Voice activity started.
+    SttVadStart,
+    /// Voice activity ended.
+    SttVadEnd,
+    /// Streaming of the answer started.
+    TtsStreamStart,
+    /// Streaming of the answer finished.
+    TtsStreamEnd,
+    /// Intent recognition progressed.
+    IntentProgress,
+    /// An event type this crate does not know.
+    Unknown(i32),
+}
+
+impl From<i32> for PipelineEventType {
+    fn from(event_type: i32) -> Self {
+        match VoiceAssistantEvent::try_from(event_type) {
+            Ok(VoiceAssistantEvent::VoiceAssistantError) => PipelineEventType::Error,
+            Ok(VoiceAssistantEvent::VoiceAssistantRunStart) => PipelineEventType::RunStart,
+            Ok(VoiceAssistantEvent::VoiceAssistantRunEnd) => PipelineEventType::RunEnd,
+            Ok(VoiceAssistantEvent::VoiceAssistantSttStart) => PipelineEventType::SttStart,
+            Ok(VoiceAssistantEvent::VoiceAssistantSttEnd) => PipelineEventType::SttEnd,
+            Ok(VoiceAssistantEvent::VoiceAssistantIntentStart) => PipelineEventType::IntentStart,
+            Ok(VoiceAssistantEvent::VoiceAssistantIntentEnd) => PipelineEventType::IntentEnd,
+            Ok(VoiceAssistantEvent::VoiceAssistantTtsStart) => PipelineEventType::TtsStart,
+            Ok(VoiceAssistantEvent::VoiceAssistantTtsEnd) => PipelineEventType::TtsEnd,
+            Ok(VoiceAssistantEvent::VoiceAssistantWakeWordStart) => {
+                PipelineEventType::WakeWordStart
+            }
+            Ok(VoiceAssistantEvent::VoiceAssistantWakeWordEnd) => PipelineEventType::WakeWordEnd,
+            Ok(VoiceAssistantEvent::VoiceAssistantSttVadStart) => PipelineEventType::SttVadStart,
+            Ok(VoiceAssistantEvent::VoiceAssistantSttVadEnd) => PipelineEventType::SttVadEnd,
+            Ok(VoiceAssistantEvent::VoiceAssistantTtsStreamStart) => {
+                PipelineEventType::TtsStreamStart
+            }
+            Ok(VoiceAssistantEvent::VoiceAssistantTtsStreamEnd) => PipelineEventType::TtsStreamEnd,
+            Ok(VoiceAssistantEvent::VoiceAssistantIntentProgress) => {
+                PipelineEventType::IntentProgress
+            }
+            Err(_) => PipelineEventType::Unknown(event_type),
+        }
+    }
+}
+
+impl From<PipelineEventType> for i32 {
+    fn from(event_type: PipelineEventType) -> Self {
+        let event = match event_type {
+            PipelineEventType::Error => VoiceAssistantEvent::VoiceAssistantError,
+            PipelineEventType::RunStart => VoiceAssistantEvent::VoiceAssistantRunStart,
+            PipelineEventType::RunEnd => VoiceAssistantEvent::VoiceAssistantRunEnd,
+            PipelineEventType::SttStart => VoiceAssistantEvent::VoiceAssistantSttStart,
+            PipelineEventType::SttEnd => VoiceAssistantEvent::VoiceAssistantSttEnd,
+            PipelineEventType::IntentStart => VoiceAssistantEvent::VoiceAssistantIntentStart,
+            PipelineEventType::IntentEnd => VoiceAssistantEvent::VoiceAssistantIntentEnd,
+            PipelineEventType::TtsStart => VoiceAssistantEvent::VoiceAssistantTtsStart,
+            PipelineEventType::TtsEnd => VoiceAssistantEvent::VoiceAssistantTtsEnd,
+            PipelineEventType::WakeWordStart => VoiceAssistantEvent::VoiceAssistantWakeWordStart,
+            PipelineEventType::WakeWordEnd => VoiceAssistantEvent::VoiceAssistantWakeWordEnd,
+            PipelineEventType::SttVadStart => VoiceAssistantEvent::VoiceAssistantSttVadStart,
+            PipelineEventType::SttVadEnd => VoiceAssistantEvent::VoiceAssistantSttVadEnd,
+            PipelineEventType::TtsStreamStart => VoiceAssistantEvent::VoiceAssistantTtsStreamStart,
+            PipelineEventType::TtsStreamEnd => VoiceAssistantEvent::VoiceAssistantTtsStreamEnd,
+            PipelineEventType::IntentProgress => VoiceAssistantEvent::VoiceAssistantIntentProgress,
+            PipelineEventType::Unknown(event_type) => return event_type,
+        };
+        event.into()
+    }
+}
+
+/// Event of a pipeline run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PipelineEvent {
+    /// What happened.
+    pub event_type: PipelineEventType,
+    /// Details of the event, e.g. the recognized `text`.
+    pub data: HashMap<String, String>,
+}
+
+/// Event types of a timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerEventType {
+    /// The timer was started.
+    Started,
+    /// The timer was changed.
+    Updated,
+    /// The timer was cancelled.
+    Cancelled,
+    /// The timer finished.
+    Finished,
+    /// An event type this crate does not know.
+    Unknown(i32),
+}
+
+impl From<i32> for TimerEventType {
+    fn from(event_type: i32) -> Self {
+        match VoiceAssistantTimerEvent::try_from(event_type) {
+            Ok(VoiceAssistantTimerEvent::VoiceAssistantTimerStarted) => TimerEventType::Started,
+            Ok(VoiceAssistantTimerEvent::VoiceAssistantTimerUpdated) => TimerEventType::Updated,
+            Ok(VoiceAssistantTimerEvent::VoiceAssistantTimerCancelled) => TimerEventType::Cancelled,
+            Ok(VoiceAssistantTimerEvent::VoiceAssistantTimerFinished) => TimerEventType::Finished,
+            Err(_) => TimerEventType::Unknown(event_type),
+        }
+    }
+}
+
+/// Something the voice assistant was told by Home Assistant.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VoiceEvent {
+    /// Progress of the running pipeline.
+    Pipeline(PipelineEvent),
+    /// A timer started, changed or finished.
+    Timer {
+        /// What happened.
+        event_type: TimerEventType,
+        /// The timer.
+        timer: VoiceAssistantTimerEventResponse,
+    },
+    /// An announcement is played.
+    Announcement(VoiceAssistantAnnounceRequest),
+    /// The active wake words were changed.
+    WakeWordsChanged(Vec<String>),
+}
+
+enum Command {
+    Start {
+        wake_word_phrase: Option<String>,
+        reply: Option<oneshot::Sender<Result<(), VoiceAssistantError>>>,
+    },
+    Stop,
+}
+
+/// State shared by all clones of a [`VoiceAssistant`].
+struct Shared {
+    active_wake_words: Mutex<Option<Vec<String>>>,
+    /// Connection id and command sender of the subscribed connection.
+    subscriber: Mutex<Option<(u64, mpsc::Sender<Command>)>>,
+    subscribed_tx: watch::Sender<bool>,
+    events_tx: broadcast::Sender<VoiceEvent>,
+    next_connection_id: AtomicU64,
+}
+
+impl Default for Shared {
+    fn default() -> Self {
+        Shared {
+            active_wake_words: Mutex::new(None),
+            subscriber: Mutex::new(None),
+            subscribed_tx: watch::channel(false).0,
+            events_tx: broadcast::channel(64).0,
+            next_connection_id: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Voice assistant of a device.
+///
+/// Clones share the same state, so one clone can be passed to
+/// [`crate::esphomeapi::EspHomeApi`] while another one starts pipelines.
+#[derive(TypedBuilder, Clone)]
+pub struct VoiceAssistant {
+    /// Microphone and speaker.
+    audio: Arc<dyn AudioDevice>,
+    /// Wake words the device can detect.
+    #[builder(default)]
+    wake_words: Vec<VoiceAssistantWakeWord>,
+    /// Ids of the wake words active at start.
+    #[builder(default)]
+    active_wake_words: Vec<String>,
+    /// Number of wake words that can be active at the same time.
+    #[builder(default = 1)]
+    max_active_wake_words: u32,
+    /// Offer streaming audio in `VoiceAssistantAudio` messages.
+    #[builder(default = true)]
+    api_audio: bool,
+    /// Host the microphone is streamed to over UDP, usually the address of Home
+    /// Assistant. Without it audio can't be streamed over UDP.
+    #[builder(default, setter(strip_option))]
+    udp_host: Option<IpAddr>,
+    #[builder(default, setter(skip))]
+    shared: Arc<Shared>,
+}
+
+impl VoiceAssistant {
+    /// Feature flags reported in the `DeviceInfoResponse`.
+    pub fn feature_flags(&self) -> u32 {
+        let flags = FEATURE_VOICE_ASSISTANT
+            | FEATURE_SPEAKER
+            | FEATURE_TIMERS
+            | FEATURE_ANNOUNCE
+            | FEATURE_START_CONVERSATION;
+        match self.api_audio {
+            true => flags | FEATURE_API_AUDIO,
+            false => flags,
+        }
+    }
+
+    /// Returns whether a client subscribed to the voice assistant.
+    pub fn is_subscribed(&self) -> bool {
+        *self.shared.subscribed_tx.borrow()
+    }
+
+    /// Waits until a client subscribed to the voice assistant.
+    pub async fn subscribed(&self) {
+        let mut subscribed_rx = self.shared.subscribed_tx.subscribe();
+        // The sender lives in `self`, so waiting can't fail.
+        let _ = subscribed_rx.wait_for(|subscribed| *subscribed).await;
+    }
+
+    /// Returns a receiver for the events of pipelines, timers and announcements.
+    pub fn events(&self) -> broadcast::Receiver<VoiceEvent> {
+        self.shared.events_tx.subscribe()
+    }
+
+    /// Ids of the active wake words.
+    pub fn active_wake_words(&self) -> Vec<String> {
+        self.shared
+            .active_wake_words
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or_else(|| self.active_wake_words.clone())
+    }
+
+    /// Starts a pipeline, e.g. after the wake word was detected.
+    ///
+    /// Resolves once the client accepted the pipeline; the microphone is streamed
+    /// until the client detected the end of the voice command. Follow the run with
+    /// [`VoiceAssistant::events`].
+    pub async fn start_pipeline(
+        &self,
+        wake_word_phrase: Option<String>,
+    ) -> Result<(), VoiceAssistantError> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.command(Command::Start {
+            wake_word_phrase,
+            reply: Some(reply_tx),
+        })
+        .await?;
+        reply_rx
+            .await
+            .map_err(|_| VoiceAssistantError::NotSubscribed)?
+    }
+
+    /// Stops the running pipeline.
+    pub async fn stop_pipeline(&self) -> Result<(), VoiceAssistantError> {
+        self.command(Command::Stop).await
+    }
+
+    async fn command(&self, command: Command) -> Result<(), VoiceAssistantError> {
+        let commands_tx = self.shared.subscriber.lock().unwrap().clone();
+        let (_, commands_tx) = commands_tx.ok_or(VoiceAssistantError::NotSubscribed)?;
+        commands_tx
+            .send(command)
+            .await
+            .map_err(|_| VoiceAssistantError::NotSubscribed)
+    }
+
+    fn configuration(&self) -> VoiceAssistantConfigurationResponse {
+        VoiceAssistantConfigurationResponse {
+            available_wake_words: self.wake_words.clone(),
+            active_wake_words: self.active_wake_words(),
+            max_active_wake_words: self.max_active_wake_words,
+        }
+    }
+
+    fn set_active_wake_words(&self, mut active_wake_words: Vec<String>) {
+        active_wake_words.retain(|id| self.wake_words.iter().any(|wake_word| wake_word.id == *id));
+        active_wake_words.truncate(self.max_active_wake_words as usize);
+        *self.shared.active_wake_words.lock().unwrap() = Some(active_wake_words.clone());
+        let _ = self
+            .shared
+            .events_tx
+            .send(VoiceEvent::WakeWordsChanged(active_wake_words));
+    }
+
+    fn publish(&self, event: VoiceEvent) {
+        // Fails only if nobody listens.
+        let _ = self.shared.events_tx.send(event);
+    }
+}
+
+/// Returns whether the voice assistant of a connection answers `message`.
+pub(crate) fn is_request(message: &ProtoMessage) -> bool {
+    matches!(
+        message,
+        ProtoMessage::SubscribeVoiceAssistantRequest(_)
+            | ProtoMessage::VoiceAssistantResponse(_)
+            | ProtoMessage::VoiceAssistantEventResponse(_)
+            | ProtoMessage::VoiceAssistantAudio(_)
+            | ProtoMessage::VoiceAssistantTimerEventResponse(_)
+            | ProtoMessage::VoiceAssistantAnnounceRequest(_)
+            | ProtoMessage::VoiceAssistantConfigurationRequest(_)
+            | ProtoMessage::VoiceAssistantSetConfiguration(_)
+    )
+}
+
+/// A pipeline run of a connection.
+#[derive(Default)]
+struct Pipeline {
+    /// Waiting for the `VoiceAssistantResponse` until then.
+    response_deadline: Option<Instant>,
+    reply: Option<oneshot::Sender<Result<(), VoiceAssistantError>>>,
+    microphone: Option<BoxStream<'static, Vec<u8>>>,
+    /// Socket the microphone is streamed to, if Home Assistant asked for UDP.
+    udp: Option<Arc<UdpSocket>>,
+    continue_conversation: bool,
+}
+
+/// Audio played by the player of a connection, one after the other.
+enum Playback {
+    /// A chunk of streamed audio, `end` after the last one.
+    Stream { data: Vec<u8>, end: bool },
+    /// A text-to-speech answer by URL.
+    Answer(String),
+    /// An announcement, answered with `VoiceAssistantAnnounceFinished`.
+    Announcement(VoiceAssistantAnnounceRequest),
+}
+
+/// Voice assistant state of one API connection.
+struct Connection {
+    voice_assistant: VoiceAssistant,
+    id: u64,
+    answers: mpsc::Sender<ProtoMessage>,
+    commands_tx: mpsc::Sender<Command>,
+    /// Plays audio without blocking the connection.
+    player: mpsc::Sender<Playback>,
+    api_audio: bool,
+    /// Bound with the first UDP pipeline and kept to receive the answer.
+    udp: Option<Arc<UdpSocket>>,
+    conversation_id: String,
+    pipeline: Option<Pipeline>,
+}
+
+/// Starts answering the voice assistant requests of one API connection.
+///
+/// Requests are passed through the returned sender. Once it is dropped, the
+/// connection no longer counts as subscribed.
+pub(crate) fn spawn(
+    voice_assistant: VoiceAssistant,
+    answers: mpsc::Sender<ProtoMessage>,
+) -> mpsc::Sender<ProtoMessage> {
+    let (requests_tx, mut requests_rx) = mpsc::channel::<ProtoMessage>(16);
+    let (commands_tx, mut commands_rx) = mpsc::channel::<Command>(4);
+    let id = voice_assistant
+        .shared
+        .next_connection_id
+        .fetch_add(1, Ordering::Relaxed);
+    let player = spawn_player(
+        voice_assistant.audio.clone(),
+        answers.clone(),
+        commands_tx.clone(),
+    );
+    let mut connection = Connection {
+        voice_assistant,
+        id,
+        answers,
+        commands_tx,
+        player,
+        api_audio: false,
+        udp: None,
+        conversation_id: String::new(),
+        pipeline: None,
+    };
+
+    tokio::spawn(async move {
+        loop {
+            let response_deadline = connection
+                .pipeline
+                .as_ref()
+                .and_then(|pipeline| pipeline.response_deadline);
+            tokio::select! {
+                request = requests_rx.recv() => match request {
+                    Some(request) => connection.handle(request).await,
+                    None => break,
+                },
+                Some(command) = commands_rx.recv() => match command {
+                    Command::Start { wake_word_phrase, reply } => {
+                        connection.start(wake_word_phrase, reply).await;
+                    }
+                    Command::Stop => connection.stop().await,
+                },
+                chunk = next_chunk(&mut connection.pipeline) => match chunk {
+                    Some(data) => connection.send_audio(data, false).await,
+                    None => {
+                        if let Some(pipeline) = &mut connection.pipeline {
+                            pipeline.microphone = None;
+                        }
+                        connection.send_audio(vec![], true).await;
+                    }
+                },
+                datagram = recv_datagram(connection.udp.clone()) => match datagram {
+                    // An empty datagram ends the audio like `end` of `VoiceAssistantAudio`.
+                    Ok(data) => {
+                        let end = data.is_empty();
+                        connection.play(Playback::Stream { data, end }).await;
+                    }
+                    Err(err) => debug!("Failed to receive voice assistant audio: {}", err),
+                },
+                _ = sleep_until(response_deadline) => {
+                    warn!("Voice assistant pipeline was not accepted in time");
+                    connection.finish(Err(VoiceAssistantError::Timeout)).await;
+                }
+            }
+        }
+        debug!("Voice assistant connection stopped");
+        connection.unsubscribe();
+    });
+
+    requests_tx
+}
+
+async fn next_chunk(pipeline: &mut Option<Pipeline>) -> Option<Vec<u8>> {
+    match pipeline
+        .as_mut()
+        .and_then(|pipeline| pipeline.microphone.as_mut())
+    {
+        Some(microphone) => microphone.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn recv_datagram(socket: Option<Arc<UdpSocket>>) -> std::io::Result<Vec<u8>> {
+    match socket {
+        Some(socket) => {
+            let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+            let length = socket.recv(&mut buffer).await?;
+            buffer.truncate(length);
+            Ok(buffer)
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Starts the player of a connection.
+///
+/// It stops once the returned sender is dropped and everything queued was played.
+fn spawn_player(
+    audio: Arc<dyn AudioDevice>,
+    answers: mpsc::Sender<ProtoMessage>,
+    commands_tx: mpsc::Sender<Command>,
+) -> mpsc::Sender<Playback> {
+    let (player_tx, mut player_rx) = mpsc::channel::<Playback>(64);
+    tokio::spawn(async move {
+        while let Some(playback) = player_rx.recv().await {
+            match playback {
+                Playback::Stream { data, end } => {
+                    if !data.is_empty() {
+                        audio.play(data).await;
+                    }
+                    if end {
+                        audio.end_of_stream().await;
+                    }
+                }
+                Playback::Answer(url) => {
+                    if let Err(err) = audio.play_media(url).await {
+                        warn!("Failed to play voice assistant answer: {}", err);
+                    }
+                }
+                Playback::Announcement(announcement) => {
+                    let mut result = Ok(());
+                    if !announcement.preannounce_media_id.is_empty() {
+                        result = audio.play_media(announcement.preannounce_media_id).await;
+                    }
+                    if result.is_ok() {
+                        result = audio.play_media(announcement.media_id).await;
+                    }
+                    if let Err(err) = &result {
+                        warn!("Failed to play announcement: {}", err);
+                    }
+                    let finished = VoiceAssistantAnnounceFinished {
+                        success: result.is_ok(),
+                    };
+                    let _ = answers
+                        .send(ProtoMessage::VoiceAssistantAnnounceFinished(finished))
+                        .await;
+                    if announcement.start_conversation {
+                        let start = Command::Start {
+                            wake_word_phrase: None,
+                            reply: None,
+                        };
+                        let _ = commands_tx.send(start).await;
+                    }
+                }
+            }
+        }
+    });
+    player_tx
+}
+
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+impl Connection {
+    /// Sends an answer; the future does not borrow `self`, which is not `Sync`.
+    fn send(&self, message: ProtoMessage) -> impl Future<Output = ()> + Send + 'static {
+        let answers = self.answers.clone();
+        async move {
+            // Fails only once the connection is gone.
+            let _ = answers.send(message).await;
+        }
+    }
+
+    /// Queues audio for the player; the future does not borrow `self`.
+    fn play(&self, playback: Playback) -> impl Future<Output = ()> + Send + 'static {
+        let player = self.player.clone();
+        async move {
+            // Fails only once the connection is gone.
+            let _ = player.send(playback).await;
+        }
+    }
+
+    /// Sends microphone audio over the transport of the pipeline.
+    fn send_audio(&self, data: Vec<u8>, end: bool) -> impl Future<Output = ()> + Send + 'static {
+        let udp = self
+            .pipeline
+            .as_ref()
+            .and_then(|pipeline| pipeline.udp.clone());
+        let answers = self.answers.clone();
+        async move {
+            match udp {
+                Some(socket) => {
+                    if let Err(err) = socket.send(&data).await {
+                        debug!("Failed to send voice assistant audio: {}", err);
+                    }
+                }
+                None => {
+                    let audio = VoiceAssistantAudio { data, end };
+                    let _ = answers.send(ProtoMessage::VoiceAssistantAudio(audio)).await;
+                }
+            }
+        }
+    }
+
+    /// Connects the UDP socket to the port Home Assistant listens on.
+    async fn connect_udp(&mut self, port: u16) -> Result<Arc<UdpSocket>, VoiceAssistantError> {
+        let host = self
+            .voice_assistant
+            .udp_host
+            .ok_or(VoiceAssistantError::UnsupportedTransport)?;
+        let address = SocketAddr::new(host, port);
+        let result = async {
+            let socket = match self.udp.clone() {
+                Some(socket) => socket,
+                None => {
+                    let unspecified = match host {
+                        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                    };
+                    Arc::new(UdpSocket::bind(SocketAddr::new(unspecified, 0)).await?)
+                }
+            };
+            socket.connect(address).await?;
+            Ok::<_, std::io::Error>(socket)
+        }
+        .await;
+        match result {
+            Ok(socket) => {
+                self.udp = Some(socket.clone());
+                Ok(socket)
+            }
+            Err(err) => {
+                warn!(
+                    "Failed to stream voice assistant audio to {}: {}",
+                    address, err
+                );
+                Err(VoiceAssistantError::UnsupportedTransport)
+            }
+        }
+    }
+
+    fn unsubscribe(&self) {
+        let shared = &self.voice_assistant.shared;
+        let mut subscriber = shared.subscriber.lock().unwrap();
+        if subscriber.as_ref().is_some_and(|(id, _)| *id == self.id) {
+            *subscriber = None;
+            shared.subscribed_tx.send_replace(false);
+        }
+    }
+
+    async fn start(
+        &mut self,
+        wake_word_phrase: Option<String>,
+        reply: Option<oneshot::Sender<Result<(), VoiceAssistantError>>>,
+    ) {
+        if self.pipeline.is_some() {
+            if let Some(reply) = reply {
+                let _ = reply.send(Err(VoiceAssistantError::Busy));
+            }
+            return;
+        }
+        self.pipeline = Some(Pipeline {
+            response_deadline: Some(Instant::now() + RESPONSE_TIMEOUT),
+            reply,
+            ..Default::default()
+        });
+        self.send(ProtoMessage::VoiceAssistantRequest(VoiceAssistantRequest {
+            start: true,
+            conversation_id: self.conversation_id.clone(),
+            flags: REQUEST_USE_VAD,
+            audio_settings: None,
+            wake_word_phrase: wake_word_phrase.unwrap_or_default(),
+        }))
+        .await;
+    }
+
+    async fn stop(&mut self) {
+        if self.pipeline.is_some() {
+            self.send_stop().await;
+            self.finish(Ok(())).await;
+        }
+    }
+
+    fn send_stop(&self) -> impl Future<Output = ()> + Send + 'static {
+        self.send(ProtoMessage::VoiceAssistantRequest(VoiceAssistantRequest {
+            start: false,
+            ..Default::default()
+        }))
+    }
+
+    /// Ends the pipeline and answers a waiting `start_pipeline`.
+    async fn finish(&mut self, result: Result<(), VoiceAssistantError>) {
+        if let Some(reply) = self.pipeline.take().and_then(|pipeline| pipeline.reply) {
+            let _ = reply.send(result);
+        }
+    }
+
+    async fn handle(&mut self, request: ProtoMessage) {
+        match request {
+            ProtoMessage::SubscribeVoiceAssistantRequest(request) => {
+                let shared = &self.voice_assistant.shared;
+                if request.subscribe {
+                    self.api_audio = request.flags & SUBSCRIBE_API_AUDIO != 0;
+                    *shared.subscriber.lock().unwrap() = Some((self.id, self.commands_tx.clone()));
+                    shared.subscribed_tx.send_replace(true);
+                } else {
+                    self.unsubscribe();
+                }
+            }
+            ProtoMessage::VoiceAssistantResponse(response) => {
+                let waiting = self
+                    .pipeline
+                    .as_ref()
+                    .is_some_and(|pipeline| pipeline.response_deadline.is_some());
+                if !waiting {
+                    return;
+                }
+                if response.error {
+                    return self.finish(Err(VoiceAssistantError::Rejected)).await;
+                }
+                let udp = match (response.port, self.api_audio) {
+                    (0, true) => Ok(None),
+                    (0, false) => Err(VoiceAssistantError::UnsupportedTransport),
+                    (port, _) => match u16::try_from(port) {
+                        Ok(port) => self.connect_udp(port).await.map(Some),
+                        Err(_) => Err(VoiceAssistantError::UnsupportedTransport),
+                    },
+                };
+                let udp = match udp {
+                    Ok(udp) => udp,
+                    Err(err) => {
+                        warn!("Voice assistant client requested an unsupported transport");
+                        self.send_stop().await;
+                        return self.finish(Err(err)).await;
+                    }
+                };
+                let microphone = self.voice_assistant.audio.microphone();
+                if let Some(pipeline) = &mut self.pipeline {
+                    pipeline.response_deadline = None;
+                    pipeline.microphone = Some(microphone);
+                    pipeline.udp = udp;
+                    if let Some(reply) = pipeline.reply.take() {
+                        let _ = reply.send(Ok(()));
+                    }
+                }
+            }
+            ProtoMessage::VoiceAssistantEventResponse(event) => self.handle_event(event).await,
+            ProtoMessage::VoiceAssistantAudio(audio) => {
+                self.play(Playback::Stream {
+                    data: audio.data,
+                    end: audio.end,
+                })
+                .await;
+            }
+            ProtoMessage::VoiceAssistantTimerEventResponse(timer) => {
+                self.voice_assistant.publish(VoiceEvent::Timer {
+                    event_type: timer.event_type.into(),
+                    timer,
+                });
+            }
+            ProtoMessage::VoiceAssistantAnnounceRequest(announcement) => {
+                self.voice_assistant
+                    .publish(VoiceEvent::Announcement(announcement.clone()));
+                self.play(Playback::Announcement(announcement)).await;
+            }
+            ProtoMessage::VoiceAssistantConfigurationRequest(_) => {
+                self.send(ProtoMessage::VoiceAssistantConfigurationResponse(
+                    self.voice_assistant.configuration(),
+                ))
+                .await;
+            }
+            ProtoMessage::VoiceAssistantSetConfiguration(configuration) => {
+                self.voice_assistant
+                    .set_active_wake_words(configuration.active_wake_words);
+            }
+            _ => {}
+        }
+    }
+
+    async fn handle_event(&mut self, event: VoiceAssistantEventResponse) {
+        let event = PipelineEvent {
+            event_type: event.event_type.into(),
+            data: event
+                .data
+                .into_iter()
+                .map(|data| (data.name, data.value))
+                .collect(),
+        };
+        self.voice_assistant
+            .publish(VoiceEvent::Pipeline(event.clone()));
+
+        match event.event_type {
+            PipelineEventType::SttVadEnd | PipelineEventType::SttEnd => {
+                if let Some(pipeline) = &mut self.pipeline {
+                    pipeline.microphone = None;
+                }
+            }
+            PipelineEventType::IntentEnd => {
+                if let Some(conversation_id) = event.data.get("conversation_id") {
+                    self.conversation_id = conversation_id.clone();
+                }
+                if let Some(pipeline) = &mut self.pipeline {
+                    pipeline.continue_conversation =
+                        event.data.get("continue_conversation").map(String::as_str) == Some("1");
+                }
+            }
+            PipelineEventType::TtsEnd => {
+                // Answers streamed neither as `VoiceAssistantAudio` nor over UDP are
+                // played by URL.
+                let streamed = self.api_audio
+                    || self
+                        .pipeline
+                        .as_ref()
+                        .is_some_and(|pipeline| pipeline.udp.is_some());
+                if let Some(url) = event.data.get("url").filter(|_| !streamed) {
+                    self.play(Playback::Answer(url.clone())).await;
+                }
+            }
+            PipelineEventType::Error => {
+                self.finish(Err(VoiceAssistantError::Rejected)).await;
+            }
+            PipelineEventType::RunEnd => {
+                let continue_conversation = self
+                    .pipeline
+                    .as_ref()
+                    .is_some_and(|pipeline| pipeline.continue_conversation);
+                self.finish(Ok(())).await;
+                if continue_conversation {
+                    self.start(None, None).await;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_event_types() {
+        for event_type in [0, 2, 8, 12, 98, 100, 42] {
+            assert_eq!(i32::from(PipelineEventType::from(event_type)), event_type);
+        }
+        assert_eq!(PipelineEventType::from(4), PipelineEventType::SttEnd);
+        assert_eq!(TimerEventType::from(3), TimerEventType::Finished);
+    }
+}
@@ -0,0 +1,115 @@
+//! An [`AudioDevice`] reading the microphone from and playing to raw PCM files.
+
+use futures::FutureExt;
+use futures::StreamExt;
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use typed_builder::TypedBuilder;
+
+use crate::voice_assistant::AudioDevice;
+
+/// Bytes per second of 16 bit mono PCM with 16 kHz.
+const BYTES_PER_SECOND: u64 = 32_000;
+
+/// Audio device backed by files.
+///
+/// The microphone plays back the `input` file, every pipeline from its start.
+/// Streamed audio is appended to the `output` file, and so are media files given as
+/// local path or `file://` URL. All played media URLs are recorded.
+///
+/// # Examples
+///
+/// ```rust
+/// use esphome_native_api::voice_assistant::file::FileAudio;
+///
+/// let audio = FileAudio::builder()
+///     .input("command.raw".into())
+///     .output("answer.raw".into())
+///     .realtime(true)
+///     .build();
+/// ```
+#[derive(TypedBuilder, Clone)]
+pub struct FileAudio {
+    /// Raw PCM file used as microphone.
+    input: PathBuf,
+    /// File the played audio is appended to.
+    output: PathBuf,
+    /// Size of the microphone chunks in bytes.
+    #[builder(default = 1024)]
+    chunk_size: usize,
+    /// Deliver the microphone chunks at the pace they would be recorded.
+    #[builder(default = false)]
+    realtime: bool,
+    #[builder(default, setter(skip))]
+    played_media: Arc<Mutex<Vec<String>>>,
+}
+
+impl FileAudio {
+    /// URLs passed to [`AudioDevice::play_media`] so far.
+    pub fn played_media(&self) -> Vec<String> {
+        self.played_media.lock().unwrap().clone()
+    }
+
+    async fn append(&self, data: &[u8]) -> std::io::Result<()> {
+        let mut output = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.output)
+            .await?;
+        output.write_all(data).await?;
+        output.flush().await
+    }
+}
+
+impl AudioDevice for FileAudio {
+    fn microphone(&self) -> BoxStream<'static, Vec<u8>> {
+        let input = self.input.clone();
+        let chunk_size = self.chunk_size.max(2);
+        let chunk_duration =
+            Duration::from_micros(chunk_size as u64 * 1_000_000 / BYTES_PER_SECOND);
+        let realtime = self.realtime;
+
+        futures::stream::once(async move { tokio::fs::read(input).await.unwrap_or_default() })
+            .flat_map(move |audio| {
+                let chunks: Vec<Vec<u8>> = audio.chunks(chunk_size).map(<[u8]>::to_vec).collect();
+                futures::stream::iter(chunks)
+            })
+            .then(move |chunk| async move {
+                if realtime {
+                    tokio::time::sleep(chunk_duration).await;
+                }
+                chunk
+            })
+            .boxed()
+    }
+
+    fn play(&self, data: Vec<u8>) -> BoxFuture<'_, ()> {
+        async move {
+            if let Err(err) = self.append(&data).await {
+                log::warn!("Failed to write audio to {:?}: {}", self.output, err);
+            }
+        }
+        .boxed()
+    }
+
+    fn end_of_stream(&self) -> BoxFuture<'_, ()> {
+        futures::future::ready(()).boxed()
+    }
+
+    fn play_media(&self, url: String) -> BoxFuture<'_, std::io::Result<()>> {
+        async move {
+            self.played_media.lock().unwrap().push(url.clone());
+            if url.contains("://") && !url.starts_with("file://") {
+                return Ok(());
+            }
+            let media = tokio::fs::read(url.trim_start_matches("file://")).await?;
+            self.append(&media).await
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,242 @@
+mod common;
+
+use esphome_native_api::bluetooth_proxy::simulated::{SimulatedBackend, SimulatedPeripheral};
+use esphome_native_api::bluetooth_proxy::{self, BluetoothProxyBackend};
+use esphome_native_api::esphomeapi::EspHomeApi;
+use esphome_native_api::esphomeclient::{ClientConnection, ClientError};
+use esphome_native_api::parser::ProtoMessage;
+use esphome_native_api::proto::{
+    BluetoothGattCharacteristic, BluetoothGattDescriptor, BluetoothGattService,
+    SubscribeBluetoothConnectionsFreeRequest,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+const TEST_DEVICE_NAME: &str = "test_proxy";
+const THERMOMETER: u64 = 0xA4C1380D1E2F;
+
+fn thermometer() -> SimulatedPeripheral {
+    SimulatedPeripheral::builder()
+        .address(THERMOMETER)
+        .name("Thermometer".to_string())
+        .services(vec![BluetoothGattService {
+            handle: 0x10,
+            characteristics: vec![BluetoothGattCharacteristic {
+                handle: 0x11,
+                descriptors: vec![BluetoothGattDescriptor {
+                    handle: 0x12,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }])
+        .values(HashMap::from([(0x11, vec![21])]))
+        .build()
+}
+
+/// Connects a client to a proxy using `backend`.
+async fn connect(
+    backend: Arc<SimulatedBackend>,
+) -> (ClientConnection, broadcast::Receiver<ProtoMessage>) {
+    let api = EspHomeApi::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .bluetooth_proxy(backend)
+        .build();
+    common::connect(&api).await
+}
+
+#[tokio::test]
+async fn test_bluetooth_proxy_gatt_flow() {
+    let backend = Arc::new(SimulatedBackend::new());
+    backend.add_peripheral(thermometer());
+    let (connection, _rx) = connect(backend.clone()).await;
+
+    assert_eq!(
+        connection
+            .device_info_response()
+            .bluetooth_proxy_feature_flags,
+        backend.feature_flags()
+    );
+
+    let device = connection
+        .bluetooth_connect(THERMOMETER)
+        .await
+        .expect("bluetooth connect failed");
+    assert_eq!(device.mtu(), 247);
+    assert!(backend.is_connected(THERMOMETER));
+
+    let services = device.services().await.expect("service discovery failed");
+    assert_eq!(services.len(), 1);
+    assert_eq!(services[0].characteristics[0].handle, 0x11);
+
+    assert_eq!(device.read(0x11).await.expect("read failed"), vec![21]);
+    device
+        .write(0x11, vec![22], true)
+        .await
+        .expect("write failed");
+    assert_eq!(backend.value(THERMOMETER, 0x11), Some(vec![22]));
+    device
+        .write_descriptor(0x12, vec![0x01, 0x00])
+        .await
+        .expect("descriptor write failed");
+    assert_eq!(
+        device.read_descriptor(0x12).await.expect("read failed"),
+        vec![0x01, 0x00]
+    );
+    match device.read(0x99).await {
+        Err(ClientError::Gatt { handle, error, .. }) => {
+            assert_eq!(
+                (handle, error),
+                (0x99, bluetooth_proxy::ERROR_INVALID_HANDLE)
+            )
+        }
+        other => panic!("expected GATT error, got {:?}", other),
+    }
+
+    let mut notifications = device.notify(0x11).await.expect("notify failed");
+    backend.notify(THERMOMETER, 0x11, vec![23]).await;
+    assert_eq!(notifications.recv().await, Some(vec![23]));
+
+    backend.drop_connection(THERMOMETER).await;
+    assert_eq!(notifications.recv().await, None);
+    assert!(matches!(
+        device.read(0x11).await,
+        Err(ClientError::Gatt {
+            error: bluetooth_proxy::ERROR_NOT_CONNECTED,
+            ..
+        })
+    ));
+}
+
+#[tokio::test]
+async fn test_bluetooth_proxy_connection_slots() {
+    let backend = Arc::new(SimulatedBackend::new());
+    for index in 0..4 {
+        backend.add_peripheral(
+            SimulatedPeripheral::builder()
+                .address(THERMOMETER + index)
+                .build(),
+        );
+    }
+    let (connection, _rx) = connect(backend.clone()).await;
+
+    let mut incoming = connection.subscribe();
+    connection
+        .send(ProtoMessage::SubscribeBluetoothConnectionsFreeRequest(
+            SubscribeBluetoothConnectionsFreeRequest {},
+        ))
+        .await
+        .unwrap();
+    let mut next_free = async || loop {
+        if let Ok(ProtoMessage::BluetoothConnectionsFreeResponse(response)) = incoming.recv().await
+        {
+            return response;
+        }
+    };
+    let free = next_free().await;
+    assert_eq!((free.free, free.limit), (3, 3));
+
+    let mut devices = Vec::new();
+    for index in 0..3 {
+        devices.push(
+            connection
+                .bluetooth_connect(THERMOMETER + index)
+                .await
+                .expect("bluetooth connect failed"),
+        );
+        let free = next_free().await;
+        assert_eq!(free.free, 2 - index as u32);
+        assert_eq!(free.allocated.len(), index as usize + 1);
+    }
+
+    match connection.bluetooth_connect(THERMOMETER + 3).await {
+        Err(ClientError::BluetoothConnection { error, .. }) => {
+            assert_eq!(error, bluetooth_proxy::ERROR_NO_RESOURCES)
+        }
+        Err(err) => panic!("expected connection error, got {:?}", err),
+        Ok(_) => panic!("expected connection error"),
+    }
+
+    devices[0].disconnect().await.expect("disconnect failed");
+    assert!(!backend.is_connected(THERMOMETER));
+    assert_eq!(next_free().await.free, 1);
+}
+
+#[tokio::test]
+async fn test_bluetooth_proxy_shares_slots_between_connections() {
+    let backend = Arc::new(SimulatedBackend::new());
+    backend.add_peripheral(thermometer());
+    let api = EspHomeApi::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .bluetooth_proxy(backend.clone())
+        .build();
+    let (first, _first_rx) = common::connect(&api).await;
+    let (second, _second_rx) = common::connect(&api.clone()).await;
+
+    let mut incoming = second.subscribe();
+    second
+        .send(ProtoMessage::SubscribeBluetoothConnectionsFreeRequest(
+            SubscribeBluetoothConnectionsFreeRequest {},
+        ))
+        .await
+        .unwrap();
+    let mut next_free = async || loop {
+        if let Ok(ProtoMessage::BluetoothConnectionsFreeResponse(response)) = incoming.recv().await
+        {
+            return response;
+        }
+    };
+    assert_eq!(next_free().await.free, 3);
+
+    let _device = first
+        .bluetooth_connect(THERMOMETER)
+        .await
+        .expect("bluetooth connect failed");
+    let free = next_free().await;
+    assert_eq!(free.free, 2);
+    assert_eq!(free.allocated, vec![THERMOMETER]);
+
+    match second.bluetooth_connect(THERMOMETER).await {
+        Err(ClientError::BluetoothConnection { error, .. }) => {
+            assert_eq!(error, bluetooth_proxy::ERROR_GATT)
+        }
+        Err(err) => panic!("expected connection error, got {:?}", err),
+        Ok(_) => panic!("expected connection error"),
+    }
+
+    // Closing the first connection frees its slot.
+    first.disconnect().await.expect("disconnect failed");
+    let free = tokio::time::timeout(std::time::Duration::from_secs(5), next_free())
+        .await
+        .expect("slot not freed");
+    assert_eq!(free.free, 3);
+    assert!(!backend.is_connected(THERMOMETER));
+}
+
+#[tokio::test]
+async fn test_bluetooth_proxy_forwards_advertisements() {
+    let backend = Arc::new(SimulatedBackend::new());
+    backend.add_peripheral(thermometer());
+    let (connection, _rx) = connect(backend.clone()).await;
+
+    let mut advertisements = connection
+        .subscribe_bluetooth_advertisements()
+        .await
+        .expect("subscribe advertisements failed");
+
+    // Scanning starts asynchronously, so advertise until one gets through.
+    let advertisement = loop {
+        backend.advertise(THERMOMETER);
+        let received =
+            tokio::time::timeout(std::time::Duration::from_millis(500), advertisements.recv())
+                .await;
+        if let Ok(advertisement) = received {
+            break advertisement.expect("stream closed");
+        }
+    };
+    assert_eq!(advertisement.address, THERMOMETER);
+    assert_eq!(advertisement.name.as_deref(), Some("Thermometer"));
+    assert_eq!(advertisement.rssi, -60);
+}
@@ -0,0 +1,16 @@
+use esphome_native_api::esphomeapi::EspHomeApi;
+use esphome_native_api::esphomeclient::{ClientConnection, EspHomeClient};
+use esphome_native_api::parser::ProtoMessage;
+use tokio::io::duplex;
+use tokio::sync::broadcast;
+
+/// Connects a client to `api`, the receiver of the device side has to be kept.
+pub async fn connect(api: &EspHomeApi) -> (ClientConnection, broadcast::Receiver<ProtoMessage>) {
+    let (client_stream, server_stream) = duplex(4096);
+    let client = EspHomeClient::builder().build();
+
+    let (start_result, connect_result) =
+        tokio::join!(api.start(server_stream), client.start(client_stream));
+    let (_tx, rx) = start_result.expect("server start failed");
+    (connect_result.expect("client connect failed"), rx)
+}
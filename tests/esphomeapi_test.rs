@@ -272,3 +272,98 @@ async fn test_protocol_change_from_encrypted_to_plaintext_on_plaintext_server()
 
     assert_eq!(response_frame, plaintext_hello_response_frame());
 }
+
+#[tokio::test]
+async fn test_keepalive_pings_and_closes_silent_connection() {
+    use esphome_native_api::keepalive::Keepalive;
+
+    let (client_stream, server_stream) = duplex(1024);
+    let (mut client_read, mut client_write) = tokio::io::split(client_stream);
+
+    let api = EspHomeApi::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .keepalive(
+            Keepalive::builder()
+                .interval(Duration::from_millis(50))
+                .timeout(Duration::from_millis(150))
+                .build(),
+        )
+        .build();
+
+    let request_frame = plaintext_hello_request_frame();
+    let write_future = async {
+        client_write
+            .write_all(&request_frame)
+            .await
+            .expect("failed to write request frame");
+        client_write
+            .flush()
+            .await
+            .expect("failed to flush request frame");
+    };
+
+    let (start_result, _) = tokio::join!(api.start(server_stream), write_future);
+    let (_tx, _outgoing_messages_rx) = start_result.expect("server start failed");
+
+    // The client never answers, so the device pings and finally closes the connection.
+    let mut received = Vec::new();
+    tokio::time::timeout(
+        Duration::from_secs(1),
+        client_read.read_to_end(&mut received),
+    )
+    .await
+    .expect("device did not close the silent connection")
+    .expect("failed to read from device");
+
+    let ping_request_frame = [0x00, 0x00, 0x07];
+    let frames = &received[plaintext_hello_response_frame().len()..];
+    assert!(!frames.is_empty());
+    assert!(frames.chunks(3).all(|frame| frame == ping_request_frame));
+}
+
+#[tokio::test]
+async fn test_device_side_ping_request_resolves() {
+    use esphome_native_api::request;
+
+    let (client_stream, server_stream) = duplex(1024);
+    let (mut client_read, mut client_write) = tokio::io::split(client_stream);
+
+    let api = EspHomeApi::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .keepalive_opt(None)
+        .build();
+
+    let request_frame = plaintext_hello_request_frame();
+    let write_future = async {
+        client_write
+            .write_all(&request_frame)
+            .await
+            .expect("failed to write request frame");
+    };
+    let (start_result, _) = tokio::join!(api.start(server_stream), write_future);
+    let (tx, outgoing_messages_rx) = start_result.expect("server start failed");
+
+    let mut response_frame = vec![0u8; plaintext_hello_response_frame().len()];
+    client_read
+        .read_exact(&mut response_frame)
+        .await
+        .expect("failed to read response frame");
+
+    let client = async {
+        let mut ping_request_frame = [0u8; 3];
+        client_read
+            .read_exact(&mut ping_request_frame)
+            .await
+            .expect("failed to read ping request");
+        assert_eq!(ping_request_frame, [0x00, 0x00, 0x07]);
+        client_write
+            .write_all(&[0x00, 0x00, 0x08])
+            .await
+            .expect("failed to write ping response");
+    };
+    let (ping, ()) = tokio::join!(
+        request::ping(&tx, outgoing_messages_rx, Duration::from_secs(1)),
+        client
+    );
+    ping.expect("ping was not answered");
+}
@@ -0,0 +1,935 @@
+use esphome_native_api::esphomeapi::EspHomeApi;
+use esphome_native_api::esphomeclient::{ClientError, EspHomeClient};
+use tokio::io::duplex;
+
+const TEST_DEVICE_NAME: &str = "test_device";
+const NOISE_PSK: &str = "xiahAckHBW7BcKEQ6mRfasIW20Md9uMh/5PjrjbAhXQ=";
+
+#[test]
+fn test_basic_client_instantiation() {
+    EspHomeClient::builder().build();
+}
+
+#[tokio::test]
+async fn test_client_connects_to_plaintext_device() {
+    let (client_stream, server_stream) = duplex(1024);
+
+    let api = EspHomeApi::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .mac("00:00:00:00:00:01".to_string())
+        .build();
+    let client = EspHomeClient::builder().build();
+
+    let (start_result, connect_result) =
+        tokio::join!(api.start(server_stream), client.start(client_stream));
+    let (_tx, _rx) = start_result.expect("server start failed");
+    let connection = connect_result.expect("client connect failed");
+
+    assert_eq!(connection.hello_response().name, TEST_DEVICE_NAME);
+    assert_eq!(connection.hello_response().api_version_major, 1);
+    assert_eq!(connection.device_info_response().name, TEST_DEVICE_NAME);
+    assert_eq!(
+        connection.device_info_response().mac_address,
+        "00:00:00:00:00:01"
+    );
+    assert!(!connection.is_closed());
+}
+
+#[tokio::test]
+async fn test_client_forwards_device_messages() {
+    use esphome_native_api::parser::ProtoMessage;
+    use esphome_native_api::proto::SensorStateResponse;
+
+    let (client_stream, server_stream) = duplex(1024);
+
+    let api = EspHomeApi::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .build();
+    let client = EspHomeClient::builder().build();
+
+    let (start_result, connect_result) =
+        tokio::join!(api.start(server_stream), client.start(client_stream));
+    let (tx, _rx) = start_result.expect("server start failed");
+    let connection = connect_result.expect("client connect failed");
+
+    let mut incoming = connection.subscribe();
+    tx.send(ProtoMessage::SensorStateResponse(SensorStateResponse {
+        key: 1,
+        state: 21.5,
+        ..Default::default()
+    }))
+    .await
+    .unwrap();
+
+    match incoming.recv().await.unwrap() {
+        ProtoMessage::SensorStateResponse(state) => {
+            assert_eq!(state.key, 1);
+            assert_eq!(state.state, 21.5);
+        }
+        message => panic!("Unexpected message: {:?}", message),
+    }
+
+    connection.disconnect().await.unwrap();
+    connection.closed().await;
+    assert!(connection.is_closed());
+}
+
+#[tokio::test]
+async fn test_client_plaintext_rejected_by_encrypted_device() {
+    let (client_stream, server_stream) = duplex(1024);
+
+    let api = EspHomeApi::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .encryption_key(NOISE_PSK.to_string())
+        .build();
+    let client = EspHomeClient::builder().build();
+
+    let (start_result, connect_result) =
+        tokio::join!(api.start(server_stream), client.start(client_stream));
+    assert!(start_result.is_err());
+    assert!(matches!(
+        connect_result
+            .err()
+            .expect("plaintext connection should be rejected"),
+        ClientError::EncryptionRequired
+    ));
+}
+
+#[tokio::test]
+async fn test_client_connects_to_encrypted_device() {
+    let (client_stream, server_stream) = duplex(1024);
+
+    let api = EspHomeApi::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .encryption_key(NOISE_PSK.to_string())
+        .build();
+    let client = EspHomeClient::builder()
+        .encryption_key(NOISE_PSK.to_string())
+        .build();
+
+    let (start_result, connect_result) =
+        tokio::join!(api.start(server_stream), client.start(client_stream));
+    let (_tx, _rx) = start_result.expect("server start failed");
+    let connection = connect_result.expect("client connect failed");
+
+    assert_eq!(connection.hello_response().name, TEST_DEVICE_NAME);
+    assert!(connection.device_info_response().api_encryption_supported);
+}
+
+#[tokio::test]
+async fn test_client_wrong_encryption_key() {
+    let (client_stream, server_stream) = duplex(1024);
+
+    let api = EspHomeApi::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .encryption_key(NOISE_PSK.to_string())
+        .build();
+    let client = EspHomeClient::builder()
+        .encryption_key("px7tsbK3C7bpXHr2OevEV2ZMg/FrNBw2+O2pNPbedtA=".to_string())
+        .build();
+
+    let (start_result, connect_result) =
+        tokio::join!(api.start(server_stream), client.start(client_stream));
+    assert!(start_result.is_err());
+    assert!(matches!(
+        connect_result.err().expect("wrong key should be rejected"),
+        ClientError::HandshakeMacFailure
+    ));
+}
+
+#[tokio::test]
+async fn test_client_invalid_encryption_key() {
+    let (client_stream, _server_stream) = duplex(1024);
+
+    let client = EspHomeClient::builder()
+        .encryption_key("not a key".to_string())
+        .build();
+
+    assert!(matches!(
+        client.start(client_stream).await.err().unwrap(),
+        ClientError::InvalidEncryptionKey
+    ));
+}
+
+#[tokio::test]
+async fn test_client_expected_name_and_mac_match() {
+    let (client_stream, server_stream) = duplex(1024);
+
+    let api = EspHomeApi::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .mac("AA:BB:CC:DD:EE:FF".to_string())
+        .encryption_key(NOISE_PSK.to_string())
+        .build();
+    let client = EspHomeClient::builder()
+        .encryption_key(NOISE_PSK.to_string())
+        .expected_name(TEST_DEVICE_NAME.to_string())
+        .expected_mac("aabbccddeeff".to_string())
+        .build();
+
+    let (start_result, connect_result) =
+        tokio::join!(api.start(server_stream), client.start(client_stream));
+    start_result.expect("server start failed");
+    connect_result.expect("client connect failed");
+}
+
+#[tokio::test]
+async fn test_client_expected_name_mismatch() {
+    let (client_stream, server_stream) = duplex(1024);
+
+    let api = EspHomeApi::builder()
+        .name("other_device".to_string())
+        .encryption_key(NOISE_PSK.to_string())
+        .build();
+    let client = EspHomeClient::builder()
+        .encryption_key(NOISE_PSK.to_string())
+        .expected_name(TEST_DEVICE_NAME.to_string())
+        .build();
+
+    let (_, connect_result) = tokio::join!(api.start(server_stream), client.start(client_stream));
+    match connect_result
+        .err()
+        .expect("wrong device should be rejected")
+    {
+        ClientError::NameMismatch { expected, received } => {
+            assert_eq!(expected, TEST_DEVICE_NAME);
+            assert_eq!(received, "other_device");
+        }
+        err => panic!("Unexpected error: {}", err),
+    }
+}
+
+#[tokio::test]
+async fn test_client_expected_mac_mismatch() {
+    let (client_stream, server_stream) = duplex(1024);
+
+    let api = EspHomeApi::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .mac("AA:BB:CC:DD:EE:00".to_string())
+        .encryption_key(NOISE_PSK.to_string())
+        .build();
+    let client = EspHomeClient::builder()
+        .encryption_key(NOISE_PSK.to_string())
+        .expected_mac("AA:BB:CC:DD:EE:FF".to_string())
+        .build();
+
+    let (_, connect_result) = tokio::join!(api.start(server_stream), client.start(client_stream));
+    assert!(matches!(
+        connect_result
+            .err()
+            .expect("wrong device should be rejected"),
+        ClientError::MacMismatch { .. }
+    ));
+}
+
+#[tokio::test]
+async fn test_client_list_entities() {
+    use esphome_native_api::esphomeclient::entities::Domain;
+    use esphome_native_api::parser::ProtoMessage;
+    use esphome_native_api::proto::{
+        ListEntitiesDoneResponse, ListEntitiesSensorResponse, ListEntitiesSwitchResponse,
+    };
+
+    let (client_stream, server_stream) = duplex(1024);
+
+    let api = EspHomeApi::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .build();
+    let client = EspHomeClient::builder().build();
+
+    let (start_result, connect_result) =
+        tokio::join!(api.start(server_stream), client.start(client_stream));
+    let (tx, mut rx) = start_result.expect("server start failed");
+    let connection = connect_result.expect("client connect failed");
+
+    tokio::spawn(async move {
+        while let Ok(message) = rx.recv().await {
+            if let ProtoMessage::ListEntitiesRequest(_) = message {
+                tx.send(ProtoMessage::ListEntitiesSensorResponse(
+                    ListEntitiesSensorResponse {
+                        key: 1,
+                        object_id: "temperature".to_string(),
+                        name: "Temperature".to_string(),
+                        unit_of_measurement: "°C".to_string(),
+                        ..Default::default()
+                    },
+                ))
+                .await
+                .unwrap();
+                tx.send(ProtoMessage::ListEntitiesSwitchResponse(
+                    ListEntitiesSwitchResponse {
+                        key: 2,
+                        object_id: "relay".to_string(),
+                        name: "Relay".to_string(),
+                        ..Default::default()
+                    },
+                ))
+                .await
+                .unwrap();
+                tx.send(ProtoMessage::ListEntitiesDoneResponse(
+                    ListEntitiesDoneResponse {},
+                ))
+                .await
+                .unwrap();
+            }
+        }
+    });
+
+    let catalog = connection
+        .list_entities()
+        .await
+        .expect("list entities failed");
+
+    assert_eq!(catalog.len(), 2);
+    assert_eq!(catalog.sensors().next().unwrap().unit_of_measurement, "°C");
+    assert_eq!(catalog.get_by_object_id("relay").unwrap().key(), 2);
+    assert_eq!(catalog.get(1).unwrap().domain(), Domain::Sensor);
+}
+
+#[tokio::test]
+async fn test_client_subscribe_states() {
+    use esphome_native_api::esphomeclient::states::EntityState;
+    use esphome_native_api::parser::ProtoMessage;
+    use esphome_native_api::proto::{SensorStateResponse, SwitchStateResponse};
+
+    let (client_stream, server_stream) = duplex(1024);
+
+    let api = EspHomeApi::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .build();
+    let client = EspHomeClient::builder().build();
+
+    let (start_result, connect_result) =
+        tokio::join!(api.start(server_stream), client.start(client_stream));
+    let (tx, mut rx) = start_result.expect("server start failed");
+    let connection = connect_result.expect("client connect failed");
+
+    let device_tx = tx.clone();
+    tokio::spawn(async move {
+        while let Ok(message) = rx.recv().await {
+            if let ProtoMessage::SubscribeStatesRequest(_) = message {
+                device_tx
+                    .send(ProtoMessage::SensorStateResponse(SensorStateResponse {
+                        key: 1,
+                        state: 20.0,
+                        ..Default::default()
+                    }))
+                    .await
+                    .unwrap();
+                device_tx
+                    .send(ProtoMessage::SwitchStateResponse(SwitchStateResponse {
+                        key: 2,
+                        state: true,
+                        ..Default::default()
+                    }))
+                    .await
+                    .unwrap();
+            }
+        }
+    });
+
+    let states = connection
+        .subscribe_states()
+        .await
+        .expect("subscribe states failed");
+    let mut changes = states.changes();
+    let mut temperature = states.watch(1);
+
+    while states.states().len() < 2 {
+        changes.recv().await.unwrap();
+    }
+    assert!(matches!(
+        states.state_of(2),
+        Some(EntityState::Switch(SwitchStateResponse { state: true, .. }))
+    ));
+
+    tx.send(ProtoMessage::SensorStateResponse(SensorStateResponse {
+        key: 1,
+        state: 21.5,
+        ..Default::default()
+    }))
+    .await
+    .unwrap();
+
+    temperature
+        .wait_for(|state| {
+            matches!(
+                state,
+                Some(EntityState::Sensor(SensorStateResponse { state, .. })) if *state == 21.5
+            )
+        })
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_managed_connection_reconnects_and_resubscribes() {
+    use esphome_native_api::esphomeclient::reconnect::{
+        Backoff, ConnectionEvent, ConnectionState, ManagedConnection,
+    };
+    use esphome_native_api::esphomeclient::states::EntityState;
+    use esphome_native_api::parser::ProtoMessage;
+    use esphome_native_api::proto::{
+        DisconnectResponse, ListEntitiesDoneResponse, ListEntitiesSensorResponse,
+        SensorStateResponse,
+    };
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    let (server_streams_tx, mut server_streams_rx) = mpsc::unbounded_channel();
+    let (device_txs_tx, mut device_txs_rx) = mpsc::unbounded_channel();
+
+    // Every accepted connection answers with a state that counts the connections.
+    tokio::spawn(async move {
+        let mut connection_count = 0.0;
+        while let Some(server_stream) = server_streams_rx.recv().await {
+            connection_count += 1.0;
+            let api = EspHomeApi::builder()
+                .name(TEST_DEVICE_NAME.to_string())
+                .build();
+            let (tx, mut rx) = api.start(server_stream).await.unwrap();
+            device_txs_tx.send(tx.clone()).unwrap();
+            tokio::spawn(async move {
+                while let Ok(message) = rx.recv().await {
+                    match message {
+                        ProtoMessage::ListEntitiesRequest(_) => {
+                            tx.send(ProtoMessage::ListEntitiesSensorResponse(
+                                ListEntitiesSensorResponse {
+                                    key: 1,
+                                    object_id: "boot_count".to_string(),
+                                    ..Default::default()
+                                },
+                            ))
+                            .await
+                            .unwrap();
+                            tx.send(ProtoMessage::ListEntitiesDoneResponse(
+                                ListEntitiesDoneResponse {},
+                            ))
+                            .await
+                            .unwrap();
+                        }
+                        ProtoMessage::SubscribeStatesRequest(_) => {
+                            tx.send(ProtoMessage::SensorStateResponse(SensorStateResponse {
+                                key: 1,
+                                state: connection_count,
+                                ..Default::default()
+                            }))
+                            .await
+                            .unwrap();
+                        }
+                        _ => {}
+                    }
+                }
+            });
+        }
+    });
+
+    let backoff = Backoff::builder()
+        .initial(Duration::from_millis(10))
+        .build();
+    let managed =
+        ManagedConnection::connect(EspHomeClient::builder().build(), backoff, move || {
+            let (client_stream, server_stream) = duplex(1024);
+            let sent = server_streams_tx.send(server_stream);
+            async move {
+                sent.map_err(|_| std::io::Error::other("device gone"))?;
+                Ok(client_stream)
+            }
+        });
+    let mut events = managed.events();
+
+    // Subscriptions made before the first connect are sent once connected.
+    let states = managed.subscribe_states().await.unwrap();
+    let mut boot_count = states.watch(1);
+    let wait_for_boot_count = |count: f32| {
+        move |state: &Option<EntityState>| {
+            matches!(
+                state,
+                Some(EntityState::Sensor(SensorStateResponse { state, .. })) if *state == count
+            )
+        }
+    };
+
+    managed.connected().await.unwrap();
+    boot_count.wait_for(wait_for_boot_count(1.0)).await.unwrap();
+    assert_eq!(managed.entities().len(), 1);
+
+    // Simulate a device reboot by dropping the connection from the device side.
+    let device_tx = device_txs_rx.recv().await.unwrap();
+    let dropped_at = tokio::time::Instant::now();
+    device_tx
+        .send(ProtoMessage::DisconnectResponse(DisconnectResponse {}))
+        .await
+        .unwrap();
+
+    boot_count.wait_for(wait_for_boot_count(2.0)).await.unwrap();
+    // Dropped connections are retried with backoff as well.
+    assert!(dropped_at.elapsed() >= Duration::from_millis(10));
+    assert_eq!(managed.state(), ConnectionState::Connected);
+
+    let mut received = Vec::new();
+    while let Ok(event) = events.try_recv() {
+        received.push(event);
+    }
+    assert!(matches!(
+        received.as_slice(),
+        [
+            ConnectionEvent::Connected { .. },
+            ConnectionEvent::Disconnected,
+            ConnectionEvent::Connected { .. }
+        ]
+    ));
+
+    managed.close().await;
+    assert_eq!(managed.state(), ConnectionState::Closed);
+}
+
+#[tokio::test]
+async fn test_client_keepalive_detects_dead_device() {
+    use esphome_native_api::keepalive::Keepalive;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::Notify;
+
+    let (client_stream, proxy_client_stream) = duplex(1024);
+    let (proxy_device_stream, server_stream) = duplex(1024);
+
+    // Forwards traffic until frozen, then keeps both streams open like a half-open connection.
+    let freeze = Arc::new(Notify::new());
+    let freeze_proxy = freeze.clone();
+    tokio::spawn(async move {
+        let (mut client_read, mut client_write) = tokio::io::split(proxy_client_stream);
+        let (mut device_read, mut device_write) = tokio::io::split(proxy_device_stream);
+        tokio::select! {
+            _ = tokio::io::copy(&mut client_read, &mut device_write) => {}
+            _ = tokio::io::copy(&mut device_read, &mut client_write) => {}
+            _ = freeze_proxy.notified() => {}
+        }
+        std::future::pending::<()>().await;
+    });
+
+    let api = EspHomeApi::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .keepalive_opt(None)
+        .build();
+    let client = EspHomeClient::builder()
+        .keepalive(
+            Keepalive::builder()
+                .interval(Duration::from_millis(50))
+                .timeout(Duration::from_millis(200))
+                .build(),
+        )
+        .build();
+
+    let (start_result, connect_result) =
+        tokio::join!(api.start(server_stream), client.start(client_stream));
+    let (_tx, _rx) = start_result.expect("server start failed");
+    let connection = connect_result.expect("client connect failed");
+
+    // Pings are answered, so the connection stays up past the timeout.
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    assert!(!connection.is_closed());
+
+    freeze.notify_one();
+    tokio::time::timeout(Duration::from_secs(2), connection.closed())
+        .await
+        .expect("client did not detect the dead device");
+}
+
+#[tokio::test]
+async fn test_client_request_helpers() {
+    use esphome_native_api::parser::ProtoMessage;
+    use esphome_native_api::proto::GetTimeResponse;
+    use std::time::Duration;
+
+    let (client_stream, server_stream) = duplex(1024);
+
+    let api = EspHomeApi::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .build();
+    let client = EspHomeClient::builder()
+        .request_timeout(Duration::from_millis(200))
+        .build();
+
+    let (start_result, connect_result) =
+        tokio::join!(api.start(server_stream), client.start(client_stream));
+    let (tx, mut rx) = start_result.expect("server start failed");
+    let connection = connect_result.expect("client connect failed");
+
+    let device_info = connection.device_info().await.expect("device info failed");
+    assert_eq!(device_info.name, TEST_DEVICE_NAME);
+
+    connection.ping().await.expect("ping failed");
+
+    // Nobody answers the time request yet.
+    assert!(matches!(
+        connection.get_time().await,
+        Err(ClientError::Timeout)
+    ));
+
+    tokio::spawn(async move {
+        while let Ok(message) = rx.recv().await {
+            if let ProtoMessage::GetTimeRequest(_) = message {
+                tx.send(ProtoMessage::GetTimeResponse(GetTimeResponse {
+                    epoch_seconds: 1_700_000_000,
+                    ..Default::default()
+                }))
+                .await
+                .unwrap();
+            }
+        }
+    });
+    let time = connection.get_time().await.expect("get time failed");
+    assert_eq!(time.epoch_seconds, 1_700_000_000);
+}
+
+#[tokio::test]
+async fn test_client_sends_typed_commands() {
+    use esphome_native_api::esphomeclient::commands::{light, switch};
+    use esphome_native_api::parser::ProtoMessage;
+
+    let (client_stream, server_stream) = duplex(1024);
+
+    let api = EspHomeApi::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .build();
+    let client = EspHomeClient::builder().build();
+
+    let (start_result, connect_result) =
+        tokio::join!(api.start(server_stream), client.start(client_stream));
+    let (_tx, mut rx) = start_result.expect("server start failed");
+    let connection = connect_result.expect("client connect failed");
+
+    connection
+        .command(light(1).turn_on().brightness(0.5).transition(2.0))
+        .await
+        .unwrap();
+    connection.command(switch(2).turn_off()).await.unwrap();
+
+    match rx.recv().await.unwrap() {
+        ProtoMessage::LightCommandRequest(request) => {
+            assert_eq!(request.key, 1);
+            assert!(request.has_state && request.state);
+            assert!(request.has_brightness);
+            assert_eq!(request.brightness, 0.5);
+            assert_eq!(request.transition_length, 2000);
+            assert!(!request.has_rgb);
+        }
+        message => panic!("Unexpected message: {:?}", message),
+    }
+    match rx.recv().await.unwrap() {
+        ProtoMessage::SwitchCommandRequest(request) => {
+            assert_eq!(request.key, 2);
+            assert!(!request.state);
+        }
+        message => panic!("Unexpected message: {:?}", message),
+    }
+}
+
+#[tokio::test]
+async fn test_client_subscribe_logs() {
+    use esphome_native_api::parser::ProtoMessage;
+    use esphome_native_api::proto::{LogLevel, SubscribeLogsResponse};
+
+    let (client_stream, server_stream) = duplex(1024);
+
+    let api = EspHomeApi::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .build();
+    let client = EspHomeClient::builder().build();
+
+    let (start_result, connect_result) =
+        tokio::join!(api.start(server_stream), client.start(client_stream));
+    let (tx, mut rx) = start_result.expect("server start failed");
+    let connection = connect_result.expect("client connect failed");
+
+    let (requests_tx, mut requests_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok(message) = rx.recv().await {
+            if let ProtoMessage::SubscribeLogsRequest(request) = message {
+                if request.dump_config {
+                    tx.send(ProtoMessage::SubscribeLogsResponse(SubscribeLogsResponse {
+                        level: LogLevel::Config.into(),
+                        message: b"\x1b[0;35m[C][wifi:443]: WiFi:\x1b[0m".to_vec(),
+                    }))
+                    .await
+                    .unwrap();
+                }
+                requests_tx.send(request).unwrap();
+            }
+        }
+    });
+
+    let mut logs = connection
+        .subscribe_logs(LogLevel::Debug, true)
+        .await
+        .expect("subscribe logs failed");
+    let record = logs.recv().await.expect("no log record");
+    assert_eq!(record.level, LogLevel::Config);
+    assert_eq!(record.tag.as_deref(), Some("wifi"));
+    assert_eq!(record.line, Some(443));
+    assert_eq!(record.message, "WiFi:");
+    let request = requests_rx.recv().await.unwrap();
+    assert_eq!(request.level, LogLevel::Debug as i32);
+
+    // A less verbose subscription keeps the level of the open streams.
+    let _info = connection
+        .subscribe_logs(LogLevel::Info, false)
+        .await
+        .expect("subscribe logs failed");
+    let _verbose = connection
+        .subscribe_logs(LogLevel::Verbose, false)
+        .await
+        .expect("subscribe logs failed");
+    let request = requests_rx.recv().await.unwrap();
+    assert_eq!(request.level, LogLevel::Verbose as i32);
+    assert!(!request.dump_config);
+}
+
+#[tokio::test]
+async fn test_client_camera_snapshot_and_stream() {
+    use esphome_native_api::camera;
+    use esphome_native_api::parser::ProtoMessage;
+
+    let (client_stream, server_stream) = duplex(1024);
+
+    let api = EspHomeApi::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .build();
+    let client = EspHomeClient::builder().build();
+
+    let (start_result, connect_result) =
+        tokio::join!(api.start(server_stream), client.start(client_stream));
+    let (tx, mut rx) = start_result.expect("server start failed");
+    let connection = connect_result.expect("client connect failed");
+
+    let front: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+    let back: Vec<u8> = vec![0xAB; 3000];
+    let (front_image, back_image) = (front.clone(), back.clone());
+    tokio::spawn(async move {
+        let mut streaming = false;
+        while let Ok(message) = rx.recv().await {
+            if let ProtoMessage::CameraImageRequest(request) = message {
+                if streaming {
+                    // The tail of an image streamed before the request.
+                    let tail = camera::chunk_image(2, &[0xFF; 10], 1024).remove(0);
+                    tx.send(ProtoMessage::CameraImageResponse(tail))
+                        .await
+                        .unwrap();
+                }
+                streaming |= request.stream;
+                // Interleave the chunks of both cameras.
+                let mut front_chunks = camera::chunk_image(1, &front_image, 1024).into_iter();
+                let mut back_chunks = camera::chunk_image(2, &back_image, 1024).into_iter();
+                loop {
+                    let chunks: Vec<_> = front_chunks
+                        .next()
+                        .into_iter()
+                        .chain(back_chunks.next())
+                        .collect();
+                    if chunks.is_empty() {
+                        break;
+                    }
+                    for chunk in chunks {
+                        tx.send(ProtoMessage::CameraImageResponse(chunk))
+                            .await
+                            .unwrap();
+                    }
+                }
+            }
+        }
+    });
+
+    let snapshot = connection
+        .camera_snapshot(2)
+        .await
+        .expect("camera snapshot failed");
+    assert_eq!(snapshot, back);
+
+    let mut stream = connection
+        .camera_stream(None)
+        .await
+        .expect("camera stream failed");
+    let mut frames = [
+        stream.recv().await.expect("no frame"),
+        stream.recv().await.expect("no frame"),
+    ];
+    frames.sort_by_key(|frame| frame.key);
+    assert_eq!(frames[0].data, front);
+    assert_eq!(frames[1].data, back);
+
+    // With a stream open the snapshot skips the rest of the current image.
+    let snapshot = connection
+        .camera_snapshot(2)
+        .await
+        .expect("camera snapshot failed");
+    assert_eq!(snapshot, back);
+}
+
+#[tokio::test]
+async fn test_client_bluetooth_gatt_operations() {
+    use esphome_native_api::parser::ProtoMessage;
+    use esphome_native_api::proto::{
+        BluetoothDeviceConnectionResponse, BluetoothGattCharacteristic, BluetoothGattErrorResponse,
+        BluetoothGattGetServicesDoneResponse, BluetoothGattGetServicesResponse,
+        BluetoothGattNotifyDataResponse, BluetoothGattNotifyResponse, BluetoothGattReadResponse,
+        BluetoothGattService, BluetoothGattWriteResponse,
+    };
+
+    const ADDRESS: u64 = 0xA4C1380D1E2F;
+    let (client_stream, server_stream) = duplex(4096);
+
+    let api = EspHomeApi::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .build();
+    let client = EspHomeClient::builder().build();
+
+    let (start_result, connect_result) =
+        tokio::join!(api.start(server_stream), client.start(client_stream));
+    let (tx, mut rx) = start_result.expect("server start failed");
+    let connection = connect_result.expect("client connect failed");
+
+    // A minimal Bluetooth proxy with one service and a failing handle 0x30.
+    tokio::spawn(async move {
+        while let Ok(message) = rx.recv().await {
+            let responses = match message {
+                ProtoMessage::BluetoothDeviceRequest(request) => {
+                    let connected = request.request_type != 1;
+                    vec![ProtoMessage::BluetoothDeviceConnectionResponse(
+                        BluetoothDeviceConnectionResponse {
+                            address: request.address,
+                            connected,
+                            mtu: if connected { 247 } else { 0 },
+                            error: 0,
+                        },
+                    )]
+                }
+                ProtoMessage::BluetoothGattGetServicesRequest(request) => (0..2)
+                    .map(|index| {
+                        ProtoMessage::BluetoothGattGetServicesResponse(
+                            BluetoothGattGetServicesResponse {
+                                address: request.address,
+                                services: vec![BluetoothGattService {
+                                    handle: 0x10 * (index + 1),
+                                    characteristics: vec![BluetoothGattCharacteristic {
+                                        handle: 0x10 * (index + 1) + 1,
+                                        ..Default::default()
+                                    }],
+                                    ..Default::default()
+                                }],
+                            },
+                        )
+                    })
+                    .chain([ProtoMessage::BluetoothGattGetServicesDoneResponse(
+                        BluetoothGattGetServicesDoneResponse {
+                            address: request.address,
+                        },
+                    )])
+                    .collect(),
+                ProtoMessage::BluetoothGattReadRequest(request) if request.handle == 0x30 => {
+                    vec![ProtoMessage::BluetoothGattErrorResponse(
+                        BluetoothGattErrorResponse {
+                            address: request.address,
+                            handle: request.handle,
+                            error: 5,
+                        },
+                    )]
+                }
+                ProtoMessage::BluetoothGattReadRequest(request) => {
+                    vec![ProtoMessage::BluetoothGattReadResponse(
+                        BluetoothGattReadResponse {
+                            address: request.address,
+                            handle: request.handle,
+                            data: vec![0x64],
+                        },
+                    )]
+                }
+                ProtoMessage::BluetoothGattWriteRequest(request) => {
+                    assert_eq!(request.data, vec![0x01, 0x02]);
+                    vec![ProtoMessage::BluetoothGattWriteResponse(
+                        BluetoothGattWriteResponse {
+                            address: request.address,
+                            handle: request.handle,
+                        },
+                    )]
+                }
+                ProtoMessage::BluetoothGattNotifyRequest(request) => vec![
+                    ProtoMessage::BluetoothGattNotifyResponse(BluetoothGattNotifyResponse {
+                        address: request.address,
+                        handle: request.handle,
+                    }),
+                    ProtoMessage::BluetoothGattNotifyDataResponse(
+                        BluetoothGattNotifyDataResponse {
+                            address: request.address,
+                            handle: request.handle,
+                            data: vec![0xAA],
+                        },
+                    ),
+                ],
+                _ => vec![],
+            };
+            for response in responses {
+                tx.send(response).await.unwrap();
+            }
+        }
+    });
+
+    let device = connection
+        .bluetooth_connect(ADDRESS)
+        .await
+        .expect("bluetooth connect failed");
+    assert_eq!(device.mtu(), 247);
+
+    let services = device.services().await.expect("service discovery failed");
+    assert_eq!(services.len(), 2);
+    assert_eq!(services[1].characteristics[0].handle, 0x21);
+
+    assert_eq!(device.read(0x21).await.expect("read failed"), vec![0x64]);
+    match device.read(0x30).await {
+        Err(ClientError::Gatt {
+            address,
+            handle,
+            error,
+        }) => assert_eq!((address, handle, error), (ADDRESS, 0x30, 5)),
+        other => panic!("expected GATT error, got {:?}", other),
+    }
+    device
+        .write(0x11, vec![0x01, 0x02], true)
+        .await
+        .expect("write failed");
+
+    let mut notifications = device.notify(0x11).await.expect("notify failed");
+    assert_eq!(notifications.recv().await, Some(vec![0xAA]));
+
+    device.disconnect().await.expect("disconnect failed");
+    assert_eq!(notifications.recv().await, None);
+}
+
+#[tokio::test]
+async fn test_client_answers_time_sync_of_device() {
+    use esphome_native_api::time_sync::TimeSync;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    let (client_stream, server_stream) = duplex(1024);
+
+    let time_sync = TimeSync::builder().build();
+    let api = EspHomeApi::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .time_sync(time_sync.clone())
+        .build();
+    let client = EspHomeClient::builder()
+        .timezone("CET-1CEST,M3.5.0,M10.5.0/3".to_string())
+        .build();
+
+    let (start_result, connect_result) =
+        tokio::join!(api.start(server_stream), client.start(client_stream));
+    let (_tx, _rx) = start_result.expect("server start failed");
+    let _connection = connect_result.expect("client connect failed");
+
+    let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let time = tokio::time::timeout(Duration::from_secs(2), time_sync.synced())
+        .await
+        .expect("time not synced in time");
+    assert!(time.epoch_seconds as u64 + 5 >= before.as_secs());
+    assert_eq!(time.timezone.as_deref(), Some("CET-1CEST,M3.5.0,M10.5.0/3"));
+    assert!(time_sync.now().is_some());
+}
@@ -0,0 +1,501 @@
+use esphome_native_api::esphomeclient::entities::Domain;
+use esphome_native_api::esphomeclient::states::EntityState;
+use esphome_native_api::esphomeclient::{ClientConnection, EspHomeClient};
+use esphome_native_api::esphomeserver::{
+    BinarySensor, Entity, EspHomeServer, Light, Select, Sensor, Switch,
+};
+use esphome_native_api::hash::hash_fnv1;
+use esphome_native_api::mdns::{MDNS_GROUP, MdnsConfig};
+use esphome_native_api::parser::ProtoMessage;
+use esphome_native_api::proto::{
+    BinarySensorStateResponse, ColorMode, LogLevel, SensorStateResponse,
+    SubscribeHomeassistantServicesRequest, SwitchCommandRequest,
+};
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::broadcast;
+use tokio::time::timeout;
+
+const TEST_DEVICE_NAME: &str = "test_device";
+
+/// A UDP port that was free a moment ago, so parallel tests don't share one.
+fn free_port() -> u16 {
+    std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Connects a client to `server`, the receiver of the server side has to be kept.
+async fn connect(server: &EspHomeServer) -> (ClientConnection, broadcast::Receiver<ProtoMessage>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let client = EspHomeClient::builder().build();
+    let (accepted, connect_result) = tokio::join!(
+        async {
+            let (stream, _) = listener.accept().await.unwrap();
+            server.start(stream).await.expect("server start failed")
+        },
+        async {
+            client
+                .start(TcpStream::connect(address).await.unwrap())
+                .await
+        }
+    );
+    let (_tx, rx) = accepted;
+    (connect_result.expect("client connect failed"), rx)
+}
+
+#[tokio::test]
+async fn test_server_passes_encryption_and_project_to_the_api() {
+    const NOISE_PSK: &str = "xiahAckHBW7BcKEQ6mRfasIW20Md9uMh/5PjrjbAhXQ=";
+    let server = EspHomeServer::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .encryption_key(NOISE_PSK.to_string())
+        .project_name("test.project".to_string())
+        .project_version("1.2.3".to_string())
+        .build();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let client = EspHomeClient::builder()
+        .encryption_key(NOISE_PSK.to_string())
+        .build();
+    let (start_result, connect_result) = tokio::join!(
+        async {
+            let (stream, _) = listener.accept().await.unwrap();
+            server.start(stream).await.expect("server start failed")
+        },
+        client.start(TcpStream::connect(address).await.unwrap())
+    );
+    let (_tx, _rx) = start_result;
+    let connection = connect_result.expect("client connect failed");
+
+    let device_info = connection.device_info_response();
+    assert!(device_info.api_encryption_supported);
+    assert_eq!(device_info.project_name, "test.project");
+    assert_eq!(device_info.project_version, "1.2.3");
+}
+
+#[tokio::test]
+async fn test_server_lists_entities() {
+    let mut server = EspHomeServer::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .build();
+    server
+        .add_entity(
+            "door",
+            BinarySensor {
+                object_id: "front_door".to_string(),
+                name: "Front door".to_string(),
+                device_class: "door".to_string(),
+                ..Default::default()
+            }
+            .into(),
+        )
+        .unwrap();
+    server
+        .add_entity(
+            "ceiling_light",
+            Entity::Light(Light {
+                name: "Ceiling light".to_string(),
+                supported_color_modes: vec![ColorMode::Brightness],
+                min_mireds: 153.0,
+                max_mireds: 500.0,
+                ..Default::default()
+            }),
+        )
+        .unwrap();
+    server
+        .add_entity(
+            "mode",
+            Select {
+                object_id: "mode".to_string(),
+                options: vec!["eco".to_string(), "comfort".to_string()],
+                ..Default::default()
+            }
+            .into(),
+        )
+        .unwrap();
+    assert_eq!(
+        server.key("door"),
+        Some(hash_fnv1(&"front_door".to_string()))
+    );
+
+    let (connection, _rx) = connect(&server).await;
+
+    let catalog = connection.list_entities().await.expect("listing failed");
+    assert_eq!(catalog.len(), 3);
+
+    let door = catalog.get_by_object_id("front_door").unwrap();
+    assert_eq!(door.domain(), Domain::BinarySensor);
+    assert_eq!(door.key(), server.key("door").unwrap());
+    assert_eq!(
+        catalog.binary_sensors().next().unwrap().device_class,
+        "door"
+    );
+
+    // The object id defaults to the entity id.
+    let light = catalog.lights().next().unwrap();
+    assert_eq!(light.object_id, "ceiling_light");
+    assert_eq!(light.key, server.key("ceiling_light").unwrap());
+    assert_eq!(
+        light.supported_color_modes,
+        vec![ColorMode::Brightness as i32]
+    );
+
+    assert_eq!(catalog.selects().next().unwrap().options.len(), 2);
+}
+
+#[tokio::test]
+async fn test_server_dumps_and_publishes_states() {
+    let mut server = EspHomeServer::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .build();
+    server
+        .add_entity("temperature", Sensor::default().into())
+        .unwrap();
+    server
+        .add_entity("motion", BinarySensor::default().into())
+        .unwrap();
+    let temperature_key = server.key("temperature").unwrap();
+
+    let state = SensorStateResponse {
+        state: 21.5,
+        ..Default::default()
+    };
+    assert_eq!(server.publish_state("temperature", state).await.unwrap(), 0);
+    assert!(
+        server
+            .publish_state("humidity", SensorStateResponse::default())
+            .await
+            .is_err()
+    );
+    assert!(
+        server
+            .publish_state("motion", SensorStateResponse::default())
+            .await
+            .is_err()
+    );
+
+    let (connection, _rx) = connect(&server).await;
+    let (unsubscribed, _unsubscribed_rx) = connect(&server).await;
+    let mut unsubscribed_messages = unsubscribed.subscribe();
+
+    let cache = connection.subscribe_states().await.unwrap();
+    let mut temperature = cache.watch(temperature_key);
+    let dumped = timeout(
+        Duration::from_secs(5),
+        temperature.wait_for(|state| state.is_some()),
+    )
+    .await
+    .expect("state not dumped in time")
+    .unwrap()
+    .clone();
+    let Some(EntityState::Sensor(dumped)) = dumped else {
+        panic!("unexpected state {:?}", dumped);
+    };
+    assert_eq!(dumped.state, 21.5);
+    assert_eq!(dumped.key, temperature_key);
+
+    let motion = BinarySensorStateResponse {
+        state: true,
+        ..Default::default()
+    };
+    assert_eq!(server.publish_state("motion", motion).await.unwrap(), 1);
+    let mut motion = cache.watch(server.key("motion").unwrap());
+    timeout(
+        Duration::from_secs(5),
+        motion.wait_for(|state| state.is_some()),
+    )
+    .await
+    .expect("state not published in time")
+    .unwrap();
+    assert!(matches!(
+        server.state("motion"),
+        Some(EntityState::BinarySensor(BinarySensorStateResponse {
+            state: true,
+            ..
+        }))
+    ));
+
+    unsubscribed.ping().await.expect("ping failed");
+    while let Ok(message) = unsubscribed_messages.try_recv() {
+        assert!(!matches!(
+            message,
+            ProtoMessage::BinarySensorStateResponse(_) | ProtoMessage::SensorStateResponse(_)
+        ));
+    }
+}
+
+#[tokio::test]
+async fn test_server_fans_out_to_all_connections() {
+    let mut server = EspHomeServer::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .build();
+    server
+        .add_entity("temperature", Sensor::default().into())
+        .unwrap();
+
+    let (first, _first_rx) = connect(&server).await;
+    let (second, _second_rx) = connect(&server).await;
+    assert_eq!(server.connection_count(), 2);
+
+    let first_states = first.subscribe_states().await.unwrap();
+    let second_states = second.subscribe_states().await.unwrap();
+    let mut first_logs = first.subscribe_logs(LogLevel::Info, false).await.unwrap();
+    second
+        .send(ProtoMessage::SubscribeHomeassistantServicesRequest(
+            SubscribeHomeassistantServicesRequest {},
+        ))
+        .await
+        .unwrap();
+    // Ping round trips ensure the server handled the subscriptions.
+    first.ping().await.unwrap();
+    second.ping().await.unwrap();
+
+    let state = SensorStateResponse {
+        state: 19.0,
+        ..Default::default()
+    };
+    assert_eq!(server.publish_state("temperature", state).await.unwrap(), 2);
+    let key = server.key("temperature").unwrap();
+    for states in [&first_states, &second_states] {
+        timeout(
+            Duration::from_secs(5),
+            states.watch(key).wait_for(|state| state.is_some()),
+        )
+        .await
+        .expect("state not received in time")
+        .unwrap();
+    }
+
+    assert_eq!(
+        server.log(LogLevel::Debug, "sensor", "Too verbose").await,
+        0
+    );
+    assert_eq!(server.log(LogLevel::Info, "sensor", "Calibrated").await, 1);
+    let record = timeout(Duration::from_secs(5), first_logs.recv())
+        .await
+        .expect("log line not received in time")
+        .unwrap();
+    assert_eq!(record.tag.as_deref(), Some("sensor"));
+    assert_eq!(record.message, "Calibrated");
+
+    let delivered = server
+        .call_homeassistant_action(
+            "light.turn_on",
+            HashMap::new(),
+            HashMap::new(),
+            HashMap::new(),
+            false,
+        )
+        .await;
+    assert_eq!(delivered, 1);
+
+    // Closed connections are removed.
+    first.disconnect().await.unwrap();
+    timeout(Duration::from_secs(5), async {
+        while server.connection_count() > 1 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("connection not removed in time");
+    let state = SensorStateResponse {
+        state: 19.5,
+        ..Default::default()
+    };
+    assert_eq!(server.publish_state("temperature", state).await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn test_server_rejects_duplicate_keys() {
+    let mut server = EspHomeServer::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .build();
+    server
+        .add_entity("temperature", Sensor::default().into())
+        .unwrap();
+    let duplicate = Sensor {
+        object_id: "temperature".to_string(),
+        ..Default::default()
+    };
+    assert!(server.add_entity("outside", duplicate.into()).is_err());
+    assert!(server.key("outside").is_none());
+    assert!(
+        server
+            .publish_state("outside", SensorStateResponse::default())
+            .await
+            .is_err()
+    );
+
+    // Replacing an entity forgets the state of the previous one.
+    let state = SensorStateResponse {
+        state: 21.5,
+        ..Default::default()
+    };
+    server.publish_state("temperature", state).await.unwrap();
+    server
+        .add_entity("temperature", Sensor::default().into())
+        .unwrap();
+    assert!(server.state("temperature").is_none());
+}
+
+#[tokio::test]
+async fn test_server_serves_listener_until_shutdown() {
+    let mut server = EspHomeServer::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .max_connections(1)
+        .build();
+    server
+        .add_entity("relay", Switch::default().into())
+        .unwrap();
+    let key = server.key("relay").unwrap();
+    let mut incoming = server.subscribe();
+    let shutdown = server.shutdown_handle();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let client = async {
+        let connection = EspHomeClient::builder()
+            .build()
+            .start(TcpStream::connect(address).await.unwrap())
+            .await
+            .expect("client connect failed");
+        assert_eq!(connection.list_entities().await.unwrap().len(), 1);
+
+        // Only one client is served at a time.
+        let rejected = EspHomeClient::builder()
+            .build()
+            .start(TcpStream::connect(address).await.unwrap())
+            .await;
+        assert!(rejected.is_err());
+
+        connection
+            .send(ProtoMessage::SwitchCommandRequest(SwitchCommandRequest {
+                key,
+                state: true,
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        let command = timeout(Duration::from_secs(5), async {
+            loop {
+                if let Ok(ProtoMessage::SwitchCommandRequest(command)) = incoming.recv().await {
+                    return command;
+                }
+            }
+        })
+        .await
+        .expect("command not received in time");
+        assert_eq!(command.key, key);
+        assert!(command.state);
+
+        shutdown.cancel();
+        timeout(Duration::from_secs(5), connection.closed())
+            .await
+            .expect("client not disconnected in time");
+    };
+
+    let (served, ()) = tokio::join!(server.serve_listener(listener), client);
+    served.expect("serving failed");
+    assert_eq!(server.connection_count(), 0);
+}
+
+#[tokio::test]
+async fn test_server_closes_clients_ignoring_the_shutdown() {
+    let server = EspHomeServer::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .build();
+    let shutdown = server.shutdown_handle();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let client = async {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        // A plaintext PingRequest, the client never answers the DisconnectRequest.
+        stream.write_all(&[0x00, 0x00, 0x07]).await.unwrap();
+        let mut pong = [0; 3];
+        stream.read_exact(&mut pong).await.unwrap();
+        assert_eq!(pong, [0x00, 0x00, 0x08]);
+
+        shutdown.cancel();
+        let mut rest = Vec::new();
+        timeout(Duration::from_secs(10), stream.read_to_end(&mut rest))
+            .await
+            .expect("connection not closed in time")
+            .unwrap();
+    };
+
+    let (served, ()) = tokio::join!(server.serve_listener(listener), client);
+    served.expect("serving failed");
+    assert_eq!(server.connection_count(), 0);
+}
+
+#[tokio::test]
+async fn test_server_advertises_over_mdns() {
+    let mdns_port = free_port();
+    let server = EspHomeServer::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .friendly_name("Test Device".to_string())
+        .mac("00:00:00:00:00:AB".to_string())
+        .mdns(
+            MdnsConfig::builder()
+                .port(mdns_port)
+                .interface(Ipv4Addr::LOCALHOST)
+                .build(),
+        )
+        .build();
+    let shutdown = server.shutdown_handle();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let api_port = listener.local_addr().unwrap().port();
+
+    let client = async {
+        // PTR query for the ESPHome service type, answered to this port.
+        let mut query = vec![0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in ["_esphomelib", "_tcp", "local"] {
+            query.push(label.len() as u8);
+            query.extend_from_slice(label.as_bytes());
+        }
+        query.extend_from_slice(&[0, 0, 12, 0, 1]);
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.set_multicast_loop_v4(true).unwrap();
+
+        let mut buffer = [0; 9000];
+        let response = timeout(Duration::from_secs(5), async {
+            loop {
+                socket
+                    .send_to(&query, (MDNS_GROUP, mdns_port))
+                    .await
+                    .unwrap();
+                let received =
+                    timeout(Duration::from_millis(200), socket.recv_from(&mut buffer)).await;
+                if let Ok(Ok((length, _))) = received {
+                    return buffer[..length].to_vec();
+                }
+            }
+        })
+        .await
+        .expect("no mDNS response in time");
+        shutdown.cancel();
+        response
+    };
+
+    let (served, response) = tokio::join!(server.serve_listener(listener), client);
+    served.expect("serving failed");
+
+    let contains = |needle: &[u8]| {
+        response
+            .windows(needle.len())
+            .any(|window| window == needle)
+    };
+    assert!(contains(TEST_DEVICE_NAME.as_bytes()));
+    assert!(contains(b"friendly_name=Test Device"));
+    assert!(contains(b"mac=0000000000ab"));
+    assert!(contains(&api_port.to_be_bytes()));
+}
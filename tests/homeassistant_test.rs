@@ -0,0 +1,189 @@
+mod common;
+
+use esphome_native_api::esphomeapi::EspHomeApi;
+use esphome_native_api::esphomeclient::ClientConnection;
+use esphome_native_api::parser::ProtoMessage;
+use esphome_native_api::proto::{
+    HomeAssistantStateResponse, SubscribeHomeAssistantStatesRequest,
+    SubscribeHomeassistantServicesRequest,
+};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time::timeout;
+
+const TEST_DEVICE_NAME: &str = "test_device";
+
+async fn subscribe_actions(connection: &ClientConnection) {
+    connection
+        .send(ProtoMessage::SubscribeHomeassistantServicesRequest(
+            SubscribeHomeassistantServicesRequest {},
+        ))
+        .await
+        .expect("subscribe failed");
+    // A ping round trip ensures the device handled the subscription.
+    connection.ping().await.expect("ping failed");
+}
+
+#[tokio::test]
+async fn test_homeassistant_action_only_sent_to_subscribers() {
+    let api = EspHomeApi::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .build();
+    let (subscribed, _subscribed_rx) = common::connect(&api).await;
+    let (unsubscribed, _unsubscribed_rx) = common::connect(&api.clone()).await;
+    let mut subscribed_messages = subscribed.subscribe();
+    let mut unsubscribed_messages = unsubscribed.subscribe();
+
+    let no_action = api
+        .call_homeassistant_action(
+            "light.turn_on",
+            HashMap::new(),
+            HashMap::new(),
+            HashMap::new(),
+            false,
+        )
+        .await;
+    assert_eq!(no_action, 0);
+
+    subscribe_actions(&subscribed).await;
+    let delivered = api
+        .call_homeassistant_action(
+            "esphome.tag_scanned",
+            HashMap::from([("tag_id".to_string(), "04-A2-B1-C2".to_string())]),
+            HashMap::from([("message".to_string(), "{{ greeting }}".to_string())]),
+            HashMap::from([("greeting".to_string(), "Welcome".to_string())]),
+            true,
+        )
+        .await;
+    assert_eq!(delivered, 1);
+
+    let action = timeout(Duration::from_secs(5), async {
+        loop {
+            if let Ok(ProtoMessage::HomeassistantActionRequest(action)) =
+                subscribed_messages.recv().await
+            {
+                return action;
+            }
+        }
+    })
+    .await
+    .expect("action not received in time");
+    assert_eq!(action.service, "esphome.tag_scanned");
+    assert!(action.is_event);
+    assert_eq!(action.data[0].key, "tag_id");
+    assert_eq!(action.data[0].value, "04-A2-B1-C2");
+    assert_eq!(action.data_template[0].value, "{{ greeting }}");
+    assert_eq!(action.variables[0].key, "greeting");
+
+    unsubscribed.ping().await.expect("ping failed");
+    while let Ok(message) = unsubscribed_messages.try_recv() {
+        assert!(!matches!(
+            message,
+            ProtoMessage::HomeassistantActionRequest(_)
+        ));
+    }
+
+    subscribed.disconnect().await.expect("disconnect failed");
+    timeout(Duration::from_secs(5), subscribed.closed())
+        .await
+        .expect("connection not closed in time");
+    // Closed connections are forgotten.
+    let after_disconnect = api
+        .call_homeassistant_action(
+            "light.turn_off",
+            HashMap::new(),
+            HashMap::new(),
+            HashMap::new(),
+            false,
+        )
+        .await;
+    assert_eq!(after_disconnect, 0);
+}
+
+async fn next_state_request(messages: &mut broadcast::Receiver<ProtoMessage>) -> (String, String) {
+    timeout(Duration::from_secs(5), async {
+        loop {
+            if let Ok(ProtoMessage::SubscribeHomeAssistantStateResponse(request)) =
+                messages.recv().await
+            {
+                return (request.entity_id, request.attribute);
+            }
+        }
+    })
+    .await
+    .expect("state not requested in time")
+}
+
+async fn send_state(connection: &ClientConnection, entity_id: &str, attribute: &str, state: &str) {
+    connection
+        .send(ProtoMessage::HomeAssistantStateResponse(
+            HomeAssistantStateResponse {
+                entity_id: entity_id.to_string(),
+                state: state.to_string(),
+                attribute: attribute.to_string(),
+            },
+        ))
+        .await
+        .expect("send failed");
+}
+
+#[tokio::test]
+async fn test_homeassistant_state_import() {
+    let api = EspHomeApi::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .build();
+    let mut sun = api.import_homeassistant_state("sun.sun", None).await;
+    let mut elevation = api
+        .import_homeassistant_state("sun.sun", Some("elevation"))
+        .await;
+    assert_eq!(*sun.borrow(), None);
+
+    let (connection, _rx) = common::connect(&api).await;
+    let mut messages = connection.subscribe();
+    connection
+        .send(ProtoMessage::SubscribeHomeAssistantStatesRequest(
+            SubscribeHomeAssistantStatesRequest {},
+        ))
+        .await
+        .expect("subscribe failed");
+
+    let mut requested = vec![
+        next_state_request(&mut messages).await,
+        next_state_request(&mut messages).await,
+    ];
+    requested.sort();
+    assert_eq!(
+        requested,
+        vec![
+            ("sun.sun".to_string(), String::new()),
+            ("sun.sun".to_string(), "elevation".to_string()),
+        ]
+    );
+
+    send_state(&connection, "sun.sun", "", "above_horizon").await;
+    send_state(&connection, "sun.sun", "elevation", "42.5").await;
+    timeout(Duration::from_secs(5), sun.changed())
+        .await
+        .expect("state not received in time")
+        .unwrap();
+    assert_eq!(sun.borrow().as_deref(), Some("above_horizon"));
+    timeout(Duration::from_secs(5), elevation.changed())
+        .await
+        .expect("attribute not received in time")
+        .unwrap();
+    assert_eq!(elevation.borrow().as_deref(), Some("42.5"));
+
+    // Entities imported later are requested from subscribed connections right away.
+    let mut presence = api.import_homeassistant_state("person.anna", None).await;
+    assert_eq!(
+        next_state_request(&mut messages).await,
+        ("person.anna".to_string(), String::new())
+    );
+    send_state(&connection, "person.anna", "", "home").await;
+    timeout(Duration::from_secs(5), presence.changed())
+        .await
+        .expect("state not received in time")
+        .unwrap();
+    assert_eq!(presence.borrow().as_deref(), Some("home"));
+}
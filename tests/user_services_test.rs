@@ -0,0 +1,89 @@
+use esphome_native_api::esphomeapi::EspHomeApi;
+use esphome_native_api::esphomeclient::{ClientError, EspHomeClient};
+use esphome_native_api::parser::ProtoMessage;
+use esphome_native_api::proto::{ListEntitiesDoneResponse, ServiceArgType};
+use esphome_native_api::user_services::{ServiceError, ServiceValue, UserService};
+use std::time::Duration;
+use tokio::io::duplex;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+const TEST_DEVICE_NAME: &str = "test_device";
+
+#[tokio::test]
+async fn test_user_service_listed_and_executed() {
+    let (calls_tx, mut calls_rx) = mpsc::channel(1);
+    let play_tone = UserService::new(
+        "play_tone",
+        &[
+            ("frequency", ServiceArgType::Int),
+            ("melody", ServiceArgType::FloatArray),
+        ],
+        move |args| {
+            let calls_tx = calls_tx.clone();
+            async move {
+                calls_tx.send(args).await.unwrap();
+            }
+        },
+    );
+    let api = EspHomeApi::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .services(vec![play_tone])
+        .build();
+
+    let (client_stream, server_stream) = duplex(4096);
+    let client = EspHomeClient::builder().build();
+    let (start_result, connect_result) =
+        tokio::join!(api.start(server_stream), client.start(client_stream));
+    let (device_tx, mut device_rx) = start_result.expect("server start failed");
+    let connection = connect_result.expect("client connect failed");
+
+    // The device application has no entities of its own.
+    tokio::spawn(async move {
+        while let Ok(message) = device_rx.recv().await {
+            if let ProtoMessage::ListEntitiesRequest(_) = message {
+                device_tx
+                    .send(ProtoMessage::ListEntitiesDoneResponse(
+                        ListEntitiesDoneResponse {},
+                    ))
+                    .await
+                    .unwrap();
+            }
+        }
+    });
+
+    // The schema is fetched by the first call.
+    connection
+        .execute_service(
+            "play_tone",
+            &[
+                ("melody", vec![0.5f32, 0.25].into()),
+                ("frequency", 440.into()),
+            ],
+        )
+        .await
+        .expect("execute failed");
+    let args = timeout(Duration::from_secs(5), calls_rx.recv())
+        .await
+        .expect("service not called in time")
+        .unwrap();
+    assert_eq!(args["frequency"], ServiceValue::Int(440));
+    assert_eq!(args["melody"], ServiceValue::FloatArray(vec![0.5, 0.25]));
+
+    let catalog = connection.list_entities().await.expect("listing failed");
+    assert_eq!(catalog.services().len(), 1);
+    assert_eq!(catalog.services()[0].args[1].name, "melody");
+
+    assert!(matches!(
+        connection.execute_service("play_song", &[]).await,
+        Err(ClientError::UnknownService(_))
+    ));
+    assert!(matches!(
+        connection
+            .execute_service("play_tone", &[("frequency", "A4".into())])
+            .await,
+        Err(ClientError::ServiceArguments(
+            ServiceError::ArgumentType { .. }
+        ))
+    ));
+}
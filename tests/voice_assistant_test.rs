@@ -0,0 +1,547 @@
+mod common;
+
+use esphome_native_api::esphomeapi::EspHomeApi;
+use esphome_native_api::esphomeclient::ClientConnection;
+use esphome_native_api::esphomeclient::voice_assistant::AudioTransport;
+use esphome_native_api::parser::ProtoMessage;
+use esphome_native_api::proto::{
+    SubscribeVoiceAssistantRequest, VoiceAssistantAnnounceRequest, VoiceAssistantAudio,
+    VoiceAssistantConfigurationRequest, VoiceAssistantEventResponse, VoiceAssistantResponse,
+    VoiceAssistantSetConfiguration, VoiceAssistantWakeWord,
+};
+use esphome_native_api::voice_assistant::file::FileAudio;
+use esphome_native_api::voice_assistant::{
+    self, AudioDevice, PipelineEventType, VoiceAssistant, VoiceAssistantError, VoiceEvent,
+};
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Semaphore, broadcast};
+use tokio::time::timeout;
+
+const TEST_DEVICE_NAME: &str = "test_satellite";
+
+fn temp_file(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "esphome_native_api_{}_{}",
+        std::process::id(),
+        name
+    ));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+fn wake_word(id: &str) -> VoiceAssistantWakeWord {
+    VoiceAssistantWakeWord {
+        id: id.to_string(),
+        wake_word: id.replace('_', " "),
+        trained_languages: vec!["en".to_string()],
+    }
+}
+
+/// Connects a client to a satellite running `voice_assistant`.
+async fn connect(
+    voice_assistant: VoiceAssistant,
+) -> (ClientConnection, broadcast::Receiver<ProtoMessage>) {
+    let api = EspHomeApi::builder()
+        .name(TEST_DEVICE_NAME.to_string())
+        .voice_assistant(voice_assistant)
+        .build();
+    common::connect(&api).await
+}
+
+/// Waits until `path` holds `expected`, audio over UDP arrives independent of messages.
+async fn wait_for_file(path: &Path, expected: &[u8]) {
+    timeout(Duration::from_secs(5), async {
+        while std::fs::read(path).unwrap_or_default() != expected {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("audio not played in time");
+}
+
+async fn next_message(
+    messages: &mut broadcast::Receiver<ProtoMessage>,
+    matches: impl Fn(&ProtoMessage) -> bool,
+) -> ProtoMessage {
+    timeout(Duration::from_secs(5), async {
+        loop {
+            let message = messages.recv().await.expect("connection closed");
+            if matches(&message) {
+                return message;
+            }
+        }
+    })
+    .await
+    .expect("message not received in time")
+}
+
+async fn send_event(connection: &ClientConnection, event_type: PipelineEventType) {
+    connection
+        .send(ProtoMessage::VoiceAssistantEventResponse(
+            VoiceAssistantEventResponse {
+                event_type: event_type.into(),
+                data: vec![],
+            },
+        ))
+        .await
+        .expect("send failed");
+}
+
+async fn subscribe(connection: &ClientConnection, voice_assistant: &VoiceAssistant) {
+    connection
+        .send(ProtoMessage::SubscribeVoiceAssistantRequest(
+            SubscribeVoiceAssistantRequest {
+                subscribe: true,
+                flags: voice_assistant::SUBSCRIBE_API_AUDIO,
+            },
+        ))
+        .await
+        .expect("subscribe failed");
+    timeout(Duration::from_secs(5), voice_assistant.subscribed())
+        .await
+        .expect("not subscribed in time");
+}
+
+#[tokio::test]
+async fn test_voice_assistant_pipeline() {
+    let input = temp_file("pipeline_input.raw");
+    let output = temp_file("pipeline_output.raw");
+    let command: Vec<u8> = (0..=255).cycle().take(2500).collect();
+    std::fs::write(&input, &command).unwrap();
+
+    let audio = FileAudio::builder()
+        .input(input.clone())
+        .output(output.clone())
+        .build();
+    let voice_assistant = VoiceAssistant::builder().audio(Arc::new(audio)).build();
+    let (connection, _rx) = connect(voice_assistant.clone()).await;
+    assert_eq!(
+        connection
+            .device_info_response()
+            .voice_assistant_feature_flags,
+        voice_assistant.feature_flags()
+    );
+
+    let mut messages = connection.subscribe();
+    let mut events = voice_assistant.events();
+    assert!(matches!(
+        voice_assistant.start_pipeline(None).await,
+        Err(VoiceAssistantError::NotSubscribed)
+    ));
+    subscribe(&connection, &voice_assistant).await;
+
+    let satellite = voice_assistant.clone();
+    let started = tokio::spawn(async move {
+        satellite
+            .start_pipeline(Some("okay nabu".to_string()))
+            .await
+    });
+    let ProtoMessage::VoiceAssistantRequest(request) = next_message(&mut messages, |message| {
+        matches!(message, ProtoMessage::VoiceAssistantRequest(_))
+    })
+    .await
+    else {
+        unreachable!()
+    };
+    assert!(request.start);
+    assert_eq!(request.wake_word_phrase, "okay nabu");
+
+    connection
+        .send(ProtoMessage::VoiceAssistantResponse(
+            VoiceAssistantResponse {
+                port: 0,
+                error: false,
+            },
+        ))
+        .await
+        .expect("send failed");
+    started.await.unwrap().expect("pipeline not started");
+
+    // The microphone is streamed until its end.
+    let mut recorded = Vec::new();
+    loop {
+        let ProtoMessage::VoiceAssistantAudio(audio) = next_message(&mut messages, |message| {
+            matches!(message, ProtoMessage::VoiceAssistantAudio(_))
+        })
+        .await
+        else {
+            unreachable!()
+        };
+        recorded.extend(audio.data);
+        if audio.end {
+            break;
+        }
+    }
+    assert_eq!(recorded, command);
+
+    send_event(&connection, PipelineEventType::SttVadEnd).await;
+    send_event(&connection, PipelineEventType::TtsStreamStart).await;
+    for chunk in [vec![1, 2, 3], vec![4, 5]] {
+        connection
+            .send(ProtoMessage::VoiceAssistantAudio(VoiceAssistantAudio {
+                data: chunk,
+                end: false,
+            }))
+            .await
+            .expect("send failed");
+    }
+    send_event(&connection, PipelineEventType::TtsStreamEnd).await;
+    send_event(&connection, PipelineEventType::RunEnd).await;
+
+    let mut received = Vec::new();
+    while received.last() != Some(&PipelineEventType::RunEnd) {
+        let event = timeout(Duration::from_secs(5), events.recv())
+            .await
+            .expect("event not received in time")
+            .unwrap();
+        if let VoiceEvent::Pipeline(event) = event {
+            received.push(event.event_type);
+        }
+    }
+    assert_eq!(
+        received,
+        vec![
+            PipelineEventType::SttVadEnd,
+            PipelineEventType::TtsStreamStart,
+            PipelineEventType::TtsStreamEnd,
+            PipelineEventType::RunEnd,
+        ]
+    );
+    // The answer is played in the background.
+    wait_for_file(&output, &[1, 2, 3, 4, 5]).await;
+
+    // The pipeline is over, so a new one can be started.
+    let satellite = voice_assistant.clone();
+    let started = tokio::spawn(async move { satellite.start_pipeline(None).await });
+    next_message(
+        &mut messages,
+        |message| matches!(message, ProtoMessage::VoiceAssistantRequest(request) if request.start),
+    )
+    .await;
+    connection
+        .send(ProtoMessage::VoiceAssistantResponse(
+            VoiceAssistantResponse {
+                port: 0,
+                error: true,
+            },
+        ))
+        .await
+        .expect("send failed");
+    assert!(matches!(
+        started.await.unwrap(),
+        Err(VoiceAssistantError::Rejected)
+    ));
+
+    let _ = std::fs::remove_file(input);
+    let _ = std::fs::remove_file(output);
+}
+
+#[tokio::test]
+async fn test_voice_assistant_udp_pipeline() {
+    let input = temp_file("udp_input.raw");
+    let output = temp_file("udp_output.raw");
+    let command: Vec<u8> = (0..=255).cycle().take(3000).collect();
+    std::fs::write(&input, &command).unwrap();
+
+    let audio = FileAudio::builder()
+        .input(input.clone())
+        .output(output.clone())
+        .build();
+    let voice_assistant = VoiceAssistant::builder()
+        .audio(Arc::new(audio))
+        .api_audio(false)
+        .udp_host(IpAddr::V4(Ipv4Addr::LOCALHOST))
+        .build();
+    assert_eq!(
+        voice_assistant.feature_flags() & voice_assistant::FEATURE_API_AUDIO,
+        0
+    );
+    let (connection, _rx) = connect(voice_assistant.clone()).await;
+
+    let mut subscription = connection
+        .subscribe_voice_assistant()
+        .await
+        .expect("subscribe failed");
+    assert_eq!(subscription.transport(), AudioTransport::Udp);
+    timeout(Duration::from_secs(5), voice_assistant.subscribed())
+        .await
+        .expect("not subscribed in time");
+
+    let satellite = voice_assistant.clone();
+    let started = tokio::spawn(async move { satellite.start_pipeline(None).await });
+    let request = timeout(Duration::from_secs(5), subscription.recv_request())
+        .await
+        .expect("request not received in time")
+        .unwrap();
+    assert!(request.start);
+    subscription.accept().await.expect("accept failed");
+    started.await.unwrap().expect("pipeline not started");
+
+    let mut recorded = Vec::new();
+    while let Some(chunk) = timeout(Duration::from_secs(5), subscription.recv_audio())
+        .await
+        .expect("audio not received in time")
+        .expect("receiving audio failed")
+    {
+        recorded.extend(chunk);
+    }
+    assert_eq!(recorded, command);
+
+    subscription
+        .send_event(PipelineEventType::SttVadEnd, &[])
+        .await
+        .unwrap();
+    subscription.send_audio(vec![1, 2, 3]).await.unwrap();
+    subscription.send_audio(vec![4, 5]).await.unwrap();
+    subscription.end_audio().await.unwrap();
+    subscription
+        .send_event(PipelineEventType::RunEnd, &[])
+        .await
+        .unwrap();
+    wait_for_file(&output, &[1, 2, 3, 4, 5]).await;
+
+    let _ = std::fs::remove_file(input);
+    let _ = std::fs::remove_file(output);
+}
+
+#[tokio::test]
+async fn test_voice_assistant_api_transport_preferred() {
+    let input = temp_file("preferred_input.raw");
+    let output = temp_file("preferred_output.raw");
+    std::fs::write(&input, [1, 2, 3, 4]).unwrap();
+
+    let audio = FileAudio::builder()
+        .input(input.clone())
+        .output(output.clone())
+        .build();
+    let voice_assistant = VoiceAssistant::builder()
+        .audio(Arc::new(audio))
+        .udp_host(IpAddr::V4(Ipv4Addr::LOCALHOST))
+        .build();
+    let (connection, _rx) = connect(voice_assistant.clone()).await;
+
+    let mut subscription = connection
+        .subscribe_voice_assistant()
+        .await
+        .expect("subscribe failed");
+    assert_eq!(subscription.transport(), AudioTransport::Api);
+    timeout(Duration::from_secs(5), voice_assistant.subscribed())
+        .await
+        .expect("not subscribed in time");
+
+    let satellite = voice_assistant.clone();
+    let started = tokio::spawn(async move { satellite.start_pipeline(None).await });
+    timeout(Duration::from_secs(5), subscription.recv_request())
+        .await
+        .expect("request not received in time")
+        .unwrap();
+    subscription.accept().await.expect("accept failed");
+    started.await.unwrap().expect("pipeline not started");
+
+    let chunk = timeout(Duration::from_secs(5), subscription.recv_audio())
+        .await
+        .expect("audio not received in time")
+        .expect("receiving audio failed");
+    assert_eq!(chunk, Some(vec![1, 2, 3, 4]));
+
+    subscription.send_audio(vec![9, 8]).await.unwrap();
+    subscription.end_audio().await.unwrap();
+    wait_for_file(&output, &[9, 8]).await;
+
+    let _ = std::fs::remove_file(input);
+    let _ = std::fs::remove_file(output);
+}
+
+#[tokio::test]
+async fn test_voice_assistant_announcement() {
+    let input = temp_file("announcement_input.raw");
+    let output = temp_file("announcement_output.raw");
+    let media = temp_file("announcement_media.raw");
+    std::fs::write(&media, [7, 8, 9]).unwrap();
+
+    let audio = Arc::new(
+        FileAudio::builder()
+            .input(input.clone())
+            .output(output.clone())
+            .build(),
+    );
+    let voice_assistant = VoiceAssistant::builder().audio(audio.clone()).build();
+    let (connection, _rx) = connect(voice_assistant.clone()).await;
+    let mut messages = connection.subscribe();
+    subscribe(&connection, &voice_assistant).await;
+
+    let media_url = format!("file://{}", media.display());
+    connection
+        .send(ProtoMessage::VoiceAssistantAnnounceRequest(
+            VoiceAssistantAnnounceRequest {
+                media_id: media_url.clone(),
+                text: "Dinner is ready".to_string(),
+                preannounce_media_id: "http://homeassistant.local/chime.mp3".to_string(),
+                start_conversation: false,
+            },
+        ))
+        .await
+        .expect("send failed");
+
+    let ProtoMessage::VoiceAssistantAnnounceFinished(finished) =
+        next_message(&mut messages, |message| {
+            matches!(message, ProtoMessage::VoiceAssistantAnnounceFinished(_))
+        })
+        .await
+    else {
+        unreachable!()
+    };
+    assert!(finished.success);
+    assert_eq!(
+        audio.played_media(),
+        vec![
+            "http://homeassistant.local/chime.mp3".to_string(),
+            media_url
+        ]
+    );
+    assert_eq!(std::fs::read(&output).unwrap(), vec![7, 8, 9]);
+
+    connection
+        .send(ProtoMessage::VoiceAssistantAnnounceRequest(
+            VoiceAssistantAnnounceRequest {
+                media_id: temp_file("missing.raw").display().to_string(),
+                ..Default::default()
+            },
+        ))
+        .await
+        .expect("send failed");
+    let ProtoMessage::VoiceAssistantAnnounceFinished(finished) =
+        next_message(&mut messages, |message| {
+            matches!(message, ProtoMessage::VoiceAssistantAnnounceFinished(_))
+        })
+        .await
+    else {
+        unreachable!()
+    };
+    assert!(!finished.success);
+
+    let _ = std::fs::remove_file(output);
+    let _ = std::fs::remove_file(media);
+}
+
+/// Audio device whose media playback waits for a permit.
+struct GatedAudio {
+    permits: Semaphore,
+}
+
+impl AudioDevice for GatedAudio {
+    fn microphone(&self) -> BoxStream<'static, Vec<u8>> {
+        Box::pin(futures::stream::empty())
+    }
+
+    fn play(&self, _data: Vec<u8>) -> BoxFuture<'_, ()> {
+        Box::pin(async {})
+    }
+
+    fn end_of_stream(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async {})
+    }
+
+    fn play_media(&self, _url: String) -> BoxFuture<'_, std::io::Result<()>> {
+        Box::pin(async {
+            self.permits.acquire().await.unwrap().forget();
+            Ok(())
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_voice_assistant_announcement_does_not_block_requests() {
+    let audio = Arc::new(GatedAudio {
+        permits: Semaphore::new(0),
+    });
+    let voice_assistant = VoiceAssistant::builder().audio(audio.clone()).build();
+    let (connection, _rx) = connect(voice_assistant.clone()).await;
+    let mut messages = connection.subscribe();
+    subscribe(&connection, &voice_assistant).await;
+
+    connection
+        .send(ProtoMessage::VoiceAssistantAnnounceRequest(
+            VoiceAssistantAnnounceRequest {
+                media_id: "http://homeassistant.local/announcement.mp3".to_string(),
+                ..Default::default()
+            },
+        ))
+        .await
+        .expect("send failed");
+
+    // Answered while the announcement is still playing.
+    connection
+        .request(
+            ProtoMessage::VoiceAssistantConfigurationRequest(VoiceAssistantConfigurationRequest {}),
+            |message| match message {
+                ProtoMessage::VoiceAssistantConfigurationResponse(response) => Some(response),
+                _ => None,
+            },
+        )
+        .await
+        .expect("configuration request failed");
+
+    audio.permits.add_permits(1);
+    let ProtoMessage::VoiceAssistantAnnounceFinished(finished) =
+        next_message(&mut messages, |message| {
+            matches!(message, ProtoMessage::VoiceAssistantAnnounceFinished(_))
+        })
+        .await
+    else {
+        unreachable!()
+    };
+    assert!(finished.success);
+}
+
+#[tokio::test]
+async fn test_voice_assistant_configuration() {
+    let audio = FileAudio::builder()
+        .input(temp_file("configuration_input.raw"))
+        .output(temp_file("configuration_output.raw"))
+        .build();
+    let voice_assistant = VoiceAssistant::builder()
+        .audio(Arc::new(audio))
+        .wake_words(vec![wake_word("okay_nabu"), wake_word("hey_jarvis")])
+        .active_wake_words(vec!["okay_nabu".to_string()])
+        .build();
+    let (connection, _rx) = connect(voice_assistant.clone()).await;
+    let mut events = voice_assistant.events();
+
+    let configuration = connection
+        .request(
+            ProtoMessage::VoiceAssistantConfigurationRequest(VoiceAssistantConfigurationRequest {}),
+            |message| match message {
+                ProtoMessage::VoiceAssistantConfigurationResponse(response) => Some(response),
+                _ => None,
+            },
+        )
+        .await
+        .expect("configuration request failed");
+    assert_eq!(configuration.available_wake_words.len(), 2);
+    assert_eq!(configuration.active_wake_words, vec!["okay_nabu"]);
+    assert_eq!(configuration.max_active_wake_words, 1);
+
+    connection
+        .send(ProtoMessage::VoiceAssistantSetConfiguration(
+            VoiceAssistantSetConfiguration {
+                active_wake_words: vec!["hey_jarvis".to_string(), "unknown".to_string()],
+            },
+        ))
+        .await
+        .expect("send failed");
+    let event = timeout(Duration::from_secs(5), events.recv())
+        .await
+        .expect("event not received in time")
+        .unwrap();
+    assert!(matches!(
+        event,
+        VoiceEvent::WakeWordsChanged(active) if active == vec!["hey_jarvis"]
+    ));
+    assert_eq!(voice_assistant.active_wake_words(), vec!["hey_jarvis"]);
+}